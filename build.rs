@@ -85,8 +85,14 @@ fn main() {
         .status()
         .unwrap();
 
+    Command::new("scripts/generate_syscalls.py")
+        .arg(path.join("assembly_templates_generated.rs"))
+        .status()
+        .unwrap();
+
     println!("cargo:rerun-if-changed=scripts/generate_syscalls.py");
     println!("cargo:rerun-if-changed=scripts/syscalls.py");
+    println!("cargo:rerun-if-changed=scripts/assembly_templates.py");
 
     let signal_bindings = Builder::default()
         .parse_callbacks(Box::new(CargoCallbacks))
@@ -22,6 +22,10 @@ class Field(object):
         types = { 8: 'uint64_t', 4: 'uint32_t', 2: 'uint16_t', 1: 'uint8_t' }
         return types[self.byte_length]
 
+    def rust_type(self):
+        types = { 8: 'u64', 4: 'u32', 2: 'u16', 1: 'u8' }
+        return types[self.byte_length]
+
 class AssemblyTemplate(object):
     """A sequence of RawBytes and Field objects, which can be used to verify
     that a given sequence of assembly instructions matches the RawBytes while
@@ -262,3 +266,70 @@ public:
         'field_end_methods': generate_field_end_methods(byte_array, template),
         'size_member': generate_size_member(byte_array), })
         f.write('\n\n')
+
+def generate_rust_match_method(template):
+    s = StringIO()
+    s.write('    pub fn matches(buffer: &[u8]) -> bool {\n')
+    s.write('        if buffer.len() < Self::SIZE {\n')
+    s.write('            return false;\n')
+    s.write('        }\n')
+    offset = 0
+    for chunk in template.chunks:
+        if not isinstance(chunk, Field):
+            s.write('        if buffer[%d..%d] != Self::BYTES[%d..%d] {\n'
+                    % (offset, offset + len(chunk), offset, offset + len(chunk)))
+            s.write('            return false;\n')
+            s.write('        }\n')
+        offset += len(chunk)
+    s.write('        true\n')
+    s.write('    }')
+    return s.getvalue()
+
+def generate_rust_substitute_method(template):
+    s = StringIO()
+    fields = template.fields()
+    args = ''.join(', %s: %s' % (f.name, f.rust_type()) for f in fields)
+
+    s.write('    pub fn substitute(buffer: &mut [u8]%s) {\n' % (args,))
+    offset = 0
+    for chunk in template.chunks:
+        if isinstance(chunk, Field):
+            s.write('        buffer[%d..%d].copy_from_slice(&%s.to_le_bytes());\n'
+                    % (offset, offset + len(chunk), chunk.name))
+        else:
+            s.write('        buffer[%d..%d].copy_from_slice(&Self::BYTES[%d..%d]);\n'
+                    % (offset, offset + len(chunk), offset, offset + len(chunk)))
+        offset += len(chunk)
+    s.write('    }')
+    return s.getvalue()
+
+def generate_rust_field_end_consts(template):
+    s = StringIO()
+    offset = 0
+    for chunk in template.chunks:
+        offset += len(chunk)
+        if isinstance(chunk, Field):
+            s.write('    pub const %s_END: usize = %d;\n' % (chunk.name.upper(), offset))
+    return s.getvalue()
+
+def generate_rust(f):
+    f.write("// This file has been autogenerated. DO NOT MODIFY!\n")
+    for name, template in templates.items():
+        bytes = template.bytes()
+        f.write("""pub struct %(struct_name)s;
+
+impl %(struct_name)s {
+    const BYTES: [u8; %(size)d] = [%(bytes)s];
+    pub const SIZE: usize = %(size)d;
+%(field_end_consts)s
+%(match_method)s
+
+%(substitute_method)s
+}
+
+""" % { 'struct_name': name,
+        'size': len(bytes),
+        'bytes': ', '.join(['0x%x' % b for b in bytes]),
+        'field_end_consts': generate_rust_field_end_consts(template),
+        'match_method': generate_rust_match_method(template),
+        'substitute_method': generate_rust_substitute_method(template), })
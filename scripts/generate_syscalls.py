@@ -185,6 +185,7 @@ generators_for = {
     'syscall_name_arch_x64_generated': lambda f: write_syscallname_arch(f, 'x64'),
     'SyscallRecordCase': write_syscall_record_cases,
     'syscall_helper_functions_generated': write_syscall_helper_functions,
+    'assembly_templates_generated': assembly_templates.generate_rust,
 }
 
 def main(argv):
@@ -1679,9 +1679,35 @@ sched_rr_get_interval_time64 = UnsupportedSyscall(x86=423)
 
 # x86-64 decided to skip ahead here to catchup
 pidfd_send_signal = UnsupportedSyscall(x86=424, x64=424)
-io_uring_setup = UnsupportedSyscall(x86=425, x64=425)
-io_uring_enter = UnsupportedSyscall(x86=426, x64=426)
-io_uring_register = UnsupportedSyscall(x86=427, x64=427)
+
+#  int io_uring_setup(u32 entries, struct io_uring_params *p);
+#
+# The io_uring_setup() system call sets up a submission queue (SQ) and
+# completion queue (CQ) with at least entries entries, and returns a
+# file descriptor which can be used to perform subsequent operations on
+# the io_uring instance.  The submission and completion queues are
+# shared between userspace and the kernel, which eliminates the need to
+# copy data when initiating and completing I/O.
+io_uring_setup = IrregularEmulatedSyscall(x86=425, x64=425)
+
+#  int io_uring_enter(unsigned int fd, unsigned int to_submit,
+#                     unsigned int min_complete, unsigned int flags,
+#                     sigset_t *sig);
+#
+# io_uring_enter() is used to initiate and complete I/O using the shared
+# submission and completion queues setup by a call to io_uring_setup(2).
+# A single call can both submit new I/O and wait for completions of I/O
+# initiated by this call or previous calls to io_uring_enter().
+io_uring_enter = IrregularEmulatedSyscall(x86=426, x64=426)
+
+#  int io_uring_register(unsigned int fd, unsigned int opcode,
+#                        void *arg, unsigned int nr_args);
+#
+# The io_uring_register() system call registers resources (e.g. user
+# buffers, files, eventfd, personality, restrictions) for use in an
+# io_uring(7) instance referenced by fd.
+io_uring_register = IrregularEmulatedSyscall(x86=427, x64=427)
+
 open_tree = UnsupportedSyscall(x86=428, x64=428)
 move_mount = UnsupportedSyscall(x86=429, x64=429)
 fsopen = UnsupportedSyscall(x86=430, x64=430)
@@ -1689,6 +1715,31 @@ fsconfig = UnsupportedSyscall(x86=431, x64=431)
 fsmount = UnsupportedSyscall(x86=432, x64=432)
 fspick = UnsupportedSyscall(x86=433, x64=433)
 
+#  int pidfd_open(pid_t pid, unsigned int flags);
+#
+# The pidfd_open() system call creates a file descriptor that refers to
+# the process whose PID is specified in pid.  The file descriptor is
+# returned as the function result; the close-on-exec flag is set on the
+# file descriptor.
+pidfd_open = EmulatedSyscall(x86=434, x64=434)
+
+#  long clone3(struct clone_args *cl_args, size_t size);
+#
+# The clone3() system call provides a superset of the functionality of
+# the older clone() interface.
+#
+# We make it fail with ENOSYS during recording so that the C library
+# falls back to clone().
+clone3 = IrregularEmulatedSyscall(x86=435, x64=435)
+
+#  long openat2(int dirfd, const char *pathname, struct open_how *how,
+#               size_t size);
+#
+# The openat2() system call is an extension of openat(2) and provides a
+# superset of its functionality.  Unlike openat(2), the flags and mode
+# are passed in struct open_how.
+openat2 = IrregularEmulatedSyscall(x86=437, x64=437)
+
 # restart_syscall is a little special.
 restart_syscall = RestartSyscall(x86=0, x64=219)
 
@@ -1,6 +1,7 @@
 use crate::{
+    bindings::kernel::sock_filter,
     kernel_abi::{
-        common::preload_interface::preload_globals,
+        common::preload_interface::{preload_globals, syscall_patch_hook},
         x64,
         x86,
         CloneParameterOrdering,
@@ -12,7 +13,7 @@ use crate::{
     kernel_supplement::{CLD_STOPPED, CLD_TRAPPED},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
-    session::task::record_task::{record_task::RecordTask, EmulatedStopType},
+    session::task::record_task::{record_task::RecordTask, EmulatedStopType, SyscallbufCodeLayout},
 };
 use std::{
     convert::{TryFrom, TryInto},
@@ -527,6 +528,9 @@ pub trait Architecture {
     const FSCONFIG: i32;
     const FSMOUNT: i32;
     const FSPICK: i32;
+    const PIDFD_OPEN: i32;
+    const CLONE3: i32;
+    const OPENAT2: i32;
     const RDCALL_INIT_PRELOAD: i32;
     const RDCALL_INIT_BUFFERS: i32;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32;
@@ -552,6 +556,9 @@ pub trait Architecture {
     #[allow(non_camel_case_types)]
     type msghdr: Copy + Default + 'static;
 
+    #[allow(non_camel_case_types)]
+    type sock_fprog: Copy + Default + 'static;
+
     #[allow(non_camel_case_types)]
     type cmsghdr: Copy + Default + 'static;
 
@@ -572,6 +579,9 @@ pub trait Architecture {
     #[allow(non_camel_case_types)]
     type rdcall_init_preload_params: Copy + 'static;
 
+    #[allow(non_camel_case_types)]
+    type rdcall_init_buffers_params: Copy + 'static;
+
     #[allow(non_camel_case_types)]
     type user_regs_struct: Copy;
 
@@ -595,6 +605,10 @@ pub trait Architecture {
 
     fn get_iovec(msgdata: &Self::iovec) -> (RemotePtr<Void>, usize);
 
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (RemotePtr<sock_filter>, usize);
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, filter: RemotePtr<sock_filter>, len: usize);
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -612,6 +626,30 @@ pub trait Architecture {
     fn rdcall_init_preload_params_globals(
         params: &Self::rdcall_init_preload_params,
     ) -> (RemotePtr<preload_globals>, RemoteCodePtr, usize);
+
+    fn rdcall_init_preload_params_syscall_patch_hooks(
+        params: &Self::rdcall_init_preload_params,
+    ) -> (RemotePtr<syscall_patch_hook>, usize);
+
+    fn rdcall_init_preload_params_syscallhook_vsyscall_entry(
+        params: &Self::rdcall_init_preload_params,
+    ) -> RemotePtr<Void>;
+
+    fn rdcall_init_preload_params_code_layout(
+        params: &Self::rdcall_init_preload_params,
+    ) -> SyscallbufCodeLayout;
+
+    fn rdcall_init_buffers_params_desched_counter_fd(
+        params: &Self::rdcall_init_buffers_params,
+    ) -> i32;
+
+    fn set_rdcall_init_buffers_params(
+        params: &mut Self::rdcall_init_buffers_params,
+        syscallbuf_ptr: RemotePtr<Void>,
+        syscallbuf_size: usize,
+        scratch_buf: RemotePtr<Void>,
+        usable_scratch_size: usize,
+    );
 }
 impl Architecture for X86Arch {
     const MMAP_SEMANTICS: MmapCallingSemantics = x86::MMAP_SEMANTICS;
@@ -1053,13 +1091,16 @@ impl Architecture for X86Arch {
     const FSCONFIG: i32 = 431;
     const FSMOUNT: i32 = 432;
     const FSPICK: i32 = 433;
+    const PIDFD_OPEN: i32 = 434;
+    const CLONE3: i32 = 435;
+    const OPENAT2: i32 = 437;
     const RDCALL_INIT_PRELOAD: i32 = 442;
     const RDCALL_INIT_BUFFERS: i32 = 443;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32 = 444;
     const RDCALL_NOTIFY_CONTROL_MSG: i32 = 445;
     const RDCALL_RELOAD_AUXV: i32 = 446;
     const RDCALL_MPROTECT_RECORD: i32 = 447;
-    const VALID_SYSCALL_COUNT: i32 = 419;
+    const VALID_SYSCALL_COUNT: i32 = 422;
     const INVALID_SYSCALL_COUNT: i32 = 17;
     // End list from generate_syscalls.py. See above.
 
@@ -1067,12 +1108,14 @@ impl Architecture for X86Arch {
     type signed_long = x86::signed_long;
     type unsigned_long = x86::unsigned_long;
     type iovec = x86::iovec;
+    type sock_fprog = x86::sock_fprog;
     type msghdr = x86::msghdr;
     type cmsghdr = x86::cmsghdr;
     type siginfo_t = x86::siginfo_t;
     type sockaddr_un = x86::sockaddr_un;
     type unsigned_word = x86::unsigned_word;
     type rdcall_init_preload_params = x86::preload_interface::rdcall_init_preload_params;
+    type rdcall_init_buffers_params = x86::preload_interface::rdcall_init_buffers_params;
     type user_regs_struct = x86::user_regs_struct;
     type user_fpregs_struct = x86::user_fpregs_struct;
     type user = x86::user;
@@ -1106,6 +1149,15 @@ impl Architecture for X86Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (RemotePtr<sock_filter>, usize) {
+        (RemotePtr::cast(prog.filter.rptr()), prog.len as usize)
+    }
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, filter: RemotePtr<sock_filter>, len: usize) {
+        prog.filter = RemotePtr::<x86::sock_filter>::cast(filter).into();
+        prog.len = len.try_into().unwrap();
+    }
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -1153,6 +1205,57 @@ impl Architecture for X86Arch {
             params.breakpoint_table_entry_size.try_into().unwrap(),
         )
     }
+
+    fn rdcall_init_preload_params_syscall_patch_hooks(
+        params: &Self::rdcall_init_preload_params,
+    ) -> (RemotePtr<syscall_patch_hook>, usize) {
+        (
+            params.syscall_patch_hooks.rptr(),
+            params.syscall_patch_hook_count.try_into().unwrap(),
+        )
+    }
+
+    fn rdcall_init_preload_params_syscallhook_vsyscall_entry(
+        params: &Self::rdcall_init_preload_params,
+    ) -> RemotePtr<Void> {
+        RemotePtr::cast(params.syscallhook_vsyscall_entry.rptr())
+    }
+
+    fn rdcall_init_preload_params_code_layout(
+        params: &Self::rdcall_init_preload_params,
+    ) -> SyscallbufCodeLayout {
+        SyscallbufCodeLayout {
+            syscallbuf_code_start: params.syscallbuf_code_start.rptr().to_code_ptr(),
+            syscallbuf_code_end: params.syscallbuf_code_end.rptr().to_code_ptr(),
+            get_pc_thunks_start: params.get_pc_thunks_start.rptr().to_code_ptr(),
+            get_pc_thunks_end: params.get_pc_thunks_end.rptr().to_code_ptr(),
+            syscallbuf_final_exit_instruction: params
+                .syscallbuf_final_exit_instruction
+                .rptr()
+                .to_code_ptr(),
+        }
+    }
+
+    fn rdcall_init_buffers_params_desched_counter_fd(
+        params: &Self::rdcall_init_buffers_params,
+    ) -> i32 {
+        params.desched_counter_fd
+    }
+
+    fn set_rdcall_init_buffers_params(
+        params: &mut Self::rdcall_init_buffers_params,
+        syscallbuf_ptr: RemotePtr<Void>,
+        syscallbuf_size: usize,
+        scratch_buf: RemotePtr<Void>,
+        usable_scratch_size: usize,
+    ) {
+        // DIFF NOTE: We don't support cloning file data into the trace yet.
+        params.cloned_file_data_fd = -1;
+        params.syscallbuf_ptr = RemotePtr::<u8>::cast(syscallbuf_ptr).into();
+        params.syscallbuf_size = syscallbuf_size.try_into().unwrap();
+        params.scratch_buf = RemotePtr::<u8>::cast(scratch_buf).into();
+        params.usable_scratch_size = usable_scratch_size.try_into().unwrap();
+    }
 }
 
 impl Architecture for X64Arch {
@@ -1595,13 +1698,16 @@ impl Architecture for X64Arch {
     const FSCONFIG: i32 = 431;
     const FSMOUNT: i32 = 432;
     const FSPICK: i32 = 433;
+    const PIDFD_OPEN: i32 = 434;
+    const CLONE3: i32 = 435;
+    const OPENAT2: i32 = 437;
     const RDCALL_INIT_PRELOAD: i32 = 442;
     const RDCALL_INIT_BUFFERS: i32 = 443;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32 = 444;
     const RDCALL_NOTIFY_CONTROL_MSG: i32 = 445;
     const RDCALL_RELOAD_AUXV: i32 = 446;
     const RDCALL_MPROTECT_RECORD: i32 = 447;
-    const VALID_SYSCALL_COUNT: i32 = 350;
+    const VALID_SYSCALL_COUNT: i32 = 353;
     const INVALID_SYSCALL_COUNT: i32 = 86;
     // End list from generate_syscalls.py. See above.
 
@@ -1609,12 +1715,14 @@ impl Architecture for X64Arch {
     type signed_long = x64::signed_long;
     type unsigned_long = x64::unsigned_long;
     type iovec = x64::iovec;
+    type sock_fprog = x64::sock_fprog;
     type msghdr = x64::msghdr;
     type cmsghdr = x64::cmsghdr;
     type siginfo_t = x64::siginfo_t;
     type sockaddr_un = x64::sockaddr_un;
     type unsigned_word = x64::unsigned_word;
     type rdcall_init_preload_params = x64::preload_interface::rdcall_init_preload_params;
+    type rdcall_init_buffers_params = x64::preload_interface::rdcall_init_buffers_params;
     type user_regs_struct = x64::user_regs_struct;
     type user_fpregs_struct = x64::user_fpregs_struct;
     type user = x64::user;
@@ -1646,6 +1754,15 @@ impl Architecture for X64Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (RemotePtr<sock_filter>, usize) {
+        (RemotePtr::cast(prog.filter.rptr()), prog.len as usize)
+    }
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, filter: RemotePtr<sock_filter>, len: usize) {
+        prog.filter = RemotePtr::<x64::sock_filter>::cast(filter).into();
+        prog.len = len.try_into().unwrap();
+    }
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -1692,4 +1809,55 @@ impl Architecture for X64Arch {
             params.breakpoint_table_entry_size.try_into().unwrap(),
         )
     }
+
+    fn rdcall_init_preload_params_syscall_patch_hooks(
+        params: &Self::rdcall_init_preload_params,
+    ) -> (RemotePtr<syscall_patch_hook>, usize) {
+        (
+            params.syscall_patch_hooks.rptr(),
+            params.syscall_patch_hook_count.try_into().unwrap(),
+        )
+    }
+
+    fn rdcall_init_preload_params_syscallhook_vsyscall_entry(
+        params: &Self::rdcall_init_preload_params,
+    ) -> RemotePtr<Void> {
+        RemotePtr::cast(params.syscallhook_vsyscall_entry.rptr())
+    }
+
+    fn rdcall_init_preload_params_code_layout(
+        params: &Self::rdcall_init_preload_params,
+    ) -> SyscallbufCodeLayout {
+        SyscallbufCodeLayout {
+            syscallbuf_code_start: params.syscallbuf_code_start.rptr().to_code_ptr(),
+            syscallbuf_code_end: params.syscallbuf_code_end.rptr().to_code_ptr(),
+            get_pc_thunks_start: params.get_pc_thunks_start.rptr().to_code_ptr(),
+            get_pc_thunks_end: params.get_pc_thunks_end.rptr().to_code_ptr(),
+            syscallbuf_final_exit_instruction: params
+                .syscallbuf_final_exit_instruction
+                .rptr()
+                .to_code_ptr(),
+        }
+    }
+
+    fn rdcall_init_buffers_params_desched_counter_fd(
+        params: &Self::rdcall_init_buffers_params,
+    ) -> i32 {
+        params.desched_counter_fd
+    }
+
+    fn set_rdcall_init_buffers_params(
+        params: &mut Self::rdcall_init_buffers_params,
+        syscallbuf_ptr: RemotePtr<Void>,
+        syscallbuf_size: usize,
+        scratch_buf: RemotePtr<Void>,
+        usable_scratch_size: usize,
+    ) {
+        // DIFF NOTE: We don't support cloning file data into the trace yet.
+        params.cloned_file_data_fd = -1;
+        params.syscallbuf_ptr = RemotePtr::<u8>::cast(syscallbuf_ptr).into();
+        params.syscallbuf_size = syscallbuf_size.try_into().unwrap();
+        params.scratch_buf = RemotePtr::<u8>::cast(scratch_buf).into();
+        params.usable_scratch_size = usable_scratch_size.try_into().unwrap();
+    }
 }
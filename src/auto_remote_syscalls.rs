@@ -31,6 +31,7 @@ use crate::{
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_profiler::{self, ProfileCategory},
     scoped_fd::ScopedFd,
     session::{
         address_space::{
@@ -757,6 +758,9 @@ impl<'a> AutoRemoteSyscalls<'a> {
     /// The syscall is finished in `t` and the result is returned.
     pub fn syscall_base(&mut self, syscallno: i32, callregs: &mut Registers) -> isize {
         log!(LogDebug, "syscall {}", syscall_name(syscallno, self.arch()));
+        let _timer = replay_profiler::timer(ProfileCategory::RemoteSyscall, || {
+            syscall_name(syscallno, self.arch())
+        });
 
         if callregs.arg1_signed() == SIGTRAP as isize
             && self.use_singlestep_path
@@ -1250,7 +1254,7 @@ fn is_usable_area(km: &KernelMapping) -> bool {
         && (km.flags().contains(MapFlags::MAP_PRIVATE))
 }
 
-fn ignore_signal(t: &dyn Task) -> bool {
+fn ignore_signal(t: &mut dyn Task) -> bool {
     let maybe_sig: MaybeStopSignal = t.maybe_stop_sig();
     if !maybe_sig.is_sig() {
         return false;
@@ -1261,7 +1265,7 @@ fn ignore_signal(t: &dyn Task) -> bool {
             return true;
         }
     } else if t.session().is_recording() {
-        let rt = t.as_record_task().unwrap();
+        let rt = t.as_record_task_mut().unwrap();
         // Better to use unwrap_sig() here as we've already made sure that maybe_sig.is_sig() above.
         if maybe_sig.unwrap_sig()
             != rt.session().as_record().unwrap().syscallbuf_desched_sig() as i32
@@ -1515,10 +1519,10 @@ fn extract_name(name: &OsStr) -> Option<&OsStr> {
     None
 }
 
-fn is_sigtrap_default_and_unblocked(t: &dyn Task) -> bool {
+fn is_sigtrap_default_and_unblocked(t: &mut dyn Task) -> bool {
     if !t.session().is_recording() {
         return true;
     }
-    let rt = t.as_record_task().unwrap();
+    let rt = t.as_record_task_mut().unwrap();
     rt.sig_disposition(SIGTRAP) == SignalDisposition::SignalDefault && !rt.is_sig_blocked(SIGTRAP)
 }
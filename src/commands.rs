@@ -1,11 +1,19 @@
 use std::io;
 
 pub mod build_id_command;
+pub mod cpu_compat_command;
+pub mod cpu_features_command;
 pub mod dump_command;
+pub mod gc_command;
+pub mod ls_command;
 pub mod ps_command;
 pub mod rd_options;
+pub mod record_command;
 pub mod replay_command;
+pub mod replay_progress;
 pub mod rerun_command;
+pub mod rm_command;
+pub mod tag_command;
 pub mod trace_info_command;
 
 pub trait RdCommand {
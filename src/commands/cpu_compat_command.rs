@@ -0,0 +1,369 @@
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    kernel_metadata::xsave_feature_string,
+    perf_counters::PerfCounters,
+    session::session_inner::session_inner::SessionInner,
+    trace::trace_reader::TraceReader,
+    util::{
+        all_cpuid_records,
+        cpuid_compatible,
+        find_cpuid_record,
+        xcr0,
+        CPUIDRecord,
+        CPUID_GETEXTENDEDFEATURES,
+        CPUID_GETFEATURES,
+        CPUID_GETXSAVE,
+        CPUID_INTELFEATURES,
+        OSXSAVE_FEATURE_FLAG,
+    },
+};
+use std::{
+    io,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+pub struct CpuCompatCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl CpuCompatCommand {
+    pub fn new(options: &RdOptions) -> CpuCompatCommand {
+        match options.cmd.clone() {
+            RdSubCommand::CpuCompat { trace_dir } => CpuCompatCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `CpuCompat` variant!"),
+        }
+    }
+}
+
+impl RdCommand for CpuCompatCommand {
+    fn run(&mut self) -> io::Result<()> {
+        let trace = TraceReader::new(self.trace_dir.as_ref());
+        let mut out = stdout();
+        if self.cpu_compat(&trace, &mut out)? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Trace cannot be replayed on this machine",
+            ))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Reg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Reg {
+    fn name(self) -> &'static str {
+        match self {
+            Reg::Eax => "eax",
+            Reg::Ebx => "ebx",
+            Reg::Ecx => "ecx",
+            Reg::Edx => "edx",
+        }
+    }
+
+    fn value(self, record: &CPUIDRecord) -> u32 {
+        match self {
+            Reg::Eax => record.out.eax,
+            Reg::Ebx => record.out.ebx,
+            Reg::Ecx => record.out.ecx,
+            Reg::Edx => record.out.edx,
+        }
+    }
+}
+
+/// The CPUID feature words that `rd cpufeatures` can mask. A tracee that saw a bit set in one
+/// of these during recording may have picked a code path that needs it.
+const FEATURE_WORDS: [(u32, u32, Reg); 8] = [
+    (CPUID_GETFEATURES, 0, Reg::Ecx),
+    (CPUID_GETFEATURES, 0, Reg::Edx),
+    (CPUID_GETEXTENDEDFEATURES, 0, Reg::Ebx),
+    (CPUID_GETEXTENDEDFEATURES, 0, Reg::Ecx),
+    (CPUID_GETEXTENDEDFEATURES, 0, Reg::Edx),
+    (CPUID_GETXSAVE, 1, Reg::Eax),
+    (CPUID_INTELFEATURES, 0, Reg::Ecx),
+    (CPUID_INTELFEATURES, 0, Reg::Edx),
+];
+
+/// Names for the feature bits that commonly decide which code path glibc and friends take.
+/// Bits not listed here are still reported, just by number.
+const FEATURE_NAMES: &[(u32, u32, u32, &str)] = &[
+    (CPUID_GETFEATURES, 2, 0, "SSE3"),
+    (CPUID_GETFEATURES, 2, 1, "PCLMULQDQ"),
+    (CPUID_GETFEATURES, 2, 9, "SSSE3"),
+    (CPUID_GETFEATURES, 2, 12, "FMA"),
+    (CPUID_GETFEATURES, 2, 13, "CMPXCHG16B"),
+    (CPUID_GETFEATURES, 2, 19, "SSE4.1"),
+    (CPUID_GETFEATURES, 2, 20, "SSE4.2"),
+    (CPUID_GETFEATURES, 2, 22, "MOVBE"),
+    (CPUID_GETFEATURES, 2, 23, "POPCNT"),
+    (CPUID_GETFEATURES, 2, 25, "AES"),
+    (CPUID_GETFEATURES, 2, 26, "XSAVE"),
+    (CPUID_GETFEATURES, 2, 27, "OSXSAVE"),
+    (CPUID_GETFEATURES, 2, 28, "AVX"),
+    (CPUID_GETFEATURES, 2, 29, "F16C"),
+    (CPUID_GETFEATURES, 2, 30, "RDRAND"),
+    (CPUID_GETFEATURES, 3, 4, "TSC"),
+    (CPUID_GETFEATURES, 3, 8, "CX8"),
+    (CPUID_GETFEATURES, 3, 15, "CMOV"),
+    (CPUID_GETFEATURES, 3, 19, "CLFLUSH"),
+    (CPUID_GETFEATURES, 3, 23, "MMX"),
+    (CPUID_GETFEATURES, 3, 24, "FXSR"),
+    (CPUID_GETFEATURES, 3, 25, "SSE"),
+    (CPUID_GETFEATURES, 3, 26, "SSE2"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 0, "FSGSBASE"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 3, "BMI1"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 4, "HLE"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 5, "AVX2"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 8, "BMI2"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 9, "ERMS"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 11, "RTM"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 16, "AVX512F"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 17, "AVX512DQ"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 18, "RDSEED"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 19, "ADX"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 23, "CLFLUSHOPT"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 24, "CLWB"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 29, "SHA"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 30, "AVX512BW"),
+    (CPUID_GETEXTENDEDFEATURES, 1, 31, "AVX512VL"),
+    (CPUID_GETEXTENDEDFEATURES, 2, 1, "AVX512VBMI"),
+    (CPUID_GETEXTENDEDFEATURES, 2, 4, "OSPKE"),
+    (CPUID_GETEXTENDEDFEATURES, 2, 9, "VAES"),
+    (CPUID_GETEXTENDEDFEATURES, 2, 10, "VPCLMULQDQ"),
+    (CPUID_GETEXTENDEDFEATURES, 2, 22, "RDPID"),
+    (CPUID_GETEXTENDEDFEATURES, 3, 4, "FSRM"),
+    (CPUID_GETXSAVE, 0, 0, "XSAVEOPT"),
+    (CPUID_GETXSAVE, 0, 1, "XSAVEC"),
+    (CPUID_GETXSAVE, 0, 2, "XGETBV_ECX1"),
+    (CPUID_GETXSAVE, 0, 3, "XSAVES"),
+    (CPUID_INTELFEATURES, 2, 0, "LAHF_LM"),
+    (CPUID_INTELFEATURES, 2, 5, "LZCNT"),
+    (CPUID_INTELFEATURES, 3, 27, "RDTSCP"),
+    (CPUID_INTELFEATURES, 3, 29, "LM"),
+];
+
+fn feature_name(leaf: u32, reg: Reg, bit: u32) -> Option<&'static str> {
+    let reg_index = reg as u32;
+    FEATURE_NAMES
+        .iter()
+        .find(|&&(l, r, b, _)| l == leaf && r == reg_index && b == bit)
+        .map(|&(_, _, _, name)| name)
+}
+
+/// The bits of CPUID leaf 1 eax that `cpuid_compatible()` compares: model, family and
+/// extended model. Stepping, type and extended family are ignored.
+fn describe_cpu_type(eax: u32) -> String {
+    format!(
+        "family {:#x} model {:#x} (eax {:#x})",
+        (eax >> 8) & 0xf,
+        ((eax >> 12) & 0xf0) | ((eax >> 4) & 0xf),
+        eax
+    )
+}
+
+impl CpuCompatCommand {
+    /// Writes a report comparing the CPU the trace was recorded on with this one. Returns
+    /// false if replay is certain to be refused or to diverge.
+    fn cpu_compat(&self, trace: &TraceReader, out: &mut dyn Write) -> io::Result<bool> {
+        let trace_records = trace.cpuid_records();
+        let our_records = all_cpuid_records();
+        let have_cpuid_faulting = SessionInner::has_cpuid_faulting();
+        let mut fatal = 0;
+        let mut warnings = 0;
+
+        write!(out, "Trace: {}\n", trace.dir().to_string_lossy())?;
+        write!(
+            out,
+            "CPUID faulting: {} during recording, {} here\n",
+            if trace.uses_cpuid_faulting() {
+                "used"
+            } else {
+                "not used"
+            },
+            if have_cpuid_faulting {
+                "available"
+            } else {
+                "not available"
+            }
+        )?;
+
+        if trace.uses_cpuid_faulting() && !have_cpuid_faulting {
+            write!(
+                out,
+                "FATAL: the trace was recorded with CPUID faulting, which this machine does not \
+                 support\n"
+            )?;
+            fatal += 1;
+        }
+
+        if !PerfCounters::supports_ticks_semantics(trace.ticks_semantics()) {
+            write!(
+                out,
+                "FATAL: the trace counts ticks as {:?}, which this machine's PMU cannot do\n",
+                trace.ticks_semantics()
+            )?;
+            fatal += 1;
+        }
+
+        // Microarchitecture.
+        match find_cpuid_record(trace_records, CPUID_GETFEATURES, 0) {
+            None => {
+                write!(
+                    out,
+                    "FATAL: the trace has no CPUID leaf {:#x} record\n",
+                    CPUID_GETFEATURES
+                )?;
+                return Ok(false);
+            }
+            Some(trace_record) => {
+                if !cpuid_compatible(trace_records) {
+                    let ours = find_cpuid_record(&our_records, CPUID_GETFEATURES, 0).unwrap();
+                    write!(
+                        out,
+                        "{}: CPUID leaf {:#x} eax: recorded on {}, this machine is {}\n",
+                        if have_cpuid_faulting { "NOTE" } else { "FATAL" },
+                        CPUID_GETFEATURES,
+                        describe_cpu_type(trace_record.out.eax),
+                        describe_cpu_type(ours.out.eax)
+                    )?;
+                    if have_cpuid_faulting {
+                        write!(
+                            out,
+                            "      CPUID faulting will present the recorded values to tracees\n"
+                        )?;
+                    } else {
+                        write!(
+                            out,
+                            "      replay refuses a different microarchitecture unless CPUID \
+                             faulting is available\n"
+                        )?;
+                        fatal += 1;
+                    }
+                }
+            }
+        }
+
+        // Feature bits the tracee was told about but that this CPU lacks. Even with CPUID
+        // faulting the tracee will execute instructions that don't exist here.
+        for &(leaf, subleaf, reg) in FEATURE_WORDS.iter() {
+            let trace_value = match find_cpuid_record(trace_records, leaf, subleaf) {
+                Some(r) => reg.value(r),
+                None => continue,
+            };
+            let our_value = match find_cpuid_record(&our_records, leaf, subleaf) {
+                Some(r) => reg.value(r),
+                None => 0,
+            };
+            let missing = trace_value & !our_value;
+            for bit in 0..32 {
+                if missing & (1 << bit) == 0 {
+                    continue;
+                }
+                write!(
+                    out,
+                    "FATAL: CPUID leaf {:#x} subleaf {} {} bit {}{} was set during recording \
+                     but is not supported here\n",
+                    leaf,
+                    subleaf,
+                    reg.name(),
+                    bit,
+                    match feature_name(leaf, reg, bit) {
+                        Some(name) => format!(" ({})", name),
+                        None => String::new(),
+                    }
+                )?;
+                fatal += 1;
+            }
+        }
+
+        // XSAVE. The dynamic loader examines XCR0 and sizes its save areas accordingly, so any
+        // difference here is practically guaranteed to diverge.
+        let tracee_xsave = find_cpuid_record(trace_records, CPUID_GETFEATURES, 0)
+            .map_or(false, |r| r.out.ecx & OSXSAVE_FEATURE_FLAG != 0);
+        if tracee_xsave {
+            let trace_xcr0 = trace.xcr0();
+            let our_xcr0 = xcr0();
+            write!(
+                out,
+                "XCR0: trace {:#x} ({}), here {:#x} ({})\n",
+                trace_xcr0,
+                xsave_feature_string(trace_xcr0),
+                our_xcr0,
+                xsave_feature_string(our_xcr0)
+            )?;
+            let missing = trace_xcr0 & !our_xcr0;
+            if missing != 0 {
+                write!(
+                    out,
+                    "FATAL: XSAVE features enabled during recording but not here: {}\n",
+                    xsave_feature_string(missing)
+                )?;
+                fatal += 1;
+            }
+            let extra = our_xcr0 & !trace_xcr0;
+            if extra != 0 {
+                write!(
+                    out,
+                    "WARNING: XSAVE features enabled here but not during recording: {}\n\
+                     \x20        glibc's dynamic loader reads XCR0, so replay will probably \
+                     diverge\n",
+                    xsave_feature_string(extra)
+                )?;
+                warnings += 1;
+            }
+            for feature in 2u32..=63 {
+                if trace_xcr0 & our_xcr0 & (1u64 << feature) == 0 {
+                    continue;
+                }
+                let trace_area = find_cpuid_record(trace_records, CPUID_GETXSAVE, feature);
+                let our_area = find_cpuid_record(&our_records, CPUID_GETXSAVE, feature);
+                if let (Some(t), Some(o)) = (trace_area, our_area) {
+                    if t.out.eax != o.out.eax || t.out.ebx != o.out.ebx {
+                        write!(
+                            out,
+                            "FATAL: XSAVE area for {} differs: recorded size {} offset {}, \
+                             here size {} offset {}\n",
+                            xsave_feature_string(1u64 << feature),
+                            t.out.eax,
+                            t.out.ebx,
+                            o.out.eax,
+                            o.out.ebx
+                        )?;
+                        fatal += 1;
+                    }
+                }
+            }
+        }
+
+        if fatal == 0 {
+            write!(
+                out,
+                "Trace should be replayable on this machine{}\n",
+                if warnings > 0 {
+                    " (see warnings above)"
+                } else {
+                    ""
+                }
+            )?;
+        } else {
+            write!(
+                out,
+                "Trace is not replayable on this machine ({} problem{})\n",
+                fatal,
+                if fatal == 1 { "" } else { "s" }
+            )?;
+        }
+        Ok(fatal == 0)
+    }
+}
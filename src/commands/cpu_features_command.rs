@@ -0,0 +1,63 @@
+use crate::{
+    commands::RdCommand,
+    kernel_metadata::xsave_feature_string,
+    util::{
+        all_cpuid_records,
+        find_cpuid_record,
+        xcr0,
+        CPUID_GETEXTENDEDFEATURES,
+        CPUID_GETFEATURES,
+        CPUID_GETXSAVE,
+    },
+};
+use std::io::{self, stderr, stdout, Write};
+
+pub struct CpuFeaturesCommand;
+
+impl CpuFeaturesCommand {
+    pub fn new() -> CpuFeaturesCommand {
+        CpuFeaturesCommand
+    }
+
+    fn cpu_features(&self, out: &mut dyn Write, info: &mut dyn Write) -> io::Result<()> {
+        let records = all_cpuid_records();
+        let (features_ecx, features_edx) = match find_cpuid_record(&records, CPUID_GETFEATURES, 0) {
+            Some(r) => (r.out.ecx, r.out.edx),
+            None => (0, 0),
+        };
+        let (ext_ebx, ext_ecx, ext_edx) =
+            match find_cpuid_record(&records, CPUID_GETEXTENDEDFEATURES, 0) {
+                Some(r) => (r.out.ebx, r.out.ecx, r.out.edx),
+                None => (0, 0, 0),
+            };
+        let xsave_eax = match find_cpuid_record(&records, CPUID_GETXSAVE, 1) {
+            Some(r) => r.out.eax,
+            None => 0,
+        };
+
+        write!(
+            out,
+            "--disable-cpuid-features {:#x},{:#x} \
+             --disable-cpuid-features-ext {:#x},{:#x},{:#x} \
+             --disable-cpuid-features-xsave {:#x}\n",
+            !features_ecx, !features_edx, !ext_ebx, !ext_ecx, !ext_edx, !xsave_eax
+        )?;
+
+        // Not part of the option string so that `rd record $(rd cpufeatures)` keeps working.
+        // A trace recorded with a different XCR0 will not replay here regardless of the
+        // CPUID masking above.
+        let our_xcr0 = xcr0();
+        write!(
+            info,
+            "XCR0 on this machine is {:#x} ({}); traces must be recorded with the same value\n",
+            our_xcr0,
+            xsave_feature_string(our_xcr0)
+        )
+    }
+}
+
+impl RdCommand for CpuFeaturesCommand {
+    fn run(&mut self) -> io::Result<()> {
+        self.cpu_features(&mut stdout(), &mut stderr())
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{
+    commands::{
+        ls_command::format_size,
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_dir::{list_traces, remove_trace, TraceDirInfo, TraceDirStatus},
+        trace_stream::trace_save_dir,
+    },
+};
+use std::{
+    io,
+    io::{stdout, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+pub struct GcCommand {
+    keep: Option<usize>,
+    older_than: Option<Duration>,
+    dry_run: bool,
+}
+
+impl GcCommand {
+    pub fn new(options: &RdOptions) -> GcCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Gc {
+                keep,
+                older_than,
+                dry_run,
+            } => GcCommand {
+                keep,
+                older_than,
+                dry_run,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Gc` variant!"),
+        }
+    }
+
+    /// `rank` is 0 for the most recent trace, 1 for the one before that etc.
+    fn should_delete(&self, trace: &TraceDirInfo, rank: usize, now: SystemTime) -> bool {
+        if let Some(keep) = self.keep {
+            if rank < keep {
+                return false;
+            }
+        }
+        match self.older_than {
+            Some(age) => match now.duration_since(trace.modified) {
+                Ok(trace_age) => trace_age > age,
+                // Modified in the future. Leave it alone.
+                Err(_) => false,
+            },
+            // Without an age limit we can't tell a dead incomplete trace from one that is
+            // being recorded right now.
+            None => trace.status != TraceDirStatus::Incomplete,
+        }
+    }
+
+    fn gc(&self, out: &mut dyn Write) -> io::Result<()> {
+        let dir = PathBuf::from(trace_save_dir());
+        let traces = list_traces(&dir)?;
+        let now = SystemTime::now();
+        let mut freed: u64 = 0;
+        let mut count: usize = 0;
+        for (rank, trace) in traces.iter().rev().enumerate() {
+            if !self.should_delete(trace, rank, now) {
+                continue;
+            }
+            if self.dry_run {
+                write!(
+                    out,
+                    "Would delete {} ({})\n",
+                    trace.path.display(),
+                    format_size(trace.size)
+                )?;
+            } else {
+                remove_trace(&trace.path)?;
+                write!(
+                    out,
+                    "Deleted {} ({})\n",
+                    trace.path.display(),
+                    format_size(trace.size)
+                )?;
+            }
+            freed += trace.size;
+            count += 1;
+        }
+        write!(
+            out,
+            "{} {} trace(s), {}\n",
+            if self.dry_run {
+                "Would delete"
+            } else {
+                "Deleted"
+            },
+            count,
+            format_size(freed)
+        )
+    }
+}
+
+impl RdCommand for GcCommand {
+    fn run(&mut self) -> io::Result<()> {
+        self.gc(&mut stdout())
+    }
+}
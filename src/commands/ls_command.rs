@@ -0,0 +1,133 @@
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_dir::{list_traces, TraceDirInfo, TraceDirStatus},
+        trace_stream::trace_save_dir,
+    },
+};
+use libc::c_char;
+use std::{
+    io,
+    io::{stdout, Write},
+    mem,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub struct LsCommand {
+    dir: Option<PathBuf>,
+}
+
+impl LsCommand {
+    pub fn new(options: &RdOptions) -> LsCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Ls { dir } => LsCommand { dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Ls` variant!"),
+        }
+    }
+
+    fn ls(&self, out: &mut dyn Write) -> io::Result<()> {
+        let dir = self
+            .dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(trace_save_dir()));
+        let traces = list_traces(&dir)?;
+
+        write!(
+            out,
+            "TRACE\tNAME\tUUID\tRECORDED\tSIZE\tSTATUS\tTAGS\tCMD\n"
+        )?;
+        for trace in &traces {
+            write_trace(trace, out)?;
+        }
+        Ok(())
+    }
+}
+
+impl RdCommand for LsCommand {
+    fn run(&mut self) -> io::Result<()> {
+        self.ls(&mut stdout())
+    }
+}
+
+fn write_trace(trace: &TraceDirInfo, out: &mut dyn Write) -> io::Result<()> {
+    let uuid = match &trace.uuid {
+        Some(uuid) => uuid
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>(),
+        None => "-".to_owned(),
+    };
+    let name = match &trace.metadata.name {
+        Some(name) => format!("@{}", name),
+        None => "-".to_owned(),
+    };
+    let tags = match trace.metadata.tags_string() {
+        t if t.is_empty() => "-".to_owned(),
+        t => t,
+    };
+    let cmd_line: Vec<String> = trace
+        .cmd_line
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    write!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        trace.path.file_name().unwrap_or_default().to_string_lossy(),
+        name,
+        uuid,
+        format_time(trace.modified),
+        format_size(trace.size),
+        status_str(trace.status),
+        tags,
+        cmd_line.join(" ")
+    )
+}
+
+fn status_str(status: TraceDirStatus) -> String {
+    match status {
+        TraceDirStatus::Ok => "ok".to_owned(),
+        TraceDirStatus::Crashed => "crashed".to_owned(),
+        TraceDirStatus::Incomplete => "incomplete".to_owned(),
+        TraceDirStatus::IncompatibleVersion(v) => format!("version-{}", v),
+        TraceDirStatus::Unreadable => "unreadable".to_owned(),
+    }
+}
+
+/// Local time, to the second.
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        libc::localtime_r(&secs, &mut tm);
+        libc::strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            b"%Y-%m-%d %H:%M:%S\0".as_ptr() as *const c_char,
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[0..len]).into_owned()
+}
+
+/// e.g. `512K`, `1.5G`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 || size >= 10.0 {
+        format!("{:.0}{}", size, UNITS[unit])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    commands::rerun_command::TraceFields,
+    commands::rerun_command::{BreakLogLocation, OutputFormat, TraceFields},
     flags::{Checksum, DumpOn},
     trace::trace_frame::FrameTime,
 };
@@ -10,6 +10,7 @@ use std::{
     num::ParseIntError,
     os::unix::ffi::{OsStrExt, OsStringExt},
     path::PathBuf,
+    time::Duration,
 };
 use structopt::{clap, clap::AppSettings, StructOpt};
 
@@ -62,6 +63,13 @@ pub struct RdOptions {
     )]
     pub fatal_errors: bool,
 
+    #[structopt(
+        long,
+        help = "During replay, report tracee writes to RD_MAGIC_SAVE_DATA_FD that don't match \
+        the recording as warnings instead of aborting."
+    )]
+    pub nonfatal_save_data_mismatch: bool,
+
     #[structopt(
         short = "M",
         long,
@@ -169,6 +177,14 @@ pub enum RdSubCommand {
     #[structopt(name = "cpufeatures")]
     CpuFeatures,
 
+    /// Check whether a trace recorded on another machine can be replayed on this one, and
+    /// explain which CPUID bit or XSAVE feature stands in the way if it can't.
+    #[structopt(name = "cpucompat")]
+    CpuCompat {
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
     /// Dump data from the recorded trace
     #[structopt(name = "dump")]
     Dump {
@@ -210,6 +226,68 @@ pub enum RdSubCommand {
         event_spec: Option<(FrameTime, Option<FrameTime>)>,
     },
 
+    /// Record the execution of a program and its descendants into a trace that
+    /// `rd replay` can replay.
+    #[structopt(name = "record", setting = AppSettings::TrailingVarArg)]
+    Record {
+        /// Save the trace in <output-trace-dir>, which must not exist yet. The default is a
+        /// new directory in the trace save directory, which also becomes the latest trace
+        #[structopt(short = "o", long = "output-trace-dir", parse(from_os_str))]
+        output_trace_dir: Option<OsString>,
+
+        /// Don't bind the tracees to a CPU. By default they are all bound to one randomly
+        /// chosen CPU, which is stored in the trace
+        #[structopt(short = "u", long = "cpu-unbound")]
+        cpu_unbound: bool,
+
+        /// Bind the tracees to CPU <bind-to-cpu>
+        #[structopt(long = "bind-to-cpu", conflicts_with = "cpu-unbound")]
+        bind_to_cpu: Option<u32>,
+
+        /// Randomize scheduling decisions, timeslices, the number of cores the tracees
+        /// see and mmap addresses to try to reproduce bugs that show up rarely
+        #[structopt(long = "chaos")]
+        chaos: bool,
+
+        /// Seed the random decisions of `--chaos` with <chaos-seed> instead of a random
+        /// seed. The seed is saved with the trace, see `rd traceinfo`
+        #[structopt(long = "chaos-seed", requires = "chaos")]
+        chaos_seed: Option<u64>,
+
+        /// Also monitor MAP_SHARED writable mappings of files, so that stores made to
+        /// them by processes outside the recording are recorded. This changes the
+        /// timing of what the tracees and those processes see of each other's stores
+        #[structopt(long = "monitor-writable-shared-memory")]
+        monitor_writable_shared_memory: bool,
+
+        /// Copy the files the tracees open read-only, and the files they map, into
+        /// the trace, so that it can be replayed where those files are missing or
+        /// different
+        #[structopt(long = "capture-files")]
+        capture_files: bool,
+
+        /// Name the trace so any command can select it as `@<name>`
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Describe the trace
+        #[structopt(long)]
+        description: Option<String>,
+
+        /// Tag the trace with `key=value`. Can be given more than once
+        #[structopt(
+            short = "t",
+            long = "tag",
+            number_of_values = 1,
+            parse(try_from_str = parse_tag)
+        )]
+        tags: Vec<(String, String)>,
+
+        /// The program to record and its arguments
+        #[structopt(required = true, parse(from_os_str))]
+        exe_args: Vec<OsString>,
+    },
+
     /// Replay a previously recorded trace.
     #[structopt(name = "replay")]
     Replay {
@@ -290,6 +368,16 @@ pub enum RdSubCommand {
         #[structopt(long = "stats", parse(try_from_str = parse_stats))]
         stats: Option<u32>,
 
+        /// Show the current event, percent done, replay speed relative to the recording and
+        /// an ETA on stderr. Requires -a
+        #[structopt(long = "progress")]
+        progress: bool,
+
+        /// When the replay finishes, print on stderr where the time went: per replay step type,
+        /// per syscall, per ptrace request and on trace decompression. Requires -a
+        #[structopt(long = "profile")]
+        profile: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
         // @TODO There are extra debugger options also passed after a `--`
@@ -311,6 +399,16 @@ pub enum RdSubCommand {
         #[structopt(short = "r", long = "raw", help = "Dump registers in raw format")]
         raw: bool,
 
+        /// Where <format> := `text` | `json` | `csv`. Output `--singlestep` records as
+        /// `name:0xvalue` pairs (the default), as one JSON object per line or as CSV with
+        /// a header line naming the fields
+        #[structopt(
+            long = "format",
+            conflicts_with = "raw",
+            parse(try_from_str = crate::commands::rerun_command::parse_format)
+        )]
+        format: Option<OutputFormat>,
+
         /// Allow replay to run on any CPU. Default is to run on the CPU stored in the trace.
         /// Note that this may cause a diverge from the recording in some cases
         #[structopt(short = "u", long)]
@@ -324,8 +422,9 @@ pub enum RdSubCommand {
         /// Where <singlestep-regs> is a comma-separated sequence of `event`, `icount'`, `ip`, `flags`,
         /// `gp_x16`, `xmm_x16`, `ymm_x16`. For the `x16` cases, we always output 16,
         /// values, the latter 8 of which are zero for x86-32. GP registers are in
-        /// architectural order (AX,CX,DX,BX,SP,BP,SI,DI,R8-R15). All data is output
-        /// in little-endian binary format; records are separated by `\n`. String
+        /// architectural order (AX,CX,DX,BX,SP,BP,SI,DI,R8-R15). With `--raw` all data
+        /// is output in little-endian binary format; records are separated by `\n`. See
+        /// `--format` for the other output formats. String
         /// instruction repetitions are treated as a single instruction if not
         /// interrupted. A 'singlestep' includes events such as system-call-exit
         /// where tracee state changes without any user-level instructions actually
@@ -333,6 +432,49 @@ pub enum RdSubCommand {
         #[structopt(long = "singlestep", parse(try_from_str = crate::commands::rerun_command::parse_regs))]
         singlestep_regs: Option<TraceFields>,
 
+        /// Output every instruction executed between <trace-start> and <trace-end>: its
+        /// address, its bytes, a disassembly and the general purpose registers it
+        /// modified as `name:old->new`. Output is text or, with `--format json`, one JSON
+        /// object per instruction
+        #[structopt(long = "instructions", conflicts_with = "raw")]
+        instructions: bool,
+
+        /// Where <break-log> is a function name, looked up in the ELF symbol tables of
+        /// the objects mapped by the tracee, or an address like `0x4005d0`. Set a
+        /// breakpoint there and print the values selected by `--print` each time it is
+        /// hit. Can be specified multiple times. Can't be combined with `--singlestep` or
+        /// `--instructions`
+        #[structopt(
+            long = "break-log",
+            number_of_values = 1,
+            parse(try_from_str = crate::commands::rerun_command::parse_break_log_location)
+        )]
+        break_log: Vec<BreakLogLocation>,
+
+        /// Where <print> is a comma-separated sequence of registers accepted by
+        /// `--singlestep` and `stack[N]`, the N-th word above the stack pointer. The
+        /// values are printed each time a `--break-log` breakpoint is hit
+        #[structopt(long = "print", parse(try_from_str = crate::commands::rerun_command::parse_regs))]
+        print: Option<TraceFields>,
+
+        /// Where <trace-calls> is a function name pattern in which `*` matches any
+        /// sequence of characters and `?` any single character. Log each call to a
+        /// matching function with its first 6 arguments, and its return value, nested
+        /// by call depth for each tid. Can be specified multiple times. Can't be combined
+        /// with `--singlestep` or `--instructions`
+        #[structopt(long = "trace-calls", number_of_values = 1)]
+        trace_calls: Vec<String>,
+
+        /// Print a symbolized backtrace of every task when replay reaches event
+        /// <backtrace-at>, then exit
+        #[structopt(long = "backtrace-at")]
+        backtrace_at: Option<FrameTime>,
+
+        /// Show the current event, percent done, replay speed relative to the recording and
+        /// an ETA on stderr
+        #[structopt(long = "progress")]
+        progress: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
@@ -350,6 +492,73 @@ pub enum RdSubCommand {
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
+
+    /// List the traces in the trace save directory with their uuid, when they were recorded,
+    /// the initial command line, size on disk and whether recording finished normally.
+    #[structopt(name = "ls")]
+    Ls {
+        /// List the traces in this directory instead of the trace save directory
+        dir: Option<PathBuf>,
+    },
+
+    /// Delete traces. Refuses to delete anything that isn't a trace directory.
+    #[structopt(name = "rm")]
+    Rm {
+        /// Also delete traces that are still being recorded (or whose recording was killed)
+        #[structopt(short = "f", long)]
+        force: bool,
+
+        /// Traces to delete, as paths or names in the trace save directory
+        #[structopt(required = true)]
+        traces: Vec<PathBuf>,
+    },
+
+    /// Show or change a trace's name, description and `key=value` tags. Prints the trace's
+    /// metadata as JSON if no changes are requested.
+    #[structopt(name = "tag")]
+    Tag {
+        /// Name the trace so any command can select it as `@<name>`. An empty name removes it
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Describe the trace. An empty description removes it
+        #[structopt(long)]
+        description: Option<String>,
+
+        /// Add a `key=value` tag, replacing any existing tag with the same key
+        #[structopt(
+            short = "t",
+            long = "tag",
+            number_of_values = 1,
+            parse(try_from_str = parse_tag)
+        )]
+        tags: Vec<(String, String)>,
+
+        /// Remove the tag with this key
+        #[structopt(long, number_of_values = 1)]
+        untag: Vec<String>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Delete old traces from the trace save directory. A trace is deleted only if it meets
+    /// every criterion given. Incomplete traces are only deleted by `--older-than`.
+    #[structopt(name = "gc")]
+    Gc {
+        /// Keep the <keep> most recent traces
+        #[structopt(long, required_unless = "older_than")]
+        keep: Option<usize>,
+
+        /// Where <older-than> := <number>`s` | `m` | `h` | `d` | `w`. Delete traces
+        /// recorded longer ago than this, e.g. `7d`
+        #[structopt(long, parse(try_from_str = parse_age))]
+        older_than: Option<Duration>,
+
+        /// Print what would be deleted without deleting anything
+        #[structopt(short = "n", long)]
+        dry_run: bool,
+    },
 }
 
 fn parse_range(range_or_single: &str) -> Result<(FrameTime, Option<FrameTime>), ParseIntError> {
@@ -398,6 +607,38 @@ fn parse_goto_event(maybe_goto_event: &str) -> Result<FrameTime, Box<dyn Error>>
     }
 }
 
+fn parse_age(age: &str) -> Result<Duration, Box<dyn Error>> {
+    let age = age.trim();
+    let unit_len = age.ends_with(|c: char| c.is_ascii_alphabetic()) as usize;
+    let (number, unit) = age.split_at(age.len() - unit_len);
+    let unit_secs: u64 = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(Box::new(clap::Error::with_description(
+                "Only `s`, `m`, `h`, `d` or `w` are valid units here",
+                clap::ErrorKind::InvalidValue,
+            )))
+        }
+    };
+    Ok(Duration::from_secs(number.parse::<u64>()? * unit_secs))
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), Box<dyn Error>> {
+    let parts: Vec<&str> = tag.splitn(2, '=').collect();
+    if parts.len() != 2 || parts[0].is_empty() || parts[0].contains(',') {
+        Err(Box::new(clap::Error::with_description(
+            "Tags must be of the form `key=value`, and the key must not contain `,`",
+            clap::ErrorKind::InvalidValue,
+        )))
+    } else {
+        Ok((parts[0].to_owned(), parts[1].to_owned()))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum PidOrCommand {
     Pid(pid_t),
@@ -0,0 +1,214 @@
+use super::rd_options::{RdOptions, RdSubCommand};
+use crate::{
+    assert_prerequisites,
+    commands::RdCommand,
+    flags::Flags,
+    log::LogLevel::LogInfo,
+    session::{
+        record_session::{RecordResult, RecordSession},
+        SessionSharedPtr,
+    },
+    trace::{trace_metadata::is_valid_trace_name, trace_writer::CloseStatus},
+    util::{running_under_rd, BindCPU},
+    wait_status::WaitStatus,
+};
+use nix::{
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::{getpid, getppid},
+};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io,
+    io::{stderr, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::Path,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set when the user asks us to stop recording, e.g. with Ctrl-C.
+static TERM_REQUEST: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_term_signal(_sig: i32) {
+    TERM_REQUEST.store(true, Ordering::SeqCst);
+}
+
+pub struct RecordCommand {
+    exe_args: Vec<OsString>,
+    output_trace_dir: Option<OsString>,
+    bind_cpu: BindCPU,
+    chaos: bool,
+    chaos_seed: Option<u64>,
+    monitor_writable_shared_memory: bool,
+    capture_files: bool,
+    name: Option<String>,
+    description: Option<String>,
+    tags: Vec<(String, String)>,
+}
+
+impl RecordCommand {
+    pub fn new(options: &RdOptions) -> RecordCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Record {
+                output_trace_dir,
+                cpu_unbound,
+                bind_to_cpu,
+                chaos,
+                chaos_seed,
+                monitor_writable_shared_memory,
+                capture_files,
+                name,
+                description,
+                tags,
+                exe_args,
+            } => RecordCommand {
+                exe_args,
+                output_trace_dir,
+                chaos,
+                chaos_seed,
+                monitor_writable_shared_memory,
+                capture_files,
+                name,
+                description,
+                tags,
+                bind_cpu: match bind_to_cpu {
+                    Some(cpu) => BindCPU::BindToCPU(cpu),
+                    None if cpu_unbound => BindCPU::UnboundCPU,
+                    None => BindCPU::RandomCPU,
+                },
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Record` variant!"),
+        }
+    }
+
+    fn install_signal_handlers() {
+        // No SA_RESTART: the scheduler's waitpid() must return EINTR so that we notice.
+        let sa = SigAction::new(
+            SigHandler::Handler(handle_term_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for sig in &[Signal::SIGINT, Signal::SIGTERM] {
+            unsafe { sigaction(*sig, &sa) }.unwrap();
+        }
+    }
+
+    fn record(&mut self) -> io::Result<WaitStatus> {
+        if let Some(name) = &self.name {
+            if !is_valid_trace_name(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Trace names must not contain `/` or whitespace",
+                ));
+            }
+        }
+        let exe_path = match find_in_path(&self.exe_args[0]) {
+            Some(path) => path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No such executable: {:?}", self.exe_args[0]),
+                ))
+            }
+        };
+        let env = tracee_env();
+
+        let session: SessionSharedPtr = RecordSession::create(
+            &exe_path,
+            &self.exe_args,
+            &env,
+            std::mem::replace(&mut self.bind_cpu, BindCPU::UnboundCPU),
+            self.output_trace_dir.as_deref(),
+        );
+        let record_session = session.as_record().unwrap();
+        if self.chaos {
+            record_session.set_enable_chaos(true, self.chaos_seed)?;
+        }
+        record_session.set_monitor_writable_shared_memory(self.monitor_writable_shared_memory);
+        if self.capture_files {
+            record_session.enable_file_capture();
+        }
+        if self.name.is_some() || self.description.is_some() || !self.tags.is_empty() {
+            record_session.set_trace_metadata(
+                self.name.take(),
+                self.description.take(),
+                std::mem::take(&mut self.tags),
+            )?;
+        }
+        Self::install_signal_handlers();
+
+        let status = loop {
+            if TERM_REQUEST.load(Ordering::SeqCst) {
+                log!(LogInfo, "Recording interrupted");
+                record_session.terminate_recording();
+                return Ok(WaitStatus::for_fatal_sig(libc::SIGINT));
+            }
+            match record_session.record_step() {
+                RecordResult::StepContinue => (),
+                RecordResult::StepExited(status) => break status,
+            }
+        };
+        record_session.close_trace_writer(CloseStatus::CloseOk);
+        Ok(status)
+    }
+}
+
+impl RdCommand for RecordCommand {
+    fn run(&mut self) -> io::Result<()> {
+        assert_prerequisites(None);
+
+        if running_under_rd() && !Flags::get().suppress_environment_warnings {
+            write!(
+                stderr(),
+                "rd: rd pid {} running under parent {}. Good luck.\n",
+                getpid(),
+                getppid()
+            )?;
+        }
+
+        let status = self.record()?;
+        // Exit the way the initial tracee did, so that rd can be dropped into scripts.
+        match (status.exit_code(), status.fatal_sig()) {
+            (Some(0), _) => Ok(()),
+            (Some(code), _) => process::exit(code as i32),
+            (None, Some(sig)) => process::exit(128 + sig),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The environment the tracees start with: ours, plus a marker that tells them (and
+/// any rd they run) that they're being recorded.
+fn tracee_env() -> Vec<OsString> {
+    let mut env: Vec<OsString> = env::vars_os()
+        .filter(|(k, _)| k != "RUNNING_UNDER_RD")
+        .map(|(k, v)| {
+            let mut kv = k;
+            kv.push("=");
+            kv.push(v);
+            kv
+        })
+        .collect();
+    env.push("RUNNING_UNDER_RD=1".into());
+    env
+}
+
+/// Find `exe` like execvp() would: as is if it contains a `/`, else in `$PATH`.
+fn find_in_path(exe: &OsStr) -> Option<OsString> {
+    if exe.as_bytes().contains(&b'/') {
+        return Some(exe.to_owned());
+    }
+    let path = env::var_os("PATH")?;
+    for dir in path.as_bytes().split(|&c| c == b':') {
+        let dir = if dir.is_empty() { b"." as &[u8] } else { dir };
+        let mut candidate = dir.to_vec();
+        candidate.push(b'/');
+        candidate.extend_from_slice(exe.as_bytes());
+        let candidate = OsString::from_vec(candidate);
+        if Path::new(&candidate).is_file() {
+            return Some(candidate);
+        }
+    }
+    None
+}
@@ -2,10 +2,11 @@ use super::rd_options::{PidOrCommand, RdOptions, RdSubCommand};
 use crate::{
     assert_prerequisites,
     bindings::kernel::{gettimeofday, timeval},
-    commands::RdCommand,
+    commands::{replay_progress::ReplayProgress, RdCommand},
     flags::Flags,
     gdb_server::gdb_server,
     log::LogLevel::LogInfo,
+    replay_profiler,
     session::{
         replay_session,
         session_inner::{session_inner::Statistics, RunCommand},
@@ -77,6 +78,12 @@ pub struct ReplayCommand {
     /// When Some(_), display statistics every N steps.
     dump_interval: Option<u32>,
 
+    /// Display progress and an ETA on stderr.
+    progress: bool,
+
+    /// Print a `replay_profiler` report on stderr at the end of the replay.
+    profile: bool,
+
     trace_dir: Option<PathBuf>,
 }
 
@@ -97,6 +104,8 @@ impl Default for ReplayCommand {
             cpu_unbound: false,
             share_private_mappings: false,
             dump_interval: None,
+            progress: false,
+            profile: false,
             gdb_options: vec![],
             trace_dir: None,
         }
@@ -123,6 +132,8 @@ impl ReplayCommand {
                 cpu_unbound,
                 gdb_x_file,
                 stats,
+                progress,
+                profile,
                 trace_dir,
                 share_private_mappings,
             } => {
@@ -197,6 +208,9 @@ impl ReplayCommand {
                     flags.dump_interval = stats;
                 }
 
+                flags.progress = progress;
+                flags.profile = profile;
+
                 flags.cpu_unbound = cpu_unbound;
 
                 if interpreter.is_some() {
@@ -221,6 +235,14 @@ impl ReplayCommand {
     }
 
     fn serve_replay_no_debugger(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.profile {
+            replay_profiler::enable();
+        }
+        let mut maybe_progress = if self.progress {
+            Some(ReplayProgress::new(self.trace_dir.as_ref()))
+        } else {
+            None
+        };
         let session: SessionSharedPtr =
             ReplaySession::create(self.trace_dir.as_ref(), self.session_flags());
         let replay_session = session.as_replay().unwrap();
@@ -254,6 +276,9 @@ impl ReplayCommand {
                 last_dump_rectime = replay_session.trace_reader().recording_time();
             }
             step_count += 1;
+            if let Some(progress) = maybe_progress.as_mut() {
+                progress.update(after_time, replay_session.trace_reader().recording_time());
+            }
             if self.dump_interval.is_some() && step_count % self.dump_interval.unwrap() == 0 {
                 let mut now = timeval::default();
                 unsafe { gettimeofday(&raw mut now, ptr::null_mut()) };
@@ -283,6 +308,12 @@ impl ReplayCommand {
                 cmd == RunCommand::RunSinglestep || !result.break_status.singlestep_complete
             );
         }
+        if let Some(progress) = maybe_progress.as_mut() {
+            progress.finish();
+        }
+        if self.profile {
+            replay_profiler::write_report(out)?;
+        }
 
         log!(LogInfo, "Replayer successfully finished");
         Ok(())
@@ -341,6 +372,20 @@ impl RdCommand for ReplayCommand {
             ));
         }
 
+        if self.progress && !self.dont_launch_debugger {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--progress requires -a",
+            ));
+        }
+
+        if self.profile && !self.dont_launch_debugger {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--profile requires -a",
+            ));
+        }
+
         assert_prerequisites(None);
 
         if running_under_rd() {
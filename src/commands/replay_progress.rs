@@ -0,0 +1,157 @@
+use crate::{
+    trace::{trace_frame::FrameTime, trace_reader::TraceReader},
+    util::probably_not_interactive,
+};
+use libc::STDERR_FILENO;
+use std::{
+    io::{stderr, Write},
+    path::PathBuf,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What `ReplayProgress` needs to know about the whole recording.
+#[derive(Copy, Clone)]
+struct TraceTotals {
+    total_events: FrameTime,
+    first_recording_time: f64,
+    last_recording_time: f64,
+}
+
+impl TraceTotals {
+    /// Reads the whole events substream to find the number of events and how long the
+    /// recording took.
+    fn count(trace_dir: Option<&PathBuf>) -> TraceTotals {
+        let mut trace = TraceReader::new(trace_dir);
+        let mut total_events: FrameTime = 0;
+        let mut first_recording_time: Option<f64> = None;
+        let mut last_recording_time: f64 = 0.0;
+        while !trace.at_end() {
+            let frame = trace.read_frame();
+            total_events = frame.time();
+            first_recording_time.get_or_insert(frame.monotonic_time());
+            last_recording_time = frame.monotonic_time();
+        }
+        TraceTotals {
+            total_events,
+            first_recording_time: first_recording_time.unwrap_or(0.0),
+            last_recording_time,
+        }
+    }
+}
+
+/// Progress line for long replays, written to stderr so the replayed stdout is left alone.
+/// On a terminal the line is redrawn in place; otherwise a new line is written now and then.
+pub struct ReplayProgress {
+    /// None until the background count of the trace's events has finished.
+    totals: Option<TraceTotals>,
+    totals_receiver: Receiver<TraceTotals>,
+    started: Instant,
+    last_report: Option<Instant>,
+    interactive: bool,
+}
+
+impl ReplayProgress {
+    /// The trace's events are counted on a separate thread, so the replay can start right
+    /// away. Reports only show the current event until the count is known.
+    pub fn new(trace_dir: Option<&PathBuf>) -> ReplayProgress {
+        let (sender, totals_receiver) = channel();
+        let trace_dir = trace_dir.cloned();
+        thread::spawn(move || {
+            // The receiver is gone if the replay finished first.
+            sender.send(TraceTotals::count(trace_dir.as_ref())).ok();
+        });
+
+        ReplayProgress {
+            totals: None,
+            totals_receiver,
+            started: Instant::now(),
+            last_report: None,
+            interactive: !probably_not_interactive(Some(STDERR_FILENO)),
+        }
+    }
+
+    fn report_interval(&self) -> Duration {
+        if self.interactive {
+            Duration::from_millis(250)
+        } else {
+            Duration::from_secs(10)
+        }
+    }
+
+    /// `time` is the event about to be replayed and `recording_time` the monotonic time at
+    /// which it was recorded. Cheap enough to call after every replay step.
+    pub fn update(&mut self, time: FrameTime, recording_time: f64) {
+        let now = Instant::now();
+        if let Some(last_report) = self.last_report {
+            if now.duration_since(last_report) < self.report_interval() {
+                return;
+            }
+        }
+        self.last_report = Some(now);
+        if self.totals.is_none() {
+            self.totals = self.totals_receiver.try_recv().ok();
+        }
+        self.report(time, recording_time, now);
+    }
+
+    /// Clear up after the last report.
+    pub fn finish(&mut self) {
+        if self.interactive && self.last_report.is_some() {
+            write!(stderr(), "\n").unwrap();
+        }
+    }
+
+    fn report(&self, time: FrameTime, recording_time: f64, now: Instant) {
+        let line = match self.totals {
+            Some(totals) => self.progress_line(&totals, time, recording_time, now),
+            None => format!("[progress] event {}", time),
+        };
+
+        if self.interactive {
+            // Return to the start of the line and clear whatever was left of the last report.
+            write!(stderr(), "\r{}\x1b[K", line).unwrap();
+        } else {
+            write!(stderr(), "{}\n", line).unwrap();
+        }
+    }
+
+    fn progress_line(
+        &self,
+        totals: &TraceTotals,
+        time: FrameTime,
+        recording_time: f64,
+        now: Instant,
+    ) -> String {
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let total = totals.total_events.max(1);
+        let percent = 100.0 * time.min(total) as f64 / total as f64;
+        let replayed = (recording_time - totals.first_recording_time).max(0.0);
+        let remaining = (totals.last_recording_time - recording_time).max(0.0);
+
+        let mut line = format!(
+            "[progress] event {}/{} ({:.1}%)",
+            time, totals.total_events, percent
+        );
+        if elapsed > 0.0 && replayed > 0.0 {
+            // How fast we replay compared to how fast the recording ran.
+            let speed = replayed / elapsed;
+            line += &format!(
+                " {:.2}x recording speed, ETA {}",
+                speed,
+                format_eta(remaining / speed)
+            );
+        } else if time > 1 && elapsed > 0.0 {
+            // The recording's clock hasn't advanced yet, so fall back to counting events.
+            let eta = elapsed * (total - time.min(total)) as f64 / time as f64;
+            line += &format!(", ETA {}", format_eta(eta));
+        }
+        line
+    }
+}
+
+fn format_eta(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
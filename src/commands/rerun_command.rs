@@ -7,30 +7,43 @@ use crate::{
     bindings::kernel::user_regs_struct as native_user_regs_struct,
     commands::{
         rd_options::{RdOptions, RdSubCommand},
+        replay_progress::ReplayProgress,
         RdCommand,
     },
+    disassembler::{disassemble, MAX_INSN_LENGTH},
+    elf_symbols::{glob_matches, resolve_symbols, SymbolCache},
     event::{Event, EventType},
     flags::Flags,
     gdb_register::{DREG_64_XMM0, DREG_64_YMM0H, DREG_XMM0, DREG_YMM0H},
     kernel_abi::SupportedArch,
-    log::LogLevel::{LogDebug, LogInfo},
+    log::LogLevel::{LogDebug, LogInfo, LogWarn},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
-    remote_ptr::RemotePtr,
+    remote_ptr::{RemotePtr, Void},
     session::{
+        address_space::address_space::{AddressSpace, BreakpointType},
         replay_session,
         replay_session::{ReplaySession, ReplayStatus},
         session_inner::RunCommand,
-        task::{task_common::write_val_mem, Task},
+        task::{task_common::write_val_mem, Task, TaskSharedPtr},
         Session,
         SessionSharedPtr,
     },
-    taskish_uid::TaskUid,
+    taskish_uid::{AddressSpaceUid, TaskUid},
     trace::trace_frame::FrameTime,
+    unwinder::{UnwindMethod, Unwinder},
     util::{raise_resource_limits, running_under_rd},
 };
-use nix::unistd::{getpid, getppid};
+use nix::{
+    sys::mman::ProtFlags,
+    unistd::{getpid, getppid},
+};
+use serde_json::json;
 use std::{
+    cell::Cell,
+    cmp::min,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
     fmt::Write as fmtWrite,
     io,
     io::{stderr, stdout, Write},
@@ -64,10 +77,116 @@ impl RdCommand for ReRunCommand {
             }
         }
 
+        if self.instructions
+            && (self.format == OutputFormat::Csv || self.format == OutputFormat::Raw)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--instructions only supports the `text` and `json` formats",
+            ));
+        }
+
+        // Stepping over a breakpoint isn't logged as an instruction, so the
+        // singlestep logs would silently miss instructions.
+        if (!self.break_log.is_empty() || !self.trace_calls.is_empty())
+            && (!self.singlestep_trace.is_empty() || self.instructions)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--break-log and --trace-calls can't be combined with --singlestep or \
+                    --instructions",
+            ));
+        }
+
         self.rerun()
     }
 }
 
+/// How many arguments `--trace-calls` shows for each call. We don't know the
+/// function prototypes so we always show this many.
+const CALL_ARGS_SHOWN: usize = 6;
+
+fn word_size(arch: SupportedArch) -> usize {
+    match arch {
+        SupportedArch::X86 => 4,
+        SupportedArch::X64 => 8,
+    }
+}
+
+/// Read the word at `addr` from `t`'s memory. Unreadable memory is read as 0.
+fn read_stack_word(t: &mut dyn Task, addr: RemotePtr<Void>) -> usize {
+    let mut buf = [0u8; 8];
+    let word_size = word_size(t.arch());
+    t.read_bytes_fallible(addr, &mut buf[0..word_size])
+        .unwrap_or(0);
+    u64::from_le_bytes(buf) as usize
+}
+
+/// Book-keeping for the breakpoints set by `--break-log` and `--trace-calls`.
+/// We hold a single `BkptUser` reference on each breakpoint address for as long
+/// as any of `labels`, `call_entries` or `call_returns` refers to it.
+#[derive(Default)]
+struct BreakpointState {
+    symbols: SymbolCache,
+    /// Executable mappings (address space, start address) that we've already
+    /// searched for `--break-log` and `--trace-calls` symbols.
+    scanned_mappings: HashSet<(AddressSpaceUid, usize)>,
+    /// Address spaces in which the `--break-log` addresses have been set.
+    vms_with_address_breakpoints: HashSet<AddressSpaceUid>,
+    /// What to call each `--break-log` breakpoint when it is hit.
+    labels: HashMap<(AddressSpaceUid, RemoteCodePtr), String>,
+    /// Entry points of the functions matched by `--trace-calls`.
+    call_entries: HashMap<(AddressSpaceUid, RemoteCodePtr), String>,
+    /// Return addresses of traced calls, with the number of calls still
+    /// expected to return there.
+    call_returns: HashMap<(AddressSpaceUid, RemoteCodePtr), usize>,
+    /// Traced calls that have not returned yet, innermost last.
+    call_stacks: HashMap<TaskUid, Vec<TracedCall>>,
+}
+
+impl BreakpointState {
+    fn is_set(&self, key: &(AddressSpaceUid, RemoteCodePtr)) -> bool {
+        self.labels.contains_key(key)
+            || self.call_entries.contains_key(key)
+            || self.call_returns.contains_key(key)
+    }
+}
+
+/// A call to a `--trace-calls` function that has not returned yet.
+struct TracedCall {
+    name: String,
+    return_address: RemoteCodePtr,
+    /// The stack pointer on entry, i.e. pointing at the return address.
+    entry_sp: usize,
+}
+
+/// What we need to know about an instruction before it is singlestepped to
+/// output it for `--instructions`.
+struct InstructionState {
+    ip: RemoteCodePtr,
+    /// Up to `MAX_INSN_LENGTH` bytes starting at `ip`, with any breakpoints we've
+    /// set replaced by the original memory contents.
+    bytes: Vec<u8>,
+    regs: Registers,
+}
+
+fn read_instruction_state(t: &mut dyn Task) -> InstructionState {
+    let ip = t.ip();
+    let mut bytes = vec![0u8; MAX_INSN_LENGTH];
+    // We may be close to the end of the mapping so a partial read is fine.
+    let nread = t
+        .read_bytes_fallible(ip.to_data_ptr::<Void>(), &mut bytes)
+        .unwrap_or(0);
+    bytes.truncate(nread);
+    t.vm()
+        .replace_breakpoints_with_original_values(&mut bytes, ip.to_data_ptr::<u8>());
+    InstructionState {
+        ip,
+        bytes,
+        regs: t.regs_ref().clone(),
+    }
+}
+
 #[repr(C)]
 union RegsData {
     native: native_user_regs_struct,
@@ -225,6 +344,9 @@ enum TraceFieldKind {
     TraceXmmReg,
     /// outputs 256-bit value
     TraceYmmReg,
+    /// outputs 64-bit value. The `reg_num` is the index of the word, counting up
+    /// from the stack pointer
+    TraceStackWord,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -236,12 +358,40 @@ struct TraceField {
 #[derive(Clone, Debug)]
 pub struct TraceFields(Vec<TraceField>);
 
+/// How `--singlestep` records are output.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// `name:0xvalue` pairs separated by spaces
+    Text,
+    /// One JSON object per record
+    Json,
+    /// A header line with the field names followed by one line per record
+    Csv,
+    /// Little-endian binary values; records are separated by `\n`
+    Raw,
+}
+
+#[derive(Clone, Debug)]
+pub enum BreakLogLocation {
+    Address(RemoteCodePtr),
+    Symbol(String),
+}
+
 pub struct ReRunCommand {
     trace_start: FrameTime,
     trace_end: FrameTime,
     function: Option<RemoteCodePtr>,
     singlestep_trace: Vec<TraceField>,
-    raw_dump: bool,
+    instructions: bool,
+    break_log: Vec<BreakLogLocation>,
+    break_log_print: Vec<TraceField>,
+    /// Function name patterns
+    trace_calls: Vec<String>,
+    backtrace_at: Option<FrameTime>,
+    progress: bool,
+    format: OutputFormat,
+    /// Set once the CSV header has been output
+    csv_header_written: Cell<bool>,
     cpu_unbound: bool,
     trace_dir: Option<PathBuf>,
 }
@@ -321,6 +471,11 @@ pub(super) fn parse_regs(regs_s: &str) -> Result<TraceFields, clap::Error> {
                 kind: TraceFieldKind::TraceXinuse,
                 reg_num: 0,
             });
+        } else if let Some(i) = find_stack_word(reg) {
+            registers.push(TraceField {
+                kind: TraceFieldKind::TraceStackWord,
+                reg_num: i,
+            });
         } else {
             return Err(clap::Error::with_description(
                 &format!("Unknown register `{}`", reg),
@@ -332,6 +487,47 @@ pub(super) fn parse_regs(regs_s: &str) -> Result<TraceFields, clap::Error> {
     Ok(TraceFields(registers))
 }
 
+pub(super) fn parse_format(format_s: &str) -> Result<OutputFormat, clap::Error> {
+    match format_s.trim() {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        "csv" => Ok(OutputFormat::Csv),
+        _ => Err(clap::Error::with_description(
+            "Only `text`, `json` or `csv` is valid here",
+            clap::ErrorKind::InvalidValue,
+        )),
+    }
+}
+
+pub(super) fn parse_break_log_location(location: &str) -> Result<BreakLogLocation, clap::Error> {
+    let location = location.trim();
+    if location.starts_with("0x") {
+        match usize::from_str_radix(&location[2..], 16) {
+            Ok(addr) => Ok(BreakLogLocation::Address(addr.into())),
+            Err(_) => Err(clap::Error::with_description(
+                &format!("Invalid address `{}`", location),
+                clap::ErrorKind::InvalidValue,
+            )),
+        }
+    } else if location.is_empty() {
+        Err(clap::Error::with_description(
+            "Please provide a symbol name or an address",
+            clap::ErrorKind::InvalidValue,
+        ))
+    } else {
+        Ok(BreakLogLocation::Symbol(location.into()))
+    }
+}
+
+/// Parse `stack[N]`, which stands for the N-th word above the stack pointer.
+fn find_stack_word(reg: &str) -> Option<u8> {
+    if reg.starts_with("stack[") && reg.ends_with(']') {
+        reg["stack[".len()..reg.len() - 1].parse::<u8>().ok()
+    } else {
+        None
+    }
+}
+
 impl ReRunCommand {
     pub fn new(options: &RdOptions) -> ReRunCommand {
         match options.cmd.clone() {
@@ -339,16 +535,34 @@ impl ReRunCommand {
                 trace_start,
                 trace_end,
                 raw,
+                format,
                 cpu_unbound,
                 function_addr,
                 singlestep_regs,
+                instructions,
+                break_log,
+                print,
+                trace_calls,
+                backtrace_at,
+                progress,
                 trace_dir,
             } => ReRunCommand {
                 trace_start: trace_start.unwrap_or(FrameTime::MIN),
                 trace_end: trace_end.unwrap_or(FrameTime::MAX),
                 function: function_addr.map(|a| a.into()),
                 singlestep_trace: singlestep_regs.map_or(Vec::new(), |r| r.0),
-                raw_dump: raw,
+                instructions,
+                break_log,
+                break_log_print: print.map_or(Vec::new(), |r| r.0),
+                trace_calls,
+                backtrace_at,
+                progress,
+                format: if raw {
+                    OutputFormat::Raw
+                } else {
+                    format.unwrap_or(OutputFormat::Text)
+                },
+                csv_header_written: Cell::new(false),
                 cpu_unbound,
                 trace_dir,
             },
@@ -366,11 +580,17 @@ impl ReRunCommand {
 
     // DIFF NOTE: In rr a result code e.g. 0 is return. We simply return Ok(()) if there is no error.
     fn rerun(&self) -> io::Result<()> {
+        let mut maybe_progress = if self.progress {
+            Some(ReplayProgress::new(self.trace_dir.as_ref()))
+        } else {
+            None
+        };
         let session: SessionSharedPtr =
             ReplaySession::create(self.trace_dir.as_ref(), self.session_flags());
         let replay_session = session.as_replay().unwrap();
         let mut instruction_count_within_event: u64 = 0;
         let mut done_first_step = false;
+        let mut breakpoint_state = BreakpointState::default();
 
         // Now that we've spawned the replay, raise our resource limits if possible.
         raise_resource_limits();
@@ -379,16 +599,42 @@ impl ReRunCommand {
             let mut cmd = RunCommand::RunContinue;
 
             let before_time: FrameTime = replay_session.trace_reader().time();
+            if let Some(progress) = maybe_progress.as_mut() {
+                progress.update(before_time, replay_session.trace_reader().recording_time());
+            }
+            if let Some(event) = self.backtrace_at {
+                if before_time >= event {
+                    if let Some(progress) = maybe_progress.as_mut() {
+                        progress.finish();
+                    }
+                    return self.write_backtraces(replay_session, &mut stdout());
+                }
+            }
             let done_initial_exec = replay_session.done_initial_exec();
             let old_task_tuid: Option<TaskUid>;
             let old_ip: RemoteCodePtr;
+            let mut before_instruction: Option<InstructionState> = None;
             {
                 let old_task = replay_session.current_task();
                 old_task_tuid = old_task.as_ref().map(|t| t.borrow().tuid());
                 old_ip = old_task.as_ref().map_or(0.into(), |t| t.borrow().ip());
+                let have_breakpoints = !self.break_log.is_empty() || !self.trace_calls.is_empty();
+                if done_initial_exec && have_breakpoints {
+                    if let Some(t) = old_task.as_ref() {
+                        self.update_breakpoints(t.borrow_mut().as_mut(), &mut breakpoint_state);
+                    }
+                }
                 if done_initial_exec && before_time >= self.trace_start {
+                    if self.instructions {
+                        before_instruction = old_task
+                            .as_ref()
+                            .map(|t| read_instruction_state(t.borrow_mut().as_mut()));
+                    }
                     if !done_first_step {
                         if self.function.is_some() {
+                            if let Some(progress) = maybe_progress.as_mut() {
+                                progress.finish();
+                            }
                             self.run_diversion_function(
                                 replay_session,
                                 old_task.unwrap().borrow_mut().as_mut(),
@@ -418,6 +664,25 @@ impl ReRunCommand {
                 break;
             }
 
+            if result.break_status.breakpoint_hit {
+                // Only `--break-log` and `--trace-calls` set breakpoints, and they
+                // aren't allowed with `--singlestep` or `--instructions`, so there's
+                // no instruction log to keep up to date here.
+                let maybe_task = result.break_status.task.as_ref().and_then(|w| w.upgrade());
+                if let Some(t) = maybe_task {
+                    let status = self.handle_breakpoint_hit(
+                        replay_session,
+                        t,
+                        &mut breakpoint_state,
+                        &mut stdout(),
+                    )?;
+                    if status == ReplayStatus::ReplayExited {
+                        break;
+                    }
+                }
+                continue;
+            }
+
             let after_time: FrameTime = replay_session.trace_reader().time();
             let singlestep_really_complete: bool;
             if cmd != RunCommand::RunContinue {
@@ -445,12 +710,22 @@ impl ReRunCommand {
                             before_time == after_time) &&
                         (!result.incomplete_fast_forward || old_ip != after_ip ||
                             before_time < after_time);
-                    if !self.singlestep_trace.is_empty()
-                        && cmd == RunCommand::RunSinglestepFastForward
+                    let executed_instruction = cmd == RunCommand::RunSinglestepFastForward
                         && (singlestep_really_complete
                             || (before_time < after_time
-                                && treat_event_completion_as_singlestep_complete(&replayed_event)))
-                    {
+                                && treat_event_completion_as_singlestep_complete(&replayed_event)));
+                    if executed_instruction {
+                        if let (Some(before), Some(t)) = (&before_instruction, old_task.as_ref()) {
+                            self.write_instruction(
+                                before,
+                                t.borrow().as_ref(),
+                                before_time,
+                                instruction_count_within_event,
+                                &mut stdout(),
+                            )?;
+                        }
+                    }
+                    if !self.singlestep_trace.is_empty() && executed_instruction {
                         self.write_regs(
                             old_task.unwrap().borrow_mut().as_mut(),
                             before_time,
@@ -475,6 +750,9 @@ impl ReRunCommand {
             }
         }
 
+        if let Some(progress) = maybe_progress.as_mut() {
+            progress.finish();
+        }
         log!(LogInfo, "Rerun successfully finished");
         Ok(())
     }
@@ -524,12 +802,436 @@ impl ReRunCommand {
         }
     }
 
-    fn write_value(&self, name: &str, value: &[u8], out: &mut dyn Write) -> io::Result<()> {
-        if self.raw_dump {
-            out.write(value)?;
+    /// Set breakpoints for all the `--break-log` and `--trace-calls` locations we
+    /// can find in `t`'s address space. Symbols are looked up again whenever a new
+    /// executable mapping shows up, e.g. after an exec or after the dynamic linker
+    /// has mapped a library.
+    fn update_breakpoints(&self, t: &mut dyn Task, state: &mut BreakpointState) {
+        let vm = t.vm_shr_ptr();
+        let vm_uid = vm.uid();
+        if state.vms_with_address_breakpoints.insert(vm_uid) {
+            for location in &self.break_log {
+                if let BreakLogLocation::Address(addr) = location {
+                    if self.set_breakpoint(t, &vm, *addr, state) {
+                        state.labels.insert((vm_uid, *addr), addr.to_string());
+                    }
+                }
+            }
+        }
+
+        let symbols: Vec<&str> = self
+            .break_log
+            .iter()
+            .filter_map(|location| match location {
+                BreakLogLocation::Symbol(name) => Some(name.as_str()),
+                BreakLogLocation::Address(_) => None,
+            })
+            .collect();
+        if symbols.is_empty() && self.trace_calls.is_empty() {
+            return;
+        }
+
+        let mut found_new_mapping = false;
+        for (_, m) in &vm.maps() {
+            if m.map.prot().contains(ProtFlags::PROT_EXEC)
+                && state
+                    .scanned_mappings
+                    .insert((vm_uid, m.map.start().as_usize()))
+            {
+                found_new_mapping = true;
+            }
+        }
+        if !found_new_mapping {
+            return;
+        }
+
+        let resolved = resolve_symbols(&mut state.symbols, &vm, |name| {
+            symbols.contains(&name)
+                || self
+                    .trace_calls
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, name))
+        });
+        for (addr, name) in resolved {
+            let key = (vm_uid, addr);
+            if symbols.contains(&name.as_str())
+                && !state.labels.contains_key(&key)
+                && self.set_breakpoint(t, &vm, addr, state)
+            {
+                state.labels.insert(key, name.clone());
+            }
+            if self
+                .trace_calls
+                .iter()
+                .any(|pattern| glob_matches(pattern, &name))
+                && !state.call_entries.contains_key(&key)
+                && self.set_breakpoint(t, &vm, addr, state)
+            {
+                state.call_entries.insert(key, name);
+            }
+        }
+    }
+
+    /// Make sure we have a breakpoint at `addr`. The caller must record the
+    /// breakpoint in `state` if this returns true.
+    fn set_breakpoint(
+        &self,
+        t: &mut dyn Task,
+        vm: &AddressSpace,
+        addr: RemoteCodePtr,
+        state: &BreakpointState,
+    ) -> bool {
+        if state.is_set(&(vm.uid(), addr)) {
+            return true;
+        }
+        if vm.add_breakpoint(t, addr, BreakpointType::BkptUser) {
+            log!(LogDebug, "Set breakpoint at {}", addr);
+            true
         } else {
-            write!(out, "{}:0x", name)?;
-            write_hex(value, out)?;
+            log!(LogWarn, "Could not set breakpoint at {}", addr);
+            false
+        }
+    }
+
+    /// Log whatever the breakpoint `t` has hit is for, then step the task over the
+    /// breakpoint so that replay can continue.
+    fn handle_breakpoint_hit(
+        &self,
+        replay_session: &ReplaySession,
+        t: TaskSharedPtr,
+        state: &mut BreakpointState,
+        out: &mut dyn Write,
+    ) -> io::Result<ReplayStatus> {
+        let addr = t.borrow().ip();
+        let vm = t.borrow().vm_shr_ptr();
+        let key = (vm.uid(), addr);
+        let time = replay_session.trace_reader().time();
+
+        if state.call_returns.contains_key(&key) {
+            self.log_call_return(t.borrow_mut().as_mut(), time, state, out)?;
+        }
+        if let Some(label) = state.labels.get(&key) {
+            write!(
+                out,
+                "[break-log] event:{} tid:{} {}",
+                time,
+                t.borrow().rec_tid,
+                label
+            )?;
+            if self.break_log_print.is_empty() {
+                write!(out, "\n")?;
+            } else {
+                write!(out, " ")?;
+                let values =
+                    self.read_fields(&self.break_log_print, t.borrow_mut().as_mut(), time, 0);
+                self.write_values(OutputFormat::Text, &values, out)?;
+            }
+        }
+        if let Some(name) = state.call_entries.get(&key).cloned() {
+            self.log_call_entry(t.borrow_mut().as_mut(), time, name, state, out)?;
+        }
+
+        // A forked child inherits the breakpoints of its parent's address space
+        // so we may hit breakpoints that aren't in `state`. Either way, step over
+        // whatever breakpoint is still there.
+        drop(t);
+        vm.suspend_breakpoint_at(addr);
+        let result = replay_session.replay_step(RunCommand::RunSinglestep);
+        // The singlestep may have been an exit or exec.
+        if vm.any_task_from_task_set().is_some() {
+            vm.restore_breakpoint_at(addr);
+        }
+        Ok(result.status)
+    }
+
+    /// `t` is at the entry point of a `--trace-calls` function. Log the call and
+    /// set a breakpoint at its return address.
+    fn log_call_entry(
+        &self,
+        t: &mut dyn Task,
+        time: FrameTime,
+        name: String,
+        state: &mut BreakpointState,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let word_size = word_size(t.arch());
+        let sp = t.regs_ref().sp();
+        let args: Vec<usize> = match t.arch() {
+            SupportedArch::X64 => {
+                let regs = t.regs_ref();
+                vec![
+                    regs.arg1(),
+                    regs.arg2(),
+                    regs.arg3(),
+                    regs.cx(),
+                    regs.arg5(),
+                    regs.arg6(),
+                ]
+            }
+            // Arguments are passed on the stack, just above the return address.
+            SupportedArch::X86 => (1..=CALL_ARGS_SHOWN)
+                .map(|i| read_stack_word(t, sp + i * word_size))
+                .collect(),
+        };
+        let return_address = RemoteCodePtr::from_val(read_stack_word(t, sp));
+
+        let stack = state.call_stacks.entry(t.tuid()).or_default();
+        let args: Vec<String> = args.iter().map(|a| format!("{:#x}", a)).collect();
+        write!(
+            out,
+            "[trace-calls] event:{} tid:{} {:indent$}{}({})\n",
+            time,
+            t.rec_tid,
+            "",
+            name,
+            args.join(", "),
+            indent = 2 * stack.len()
+        )?;
+        stack.push(TracedCall {
+            name,
+            return_address,
+            entry_sp: sp.as_usize(),
+        });
+
+        let vm = t.vm_shr_ptr();
+        let key = (vm.uid(), return_address);
+        if self.set_breakpoint(t, &vm, return_address, state) {
+            *state.call_returns.entry(key).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    /// `t` is at a return address of a traced call. Log the return value of the
+    /// call if it was made by `t`, along with any calls that were unwound without
+    /// returning (e.g. by `longjmp`).
+    fn log_call_return(
+        &self,
+        t: &mut dyn Task,
+        time: FrameTime,
+        state: &mut BreakpointState,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let sp = t.regs_ref().sp().as_usize();
+        let ip = t.ip();
+        let word_size = word_size(t.arch());
+        let stack = match state.call_stacks.get_mut(&t.tuid()) {
+            Some(stack) => stack,
+            None => return Ok(()),
+        };
+        let mut finished = Vec::new();
+        while let Some(call) = stack.last() {
+            // `ret` popped the return address so any call whose entry sp is
+            // below the current sp is over.
+            if call.entry_sp >= sp {
+                break;
+            }
+            finished.push(stack.pop().unwrap());
+        }
+        // Innermost call first
+        let depth = stack.len() + finished.len();
+
+        let vm = t.vm_shr_ptr();
+        for (i, call) in finished.iter().enumerate() {
+            let indent = 2 * (depth - 1 - i);
+            if call.return_address == ip && call.entry_sp + word_size == sp {
+                write!(
+                    out,
+                    "[trace-calls] event:{} tid:{} {:indent$}{} = {:#x}\n",
+                    time,
+                    t.rec_tid,
+                    "",
+                    call.name,
+                    t.regs_ref().ax(),
+                    indent = indent
+                )?;
+            } else {
+                write!(
+                    out,
+                    "[trace-calls] event:{} tid:{} {:indent$}{} unwound\n",
+                    time,
+                    t.rec_tid,
+                    "",
+                    call.name,
+                    indent = indent
+                )?;
+            }
+
+            let key = (vm.uid(), call.return_address);
+            let remaining = match state.call_returns.get_mut(&key) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                }
+                None => continue,
+            };
+            if remaining == 0 {
+                state.call_returns.remove(&key);
+                if !state.is_set(&key) {
+                    vm.remove_breakpoint(call.return_address, BreakpointType::BkptUser, t);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Output the stack of every task in `replay_session`.
+    fn write_backtraces(
+        &self,
+        replay_session: &ReplaySession,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let time = replay_session.trace_reader().time();
+        let tasks: Vec<TaskSharedPtr> = replay_session.tasks().values().cloned().collect();
+        let mut unwinder = Unwinder::new();
+        for t in tasks {
+            let frames = unwinder.backtrace(t.borrow_mut().as_mut());
+            let vm = t.borrow().vm_shr_ptr();
+            write!(
+                out,
+                "[backtrace] event:{} tid:{}\n",
+                time,
+                t.borrow().rec_tid
+            )?;
+            for (i, frame) in frames.iter().enumerate() {
+                let method = match frame.method {
+                    UnwindMethod::FramePointer => " [frame pointer]",
+                    UnwindMethod::Registers | UnwindMethod::Cfi => "",
+                };
+                write!(
+                    out,
+                    "#{:<3} {} in {}{}\n",
+                    i,
+                    frame.ip,
+                    unwinder.describe(&vm, frame),
+                    method
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_values(
+        &self,
+        format: OutputFormat,
+        values: &[(String, Vec<u8>)],
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        match format {
+            OutputFormat::Raw => {
+                for (_, value) in values {
+                    out.write(value)?;
+                }
+            }
+            OutputFormat::Text => {
+                for (i, (name, value)) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, " ")?;
+                    }
+                    write!(out, "{}:0x", name)?;
+                    write_hex(value, out)?;
+                }
+            }
+            OutputFormat::Json => {
+                write!(out, "{{")?;
+                for (i, (name, value)) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    if name == "event" || name == "icount" {
+                        write!(
+                            out,
+                            "\"{}\":{}",
+                            name,
+                            u64::from_le_bytes(value[..].try_into().unwrap())
+                        )?;
+                    } else {
+                        write!(out, "\"{}\":\"0x", name)?;
+                        write_hex(value, out)?;
+                        write!(out, "\"")?;
+                    }
+                }
+                write!(out, "}}")?;
+            }
+            OutputFormat::Csv => {
+                if !self.csv_header_written.replace(true) {
+                    let names: Vec<&str> = values.iter().map(|(name, _)| name.as_str()).collect();
+                    write!(out, "{}\n", names.join(","))?;
+                }
+                for (i, (_, value)) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    write!(out, "0x")?;
+                    write_hex(value, out)?;
+                }
+            }
+        }
+        write!(out, "\n")?;
+        Ok(())
+    }
+
+    /// Output the instruction described by `before` and the registers that `t`
+    /// changed by executing it.
+    fn write_instruction(
+        &self,
+        before: &InstructionState,
+        t: &dyn Task,
+        event: FrameTime,
+        instruction_count: u64,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let (length, text) = match disassemble(t.arch(), &before.bytes, before.ip.as_usize() as u64)
+        {
+            Some(insn) => (insn.length, insn.text),
+            None => (min(1, before.bytes.len()), "(bad)".to_owned()),
+        };
+        let mut bytes = String::new();
+        for (i, b) in before.bytes[0..length].iter().enumerate() {
+            if i > 0 {
+                bytes.push(' ');
+            }
+            write!(bytes, "{:02x}", b).unwrap();
+        }
+
+        // The new ip is always different and is output as part of the next
+        // instruction anyway.
+        let changed: Vec<(&str, u64, u64)> = before
+            .regs
+            .register_values()
+            .into_iter()
+            .zip(t.regs_ref().register_values())
+            .filter(|((name, old), (_, new))| old != new && *name != "rip" && *name != "eip")
+            .map(|((name, old), (_, new))| (name, old, new))
+            .collect();
+
+        if self.format == OutputFormat::Json {
+            let mut changed_json = serde_json::Map::new();
+            for (name, old, new) in changed {
+                changed_json.insert(
+                    name.to_owned(),
+                    json!([format!("{:#x}", old), format!("{:#x}", new)]),
+                );
+            }
+            let record = json!({
+                "event": event,
+                "icount": instruction_count,
+                "tid": t.rec_tid,
+                "ip": before.ip.to_string(),
+                "bytes": bytes,
+                "insn": text,
+                "changed": changed_json,
+            });
+            write!(out, "{}\n", record)?;
+        } else {
+            write!(
+                out,
+                "event:{} icount:{} tid:{} {}: {:<30}{}",
+                event, instruction_count, t.rec_tid, before.ip, bytes, text
+            )?;
+            for (name, old, new) in changed {
+                write!(out, " {}:{:#x}->{:#x}", name, old, new)?;
+            }
+            write!(out, "\n")?;
         }
         Ok(())
     }
@@ -541,34 +1243,40 @@ impl ReRunCommand {
         instruction_count: u64,
         out: &mut dyn Write,
     ) -> io::Result<()> {
+        let values = self.read_fields(&self.singlestep_trace, t, event, instruction_count);
+        self.write_values(self.format, &values, out)
+    }
+
+    /// Collect the name and little-endian value of each of `fields`.
+    fn read_fields(
+        &self,
+        fields: &[TraceField],
+        t: &mut dyn Task,
+        event: FrameTime,
+        instruction_count: u64,
+    ) -> Vec<(String, Vec<u8>)> {
+        let mut values: Vec<(String, Vec<u8>)> = Vec::new();
         let mut got_gp_regs = false;
         let mut gp_regs: RegsData = unsafe { mem::zeroed() };
-        let mut first = true;
-
-        for field in &self.singlestep_trace {
-            if first {
-                first = false;
-            } else if !self.raw_dump {
-                write!(out, " ")?;
-            }
 
+        for field in fields {
             match field.kind {
                 TraceFieldKind::TraceEventNumber => {
                     let value: u64 = event;
-                    self.write_value("event", &value.to_le_bytes(), out)?;
+                    values.push(("event".into(), value.to_le_bytes().to_vec()));
                 }
                 TraceFieldKind::TraceInstructionCount => {
-                    self.write_value("icount", &instruction_count.to_le_bytes(), out)?;
+                    values.push(("icount".into(), instruction_count.to_le_bytes().to_vec()));
                 }
                 TraceFieldKind::TraceIp => {
                     // Note the `as u64` to make write_regs() output length uniform between x86 and x86_64
                     let value: u64 = t.regs_ref().ip().register_value() as u64;
                     match t.arch() {
                         SupportedArch::X86 => {
-                            self.write_value("eip", &value.to_le_bytes(), out)?;
+                            values.push(("eip".into(), value.to_le_bytes().to_vec()));
                         }
                         SupportedArch::X64 => {
-                            self.write_value("rip", &value.to_le_bytes(), out)?;
+                            values.push(("rip".into(), value.to_le_bytes().to_vec()));
                         }
                     }
                 }
@@ -578,7 +1286,7 @@ impl ReRunCommand {
                         Registers::X86(_) => 0,
                         Registers::X64(regs) => regs.fs_base,
                     };
-                    self.write_value("fsbase", &value.to_le_bytes(), out)?;
+                    values.push(("fsbase".into(), value.to_le_bytes().to_vec()));
                 }
                 TraceFieldKind::TraceGsbase => {
                     // @TODO will rr also give 0 for x86?
@@ -586,17 +1294,17 @@ impl ReRunCommand {
                         Registers::X86(_) => 0,
                         Registers::X64(regs) => regs.gs_base,
                     };
-                    self.write_value("gsbase", &value.to_le_bytes(), out)?;
+                    values.push(("gsbase".into(), value.to_le_bytes().to_vec()));
                 }
                 TraceFieldKind::TraceFlags => {
                     // Note the `as u64` to make write_regs() output length uniform between x86 and x86_64
                     let value: u64 = t.regs_ref().flags() as u64;
                     match t.arch() {
                         SupportedArch::X86 => {
-                            self.write_value("eflags", &value.to_le_bytes(), out)?;
+                            values.push(("eflags".into(), value.to_le_bytes().to_vec()));
                         }
                         SupportedArch::X64 => {
-                            self.write_value("rflags", &value.to_le_bytes(), out)?;
+                            values.push(("rflags".into(), value.to_le_bytes().to_vec()));
                         }
                     }
                 }
@@ -605,24 +1313,23 @@ impl ReRunCommand {
                     let value: u64 = t.regs_ref().original_syscallno() as u64;
                     match t.arch() {
                         SupportedArch::X86 => {
-                            self.write_value("orig_eax", &value.to_le_bytes(), out)?;
+                            values.push(("orig_eax".into(), value.to_le_bytes().to_vec()));
                         }
                         SupportedArch::X64 => {
-                            self.write_value("orig_rax", &value.to_le_bytes(), out)?;
+                            values.push(("orig_rax".into(), value.to_le_bytes().to_vec()));
                         }
                     }
                 }
                 TraceFieldKind::TraceSegReg => {
                     let value: u64 = seg_reg(t.regs_ref(), field.reg_num);
-                    self.write_value(
-                        SEG_REG_NAMES[field.reg_num as usize],
-                        &value.to_le_bytes(),
-                        out,
-                    )?;
+                    values.push((
+                        SEG_REG_NAMES[field.reg_num as usize].into(),
+                        value.to_le_bytes().to_vec(),
+                    ));
                 }
                 TraceFieldKind::TraceXinuse => {
                     let value: u64 = t.extra_regs_ref().read_xinuse().unwrap_or(0);
-                    self.write_value("xinuse", &value.to_le_bytes(), out)?;
+                    values.push(("xinuse".into(), value.to_le_bytes().to_vec()));
                 }
                 // @TODO Will this work properly if rr is a x86 build?
                 TraceFieldKind::TraceGpReg => {
@@ -649,7 +1356,7 @@ impl ReRunCommand {
                     } else {
                         GP_REG_NAMES[field.reg_num as usize]
                     };
-                    self.write_value(name, &value.to_le_bytes(), out)?;
+                    values.push((name.into(), value.to_le_bytes().to_vec()));
                 }
                 TraceFieldKind::TraceXmmReg => {
                     let mut value = [0u8; 16];
@@ -673,7 +1380,7 @@ impl ReRunCommand {
                     }
                     let mut name = String::new();
                     write!(name, "xmm{}", field.reg_num).unwrap();
-                    self.write_value(&name, &value, out)?;
+                    values.push((name, value.to_vec()));
                 }
                 TraceFieldKind::TraceYmmReg => {
                     let mut value = [0u8; 32];
@@ -705,11 +1412,17 @@ impl ReRunCommand {
                     }
                     let mut name = String::new();
                     write!(name, "ymm{}", field.reg_num).unwrap();
-                    self.write_value(&name, &value, out)?;
+                    values.push((name, value.to_vec()));
+                }
+                TraceFieldKind::TraceStackWord => {
+                    let addr = t.regs_ref().sp() + field.reg_num as usize * word_size(t.arch());
+                    let value = read_stack_word(t, addr) as u64;
+                    let mut name = String::new();
+                    write!(name, "stack[{}]", field.reg_num).unwrap();
+                    values.push((name, value.to_le_bytes().to_vec()));
                 }
             }
         }
-        write!(out, "\n")?;
-        Ok(())
+        values
     }
 }
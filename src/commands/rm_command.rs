@@ -0,0 +1,62 @@
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_dir::{is_trace_dir, remove_trace, trace_dir_info, TraceDirStatus},
+        trace_reader::resolve_trace_name,
+    },
+};
+use std::{io, path::PathBuf};
+
+pub struct RmCommand {
+    force: bool,
+    traces: Vec<PathBuf>,
+}
+
+impl RmCommand {
+    pub fn new(options: &RdOptions) -> RmCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Rm { force, traces } => RmCommand { force, traces },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Rm` variant!"),
+        }
+    }
+
+    fn rm(&self) -> io::Result<()> {
+        // Check everything before deleting anything, so a typo in the last argument doesn't
+        // leave the job half done.
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        for trace in &self.traces {
+            let dir = PathBuf::from(resolve_trace_name(Some(trace)));
+            if !is_trace_dir(&dir) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}' is not a trace directory", trace.display()),
+                ));
+            }
+            if !self.force && trace_dir_info(&dir)?.status == TraceDirStatus::Incomplete {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "`{}' is incomplete and may still be being recorded. \
+                         Use --force to delete it anyway",
+                        trace.display()
+                    ),
+                ));
+            }
+            dirs.push(dir);
+        }
+
+        for dir in &dirs {
+            remove_trace(dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl RdCommand for RmCommand {
+    fn run(&mut self) -> io::Result<()> {
+        self.rm()
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_dir::is_trace_dir,
+        trace_metadata::{is_valid_trace_name, TraceMetadata},
+        trace_reader::resolve_trace_name,
+    },
+};
+use std::{
+    io,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+pub struct TagCommand {
+    name: Option<String>,
+    description: Option<String>,
+    tags: Vec<(String, String)>,
+    untag: Vec<String>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl TagCommand {
+    pub fn new(options: &RdOptions) -> TagCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Tag {
+                name,
+                description,
+                tags,
+                untag,
+                trace_dir,
+            } => TagCommand {
+                name,
+                description,
+                tags,
+                untag,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Tag` variant!"),
+        }
+    }
+
+    fn tag(&self, out: &mut dyn Write) -> io::Result<()> {
+        let dir = PathBuf::from(resolve_trace_name(self.trace_dir.as_ref()));
+        if !is_trace_dir(&dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}' is not a trace directory", dir.display()),
+            ));
+        }
+        let mut metadata = TraceMetadata::load(&dir)?;
+
+        let unchanged = self.name.is_none()
+            && self.description.is_none()
+            && self.tags.is_empty()
+            && self.untag.is_empty();
+        if unchanged {
+            let json = serde_json::to_string_pretty(&metadata).unwrap();
+            return write!(out, "{}\n", json);
+        }
+
+        match self.name.as_deref() {
+            Some("") => metadata.name = None,
+            Some(name) if !is_valid_trace_name(name) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Trace names must not contain `/` or whitespace",
+                ));
+            }
+            Some(name) => metadata.name = Some(name.to_owned()),
+            None => (),
+        }
+        match self.description.as_deref() {
+            Some("") => metadata.description = None,
+            Some(description) => metadata.description = Some(description.to_owned()),
+            None => (),
+        }
+        for key in &self.untag {
+            metadata.tags.remove(key);
+        }
+        for (key, value) in &self.tags {
+            metadata.tags.insert(key.clone(), value.clone());
+        }
+        metadata.save(&dir)
+    }
+}
+
+impl RdCommand for TagCommand {
+    fn run(&mut self) -> io::Result<()> {
+        self.tag(&mut stdout())
+    }
+}
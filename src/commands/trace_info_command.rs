@@ -8,11 +8,17 @@ use crate::{
         replay_session::{Flags, ReplaySession, ReplayStatus},
         session_inner::RunCommand,
     },
-    trace::trace_reader::TraceReader,
+    trace::{trace_metadata::TraceMetadata, trace_reader::TraceReader},
     util::read_env,
 };
 use serde::Serialize;
-use std::{convert::TryInto, ffi::CString, io, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    ffi::CString,
+    io,
+    path::{Path, PathBuf},
+};
 
 pub struct TraceInfoCommand {
     trace_dir: Option<PathBuf>,
@@ -37,6 +43,13 @@ struct TraceHeader {
     ticks_semantics: String,
     cpuid_records: Vec<[u32; 6]>,
     environ: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    tags: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chaos_seed: Option<u64>,
 }
 
 impl RdCommand for TraceInfoCommand {
@@ -44,6 +57,7 @@ impl RdCommand for TraceInfoCommand {
         let trace = TraceReader::new(self.trace_dir.as_ref());
 
         let uuid_bytes = trace.uuid().bytes;
+        let metadata = TraceMetadata::load(Path::new(trace.dir()))?;
         let xcr0 = trace.xcr0();
         let bind_to_cpu = trace.bound_to_cpu();
         let cpuid_faulting = trace.uses_cpuid_faulting();
@@ -94,6 +108,10 @@ impl RdCommand for TraceInfoCommand {
             ticks_semantics,
             cpuid_records,
             environ: environ_strings,
+            name: metadata.name,
+            description: metadata.description,
+            tags: metadata.tags,
+            chaos_seed: metadata.chaos_seed,
         };
 
         let serialized = serde_json::to_string(&header).unwrap();
@@ -0,0 +1,1694 @@
+//! A small table driven x86/x86-64 disassembler producing Intel syntax.
+//!
+//! This is only meant to make instruction traces readable. It knows the general
+//! purpose instructions and the common SSE/AVX moves and arithmetic well. For
+//! anything else it still works out the length of the instruction (so that
+//! decoding can continue) but the mnemonic may just describe the opcode.
+
+use crate::kernel_abi::SupportedArch;
+use std::fmt::Write;
+
+/// The longest legal x86 instruction.
+pub const MAX_INSN_LENGTH: usize = 15;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    pub length: usize,
+    pub text: String,
+}
+
+/// Disassemble the instruction at the start of `bytes`, which lives at address `ip`.
+/// `None` is returned if `bytes` doesn't hold a complete, valid instruction.
+pub fn disassemble(arch: SupportedArch, bytes: &[u8], ip: u64) -> Option<Instruction> {
+    let mut d = Decoder::new(arch, bytes, ip);
+    let text = d.decode()?;
+    if d.pos > MAX_INSN_LENGTH {
+        return None;
+    }
+    Some(Instruction {
+        length: d.pos,
+        text,
+    })
+}
+
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+const REGS8_LEGACY: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REGS16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const SEG_REGS: [&str; 8] = ["es", "cs", "ss", "ds", "fs", "gs", "?", "?"];
+
+const CONDITION_CODES: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+const GROUP1: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const GROUP2: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const GROUP3: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+
+#[derive(Copy, Clone)]
+struct ModRM {
+    md: u8,
+    /// Includes REX.R (or the VEX equivalent)
+    reg: u8,
+    /// Includes REX.B when `md == 3`
+    rm: u8,
+}
+
+#[derive(Copy, Clone)]
+struct Vex {
+    /// 1 = 0f, 2 = 0f38, 3 = 0f3a
+    map: u8,
+    /// 0 = none, 1 = 66, 2 = f3, 3 = f2
+    pp: u8,
+    l: bool,
+    w: bool,
+    vvvv: u8,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    ip: u64,
+    is_64: bool,
+    opsize_prefix: bool,
+    addrsize_prefix: bool,
+    /// 0xf2 or 0xf3
+    rep: Option<u8>,
+    lock: bool,
+    segment: Option<&'static str>,
+    rex: u8,
+    vex: Option<Vex>,
+    modrm: Option<ModRM>,
+    /// The memory operand, without any size qualifier. Filled in when the ModRM
+    /// byte (and any SIB and displacement) is consumed.
+    mem: String,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(arch: SupportedArch, bytes: &'a [u8], ip: u64) -> Decoder<'a> {
+        Decoder {
+            bytes,
+            pos: 0,
+            ip,
+            is_64: arch == SupportedArch::X64,
+            opsize_prefix: false,
+            addrsize_prefix: false,
+            rep: None,
+            lock: false,
+            segment: None,
+            rex: 0,
+            vex: None,
+            modrm: None,
+            mem: String::new(),
+        }
+    }
+
+    fn next_u8(&mut self) -> Option<u8> {
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn peek_u8(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    /// Read a little-endian immediate of `size` bytes, sign-extended.
+    fn imm(&mut self, size: usize) -> Option<i64> {
+        if size == 0 {
+            return Some(0);
+        }
+        let mut v: u64 = 0;
+        for i in 0..size {
+            v |= (self.next_u8()? as u64) << (8 * i);
+        }
+        let shift = 64 - 8 * size as u32;
+        Some(((v << shift) as i64) >> shift)
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0 || self.vex.map_or(false, |v| v.w)
+    }
+
+    fn rex_r(&self) -> u8 {
+        if self.rex & 4 != 0 {
+            8
+        } else {
+            0
+        }
+    }
+
+    fn rex_x(&self) -> u8 {
+        if self.rex & 2 != 0 {
+            8
+        } else {
+            0
+        }
+    }
+
+    fn rex_b(&self) -> u8 {
+        if self.rex & 1 != 0 {
+            8
+        } else {
+            0
+        }
+    }
+
+    /// The operand size in bytes. `d64` is set for instructions which default to
+    /// 64-bit operands in long mode, e.g. push and pop.
+    fn opsize(&self, d64: bool) -> usize {
+        if self.is_64 && self.rex_w() {
+            8
+        } else if self.opsize_prefix {
+            2
+        } else if self.is_64 && d64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn addrsize(&self) -> usize {
+        match (self.is_64, self.addrsize_prefix) {
+            (true, false) => 8,
+            (true, true) => 4,
+            (false, false) => 4,
+            (false, true) => 2,
+        }
+    }
+
+    fn reg_name(&self, num: u8, size: usize) -> &'static str {
+        let num = num as usize & 15;
+        match size {
+            1 if self.rex == 0 && num < 8 => REGS8_LEGACY[num],
+            1 => REGS8[num],
+            2 => REGS16[num],
+            4 => REGS32[num],
+            _ => REGS64[num],
+        }
+    }
+
+    fn addr_reg_name(&self, num: u8) -> &'static str {
+        if self.addrsize() == 8 {
+            REGS64[num as usize & 15]
+        } else {
+            REGS32[num as usize & 15]
+        }
+    }
+
+    fn modrm(&mut self) -> Option<ModRM> {
+        if let Some(m) = self.modrm {
+            return Some(m);
+        }
+        let b = self.next_u8()?;
+        let md = b >> 6;
+        let reg = ((b >> 3) & 7) | self.rex_r();
+        let mut rm = b & 7;
+        if md == 3 {
+            rm |= self.rex_b();
+        } else if self.addrsize() == 2 {
+            self.decode_mem16(md, rm)?;
+        } else {
+            self.decode_mem(md, rm)?;
+        }
+        let m = ModRM { md, reg, rm };
+        self.modrm = Some(m);
+        Some(m)
+    }
+
+    fn decode_mem(&mut self, md: u8, rm: u8) -> Option<()> {
+        let mut base: Option<&'static str> = None;
+        let mut index: Option<(&'static str, u8)> = None;
+        let mut rip_relative = false;
+        let disp_size;
+
+        if rm == 4 {
+            let sib = self.next_u8()?;
+            let scale = 1 << (sib >> 6);
+            let index_num = ((sib >> 3) & 7) | self.rex_x();
+            let base_num = sib & 7;
+            if index_num != 4 {
+                index = Some((self.addr_reg_name(index_num), scale));
+            }
+            if base_num == 5 && md == 0 {
+                disp_size = 4;
+            } else {
+                base = Some(self.addr_reg_name(base_num | self.rex_b()));
+                disp_size = [0, 1, 4][md as usize];
+            }
+        } else if rm == 5 && md == 0 {
+            rip_relative = self.is_64;
+            disp_size = 4;
+        } else {
+            base = Some(self.addr_reg_name(rm | self.rex_b()));
+            disp_size = [0, 1, 4][md as usize];
+        }
+
+        let disp = self.imm(disp_size)?;
+        let mut s = String::new();
+        if let Some(seg) = self.segment {
+            write!(s, "{}:", seg).unwrap();
+        }
+        s.push('[');
+        let mut have_term = false;
+        if rip_relative {
+            s.push_str(if self.addrsize() == 8 { "rip" } else { "eip" });
+            have_term = true;
+        }
+        if let Some(b) = base {
+            s.push_str(b);
+            have_term = true;
+        }
+        if let Some((i, scale)) = index {
+            if have_term {
+                s.push('+');
+            }
+            write!(s, "{}*{}", i, scale).unwrap();
+            have_term = true;
+        }
+        if !have_term {
+            write!(s, "{:#x}", disp as u32).unwrap();
+        } else if disp < 0 {
+            write!(s, "-{:#x}", -(disp as i128)).unwrap();
+        } else if disp > 0 {
+            write!(s, "+{:#x}", disp).unwrap();
+        }
+        s.push(']');
+        self.mem = s;
+        Some(())
+    }
+
+    fn decode_mem16(&mut self, md: u8, rm: u8) -> Option<()> {
+        const BASES: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+        let (base, disp) = if md == 0 && rm == 6 {
+            (None, self.imm(2)?)
+        } else {
+            (Some(BASES[rm as usize]), self.imm([0, 1, 2][md as usize])?)
+        };
+        let mut s = String::new();
+        if let Some(seg) = self.segment {
+            write!(s, "{}:", seg).unwrap();
+        }
+        match base {
+            Some(b) if disp < 0 => write!(s, "[{}-{:#x}]", b, -disp).unwrap(),
+            Some(b) if disp > 0 => write!(s, "[{}+{:#x}]", b, disp).unwrap(),
+            Some(b) => write!(s, "[{}]", b).unwrap(),
+            None => write!(s, "[{:#x}]", disp as u16).unwrap(),
+        }
+        self.mem = s;
+        Some(())
+    }
+
+    fn mem_operand(&self, size: usize) -> String {
+        let qualifier = match size {
+            1 => "byte ptr ",
+            2 => "word ptr ",
+            4 => "dword ptr ",
+            8 => "qword ptr ",
+            10 => "tbyte ptr ",
+            16 => "xmmword ptr ",
+            32 => "ymmword ptr ",
+            _ => "",
+        };
+        format!("{}{}", qualifier, self.mem)
+    }
+
+    fn vec_reg(&self, num: u8) -> String {
+        let wide = self.vex.map_or(false, |v| v.l);
+        format!("{}{}", if wide { "ymm" } else { "xmm" }, num & 15)
+    }
+
+    fn vec_size(&self) -> usize {
+        if self.vex.map_or(false, |v| v.l) {
+            32
+        } else {
+            16
+        }
+    }
+
+    /// True if an SSE integer instruction operates on xmm rather than mmx registers.
+    fn uses_xmm(&self) -> bool {
+        self.opsize_prefix || self.vex.is_some()
+    }
+
+    fn size_for(&self, c: char, d64: bool) -> usize {
+        match c {
+            'b' => 1,
+            'w' => 2,
+            'd' => 4,
+            'q' => 8,
+            'v' => self.opsize(d64),
+            'z' => self.opsize(d64).min(4),
+            'y' => {
+                if self.is_64 && self.rex_w() {
+                    8
+                } else {
+                    4
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn target(&self, rel: i64) -> String {
+        let next = self.ip.wrapping_add(self.pos as u64);
+        let target = next.wrapping_add(rel as u64);
+        if self.is_64 {
+            format!("{:#x}", target)
+        } else {
+            format!("{:#x}", target as u32)
+        }
+    }
+
+    fn immediate(&self, value: i64, size: usize) -> String {
+        if value < 0 && size < 8 {
+            let mask = if size >= 8 {
+                u64::MAX
+            } else {
+                (1u64 << (size * 8)) - 1
+            };
+            format!("{:#x}", value as u64 & mask)
+        } else {
+            format!("{:#x}", value)
+        }
+    }
+
+    /// Decode an operand in the notation of the Intel opcode maps, e.g. `Ev`, `Gb`, `Iz`.
+    fn operand(&mut self, spec: &str, d64: bool) -> Option<String> {
+        let mut chars = spec.chars();
+        let kind = chars.next()?;
+        let size_char = chars.next().unwrap_or(' ');
+        let size = self.size_for(size_char, d64);
+        let s = match kind {
+            'E' => {
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    self.reg_name(m.rm, size).to_owned()
+                } else {
+                    self.mem_operand(size)
+                }
+            }
+            'M' => {
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    return None;
+                }
+                self.mem_operand(size)
+            }
+            'G' => {
+                let m = self.modrm()?;
+                self.reg_name(m.reg, size).to_owned()
+            }
+            'S' => {
+                let m = self.modrm()?;
+                SEG_REGS[(m.reg & 7) as usize].to_owned()
+            }
+            'Z' => {
+                let low = self.bytes[self.pos - 1] & 7;
+                self.reg_name(low | self.rex_b(), size).to_owned()
+            }
+            'I' => {
+                if size_char == 'v' {
+                    // mov r64, imm64 is the only instruction with a full size immediate
+                    let size = self.opsize(false);
+                    let v = self.imm(size)?;
+                    self.immediate(v, size)
+                } else if size_char == 'S' {
+                    let v = self.imm(1)?;
+                    self.immediate(v, self.opsize(d64))
+                } else {
+                    let v = self.imm(size)?;
+                    self.immediate(
+                        v,
+                        if size_char == 'z' {
+                            self.opsize(d64)
+                        } else {
+                            size
+                        },
+                    )
+                }
+            }
+            'J' => {
+                let v = self.imm(size)?;
+                self.target(v)
+            }
+            'O' => {
+                let addr = self.imm(self.addrsize())? as u64;
+                let mut s: String = match size {
+                    1 => "byte ptr ".into(),
+                    2 => "word ptr ".into(),
+                    4 => "dword ptr ".into(),
+                    _ => "qword ptr ".into(),
+                };
+                if let Some(seg) = self.segment {
+                    write!(s, "{}:", seg).unwrap();
+                }
+                write!(s, "[{:#x}]", addr).unwrap();
+                s
+            }
+            'A' => {
+                // Fixed accumulator, e.g. `AL` or `Av` (rAX)
+                self.reg_name(0, if size_char == 'L' { 1 } else { size })
+                    .to_owned()
+            }
+            'C' => {
+                // `CL`
+                "cl".to_owned()
+            }
+            'D' => {
+                // `DX`
+                "dx".to_owned()
+            }
+            '1' => "1".to_owned(),
+            'V' => {
+                let m = self.modrm()?;
+                self.vec_reg(m.reg)
+            }
+            'H' => {
+                let vvvv = self.vex.map_or(0, |v| v.vvvv);
+                self.vec_reg(vvvv)
+            }
+            'W' => {
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    self.vec_reg(m.rm)
+                } else {
+                    // Scalar single and double instructions only touch the low element.
+                    // An explicit size wins over the one implied by the prefix.
+                    let size = match (size_char, self.sse_prefix()) {
+                        ('d', _) => 4,
+                        ('q', _) => 8,
+                        (_, 2) => 4,
+                        (_, 3) => 8,
+                        _ => self.vec_size(),
+                    };
+                    self.mem_operand(size)
+                }
+            }
+            'P' => {
+                let m = self.modrm()?;
+                if self.uses_xmm() {
+                    self.vec_reg(m.reg)
+                } else {
+                    format!("mm{}", m.reg & 7)
+                }
+            }
+            'Q' => {
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    if self.uses_xmm() {
+                        self.vec_reg(m.rm)
+                    } else {
+                        format!("mm{}", m.rm & 7)
+                    }
+                } else {
+                    self.mem_operand(if self.uses_xmm() { self.vec_size() } else { 8 })
+                }
+            }
+            _ => return None,
+        };
+        Some(s)
+    }
+
+    /// Format `mnemonic` followed by the operands given by the comma separated `specs`.
+    fn insn(&mut self, mnemonic: &str, specs: &str, d64: bool) -> Option<String> {
+        let mut operands = Vec::new();
+        for spec in specs.split(',').filter(|s| !s.is_empty()) {
+            operands.push(self.operand(spec, d64)?);
+        }
+        let mut s = String::new();
+        if self.lock {
+            s.push_str("lock ");
+        }
+        s.push_str(mnemonic);
+        if !operands.is_empty() {
+            s.push(' ');
+            s.push_str(&operands.join(", "));
+        }
+        Some(s)
+    }
+
+    fn decode(&mut self) -> Option<String> {
+        loop {
+            let b = self.peek_u8()?;
+            match b {
+                0x66 => self.opsize_prefix = true,
+                0x67 => self.addrsize_prefix = true,
+                0xf0 => self.lock = true,
+                0xf2 | 0xf3 => self.rep = Some(b),
+                0x26 => self.segment = Some("es"),
+                0x2e => self.segment = Some("cs"),
+                0x36 => self.segment = Some("ss"),
+                0x3e => self.segment = Some("ds"),
+                0x64 => self.segment = Some("fs"),
+                0x65 => self.segment = Some("gs"),
+                _ => break,
+            }
+            self.pos += 1;
+            if self.pos >= MAX_INSN_LENGTH {
+                return None;
+            }
+        }
+
+        if self.is_64 {
+            if let Some(b @ 0x40..=0x4f) = self.peek_u8() {
+                self.rex = b;
+                self.pos += 1;
+            }
+        }
+
+        let op = self.next_u8()?;
+        match op {
+            0xc4 | 0xc5
+                if self.is_64 || self.bytes.get(self.pos).map_or(false, |b| b >> 6 == 3) =>
+            {
+                self.decode_vex(op)
+            }
+            0x62 if self.is_64 => self.decode_evex(),
+            0x0f => self.decode_0f(),
+            _ => self.decode_one_byte(op),
+        }
+    }
+
+    fn string_insn(&mut self, base: &str, byte_form: bool) -> Option<String> {
+        let suffix = if byte_form {
+            "b"
+        } else {
+            match self.opsize(false) {
+                2 => "w",
+                4 => "d",
+                _ => "q",
+            }
+        };
+        let prefix = match self.rep {
+            Some(0xf3) if base == "cmps" || base == "scas" => "repe ",
+            Some(0xf3) => "rep ",
+            Some(_) => "repne ",
+            None => "",
+        };
+        Some(format!("{}{}{}", prefix, base, suffix))
+    }
+
+    fn decode_one_byte(&mut self, op: u8) -> Option<String> {
+        let is_64 = self.is_64;
+        if op < 0x40 && op & 7 < 6 {
+            let mnemonic = GROUP1[(op >> 3) as usize];
+            let specs = ["Eb,Gb", "Ev,Gv", "Gb,Eb", "Gv,Ev", "AL,Ib", "Av,Iz"][(op & 7) as usize];
+            return self.insn(mnemonic, specs, false);
+        }
+        match op {
+            0x06 | 0x0e | 0x16 | 0x1e if !is_64 => {
+                let seg = SEG_REGS[(op >> 3) as usize];
+                Some(format!("push {}", seg))
+            }
+            0x07 | 0x17 | 0x1f if !is_64 => {
+                let seg = SEG_REGS[(op >> 3) as usize];
+                Some(format!("pop {}", seg))
+            }
+            0x27 if !is_64 => Some("daa".into()),
+            0x2f if !is_64 => Some("das".into()),
+            0x37 if !is_64 => Some("aaa".into()),
+            0x3f if !is_64 => Some("aas".into()),
+            0x40..=0x47 => self.insn("inc", "Zv", false),
+            0x48..=0x4f => self.insn("dec", "Zv", false),
+            0x50..=0x57 => self.insn("push", "Zv", true),
+            0x58..=0x5f => self.insn("pop", "Zv", true),
+            0x60 if !is_64 => Some("pusha".into()),
+            0x61 if !is_64 => Some("popa".into()),
+            0x63 if is_64 => self.insn("movsxd", "Gv,Ed", false),
+            0x63 => self.insn("arpl", "Ew,Gw", false),
+            0x68 => self.insn("push", "Iz", true),
+            0x69 => self.insn("imul", "Gv,Ev,Iz", false),
+            0x6a => self.insn("push", "IS", true),
+            0x6b => self.insn("imul", "Gv,Ev,IS", false),
+            0x6c => self.string_insn("ins", true),
+            0x6d => self.string_insn("ins", false),
+            0x6e => self.string_insn("outs", true),
+            0x6f => self.string_insn("outs", false),
+            0x70..=0x7f => {
+                let mnemonic = format!("j{}", CONDITION_CODES[(op & 0xf) as usize]);
+                self.insn(&mnemonic, "Jb", false)
+            }
+            0x80..=0x83 => {
+                if op == 0x82 && is_64 {
+                    return None;
+                }
+                let m = self.modrm()?;
+                let specs = match op {
+                    0x81 => "Ev,Iz",
+                    0x83 => "Ev,IS",
+                    _ => "Eb,Ib",
+                };
+                self.insn(GROUP1[(m.reg & 7) as usize], specs, false)
+            }
+            0x84 => self.insn("test", "Eb,Gb", false),
+            0x85 => self.insn("test", "Ev,Gv", false),
+            0x86 => self.insn("xchg", "Eb,Gb", false),
+            0x87 => self.insn("xchg", "Ev,Gv", false),
+            0x88 => self.insn("mov", "Eb,Gb", false),
+            0x89 => self.insn("mov", "Ev,Gv", false),
+            0x8a => self.insn("mov", "Gb,Eb", false),
+            0x8b => self.insn("mov", "Gv,Ev", false),
+            0x8c => self.insn("mov", "Ew,Sw", false),
+            0x8d => self.insn("lea", "Gv,M", false),
+            0x8e => self.insn("mov", "Sw,Ew", false),
+            0x8f => self.insn("pop", "Ev", true),
+            0x90 if self.rex_b() == 0 => {
+                if self.rep == Some(0xf3) {
+                    Some("pause".into())
+                } else {
+                    Some("nop".into())
+                }
+            }
+            0x90..=0x97 => self.insn("xchg", "Zv,Av", false),
+            0x98 => Some(["cbw", "cwde", "cdqe"][self.opsize(false) / 4].into()),
+            0x99 => Some(["cwd", "cdq", "cqo"][self.opsize(false) / 4].into()),
+            0x9b => Some("fwait".into()),
+            0x9c => Some(if is_64 { "pushfq" } else { "pushfd" }.into()),
+            0x9d => Some(if is_64 { "popfq" } else { "popfd" }.into()),
+            0x9e => Some("sahf".into()),
+            0x9f => Some("lahf".into()),
+            0xa0 => self.insn("mov", "AL,Ob", false),
+            0xa1 => self.insn("mov", "Av,Ov", false),
+            0xa2 => self.insn("mov", "Ob,AL", false),
+            0xa3 => self.insn("mov", "Ov,Av", false),
+            0xa4 => self.string_insn("movs", true),
+            0xa5 => self.string_insn("movs", false),
+            0xa6 => self.string_insn("cmps", true),
+            0xa7 => self.string_insn("cmps", false),
+            0xa8 => self.insn("test", "AL,Ib", false),
+            0xa9 => self.insn("test", "Av,Iz", false),
+            0xaa => self.string_insn("stos", true),
+            0xab => self.string_insn("stos", false),
+            0xac => self.string_insn("lods", true),
+            0xad => self.string_insn("lods", false),
+            0xae => self.string_insn("scas", true),
+            0xaf => self.string_insn("scas", false),
+            0xb0..=0xb7 => self.insn("mov", "Zb,Ib", false),
+            0xb8..=0xbf => self.insn("mov", "Zv,Iv", false),
+            0xc0 | 0xc1 | 0xd0 | 0xd1 | 0xd2 | 0xd3 => {
+                let m = self.modrm()?;
+                let specs = match op {
+                    0xc0 => "Eb,Ib",
+                    0xc1 => "Ev,Ib",
+                    0xd0 => "Eb,1",
+                    0xd1 => "Ev,1",
+                    0xd2 => "Eb,CL",
+                    _ => "Ev,CL",
+                };
+                self.insn(GROUP2[(m.reg & 7) as usize], specs, false)
+            }
+            0xc2 => self.insn("ret", "Iw", false),
+            0xc3 => Some(
+                if self.rep == Some(0xf3) {
+                    "repz ret"
+                } else {
+                    "ret"
+                }
+                .into(),
+            ),
+            0xc6 | 0xc7 => {
+                let m = self.modrm()?;
+                if m.reg & 7 != 0 {
+                    return None;
+                }
+                self.insn("mov", if op == 0xc6 { "Eb,Ib" } else { "Ev,Iz" }, false)
+            }
+            0xc8 => self.insn("enter", "Iw,Ib", false),
+            0xc9 => Some("leave".into()),
+            0xca => self.insn("retf", "Iw", false),
+            0xcb => Some("retf".into()),
+            0xcc => Some("int3".into()),
+            0xcd => self.insn("int", "Ib", false),
+            0xce if !is_64 => Some("into".into()),
+            0xcf => Some("iret".into()),
+            0xd4 if !is_64 => self.insn("aam", "Ib", false),
+            0xd5 if !is_64 => self.insn("aad", "Ib", false),
+            0xd7 => Some("xlat".into()),
+            0xd8..=0xdf => {
+                // x87. We don't name these but still need to find the length.
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    Some(format!(
+                        "(x87 {:02x} {:02x})",
+                        op,
+                        0xc0 | ((m.reg & 7) << 3) | (m.rm & 7)
+                    ))
+                } else {
+                    let mnemonic = format!("(x87 {:02x}/{})", op, m.reg & 7);
+                    self.insn(&mnemonic, "M", false)
+                }
+            }
+            0xe0 => self.insn("loopne", "Jb", false),
+            0xe1 => self.insn("loope", "Jb", false),
+            0xe2 => self.insn("loop", "Jb", false),
+            0xe3 => self.insn(if is_64 { "jrcxz" } else { "jecxz" }, "Jb", false),
+            0xe4 => self.insn("in", "AL,Ib", false),
+            0xe5 => self.insn("in", "Az,Ib", false),
+            0xe6 => self.insn("out", "Ib,AL", false),
+            0xe7 => self.insn("out", "Ib,Az", false),
+            0xe8 => self.insn("call", "Jz", true),
+            0xe9 => self.insn("jmp", "Jz", true),
+            0xeb => self.insn("jmp", "Jb", false),
+            0xec => self.insn("in", "AL,DX", false),
+            0xed => self.insn("in", "Az,DX", false),
+            0xee => self.insn("out", "DX,AL", false),
+            0xef => self.insn("out", "DX,Az", false),
+            0xf1 => Some("int1".into()),
+            0xf4 => Some("hlt".into()),
+            0xf5 => Some("cmc".into()),
+            0xf6 | 0xf7 => {
+                let m = self.modrm()?;
+                let reg = (m.reg & 7) as usize;
+                let specs = match (op, reg) {
+                    (0xf6, 0) | (0xf6, 1) => "Eb,Ib",
+                    (0xf7, 0) | (0xf7, 1) => "Ev,Iz",
+                    (0xf6, _) => "Eb",
+                    _ => "Ev",
+                };
+                self.insn(GROUP3[reg], specs, false)
+            }
+            0xf8 => Some("clc".into()),
+            0xf9 => Some("stc".into()),
+            0xfa => Some("cli".into()),
+            0xfb => Some("sti".into()),
+            0xfc => Some("cld".into()),
+            0xfd => Some("std".into()),
+            0xfe => {
+                let m = self.modrm()?;
+                match m.reg & 7 {
+                    0 => self.insn("inc", "Eb", false),
+                    1 => self.insn("dec", "Eb", false),
+                    _ => None,
+                }
+            }
+            0xff => {
+                let m = self.modrm()?;
+                match m.reg & 7 {
+                    0 => self.insn("inc", "Ev", false),
+                    1 => self.insn("dec", "Ev", false),
+                    2 => self.insn("call", "Ev", true),
+                    3 => self.insn("call far", "M", false),
+                    4 => self.insn("jmp", "Ev", true),
+                    5 => self.insn("jmp far", "M", false),
+                    6 => self.insn("push", "Ev", true),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The suffix used by SSE floating point instructions for the mandatory prefix.
+    fn sse_suffix(&self) -> &'static str {
+        ["ps", "pd", "ss", "sd"][self.sse_prefix() as usize]
+    }
+
+    fn sse_prefix(&self) -> u8 {
+        match self.vex {
+            Some(v) => v.pp,
+            None if self.opsize_prefix => 1,
+            None if self.rep == Some(0xf3) => 2,
+            None if self.rep == Some(0xf2) => 3,
+            None => 0,
+        }
+    }
+
+    /// Two operand SSE instruction, which becomes a three operand instruction with VEX.
+    fn sse(&mut self, mnemonic: &str, specs: &str) -> Option<String> {
+        if self.vex.is_some() {
+            let mnemonic = format!("v{}", mnemonic);
+            let mut parts = specs.splitn(2, ',');
+            let first = parts.next().unwrap_or("");
+            let rest = parts.next().unwrap_or("");
+            let specs = format!("{},H,{}", first, rest);
+            self.insn(&mnemonic, &specs, false)
+        } else {
+            self.insn(mnemonic, specs, false)
+        }
+    }
+
+    /// SSE move-like instruction, which doesn't take an extra source operand with VEX.
+    fn sse_move(&mut self, mnemonic: &str, specs: &str) -> Option<String> {
+        if self.vex.is_some() {
+            let mnemonic = format!("v{}", mnemonic);
+            self.insn(&mnemonic, specs, false)
+        } else {
+            self.insn(mnemonic, specs, false)
+        }
+    }
+
+    fn decode_0f(&mut self) -> Option<String> {
+        let op = self.next_u8()?;
+        self.decode_0f_op(op)
+    }
+
+    fn decode_0f_op(&mut self, op: u8) -> Option<String> {
+        let pp = self.sse_prefix();
+        match op {
+            0x00 => {
+                let m = self.modrm()?;
+                let names = ["sldt", "str", "lldt", "ltr", "verr", "verw", "?", "?"];
+                self.insn(names[(m.reg & 7) as usize], "Ew", false)
+            }
+            0x01 => {
+                let m = self.modrm()?;
+                if m.md == 3 {
+                    let b = 0xc0 | ((m.reg & 7) << 3) | (m.rm & 7);
+                    let name = match b {
+                        0xc8 => "monitor",
+                        0xc9 => "mwait",
+                        0xd0 => "xgetbv",
+                        0xd1 => "xsetbv",
+                        0xd5 => "xend",
+                        0xd6 => "xtest",
+                        0xf8 => "swapgs",
+                        0xf9 => "rdtscp",
+                        _ => return Some(format!("(0f 01 {:02x})", b)),
+                    };
+                    Some(name.into())
+                } else {
+                    let names = [
+                        "sgdt", "sidt", "lgdt", "lidt", "smsw", "?", "lmsw", "invlpg",
+                    ];
+                    self.insn(names[(m.reg & 7) as usize], "M", false)
+                }
+            }
+            0x05 => Some("syscall".into()),
+            0x06 => Some("clts".into()),
+            0x07 => Some("sysret".into()),
+            0x0b => Some("ud2".into()),
+            0x0d => self.insn("prefetchw", "M", false),
+            0x10 | 0x11 => {
+                let specs = if op == 0x10 { "V,W" } else { "W,V" };
+                let mnemonic = format!("mov{}", ["ups", "upd", "ss", "sd"][pp as usize]);
+                self.sse_move(&mnemonic, specs)
+            }
+            0x12 | 0x13 => {
+                let mnemonic = if pp == 1 { "movlpd" } else { "movlps" };
+                self.sse_move(mnemonic, if op == 0x12 { "V,Wq" } else { "Wq,V" })
+            }
+            0x14 => {
+                let mnemonic = format!("unpckl{}", self.sse_suffix());
+                self.sse(&mnemonic, "V,W")
+            }
+            0x15 => {
+                let mnemonic = format!("unpckh{}", self.sse_suffix());
+                self.sse(&mnemonic, "V,W")
+            }
+            0x16 | 0x17 => {
+                let mnemonic = if pp == 1 { "movhpd" } else { "movhps" };
+                self.sse_move(mnemonic, if op == 0x16 { "V,Wq" } else { "Wq,V" })
+            }
+            0x18 => {
+                let m = self.modrm()?;
+                let names = ["prefetchnta", "prefetcht0", "prefetcht1", "prefetcht2"];
+                match names.get((m.reg & 7) as usize) {
+                    Some(name) => self.insn(name, "M", false),
+                    None => self.insn("nop", "Ev", false),
+                }
+            }
+            0x19..=0x1f => {
+                if op == 0x1e && self.rep == Some(0xf3) && self.peek_u8() == Some(0xfa) {
+                    self.pos += 1;
+                    return Some("endbr64".into());
+                }
+                if op == 0x1e && self.rep == Some(0xf3) && self.peek_u8() == Some(0xfb) {
+                    self.pos += 1;
+                    return Some("endbr32".into());
+                }
+                self.insn("nop", "Ev", false)
+            }
+            0x28 | 0x29 => {
+                let mnemonic = if pp == 1 { "movapd" } else { "movaps" };
+                self.sse_move(mnemonic, if op == 0x28 { "V,W" } else { "W,V" })
+            }
+            0x2a => {
+                let mnemonic = format!("cvtsi2{}", &self.sse_suffix()[1..]);
+                self.sse(&mnemonic, "V,Ey")
+            }
+            0x2c | 0x2d => {
+                let base = if op == 0x2c { "cvtt" } else { "cvt" };
+                let mnemonic = format!("{}{}2si", base, &self.sse_suffix()[1..]);
+                self.sse_move(&mnemonic, "Gy,W")
+            }
+            0x2e | 0x2f => {
+                let base = if op == 0x2e { "ucomis" } else { "comis" };
+                let mnemonic = format!("{}{}", base, if pp == 1 { "d" } else { "s" });
+                self.sse_move(&mnemonic, "V,W")
+            }
+            0x30 => Some("wrmsr".into()),
+            0x31 => Some("rdtsc".into()),
+            0x32 => Some("rdmsr".into()),
+            0x33 => Some("rdpmc".into()),
+            0x34 => Some("sysenter".into()),
+            0x35 => Some("sysexit".into()),
+            0x38 => {
+                let op2 = self.next_u8()?;
+                self.decode_0f38(op2)
+            }
+            0x3a => {
+                let op2 = self.next_u8()?;
+                self.decode_0f3a(op2)
+            }
+            0x40..=0x4f => {
+                let mnemonic = format!("cmov{}", CONDITION_CODES[(op & 0xf) as usize]);
+                self.insn(&mnemonic, "Gv,Ev", false)
+            }
+            0x50 => {
+                let mnemonic = format!("movmsk{}", self.sse_suffix());
+                self.sse_move(&mnemonic, "Gd,W")
+            }
+            0x51..=0x5f => {
+                let names = [
+                    "sqrt", "rsqrt", "rcp", "and", "andn", "or", "xor", "add", "mul", "cvt", "cvt",
+                    "sub", "min", "div", "max",
+                ];
+                let base = names[(op - 0x51) as usize];
+                let mnemonic = match op {
+                    0x54..=0x57 => format!("{}{}", base, if pp == 1 { "pd" } else { "ps" }),
+                    0x5a => ["cvtps2pd", "cvtpd2ps", "cvtss2sd", "cvtsd2ss"][pp as usize].into(),
+                    0x5b => ["cvtdq2ps", "cvtps2dq", "cvttps2dq", "?"][pp as usize].into(),
+                    _ => format!("{}{}", base, self.sse_suffix()),
+                };
+                if op == 0x5a || op == 0x5b {
+                    self.sse_move(&mnemonic, "V,W")
+                } else {
+                    self.sse(&mnemonic, "V,W")
+                }
+            }
+            0x60..=0x6d => {
+                let names = [
+                    "punpcklbw",
+                    "punpcklwd",
+                    "punpckldq",
+                    "packsswb",
+                    "pcmpgtb",
+                    "pcmpgtw",
+                    "pcmpgtd",
+                    "packuswb",
+                    "punpckhbw",
+                    "punpckhwd",
+                    "punpckhdq",
+                    "packssdw",
+                    "punpcklqdq",
+                    "punpckhqdq",
+                ];
+                self.sse(names[(op - 0x60) as usize], "P,Q")
+            }
+            0x6e => {
+                let mnemonic = if self.rex_w() { "movq" } else { "movd" };
+                self.sse_move(mnemonic, "P,Ey")
+            }
+            0x6f | 0x7f => {
+                let mnemonic = ["movq", "movdqa", "movdqu", "?"][pp as usize];
+                self.sse_move(mnemonic, if op == 0x6f { "P,Q" } else { "Q,P" })
+            }
+            0x70 => {
+                let mnemonic = ["pshufw", "pshufd", "pshufhw", "pshuflw"][pp as usize];
+                self.sse_move(mnemonic, "P,Q,Ib")
+            }
+            0x71..=0x73 => {
+                let m = self.modrm()?;
+                let kind = ["w", "d", "q"][(op - 0x71) as usize];
+                let mnemonic = match (m.reg & 7, op) {
+                    (2, _) => format!("psrl{}", kind),
+                    (4, _) => format!("psra{}", kind),
+                    (6, _) => format!("psll{}", kind),
+                    (3, 0x73) => "psrldq".into(),
+                    (7, 0x73) => "pslldq".into(),
+                    _ => return None,
+                };
+                self.sse_move(&mnemonic, "Q,Ib")
+            }
+            0x74 => self.sse("pcmpeqb", "P,Q"),
+            0x75 => self.sse("pcmpeqw", "P,Q"),
+            0x76 => self.sse("pcmpeqd", "P,Q"),
+            0x77 => Some(
+                match self.vex {
+                    Some(v) if v.l => "vzeroall",
+                    Some(_) => "vzeroupper",
+                    None => "emms",
+                }
+                .into(),
+            ),
+            0x7e => {
+                if pp == 2 {
+                    self.sse_move("movq", "V,Wq")
+                } else {
+                    let mnemonic = if self.rex_w() { "movq" } else { "movd" };
+                    self.sse_move(mnemonic, "Ey,P")
+                }
+            }
+            0x80..=0x8f => {
+                let mnemonic = format!("j{}", CONDITION_CODES[(op & 0xf) as usize]);
+                self.insn(&mnemonic, "Jz", true)
+            }
+            0x90..=0x9f => {
+                let mnemonic = format!("set{}", CONDITION_CODES[(op & 0xf) as usize]);
+                self.insn(&mnemonic, "Eb", false)
+            }
+            0xa0 => Some("push fs".into()),
+            0xa1 => Some("pop fs".into()),
+            0xa2 => Some("cpuid".into()),
+            0xa3 => self.insn("bt", "Ev,Gv", false),
+            0xa4 => self.insn("shld", "Ev,Gv,Ib", false),
+            0xa5 => self.insn("shld", "Ev,Gv,CL", false),
+            0xa8 => Some("push gs".into()),
+            0xa9 => Some("pop gs".into()),
+            0xab => self.insn("bts", "Ev,Gv", false),
+            0xac => self.insn("shrd", "Ev,Gv,Ib", false),
+            0xad => self.insn("shrd", "Ev,Gv,CL", false),
+            0xae => {
+                let m = self.modrm()?;
+                let reg = (m.reg & 7) as usize;
+                if m.md == 3 {
+                    if self.rep == Some(0xf3) {
+                        let names = ["rdfsbase", "rdgsbase", "wrfsbase", "wrgsbase"];
+                        return match names.get(reg) {
+                            Some(name) => self.insn(name, "Ey", false),
+                            None => None,
+                        };
+                    }
+                    return match reg {
+                        5 => Some("lfence".into()),
+                        6 => Some("mfence".into()),
+                        7 => Some("sfence".into()),
+                        _ => None,
+                    };
+                }
+                let names = [
+                    "fxsave", "fxrstor", "ldmxcsr", "stmxcsr", "xsave", "xrstor", "xsaveopt",
+                    "clflush",
+                ];
+                let name = if self.rex_w() && reg < 2 {
+                    ["fxsave64", "fxrstor64"][reg]
+                } else if self.rex_w() && (reg == 4 || reg == 5 || reg == 6) {
+                    ["xsave64", "xrstor64", "xsaveopt64"][reg - 4]
+                } else {
+                    names[reg]
+                };
+                self.insn(name, "M", false)
+            }
+            0xaf => self.insn("imul", "Gv,Ev", false),
+            0xb0 => self.insn("cmpxchg", "Eb,Gb", false),
+            0xb1 => self.insn("cmpxchg", "Ev,Gv", false),
+            0xb3 => self.insn("btr", "Ev,Gv", false),
+            0xb6 => self.insn("movzx", "Gv,Eb", false),
+            0xb7 => self.insn("movzx", "Gv,Ew", false),
+            0xb8 if self.rep == Some(0xf3) => self.insn("popcnt", "Gv,Ev", false),
+            0xba => {
+                let m = self.modrm()?;
+                let names = ["?", "?", "?", "?", "bt", "bts", "btr", "btc"];
+                match m.reg & 7 {
+                    4..=7 => self.insn(names[(m.reg & 7) as usize], "Ev,Ib", false),
+                    _ => None,
+                }
+            }
+            0xbb => self.insn("btc", "Ev,Gv", false),
+            0xbc => self.insn(
+                if self.rep == Some(0xf3) {
+                    "tzcnt"
+                } else {
+                    "bsf"
+                },
+                "Gv,Ev",
+                false,
+            ),
+            0xbd => self.insn(
+                if self.rep == Some(0xf3) {
+                    "lzcnt"
+                } else {
+                    "bsr"
+                },
+                "Gv,Ev",
+                false,
+            ),
+            0xbe => self.insn("movsx", "Gv,Eb", false),
+            0xbf => self.insn("movsx", "Gv,Ew", false),
+            0xc0 => self.insn("xadd", "Eb,Gb", false),
+            0xc1 => self.insn("xadd", "Ev,Gv", false),
+            0xc2 => {
+                let mnemonic = format!("cmp{}", self.sse_suffix());
+                self.sse(&mnemonic, "V,W,Ib")
+            }
+            0xc3 => self.insn("movnti", "My,Gy", false),
+            0xc4 => self.sse("pinsrw", "P,Ed,Ib"),
+            0xc5 => self.sse_move("pextrw", "Gd,Q,Ib"),
+            0xc6 => {
+                let mnemonic = format!("shuf{}", if pp == 1 { "pd" } else { "ps" });
+                self.sse(&mnemonic, "V,W,Ib")
+            }
+            0xc7 => {
+                let m = self.modrm()?;
+                match (m.reg & 7, m.md == 3) {
+                    (1, false) => self.insn(
+                        if self.rex_w() {
+                            "cmpxchg16b"
+                        } else {
+                            "cmpxchg8b"
+                        },
+                        "M",
+                        false,
+                    ),
+                    (6, true) => self.insn("rdrand", "Ev", false),
+                    (7, true) => self.insn("rdseed", "Ev", false),
+                    _ => None,
+                }
+            }
+            0xc8..=0xcf => self.insn("bswap", "Zv", false),
+            0xd0..=0xff => {
+                let names = [
+                    "addsubp",
+                    "psrlw",
+                    "psrld",
+                    "psrlq",
+                    "paddq",
+                    "pmullw",
+                    "movq",
+                    "pmovmskb",
+                    "psubusb",
+                    "psubusw",
+                    "pminub",
+                    "pand",
+                    "paddusb",
+                    "paddusw",
+                    "pmaxub",
+                    "pandn",
+                    "pavgb",
+                    "psraw",
+                    "psrad",
+                    "pavgw",
+                    "pmulhuw",
+                    "pmulhw",
+                    "cvt",
+                    "movntdq",
+                    "psubsb",
+                    "psubsw",
+                    "pminsw",
+                    "por",
+                    "paddsb",
+                    "paddsw",
+                    "pmaxsw",
+                    "pxor",
+                    "lddqu",
+                    "psllw",
+                    "pslld",
+                    "psllq",
+                    "pmuludq",
+                    "pmaddwd",
+                    "psadbw",
+                    "maskmovdqu",
+                    "psubb",
+                    "psubw",
+                    "psubd",
+                    "psubq",
+                    "paddb",
+                    "paddw",
+                    "paddd",
+                    "ud0",
+                ];
+                let name = names[(op - 0xd0) as usize];
+                match op {
+                    0xd6 => self.sse_move("movq", "Wq,V"),
+                    0xd7 => self.sse_move("pmovmskb", "Gd,Q"),
+                    0xe6 => {
+                        let mnemonic = ["?", "cvttpd2dq", "cvtdq2pd", "cvtpd2dq"][pp as usize];
+                        self.sse_move(mnemonic, "V,W")
+                    }
+                    0xe7 => self.sse_move(if pp == 1 { "movntdq" } else { "movntq" }, "M,P"),
+                    0xf0 => self.sse_move("lddqu", "V,M"),
+                    0xf7 => self.sse_move(if pp == 1 { "maskmovdqu" } else { "maskmovq" }, "P,Q"),
+                    0xff => self.insn("ud0", "Gv,Ev", false),
+                    _ => self.sse(name, "P,Q"),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn decode_0f38(&mut self, op: u8) -> Option<String> {
+        let pp = self.sse_prefix();
+        let name = match op {
+            0x00 => "pshufb",
+            0x01 => "phaddw",
+            0x02 => "phaddd",
+            0x04 => "pmaddubsw",
+            0x0b => "pmulhrsw",
+            0x17 => "ptest",
+            0x1c => "pabsb",
+            0x1d => "pabsw",
+            0x1e => "pabsd",
+            0x29 => "pcmpeqq",
+            0x37 => "pcmpgtq",
+            0x38 => "pminsb",
+            0x39 => "pminsd",
+            0x3a => "pminuw",
+            0x3b => "pminud",
+            0x3c => "pmaxsb",
+            0x3d => "pmaxsd",
+            0x3e => "pmaxuw",
+            0x3f => "pmaxud",
+            0x40 => "pmulld",
+            0xf0 | 0xf1 if pp == 3 => {
+                return self.insn("crc32", if op == 0xf0 { "Gy,Eb" } else { "Gy,Ev" }, false);
+            }
+            0xf0 => return self.insn("movbe", "Gv,M", false),
+            0xf1 => return self.insn("movbe", "M,Gv", false),
+            _ => {
+                let mnemonic = format!("(0f 38 {:02x})", op);
+                return self.insn(&mnemonic, "V,W", false);
+            }
+        };
+        self.sse(name, "P,Q")
+    }
+
+    fn decode_0f3a(&mut self, op: u8) -> Option<String> {
+        match op {
+            0x0f => self.sse("palignr", "P,Q,Ib"),
+            0x16 => {
+                let mnemonic = if self.rex_w() { "pextrq" } else { "pextrd" };
+                self.sse_move(mnemonic, "Ey,V,Ib")
+            }
+            0x22 => {
+                let mnemonic = if self.rex_w() { "pinsrq" } else { "pinsrd" };
+                self.sse(mnemonic, "V,Ey,Ib")
+            }
+            0x63 => self.sse_move("pcmpistri", "V,W,Ib"),
+            _ => {
+                let mnemonic = format!("(0f 3a {:02x})", op);
+                self.insn(&mnemonic, "V,W,Ib", false)
+            }
+        }
+    }
+
+    fn decode_vex(&mut self, prefix: u8) -> Option<String> {
+        let b1 = self.next_u8()?;
+        // The R, X, B and vvvv bits are stored inverted.
+        let vex = if prefix == 0xc5 {
+            self.rex = 0x40 | if b1 & 0x80 == 0 { 4 } else { 0 };
+            Vex {
+                map: 1,
+                pp: b1 & 3,
+                l: b1 & 4 != 0,
+                w: false,
+                vvvv: (!b1 >> 3) & 15,
+            }
+        } else {
+            let b2 = self.next_u8()?;
+            let mut rex = 0x40;
+            if b1 & 0x80 == 0 {
+                rex |= 4;
+            }
+            if b1 & 0x40 == 0 {
+                rex |= 2;
+            }
+            if b1 & 0x20 == 0 {
+                rex |= 1;
+            }
+            if !self.is_64 {
+                rex = 0x40;
+            }
+            self.rex = rex;
+            Vex {
+                map: b1 & 0x1f,
+                pp: b2 & 3,
+                l: b2 & 4 != 0,
+                w: b2 & 0x80 != 0,
+                vvvv: (!b2 >> 3) & 15,
+            }
+        };
+        self.vex = Some(vex);
+        let op = self.next_u8()?;
+        match vex.map {
+            1 => self.decode_0f_op(op),
+            2 => self.decode_0f38(op),
+            3 => self.decode_0f3a(op),
+            _ => None,
+        }
+    }
+
+    /// AVX-512. We only work out the length of these.
+    fn decode_evex(&mut self) -> Option<String> {
+        let p0 = self.next_u8()?;
+        let _p1 = self.next_u8()?;
+        let _p2 = self.next_u8()?;
+        let map = p0 & 3;
+        let op = self.next_u8()?;
+        let m = self.modrm()?;
+        let has_imm = map == 3;
+        let mut s = format!("(evex map {} {:02x})", map, op);
+        if m.md != 3 {
+            write!(s, " {}", self.mem).unwrap();
+        }
+        if has_imm {
+            self.imm(1)?;
+        }
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis64(bytes: &[u8]) -> (usize, String) {
+        let insn = disassemble(SupportedArch::X64, bytes, 0x1000).unwrap();
+        (insn.length, insn.text)
+    }
+
+    fn dis32(bytes: &[u8]) -> (usize, String) {
+        let insn = disassemble(SupportedArch::X86, bytes, 0x1000).unwrap();
+        (insn.length, insn.text)
+    }
+
+    #[test]
+    fn general_purpose_x64() {
+        assert_eq!((1, "push rbp".into()), dis64(&[0x55]));
+        assert_eq!((3, "mov rbp, rsp".into()), dis64(&[0x48, 0x89, 0xe5]));
+        assert_eq!(
+            (4, "mov qword ptr [rbp-0x8], rdi".into()),
+            dis64(&[0x48, 0x89, 0x7d, 0xf8])
+        );
+        assert_eq!(
+            (7, "lea rdi, [rip+0x200]".into()),
+            dis64(&[0x48, 0x8d, 0x3d, 0x00, 0x02, 0x00, 0x00])
+        );
+        assert_eq!(
+            (5, "call 0x1105".into()),
+            dis64(&[0xe8, 0x00, 0x01, 0x00, 0x00])
+        );
+        assert_eq!((2, "syscall".into()), dis64(&[0x0f, 0x05]));
+        assert_eq!((3, "xor r8d, r8d".into()), dis64(&[0x45, 0x31, 0xc0]));
+        assert_eq!(
+            (3, "mov eax, dword ptr [rbx+rcx*4]".into()),
+            dis64(&[0x8b, 0x04, 0x8b])
+        );
+        assert_eq!((4, "endbr64".into()), dis64(&[0xf3, 0x0f, 0x1e, 0xfa]));
+        assert_eq!((2, "jne 0x1010".into()), dis64(&[0x75, 0x0e]));
+        assert_eq!(
+            (10, "mov rax, 0x1122334455667788".into()),
+            dis64(&[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11])
+        );
+        assert_eq!(
+            (4, "sub rsp, 0x10".into()),
+            dis64(&[0x48, 0x83, 0xec, 0x10])
+        );
+        assert_eq!((3, "rep stosq".into()), dis64(&[0xf3, 0x48, 0xab]));
+        assert_eq!(
+            (13, "mov qword ptr fs:[0x28], 0x0".into()),
+            dis64(&[0x64, 0x48, 0xc7, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+        );
+    }
+
+    #[test]
+    fn sse_and_avx() {
+        assert_eq!(
+            (4, "movdqa xmm0, xmm1".into()),
+            dis64(&[0x66, 0x0f, 0x6f, 0xc1])
+        );
+        assert_eq!((3, "pxor mm0, mm1".into()), dis64(&[0x0f, 0xef, 0xc1]));
+        assert_eq!(
+            (4, "vmovdqu ymm0, ymmword ptr [rdi]".into()),
+            dis64(&[0xc5, 0xfe, 0x6f, 0x07])
+        );
+        assert_eq!(
+            (4, "vpxor xmm0, xmm0, xmm0".into()),
+            dis64(&[0xc5, 0xf9, 0xef, 0xc0])
+        );
+        assert_eq!((3, "vzeroupper".into()), dis64(&[0xc5, 0xf8, 0x77]));
+        assert_eq!(
+            (8, "movsd xmm0, qword ptr [rip+0x10]".into()),
+            dis64(&[0xf2, 0x0f, 0x10, 0x05, 0x10, 0x00, 0x00, 0x00])
+        );
+    }
+
+    #[test]
+    fn general_purpose_x86() {
+        assert_eq!((1, "inc eax".into()), dis32(&[0x40]));
+        assert_eq!((2, "int 0x80".into()), dis32(&[0xcd, 0x80]));
+        assert_eq!(
+            (4, "mov eax, dword ptr [esp+0x4]".into()),
+            dis32(&[0x8b, 0x44, 0x24, 0x04])
+        );
+    }
+
+    /// Each case is one whole instruction and its expected text.
+    fn check_table(arch: SupportedArch, cases: &[(&[u8], &str)]) {
+        for (bytes, text) in cases {
+            let insn = disassemble(arch, bytes, 0x1000);
+            assert_eq!(
+                Some(Instruction {
+                    length: bytes.len(),
+                    text: text.to_string(),
+                }),
+                insn,
+                "decoding {:02x?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        check_table(
+            SupportedArch::X64,
+            &[
+                // Operand size
+                (&[0x66, 0x89, 0xc8], "mov ax, cx"),
+                (&[0x66, 0x8b, 0x07], "mov ax, word ptr [rdi]"),
+                (&[0x66, 0x83, 0xc0, 0x01], "add ax, 0x1"),
+                (
+                    &[0x66, 0xc7, 0x00, 0x34, 0x12],
+                    "mov word ptr [rax], 0x1234",
+                ),
+                (
+                    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+                    "nop word ptr [rax+rax*1]",
+                ),
+                // Address size
+                (&[0x67, 0x8b, 0x00], "mov eax, dword ptr [eax]"),
+                // rep/repne and lock
+                (&[0xf3, 0xa4], "rep movsb"),
+                (&[0xf3, 0x48, 0xa5], "rep movsq"),
+                (&[0xf2, 0xae], "repne scasb"),
+                (&[0xf3, 0xc3], "repz ret"),
+                (&[0xf0, 0xff, 0x07], "lock inc dword ptr [rdi]"),
+                (
+                    &[0xf0, 0x48, 0x0f, 0xb1, 0x0f],
+                    "lock cmpxchg qword ptr [rdi], rcx",
+                ),
+                // Mandatory prefixes select the instruction
+                (&[0xf3, 0x0f, 0xb8, 0xc1], "popcnt eax, ecx"),
+                (&[0xf3, 0x48, 0x0f, 0xbc, 0xc1], "tzcnt rax, rcx"),
+                (&[0x0f, 0x58, 0xc1], "addps xmm0, xmm1"),
+                (&[0x66, 0x0f, 0x58, 0xc1], "addpd xmm0, xmm1"),
+                (&[0xf3, 0x0f, 0x58, 0xc1], "addss xmm0, xmm1"),
+                (&[0xf2, 0x0f, 0x58, 0xc1], "addsd xmm0, xmm1"),
+                (&[0xf3, 0x0f, 0x10, 0x07], "movss xmm0, dword ptr [rdi]"),
+                (
+                    &[0xf2, 0x0f, 0x11, 0x47, 0x08],
+                    "movsd qword ptr [rdi+0x8], xmm0",
+                ),
+                (&[0x66, 0x0f, 0xd6, 0x07], "movq qword ptr [rdi], xmm0"),
+                (&[0xf3, 0x0f, 0x7e, 0x07], "movq xmm0, qword ptr [rdi]"),
+                // REX
+                (&[0x40, 0x88, 0xf7], "mov dil, sil"),
+                (&[0x88, 0xf7], "mov bh, dh"),
+                (&[0x41, 0x50], "push r8"),
+                (&[0x49, 0x89, 0xc0], "mov r8, rax"),
+                (&[0x4c, 0x89, 0xc0], "mov rax, r8"),
+                (&[0x48, 0x63, 0xc7], "movsxd rax, edi"),
+                (&[0x48, 0x0f, 0xbe, 0xc0], "movsx rax, al"),
+                (&[0x44, 0x0f, 0xb6, 0xc0], "movzx r8d, al"),
+                (&[0x66, 0x48, 0x0f, 0x6e, 0xc0], "movq xmm0, rax"),
+            ],
+        );
+    }
+
+    #[test]
+    fn modrm_and_sib() {
+        check_table(
+            SupportedArch::X64,
+            &[
+                (&[0x8b, 0x04, 0x24], "mov eax, dword ptr [rsp]"),
+                (&[0x8b, 0x44, 0x24, 0x08], "mov eax, dword ptr [rsp+0x8]"),
+                (
+                    &[0x8b, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00],
+                    "mov eax, dword ptr [rsp+0x100]",
+                ),
+                (&[0x8b, 0x04, 0x08], "mov eax, dword ptr [rax+rcx*1]"),
+                (
+                    &[0x8b, 0x44, 0x88, 0x10],
+                    "mov eax, dword ptr [rax+rcx*4+0x10]",
+                ),
+                (
+                    &[0x48, 0x8b, 0x84, 0xc8, 0x80, 0x00, 0x00, 0x00],
+                    "mov rax, qword ptr [rax+rcx*8+0x80]",
+                ),
+                // No base
+                (
+                    &[0x8b, 0x04, 0xcd, 0x00, 0x00, 0x00, 0x00],
+                    "mov eax, dword ptr [rcx*8]",
+                ),
+                (
+                    &[0x8b, 0x04, 0x25, 0x78, 0x56, 0x34, 0x12],
+                    "mov eax, dword ptr [0x12345678]",
+                ),
+                // rbp and r13 as a base always take a displacement
+                (&[0x8b, 0x45, 0x00], "mov eax, dword ptr [rbp]"),
+                (&[0x41, 0x8b, 0x45, 0x00], "mov eax, dword ptr [r13]"),
+                // No index
+                (&[0x8b, 0x4c, 0x25, 0x00], "mov ecx, dword ptr [rbp]"),
+                (&[0x41, 0x8b, 0x04, 0x24], "mov eax, dword ptr [r12]"),
+                (&[0x4d, 0x8b, 0x0c, 0x24], "mov r9, qword ptr [r12]"),
+                // REX.X and REX.B extend the SIB fields
+                (&[0x4a, 0x8b, 0x04, 0xe0], "mov rax, qword ptr [rax+r12*8]"),
+                (&[0x43, 0x8b, 0x04, 0x88], "mov eax, dword ptr [r8+r9*4]"),
+            ],
+        );
+    }
+
+    #[test]
+    fn rip_relative() {
+        check_table(
+            SupportedArch::X64,
+            &[
+                (
+                    &[0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00],
+                    "mov rax, qword ptr [rip+0x10]",
+                ),
+                (
+                    &[0x8b, 0x0d, 0xf0, 0xff, 0xff, 0xff],
+                    "mov ecx, dword ptr [rip-0x10]",
+                ),
+                (
+                    &[0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00],
+                    "lea rax, [rip]",
+                ),
+                (
+                    &[0xff, 0x25, 0x02, 0x00, 0x00, 0x00],
+                    "jmp qword ptr [rip+0x2]",
+                ),
+                (
+                    &[0xff, 0x15, 0xf0, 0xff, 0xff, 0xff],
+                    "call qword ptr [rip-0x10]",
+                ),
+                // The immediate follows the displacement
+                (
+                    &[0xc7, 0x05, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+                    "mov dword ptr [rip+0x8], 0x1",
+                ),
+                (
+                    &[0x80, 0x3d, 0x07, 0x00, 0x00, 0x00, 0x00],
+                    "cmp byte ptr [rip+0x7], 0x0",
+                ),
+                (
+                    &[0xc5, 0xfa, 0x6f, 0x05, 0x00, 0x01, 0x00, 0x00],
+                    "vmovdqu xmm0, xmmword ptr [rip+0x100]",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn vex() {
+        check_table(
+            SupportedArch::X64,
+            &[
+                // Two byte VEX, L selects xmm or ymm
+                (&[0xc5, 0xf8, 0x28, 0xc1], "vmovaps xmm0, xmm1"),
+                (&[0xc5, 0xfc, 0x28, 0xc1], "vmovaps ymm0, ymm1"),
+                (&[0xc5, 0xf9, 0x6f, 0x07], "vmovdqa xmm0, xmmword ptr [rdi]"),
+                (&[0xc5, 0xfd, 0x7f, 0x07], "vmovdqa ymmword ptr [rdi], ymm0"),
+                (&[0xc5, 0xfb, 0x10, 0x07], "vmovsd xmm0, qword ptr [rdi]"),
+                // vvvv is the extra source
+                (&[0xc5, 0xf1, 0xfe, 0xc2], "vpaddd xmm0, xmm1, xmm2"),
+                (&[0xc5, 0xf5, 0xfe, 0xc2], "vpaddd ymm0, ymm1, ymm2"),
+                (&[0xc5, 0xf9, 0xd7, 0xc1], "vpmovmskb eax, xmm1"),
+                // Three byte VEX, with the inverted R/X/B bits and the 0f38/0f3a maps
+                (
+                    &[0xc4, 0xc1, 0x7d, 0x6f, 0x00],
+                    "vmovdqa ymm0, ymmword ptr [r8]",
+                ),
+                (&[0xc4, 0x41, 0x34, 0x58, 0xc2], "vaddps ymm8, ymm9, ymm10"),
+                (&[0xc4, 0xe2, 0x7d, 0x00, 0xc1], "vpshufb ymm0, ymm0, ymm1"),
+                (
+                    &[0xc4, 0xe3, 0x79, 0x0f, 0xc1, 0x04],
+                    "vpalignr xmm0, xmm0, xmm1, 0x4",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn x86_32() {
+        check_table(
+            SupportedArch::X86,
+            &[
+                (&[0x55], "push ebp"),
+                (&[0x5d], "pop ebp"),
+                (&[0x48], "dec eax"),
+                (&[0x89, 0xe5], "mov ebp, esp"),
+                (&[0x66, 0x89, 0xc8], "mov ax, cx"),
+                (&[0x66, 0xb8, 0x34, 0x12], "mov ax, 0x1234"),
+                (&[0x8b, 0x45, 0x08], "mov eax, dword ptr [ebp+0x8]"),
+                (&[0x8b, 0x04, 0x24], "mov eax, dword ptr [esp]"),
+                (&[0xff, 0x34, 0x24], "push dword ptr [esp]"),
+                // mod 00 rm 101 is an absolute address, not rip relative
+                (
+                    &[0x8b, 0x05, 0x78, 0x56, 0x34, 0x12],
+                    "mov eax, dword ptr [0x12345678]",
+                ),
+                (
+                    &[0xa1, 0x78, 0x56, 0x34, 0x12],
+                    "mov eax, dword ptr [0x12345678]",
+                ),
+                (
+                    &[0xff, 0x15, 0x00, 0x20, 0x00, 0x00],
+                    "call dword ptr [0x2000]",
+                ),
+                (
+                    &[0x8b, 0x04, 0x8d, 0x00, 0x10, 0x00, 0x00],
+                    "mov eax, dword ptr [ecx*4+0x1000]",
+                ),
+                (
+                    &[0x65, 0xa1, 0x14, 0x00, 0x00, 0x00],
+                    "mov eax, dword ptr gs:[0x14]",
+                ),
+                // 16 bit addressing
+                (&[0x67, 0x8b, 0x07], "mov eax, dword ptr [bx]"),
+                (&[0xe8, 0xfb, 0xff, 0xff, 0xff], "call 0x1000"),
+                (&[0xeb, 0xfe], "jmp 0x1000"),
+                (&[0x0f, 0x84, 0x00, 0x00, 0x00, 0x00], "je 0x1006"),
+                (&[0xf3, 0xab], "rep stosd"),
+                (
+                    &[0xf3, 0x0f, 0x10, 0x45, 0x08],
+                    "movss xmm0, dword ptr [ebp+0x8]",
+                ),
+                (&[0x66, 0x0f, 0x6f, 0xc1], "movdqa xmm0, xmm1"),
+                // c5 is VEX rather than lds when the modrm byte would be a register
+                (&[0xc5, 0xf9, 0xef, 0xc0], "vpxor xmm0, xmm0, xmm0"),
+            ],
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(disassemble(SupportedArch::X64, &[0x48, 0x89], 0).is_none());
+        assert!(disassemble(SupportedArch::X64, &[], 0).is_none());
+    }
+}
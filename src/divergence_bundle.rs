@@ -0,0 +1,184 @@
+//! When replay diverges from the recording we write a directory of diagnostics that can be
+//! attached to a bug report, before aborting as usual:
+//!
+//! - `summary.txt`: rd version, CPU microarchitecture, the trace and its uuid, the task, the
+//!   current event and why we think replay diverged
+//! - `registers.txt`: the register sets involved side by side, differences marked
+//! - `address_space.txt`: rd's idea of the task's address space (`AddressSpace::dump()`)
+//! - `proc_maps.txt`: the kernel's idea of it, a copy of /proc/<tid>/maps
+//! - `frames.txt`: the last `LAST_FRAMES` trace frames up to and including the current one
+//!
+//! The directory is created under $RD_DIVERGENCE_DIR, or the temporary directory if that isn't
+//! set. Writing it is best effort: problems are logged and otherwise ignored since we are about
+//! to abort anyway.
+
+use crate::{
+    log::LogLevel::LogError,
+    perf_counters::cpu_microarch_name,
+    registers::Registers,
+    session::{address_space::address_space::AddressSpace, task::replay_task::ReplayTask},
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::TraceReader,
+    },
+    util::tmp_dir,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    env::var_os,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const LAST_FRAMES: usize = 100;
+
+/// Write a bundle for `t`, which has diverged because of `reason`. `regs` are the labeled
+/// register sets to compare, e.g. the replaying and the recorded registers.
+///
+/// Returns the directory the bundle was written to.
+pub fn write_divergence_bundle(
+    t: &ReplayTask,
+    reason: &str,
+    regs: &[(&str, &Registers)],
+) -> Option<PathBuf> {
+    let time = t.current_frame_time();
+    let parent = var_os("RD_DIVERGENCE_DIR").unwrap_or_else(tmp_dir);
+    let dir = Path::new(&parent).join(format!("rd-divergence-{}-{}", t.rec_tid, time));
+    match write_bundle(&dir, t, reason, regs) {
+        Ok(()) => {
+            log!(LogError, "Divergence diagnostics written to {:?}", dir);
+            Some(dir)
+        }
+        Err(e) => {
+            log!(
+                LogError,
+                "Could not write divergence diagnostics to {:?}: {}",
+                dir,
+                e
+            );
+            None
+        }
+    }
+}
+
+fn create(dir: &Path, name: &str) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(dir.join(name))?))
+}
+
+fn write_bundle(
+    dir: &Path,
+    t: &ReplayTask,
+    reason: &str,
+    regs: &[(&str, &Registers)],
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let trace_dir = t.trace_reader().dir().to_owned();
+
+    let mut summary = create(dir, "summary.txt")?;
+    let uuid: String = t
+        .trace_reader()
+        .uuid()
+        .bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    write!(summary, "reason: {}\n", reason)?;
+    write!(summary, "rd version: {}\n", env!("CARGO_PKG_VERSION"))?;
+    write!(summary, "cpu microarch: {}\n", cpu_microarch_name())?;
+    write!(summary, "trace: {:?}\n", trace_dir)?;
+    write!(summary, "trace uuid: {}\n", uuid)?;
+    write!(
+        summary,
+        "task: tid {} (rec: {}), ticks {}\n",
+        t.tid,
+        t.rec_tid,
+        t.tick_count()
+    )?;
+    write!(summary, "current event:\n")?;
+    t.current_trace_frame()
+        .dump(Some(&mut summary as &mut dyn Write))?;
+    write!(summary, "}}\n")?;
+    summary.flush()?;
+
+    let mut registers = create(dir, "registers.txt")?;
+    write_registers_side_by_side(&mut registers, regs)?;
+    registers.flush()?;
+
+    fs::write(dir.join("address_space.txt"), t.vm().dump())?;
+    // Copy what the kernel says verbatim. If this fails the task is probably gone, which is
+    // worth knowing too.
+    match fs::read(format!("/proc/{}/maps", t.tid)) {
+        Ok(maps) => fs::write(dir.join("proc_maps.txt"), maps)?,
+        Err(e) => fs::write(
+            dir.join("proc_maps.txt"),
+            format!(
+                "Could not read /proc/{}/maps: {}\nrd's view:\n{}",
+                t.tid,
+                e,
+                AddressSpace::dump_process_maps(t)
+            ),
+        )?,
+    }
+
+    let mut frames = create(dir, "frames.txt")?;
+    for frame in last_frames(&trace_dir, t.current_frame_time()) {
+        frame.dump(Some(&mut frames as &mut dyn Write))?;
+        write!(frames, "}}\n")?;
+    }
+    frames.flush()
+}
+
+/// One row per register of the first register set, one column per register set.
+fn write_registers_side_by_side(
+    out: &mut dyn Write,
+    regs: &[(&str, &Registers)],
+) -> io::Result<()> {
+    let first = match regs.first() {
+        Some((_, r)) => r.register_values(),
+        None => return Ok(()),
+    };
+    let others: Vec<HashMap<&str, u64>> = regs[1..]
+        .iter()
+        .map(|(_, r)| r.register_values().into_iter().collect())
+        .collect();
+
+    write!(out, "{:<12}", "")?;
+    for (label, _) in regs {
+        write!(out, " {:>20}", label)?;
+    }
+    write!(out, "\n")?;
+    for (name, value) in first {
+        let mut differs = false;
+        write!(out, "{:<12} {:>#20x}", name, value)?;
+        for other in &others {
+            match other.get(name) {
+                Some(&v) => {
+                    differs |= v != value;
+                    write!(out, " {:>#20x}", v)?;
+                }
+                None => write!(out, " {:>20}", "-")?,
+            }
+        }
+        write!(out, "{}\n", if differs { "  <--" } else { "" })?;
+    }
+    Ok(())
+}
+
+/// The frames of the trace up to and including `time`, at most `LAST_FRAMES` of them.
+fn last_frames(trace_dir: &OsStr, time: FrameTime) -> VecDeque<TraceFrame> {
+    let mut trace = TraceReader::new(Some(&trace_dir));
+    let mut frames = VecDeque::with_capacity(LAST_FRAMES);
+    while !trace.at_end() {
+        let frame = trace.read_frame();
+        if frame.time() > time {
+            break;
+        }
+        if frames.len() == LAST_FRAMES {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+    }
+    frames
+}
@@ -0,0 +1,289 @@
+use crate::{
+    log::LogLevel::LogDebug,
+    remote_code_ptr::RemoteCodePtr,
+    session::address_space::{address_space::AddressSpace, kernel_mapping::KernelMapping},
+};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use nix::sys::mman::ProtFlags;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    rc::Rc,
+};
+
+/// A function symbol found in the `.symtab` or `.dynsym` of an ELF object.
+#[derive(Clone, Debug)]
+pub struct ElfSymbol {
+    pub name: String,
+    /// Where the symbol's first byte lives in the ELF file. We use file offsets
+    /// rather than virtual addresses because that is what we can match up with a
+    /// `KernelMapping` without knowing the load bias of the object.
+    pub file_offset: u64,
+    pub size: u64,
+}
+
+/// All the function symbols of a single ELF object, sorted by file offset.
+pub struct ElfSymbols {
+    symbols: Vec<ElfSymbol>,
+}
+
+impl ElfSymbols {
+    pub fn read(path: &Path) -> io::Result<ElfSymbols> {
+        let data = fs::read(path)?;
+        Self::parse(&data)
+    }
+
+    /// Parse an ELF image that is already in memory, e.g. a copy of the vDSO.
+    pub fn parse(data: &[u8]) -> io::Result<ElfSymbols> {
+        let elf = match Elf::parse(data) {
+            Ok(elf) => elf,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        };
+
+        let mut symbols = Vec::new();
+        let tables = [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+        for (symtab, strtab) in tables.iter() {
+            for sym in symtab.iter() {
+                if !sym.is_function() || sym.st_value == 0 {
+                    continue;
+                }
+                let name = match strtab.get(sym.st_name) {
+                    Some(Ok(name)) if !name.is_empty() => name,
+                    _ => continue,
+                };
+                let maybe_file_offset = elf.program_headers.iter().find_map(|ph| {
+                    if ph.p_type == PT_LOAD
+                        && sym.st_value >= ph.p_vaddr
+                        && sym.st_value < ph.p_vaddr + ph.p_filesz
+                    {
+                        Some(sym.st_value - ph.p_vaddr + ph.p_offset)
+                    } else {
+                        None
+                    }
+                });
+                if let Some(file_offset) = maybe_file_offset {
+                    symbols.push(ElfSymbol {
+                        name: name.to_owned(),
+                        file_offset,
+                        size: sym.st_size,
+                    });
+                }
+            }
+        }
+
+        // The same symbol will usually appear in both .symtab and .dynsym.
+        symbols.sort_by(|a, b| {
+            a.file_offset
+                .cmp(&b.file_offset)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        symbols.dedup_by(|a, b| a.file_offset == b.file_offset && a.name == b.name);
+
+        Ok(ElfSymbols { symbols })
+    }
+
+    pub fn symbols(&self) -> &[ElfSymbol] {
+        &self.symbols
+    }
+
+    /// Return the symbol whose extent covers `file_offset`. Symbols with a size
+    /// of 0 are assumed to cover exactly one byte.
+    pub fn find_by_file_offset(&self, file_offset: u64) -> Option<&ElfSymbol> {
+        let index = match self
+            .symbols
+            .binary_search_by(|s| s.file_offset.cmp(&file_offset))
+        {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        // There may be aliases at the same offset; any of them will do.
+        let sym = &self.symbols[index];
+        if file_offset < sym.file_offset + sym.size.max(1) {
+            Some(sym)
+        } else {
+            None
+        }
+    }
+}
+
+/// Caches the parsed symbols of ELF objects by path so that repeated lookups
+/// (e.g. after every mmap) don't re-read the same files.
+#[derive(Default)]
+pub struct SymbolCache {
+    objects: HashMap<OsString, Option<Rc<ElfSymbols>>>,
+}
+
+impl SymbolCache {
+    pub fn new() -> SymbolCache {
+        Default::default()
+    }
+
+    /// Returns `None` if `path` could not be read or is not an ELF object.
+    pub fn get(&mut self, path: &OsStr) -> Option<Rc<ElfSymbols>> {
+        if let Some(maybe_syms) = self.objects.get(path) {
+            return maybe_syms.clone();
+        }
+        let maybe_syms = match ElfSymbols::read(Path::new(path)) {
+            Ok(syms) => Some(Rc::new(syms)),
+            Err(e) => {
+                log!(LogDebug, "Could not read symbols from {:?}: {:?}", path, e);
+                None
+            }
+        };
+        self.objects.insert(path.to_owned(), maybe_syms.clone());
+        maybe_syms
+    }
+
+    /// Symbols for the object backing `m`. During replay the file we actually
+    /// mapped may differ from the recorded one (e.g. a copy in the trace
+    /// directory) so try both.
+    pub fn get_for_mapping(
+        &mut self,
+        map: &KernelMapping,
+        recorded_map: &KernelMapping,
+    ) -> Option<Rc<ElfSymbols>> {
+        for km in &[map, recorded_map] {
+            if km.fsname().is_empty() || !km.fsname().as_bytes().starts_with(b"/") {
+                continue;
+            }
+            if let Some(syms) = self.get(km.fsname()) {
+                return Some(syms);
+            }
+        }
+        None
+    }
+}
+
+/// The result of resolving a tracee address to a symbol.
+#[derive(Clone, Debug)]
+pub struct Symbolized {
+    pub name: String,
+    pub offset: usize,
+    /// The recorded name of the object containing the symbol.
+    pub object: OsString,
+}
+
+/// Find the runtime addresses of every function symbol in executable mappings of
+/// `vm` for which `matches` returns true.
+pub fn resolve_symbols<F: Fn(&str) -> bool>(
+    cache: &mut SymbolCache,
+    vm: &AddressSpace,
+    matches: F,
+) -> Vec<(RemoteCodePtr, String)> {
+    let mut result = Vec::new();
+    for (_, m) in &vm.maps() {
+        if !m.map.prot().contains(ProtFlags::PROT_EXEC) {
+            continue;
+        }
+        let syms = match cache.get_for_mapping(&m.map, &m.recorded_map) {
+            Some(syms) => syms,
+            None => continue,
+        };
+        let map_offset = m.map.file_offset_bytes();
+        let map_end_offset = map_offset + m.map.size() as u64;
+        for sym in syms.symbols() {
+            if sym.file_offset >= map_offset
+                && sym.file_offset < map_end_offset
+                && matches(&sym.name)
+            {
+                let addr = m.map.start().as_usize() + (sym.file_offset - map_offset) as usize;
+                result.push((RemoteCodePtr::from_val(addr), sym.name.clone()));
+            }
+        }
+    }
+    result
+}
+
+/// Find the function containing `addr` in `vm`, if any.
+pub fn symbolize(
+    cache: &mut SymbolCache,
+    vm: &AddressSpace,
+    addr: RemoteCodePtr,
+) -> Option<Symbolized> {
+    let m = vm.mapping_of(addr.to_data_ptr())?;
+    let syms = cache.get_for_mapping(&m.map, &m.recorded_map)?;
+    let file_offset =
+        (addr.as_usize() - m.map.start().as_usize()) as u64 + m.map.file_offset_bytes();
+    syms.find_by_file_offset(file_offset).map(|sym| Symbolized {
+        name: sym.name.clone(),
+        offset: (file_offset - sym.file_offset) as usize,
+        object: m.recorded_map.fsname().to_owned(),
+    })
+}
+
+/// Match `name` against a shell style `pattern` where `*` matches any sequence of
+/// characters and `?` matches any single character.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if the current attempt to match after a `*` fails.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str, file_offset: u64, size: u64) -> ElfSymbol {
+        ElfSymbol {
+            name: name.into(),
+            file_offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn find_by_file_offset_test() {
+        let syms = ElfSymbols {
+            symbols: vec![
+                sym("a", 0x100, 0x10),
+                sym("b", 0x200, 0),
+                sym("c", 0x300, 0x20),
+            ],
+        };
+        assert!(syms.find_by_file_offset(0xff).is_none());
+        assert_eq!("a", syms.find_by_file_offset(0x100).unwrap().name);
+        assert_eq!("a", syms.find_by_file_offset(0x10f).unwrap().name);
+        assert!(syms.find_by_file_offset(0x110).is_none());
+        assert_eq!("b", syms.find_by_file_offset(0x200).unwrap().name);
+        assert!(syms.find_by_file_offset(0x201).is_none());
+        assert_eq!("c", syms.find_by_file_offset(0x31f).unwrap().name);
+        assert!(syms.find_by_file_offset(0x320).is_none());
+    }
+
+    #[test]
+    fn glob_matches_test() {
+        assert!(glob_matches("malloc", "malloc"));
+        assert!(!glob_matches("malloc", "malloc_usable_size"));
+        assert!(glob_matches("malloc*", "malloc_usable_size"));
+        assert!(glob_matches("*alloc", "calloc"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("pthread_*_lock", "pthread_mutex_lock"));
+        assert!(!glob_matches("pthread_*_lock", "pthread_mutex_unlock_"));
+        assert!(glob_matches("str?mp", "strcmp"));
+        assert!(!glob_matches("str?mp", "strncmp"));
+        assert!(glob_matches("*a*b", "xaxxab"));
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_profiler::{self, ProfileCategory},
     session::{
         address_space::{BreakpointType, DebugStatus, WatchConfig},
         task::{
@@ -89,6 +90,9 @@ pub fn fast_forward_through_instruction<T: Task>(
     debug_assert!(
         how == ResumeRequest::ResumeSinglestep || how == ResumeRequest::ResumeSysemuSinglestep
     );
+    let _timer = replay_profiler::timer(ProfileCategory::FastForward, || {
+        "fast_forward_through_instruction".into()
+    });
     let mut result = FastForwardStatus::new();
 
     let ip = t.ip();
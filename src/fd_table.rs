@@ -78,22 +78,30 @@ impl FdTable {
     pub fn emulate_read(
         &self,
         fd: i32,
-        t: &RecordTask,
+        t: &mut RecordTask,
         ranges: &Vec<Range>,
-        offset: &LazyOffset,
         result: &mut u64,
     ) -> bool {
         match self.fds.get(&fd) {
-            Some(f) => f.borrow().emulate_read(t, ranges, offset, result),
+            Some(f) => f.borrow().emulate_read(t, ranges, result),
             None => false,
         }
     }
-    pub fn filter_getdents(&self, fd: i32, t: &RecordTask) {
+    pub fn filter_getdents(&self, fd: i32, t: &mut RecordTask) {
         match self.fds.get(&fd) {
             Some(f) => f.borrow_mut().filter_getdents(t),
             None => (),
         }
     }
+    /// Record the memory written by io_uring operations whose completions `t`
+    /// can now see, for every io_uring `t` has an fd for.
+    pub fn record_io_uring_completions(&self, t: &mut RecordTask) {
+        for f in self.fds.values() {
+            if let Some(monitor) = f.borrow_mut().as_io_uring_monitor_mut() {
+                monitor.record_completions(t);
+            }
+        }
+    }
     pub fn is_rd_fd(&self, fd: i32) -> bool {
         match self.fds.get(&fd) {
             Some(f) => f.borrow().is_rd_fd(),
@@ -6,6 +6,7 @@ use crate::{
     remote_ptr::{RemotePtr, Void},
     session::task::{record_task::record_task::RecordTask, Task},
 };
+use io_uring_monitor::IoUringMonitor;
 use mmapped_file_monitor::MmappedFileMonitor;
 use std::{
     cell::RefCell,
@@ -16,6 +17,7 @@ use std::{
 };
 
 pub mod base_file_monitor;
+pub mod io_uring_monitor;
 pub mod magic_save_data_monitor;
 pub mod mmapped_file_monitor;
 pub mod preserve_file_monitor;
@@ -33,6 +35,7 @@ pub struct FileMonitorInner;
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum FileMonitorType {
     Base,
+    IoUring,
     MagicSaveData,
     Mmapped,
     Preserve,
@@ -223,6 +226,10 @@ pub trait FileMonitor {
         None
     }
 
+    fn as_io_uring_monitor_mut(&mut self) -> Option<&mut IoUringMonitor> {
+        None
+    }
+
     /// Overriding this to return true will cause close() (and related fd-smashing
     /// operations such as dup2) to return EBADF, and hide it from the tracee's
     /// /proc/pid/fd/
@@ -265,17 +272,13 @@ pub trait FileMonitor {
     /// result is stored in the last parameter. The emulation should write to the
     /// task's memory ranges.
     /// Only called during recording.
-    fn emulate_read(
-        &self,
-        _t: &RecordTask,
-        _vr: &Vec<Range>,
-        _o: &LazyOffset,
-        _l: &mut u64,
-    ) -> bool {
+    /// DIFF NOTE: rr also passes the LazyOffset of the read. That would need
+    /// its own mutable borrow of `t` and no monitor needs it, so we don't.
+    fn emulate_read(&self, _t: &mut RecordTask, _vr: &Vec<Range>, _l: &mut u64) -> bool {
         false
     }
 
     /// Allows the FileMonitor to rewrite the output of a getdents/getdents64 call
     /// if desired.
-    fn filter_getdents(&self, _t: &RecordTask) {}
+    fn filter_getdents(&self, _t: &mut RecordTask) {}
 }
@@ -0,0 +1,344 @@
+//! Recording io_uring instances.
+//!
+//! The submission queue (SQ) and completion queue (CQ) rings of an io_uring,
+//! and its array of submission queue entries (SQEs), are memory shared between
+//! the tracee and the kernel. When the tracee maps them, record_syscall's
+//! `process_mmap()` records their initial contents and has
+//! `MonitoredSharedMemory` take them over. That records the kernel's writes to
+//! the rings (new completion queue entries (CQEs), the SQ head moving on) when
+//! the tracee gets to see them, and publishes the tracee's writes (SQEs, the SQ
+//! tail, the CQ head) to the kernel before every io_uring_enter().
+//!
+//! That leaves the memory the kernel writes on behalf of the operations
+//! themselves, e.g. the buffer of a read. The `IoUringMonitor` of the io_uring's
+//! fd notes which memory each submitted operation will write, and records it
+//! when the operation's CQE appears. CQEs only identify their operation by the
+//! user_data of its SQE, which applications don't have to keep unique. When
+//! several operations with the same user_data are in flight, we can't tell
+//! which one completed, so every CQE for that user_data records the memory of
+//! all of them in full. Operations we don't know the memory writes of (and reads
+//! into buffers the kernel picks, IOSQE_BUFFER_SELECT) are logged as warnings,
+//! because replay will diverge if the tracee looks at what they wrote.
+//!
+//! The tracee only sees new CQEs after a syscall, so a tracee that busy-polls
+//! the CQ ring (typically with IORING_SETUP_SQPOLL) won't make progress.
+//!
+//! During replay the monitor just stands in for the recorded one; everything
+//! the kernel wrote comes from the trace.
+
+use crate::{
+    arch::Architecture,
+    bindings::kernel::statx,
+    file_monitor::{FileMonitor, FileMonitorType, Range},
+    kernel_supplement::{
+        io_uring_cqe,
+        io_uring_params,
+        io_uring_sqe,
+        IORING_CQE_F_MORE,
+        IORING_FEAT_SINGLE_MMAP,
+        IORING_OFF_CQ_RING,
+        IORING_OFF_SQES,
+        IORING_OFF_SQ_RING,
+        IORING_OP_ACCEPT,
+        IORING_OP_ASYNC_CANCEL,
+        IORING_OP_CLOSE,
+        IORING_OP_CONNECT,
+        IORING_OP_EPOLL_CTL,
+        IORING_OP_FADVISE,
+        IORING_OP_FALLOCATE,
+        IORING_OP_FILES_UPDATE,
+        IORING_OP_FSYNC,
+        IORING_OP_LINK_TIMEOUT,
+        IORING_OP_MADVISE,
+        IORING_OP_NOP,
+        IORING_OP_OPENAT,
+        IORING_OP_OPENAT2,
+        IORING_OP_POLL_ADD,
+        IORING_OP_POLL_REMOVE,
+        IORING_OP_READ,
+        IORING_OP_READV,
+        IORING_OP_READ_FIXED,
+        IORING_OP_RECV,
+        IORING_OP_SEND,
+        IORING_OP_SENDMSG,
+        IORING_OP_SYNC_FILE_RANGE,
+        IORING_OP_TIMEOUT,
+        IORING_OP_TIMEOUT_REMOVE,
+        IORING_OP_WRITE,
+        IORING_OP_WRITEV,
+        IORING_OP_WRITE_FIXED,
+        IOSQE_BUFFER_SELECT,
+    },
+    log::LogLevel::{LogDebug, LogWarn},
+    record_syscall::record_ranges,
+    remote_ptr::{RemotePtr, Void},
+    session::task::{
+        record_task::record_task::RecordTask,
+        task_common::{read_mem, read_val_mem},
+        Task,
+    },
+};
+use std::{collections::HashMap, mem::size_of};
+
+/// What /proc/<pid>/fd/<fd> of an io_uring fd points to.
+pub const IO_URING_FILE_NAME: &str = "anon_inode:[io_uring]";
+
+/// Memory an io_uring operation writes when it completes.
+struct PendingWrite {
+    ranges: Vec<Range>,
+    /// True if a successful operation writes all of `ranges`, rather than as
+    /// many bytes as its result says.
+    whole: bool,
+}
+
+/// The operations in flight with one user_data.
+#[derive(Default)]
+struct PendingOps {
+    /// How many CQEs that end an operation we're still waiting for.
+    in_flight: usize,
+    /// The memory written by the operations, if any.
+    writes: Vec<PendingWrite>,
+    /// True if more than one operation was in flight at some point, so a CQE
+    /// doesn't tell us which operation it's for.
+    ambiguous: bool,
+}
+
+pub struct IoUringMonitor {
+    /// As returned by io_uring_setup().
+    params: io_uring_params,
+    sq_ring: Option<RemotePtr<Void>>,
+    cq_ring: Option<RemotePtr<Void>>,
+    sqes: Option<RemotePtr<Void>>,
+    /// The CQ tail when we last looked for completions.
+    cq_tail_seen: u32,
+    /// Operations in flight, by the user_data of their SQE.
+    pending_ops: HashMap<u64, PendingOps>,
+}
+
+impl FileMonitor for IoUringMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::IoUring
+    }
+
+    fn as_io_uring_monitor_mut(&mut self) -> Option<&mut IoUringMonitor> {
+        Some(self)
+    }
+}
+
+impl IoUringMonitor {
+    pub fn new(params: io_uring_params) -> IoUringMonitor {
+        IoUringMonitor {
+            params,
+            sq_ring: None,
+            cq_ring: None,
+            sqes: None,
+            cq_tail_seen: 0,
+            pending_ops: HashMap::new(),
+        }
+    }
+
+    /// Notification that the part of the io_uring at `offset` (one of the
+    /// IORING_OFF_* values) was mapped at `addr`.
+    pub fn did_map(&mut self, addr: RemotePtr<Void>, offset: u64) {
+        if offset == IORING_OFF_SQ_RING as u64 {
+            self.sq_ring = Some(addr);
+            if self.params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+                // The CQ ring lives in the same mapping.
+                self.cq_ring = Some(addr);
+            }
+        } else if offset == IORING_OFF_CQ_RING as u64 {
+            self.cq_ring = Some(addr);
+        } else if offset == IORING_OFF_SQES as u64 {
+            self.sqes = Some(addr);
+        }
+    }
+
+    /// Notification that `t` is about to call io_uring_enter() on this io_uring,
+    /// after the tracee's writes to the rings have been published. Note the
+    /// memory that the operations it's submitting will write.
+    pub fn will_enter(&mut self, t: &mut RecordTask) {
+        let (sq_ring, sqes) = match (self.sq_ring, self.sqes) {
+            (Some(sq_ring), Some(sqes)) => (sq_ring, sqes),
+            _ => return,
+        };
+        let sq_off = self.params.sq_off;
+        let head = read_ring_u32(t, sq_ring, sq_off.head);
+        let tail = read_ring_u32(t, sq_ring, sq_off.tail);
+        let mask = read_ring_u32(t, sq_ring, sq_off.ring_mask);
+        let mut i = head;
+        while i != tail {
+            let index = read_ring_u32(
+                t,
+                sq_ring,
+                sq_off.array + (i & mask) * size_of::<u32>() as u32,
+            );
+            let sqe: io_uring_sqe = read_val_mem(
+                t,
+                RemotePtr::cast(sqes + index as usize * size_of::<io_uring_sqe>()),
+                None,
+            );
+            let write = pending_write(t, &sqe);
+            let ops = self.pending_ops.entry(sqe.user_data).or_default();
+            if ops.in_flight > 0 && !ops.ambiguous {
+                log!(
+                    LogDebug,
+                    "Several io_uring operations with user_data {:#x} in flight",
+                    sqe.user_data
+                );
+                ops.ambiguous = true;
+            }
+            ops.in_flight += 1;
+            ops.writes.extend(write);
+            i = i.wrapping_add(1);
+        }
+    }
+
+    /// Record the memory written by the operations whose CQEs `t` can now see.
+    pub fn record_completions(&mut self, t: &mut RecordTask) {
+        let cq_ring = match self.cq_ring {
+            Some(cq_ring) => cq_ring,
+            None => return,
+        };
+        let cq_off = self.params.cq_off;
+        let tail = read_ring_u32(t, cq_ring, cq_off.tail);
+        let mask = read_ring_u32(t, cq_ring, cq_off.ring_mask);
+        let mut i = self.cq_tail_seen;
+        while i != tail {
+            let cqe: io_uring_cqe = read_val_mem(
+                t,
+                RemotePtr::cast(
+                    cq_ring
+                        + cq_off.cqes as usize
+                        + (i & mask) as usize * size_of::<io_uring_cqe>(),
+                ),
+                None,
+            );
+            if let Some(ops) = self.pending_ops.get_mut(&cqe.user_data) {
+                // If we can't tell which operation completed, record what all of
+                // them might have written.
+                if ops.ambiguous || cqe.res >= 0 {
+                    for write in &ops.writes {
+                        let num_bytes = if ops.ambiguous || write.whole {
+                            write.ranges.iter().map(|r| r.length).sum()
+                        } else {
+                            cqe.res as usize
+                        };
+                        record_ranges(t, &write.ranges, num_bytes);
+                    }
+                }
+                if cqe.flags & IORING_CQE_F_MORE == 0 {
+                    // Not a multishot operation that will post more CQEs.
+                    ops.in_flight = ops.in_flight.saturating_sub(1);
+                    if ops.in_flight == 0 {
+                        self.pending_ops.remove(&cqe.user_data);
+                    }
+                }
+            }
+            i = i.wrapping_add(1);
+        }
+        self.cq_tail_seen = tail;
+    }
+}
+
+fn read_ring_u32(t: &mut RecordTask, ring: RemotePtr<Void>, offset: u32) -> u32 {
+    read_val_mem(t, RemotePtr::cast(ring + offset as usize), None)
+}
+
+/// The memory the operation `sqe` will write when it completes, if any.
+fn pending_write(t: &mut RecordTask, sqe: &io_uring_sqe) -> Option<PendingWrite> {
+    let opcode = sqe.opcode as u32;
+    if sqe.flags as u32 & IOSQE_BUFFER_SELECT != 0 {
+        log!(
+            LogWarn,
+            "Not recording memory written by io_uring opcode {} into a selected buffer",
+            opcode
+        );
+        return None;
+    }
+    let (ranges, whole) = match opcode {
+        IORING_OP_READ | IORING_OP_READ_FIXED | IORING_OP_RECV => (
+            vec![Range::new(
+                RemotePtr::from(sqe.addr as usize),
+                sqe.len as usize,
+            )],
+            false,
+        ),
+        IORING_OP_READV => {
+            let iov = RemotePtr::from(sqe.addr as usize);
+            let ranges =
+                rd_arch_function_selfless!(read_iovecs, t.arch(), t, iov, sqe.len as usize);
+            (ranges, false)
+        }
+        IORING_OP_STATX => (
+            // The statx buffer is in addr2.
+            vec![Range::new(
+                RemotePtr::from(sqe.off as usize),
+                size_of::<statx>(),
+            )],
+            true,
+        ),
+        IORING_OP_ACCEPT => {
+            // The peer's address goes to addr, its length to the socklen_t at addr2
+            // (which holds the size of the buffer at addr).
+            let addrlen_ptr = RemotePtr::<u32>::from(sqe.off as usize);
+            if addrlen_ptr.is_null() {
+                return None;
+            }
+            let addrlen = read_val_mem(t, addrlen_ptr, None);
+            (
+                vec![
+                    Range::new(RemotePtr::cast(addrlen_ptr), size_of::<u32>()),
+                    Range::new(RemotePtr::from(sqe.addr as usize), addrlen as usize),
+                ],
+                true,
+            )
+        }
+        IORING_OP_NOP
+        | IORING_OP_WRITEV
+        | IORING_OP_FSYNC
+        | IORING_OP_WRITE_FIXED
+        | IORING_OP_POLL_ADD
+        | IORING_OP_POLL_REMOVE
+        | IORING_OP_SYNC_FILE_RANGE
+        | IORING_OP_SENDMSG
+        | IORING_OP_TIMEOUT
+        | IORING_OP_TIMEOUT_REMOVE
+        | IORING_OP_ASYNC_CANCEL
+        | IORING_OP_LINK_TIMEOUT
+        | IORING_OP_CONNECT
+        | IORING_OP_FALLOCATE
+        | IORING_OP_OPENAT
+        | IORING_OP_CLOSE
+        | IORING_OP_FILES_UPDATE
+        | IORING_OP_WRITE
+        | IORING_OP_FADVISE
+        | IORING_OP_MADVISE
+        | IORING_OP_SEND
+        | IORING_OP_OPENAT2
+        | IORING_OP_EPOLL_CTL => return None,
+        _ => {
+            // Includes IORING_OP_RECVMSG.
+            log!(
+                LogWarn,
+                "Not recording memory written by io_uring opcode {}",
+                opcode
+            );
+            return None;
+        }
+    };
+    Some(PendingWrite { ranges, whole })
+}
+
+fn read_iovecs<Arch: Architecture>(
+    t: &mut RecordTask,
+    iov: RemotePtr<Void>,
+    count: usize,
+) -> Vec<Range> {
+    read_mem(t, RemotePtr::<Arch::iovec>::cast(iov), count, None)
+        .iter()
+        .map(|v| {
+            let (data, length) = Arch::get_iovec(v);
+            Range::new(data, length)
+        })
+        .collect()
+}
@@ -6,9 +6,16 @@ use crate::{
         LazyOffset,
         Range,
     },
+    flags::Flags,
+    log::LogLevel::LogWarn,
     remote_ptr::{RemotePtr, Void},
     session::task::replay_task::ReplayTask,
 };
+use std::{
+    cmp::{max, min},
+    fmt::Write,
+    str,
+};
 
 /// A FileMonitor to track writes to RR_MAGIC_SAVE_DATA_FD.
 pub struct MagicSaveDataMonitor;
@@ -21,7 +28,7 @@ impl FileMonitor for MagicSaveDataMonitor {
     fn did_write<'b, 'a: 'b>(&mut self, rv: &[Range], l: &mut LazyOffset<'b, 'a>) {
         for r in rv {
             if l.t.session().is_recording() {
-                let rec_task = l.t.as_record_task().unwrap();
+                let rec_task = l.t.as_record_task_mut().unwrap();
                 rec_task.record_remote(r.data, r.length);
             } else if l.t.session().is_replaying() {
                 let mut bytes: Vec<u8> = Vec::with_capacity(r.length);
@@ -48,11 +55,227 @@ impl MagicSaveDataMonitor {
     }
 }
 
-fn notify_save_data_error(
-    _t: &ReplayTask,
-    _addr: RemotePtr<Void>,
-    _rec_buf: &[u8],
-    _rep_buf: &[u8],
-) {
-    unimplemented!()
+/// Bytes per line in the hexdumps of `notify_save_data_error()`.
+const DUMP_LINE_LEN: usize = 16;
+
+/// Lines of the hexdumps to show before the line with the first difference.
+const DUMP_LINES_BEFORE: usize = 4;
+
+/// Lines of the hexdumps to show, at most.
+const DUMP_MAX_LINES: usize = 32;
+
+/// Report that replay wrote `rep_buf` to the save-data fd where the recording
+/// wrote `rec_buf` (recorded from `addr`). This is how tracee-side assertions
+/// catch replay divergence, so it's fatal unless the user asked otherwise.
+fn notify_save_data_error(t: &ReplayTask, addr: RemotePtr<Void>, rec_buf: &[u8], rep_buf: &[u8]) {
+    let mut report = String::new();
+    {
+        let frame = t.current_trace_frame();
+        writeln!(
+            report,
+            "Divergence in contents of 'tracee-save buffer' at {} during event `{}` at time {} \
+             in task {} (rec: {}).",
+            addr,
+            frame.event(),
+            frame.time(),
+            t.tid,
+            t.rec_tid
+        )
+        .unwrap();
+    }
+    if rec_buf.len() != rep_buf.len() {
+        writeln!(
+            report,
+            "Recording wrote {} bytes but replay wrote {} bytes.",
+            rec_buf.len(),
+            rep_buf.len()
+        )
+        .unwrap();
+    }
+    let first_diff = rec_buf
+        .iter()
+        .zip(rep_buf.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| min(rec_buf.len(), rep_buf.len()));
+    writeln!(report, "First difference at offset {:#x}.", first_diff).unwrap();
+    write!(
+        report,
+        "Hexdump diff (`-` recording, `+` replay):\n{}",
+        format_dump_diff(rec_buf, rep_buf, first_diff)
+    )
+    .unwrap();
+    if let (Some(rec_text), Some(rep_text)) = (printable_text(rec_buf), printable_text(rep_buf)) {
+        writeln!(
+            report,
+            "As text, recording wrote\n{:?}\nbut replay wrote\n{:?}",
+            rec_text, rep_text
+        )
+        .unwrap();
+    }
+
+    if Flags::get().nonfatal_save_data_mismatch {
+        log!(LogWarn, "{}", report);
+    } else {
+        ed_assert!(t, false, "{}", report);
+    }
+}
+
+/// A hexdump of `rec_buf` and `rep_buf` side by side, one line per
+/// `DUMP_LINE_LEN` bytes. Lines that are the same in both are printed once;
+/// lines that differ are printed from each buffer with a `-` or `+` prefix.
+/// Only a window of the buffers around `first_diff` is dumped, the buffers can
+/// be large.
+fn format_dump_diff(rec_buf: &[u8], rep_buf: &[u8], first_diff: usize) -> String {
+    let mut out = String::new();
+    let len = max(rec_buf.len(), rep_buf.len());
+    let first_diff_line = first_diff / DUMP_LINE_LEN;
+    let start = first_diff_line.saturating_sub(DUMP_LINES_BEFORE) * DUMP_LINE_LEN;
+    let end = min(len, start + DUMP_MAX_LINES * DUMP_LINE_LEN);
+    if start > 0 {
+        writeln!(out, "  ... {:#x} bytes before ...", start).unwrap();
+    }
+    let mut offset = start;
+    while offset < end {
+        let rec_line = dump_line(rec_buf, offset);
+        let rep_line = dump_line(rep_buf, offset);
+        if rec_line == rep_line {
+            writeln!(out, "  {:08x}: {}", offset, rec_line.unwrap()).unwrap();
+        } else {
+            if let Some(line) = rec_line {
+                writeln!(out, "- {:08x}: {}", offset, line).unwrap();
+            }
+            if let Some(line) = rep_line {
+                writeln!(out, "+ {:08x}: {}", offset, line).unwrap();
+            }
+        }
+        offset += DUMP_LINE_LEN;
+    }
+    if end < len {
+        writeln!(out, "  ... {:#x} bytes after ...", len - end).unwrap();
+    }
+    out
+}
+
+/// The hex bytes of `buf` in the line starting at `offset`, or None if `buf`
+/// ends before then.
+fn dump_line(buf: &[u8], offset: usize) -> Option<String> {
+    if offset >= buf.len() {
+        return None;
+    }
+    let end = min(offset + DUMP_LINE_LEN, buf.len());
+    let hex: Vec<String> = buf[offset..end]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(hex.join(" "))
+}
+
+/// `buf` as a string if it's all printable ASCII or whitespace (allowing a
+/// trailing NUL), otherwise None.
+fn printable_text(buf: &[u8]) -> Option<&str> {
+    let buf = match buf.split_last() {
+        Some((0, rest)) => rest,
+        _ => buf,
+    };
+    if buf.is_empty()
+        || !buf
+            .iter()
+            .all(|&c| c.is_ascii_graphic() || c.is_ascii_whitespace())
+    {
+        return None;
+    }
+    str::from_utf8(buf).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_dump_diff_equal_length() {
+        let rec: Vec<u8> = (0..40).collect();
+        let mut rep = rec.clone();
+        rep[20] = 0xff;
+        let dump = format_dump_diff(&rec, &rep, 20);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "  00000000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f",
+                "- 00000010: 10 11 12 13 14 15 16 17 18 19 1a 1b 1c 1d 1e 1f",
+                "+ 00000010: 10 11 12 13 ff 15 16 17 18 19 1a 1b 1c 1d 1e 1f",
+                "  00000020: 20 21 22 23 24 25 26 27",
+            ]
+        );
+    }
+
+    #[test]
+    fn format_dump_diff_different_length() {
+        let rec = [1u8; 20];
+        let rep = [1u8; 16];
+        assert_eq!(
+            format_dump_diff(&rec, &rep, 16),
+            "  00000000: 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01\n\
+             - 00000010: 01 01 01 01\n"
+        );
+        assert_eq!(
+            format_dump_diff(&rep, &rec, 16),
+            "  00000000: 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01\n\
+             + 00000010: 01 01 01 01\n"
+        );
+    }
+
+    #[test]
+    fn format_dump_diff_at_start() {
+        let rec = [0u8; 1024];
+        let mut rep = rec;
+        rep[0] = 1;
+        let dump = format_dump_diff(&rec, &rep, 0);
+        let lines: Vec<&str> = dump.lines().collect();
+        // The differing line twice, the rest of the window, then what was left out.
+        assert_eq!(lines.len(), DUMP_MAX_LINES + 2);
+        assert_eq!(
+            lines[0],
+            "- 00000000: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+        assert_eq!(
+            lines[1],
+            "+ 00000000: 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+        assert_eq!(lines[DUMP_MAX_LINES + 1], "  ... 0x200 bytes after ...");
+    }
+
+    #[test]
+    fn format_dump_diff_at_end() {
+        let rec = [0u8; 1024];
+        let mut rep = rec;
+        rep[1023] = 1;
+        let dump = format_dump_diff(&rec, &rep, 1023);
+        let lines: Vec<&str> = dump.lines().collect();
+        // What was left out, the lines before the difference, then the differing line twice.
+        assert_eq!(lines.len(), DUMP_LINES_BEFORE + 3);
+        assert_eq!(lines[0], "  ... 0x3b0 bytes before ...");
+        assert_eq!(
+            lines[DUMP_LINES_BEFORE],
+            "  000003e0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+        assert_eq!(
+            lines[DUMP_LINES_BEFORE + 1],
+            "- 000003f0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+        assert_eq!(
+            lines[DUMP_LINES_BEFORE + 2],
+            "+ 000003f0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 01"
+        );
+    }
+
+    #[test]
+    fn printable_text_test() {
+        assert_eq!(printable_text(b"hello world\n"), Some("hello world\n"));
+        assert_eq!(printable_text(b"hello\0"), Some("hello"));
+        assert_eq!(printable_text(b""), None);
+        assert_eq!(printable_text(b"\0"), None);
+        assert_eq!(printable_text(b"a\0b"), None);
+        assert_eq!(printable_text(&[0x68, 0x69, 0xff]), None);
+    }
 }
@@ -1,9 +1,18 @@
 use crate::{
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    fd_table::{FdTable, FdTableSharedPtr},
     file_monitor::{FileMonitor, FileMonitorType},
-    session::task::{record_task::record_task::RecordTask, Task},
+    kernel_abi::{is_getdents64_syscall, x64, x86, SupportedArch},
+    remote_ptr::RemotePtr,
+    session::task::{
+        record_task::record_task::RecordTask,
+        task_common::{read_mem, write_mem},
+        Task,
+    },
     taskish_uid::TaskUid,
 };
-use std::ffi::OsStr;
+use libc::pid_t;
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, str};
 
 /// A FileMonitor to intercept enumerations of /proc/<pid>/fd so that entries
 /// for rr's private fds can be hidden when <pid> is a tracee.
@@ -18,13 +27,215 @@ impl FileMonitor for ProcFdDirMonitor {
         FileMonitorType::ProcFd
     }
 
-    fn filter_getdents(&self, _t: &RecordTask) {
-        unimplemented!()
+    /// If `t` is reading /proc/<pid>/fd of a tracee, remove the entries for
+    /// rd's own fds from the getdents/getdents64 result. The filtered result
+    /// is what gets recorded.
+    fn filter_getdents(&self, t: &mut RecordTask) {
+        ed_assert!(t, !t.session().is_replaying());
+        let tuid = match self.maybe_tuid {
+            Some(tuid) => tuid,
+            None => return,
+        };
+        let fds: FdTableSharedPtr = if tuid == t.tuid() {
+            t.fd_table_shr_ptr()
+        } else {
+            match t.session().find_task_from_task_uid(tuid) {
+                Some(target) if target.borrow().tuid() == tuid => {
+                    target.borrow().fd_table_shr_ptr()
+                }
+                _ => return,
+            }
+        };
+        let fds_ref = fds.borrow();
+        filter_dirents(t, &fds_ref);
     }
 }
 
 impl ProcFdDirMonitor {
-    pub fn new(_t: &dyn Task, _pathname: &OsStr) -> ProcFdDirMonitor {
-        unimplemented!()
+    pub fn new(t: &dyn Task, pathname: &OsStr) -> ProcFdDirMonitor {
+        // XXX this makes some assumptions about namespaces... Probably fails
+        // if `t` is not the same pid namespace as rd
+        let mut maybe_tuid = None;
+        let path = pathname.as_bytes();
+        if path.len() > 6 && path.starts_with(b"/proc/") {
+            let rest = &path[6..];
+            let end = rest.iter().position(|&c| c == b'/').unwrap_or(rest.len());
+            let maybe_tid = str::from_utf8(&rest[0..end])
+                .ok()
+                .and_then(|s| s.parse::<pid_t>().ok());
+            if let Some(tid) = maybe_tid {
+                if tid == t.rec_tid {
+                    maybe_tuid = Some(t.tuid());
+                } else if let Some(target) = t.session().find_task_from_rec_tid(tid) {
+                    maybe_tuid = Some(target.borrow().tuid());
+                }
+            }
+        }
+
+        ProcFdDirMonitor { maybe_tuid }
+    }
+}
+
+/// Byte offsets of `d_reclen` and `d_name` in the records returned by
+/// getdents (`dirent`) or getdents64 (`dirent64`) for `arch`.
+fn dirent_layout(arch: SupportedArch, is_getdents64: bool) -> (usize, usize) {
+    match (arch, is_getdents64) {
+        (SupportedArch::X86, false) => (
+            offset_of!(x86::dirent, d_reclen),
+            offset_of!(x86::dirent, d_name),
+        ),
+        (SupportedArch::X86, true) => (
+            offset_of!(x86::dirent64, d_reclen),
+            offset_of!(x86::dirent64, d_name),
+        ),
+        (SupportedArch::X64, false) => (
+            offset_of!(x64::dirent, d_reclen),
+            offset_of!(x64::dirent, d_name),
+        ),
+        (SupportedArch::X64, true) => (
+            offset_of!(x64::dirent64, d_reclen),
+            offset_of!(x64::dirent64, d_name),
+        ),
+    }
+}
+
+/// Remove the records for the fds `is_rd_fd` accepts from `buf`, which holds
+/// the dirent records of a /proc/<pid>/fd directory.
+fn filter_dirent_structs<F: Fn(i32) -> bool>(
+    buf: &mut Vec<u8>,
+    layout: (usize, usize),
+    is_rd_fd: F,
+) {
+    let (reclen_offset, name_offset) = layout;
+    let mut cur = 0;
+    while cur + name_offset <= buf.len() {
+        let reclen =
+            u16::from_ne_bytes([buf[cur + reclen_offset], buf[cur + reclen_offset + 1]]) as usize;
+        if reclen < name_offset || cur + reclen > buf.len() {
+            // Malformed. Leave the rest alone.
+            break;
+        }
+        let name = &buf[cur + name_offset..cur + reclen];
+        let name = &name[0..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+        let is_rd_fd = str::from_utf8(name)
+            .ok()
+            .and_then(|s| s.parse::<i32>().ok())
+            .map_or(false, |fd| is_rd_fd(fd));
+        if is_rd_fd {
+            buf.drain(cur..cur + reclen);
+        } else {
+            cur += reclen;
+        }
+    }
+}
+
+fn filter_dirents(t: &mut RecordTask, fds: &FdTable) {
+    let mut regs = t.regs_ref().clone();
+    let ptr = RemotePtr::<u8>::from(regs.arg2());
+    let is_getdents64 = is_getdents64_syscall(regs.original_syscallno() as i32, t.arch());
+    let layout = dirent_layout(t.arch(), is_getdents64);
+
+    loop {
+        if regs.syscall_failed() || regs.syscall_result() == 0 {
+            t.set_regs(&regs);
+            return;
+        }
+
+        let mut buf = read_mem(t, ptr, regs.syscall_result(), None);
+        filter_dirent_structs(&mut buf, layout, |fd| fds.is_rd_fd(fd));
+        if !buf.is_empty() {
+            write_mem(t, ptr, &buf, None);
+            regs.set_syscall_result(buf.len());
+            t.set_regs(&regs);
+            t.record_local(RemotePtr::cast(ptr), &buf);
+            return;
+        }
+
+        // Every entry we got was one of ours. Ask for more, so that we don't
+        // return 0 and signal the end of the directory too early.
+        let ret = {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            rd_syscall!(
+                remote,
+                regs.original_syscallno() as i32,
+                regs.arg1(),
+                regs.arg2(),
+                regs.arg3()
+            )
+        };
+        regs.set_syscall_result_signed(ret);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The layout of `struct linux_dirent64` on x86-64.
+    fn layout() -> (usize, usize) {
+        dirent_layout(SupportedArch::X64, true)
+    }
+
+    /// A dirent record for `name` that is `reclen` bytes long.
+    fn dirent(name: &str, reclen: usize) -> Vec<u8> {
+        let (reclen_offset, name_offset) = layout();
+        let mut d = vec![0u8; reclen];
+        d[reclen_offset..reclen_offset + 2].copy_from_slice(&(reclen as u16).to_ne_bytes());
+        let end = (name_offset + name.len()).min(reclen);
+        d[name_offset..end].copy_from_slice(&name.as_bytes()[0..end - name_offset]);
+        d
+    }
+
+    fn dirents(names: &[&str]) -> Vec<u8> {
+        names.iter().flat_map(|name| dirent(name, 24)).collect()
+    }
+
+    fn is_rd_fd(fd: i32) -> bool {
+        fd == 100 || fd == 101
+    }
+
+    #[test]
+    fn filter_dirent_structs_test() {
+        let mut buf = dirents(&[".", "..", "0", "100", "1", "101"]);
+        filter_dirent_structs(&mut buf, layout(), is_rd_fd);
+        assert_eq!(dirents(&[".", "..", "0", "1"]), buf);
+
+        let mut buf = dirents(&["100", "101"]);
+        filter_dirent_structs(&mut buf, layout(), is_rd_fd);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn filter_dirent_structs_unterminated_name_test() {
+        // The name runs right up to the end of the record.
+        let (_, name_offset) = layout();
+        let mut buf = dirent("100", name_offset + 3);
+        buf.extend(dirent("1", 24));
+        filter_dirent_structs(&mut buf, layout(), is_rd_fd);
+        assert_eq!(dirents(&["1"]), buf);
+    }
+
+    #[test]
+    fn filter_dirent_structs_malformed_test() {
+        let (reclen_offset, name_offset) = layout();
+        // A record shorter than its header, or one that claims to be longer
+        // than the buffer, ends filtering. Everything from it on is left alone.
+        for &bad_reclen in &[0, name_offset - 1, 200] {
+            let mut bad = dirents(&["100", "101"]);
+            bad[reclen_offset..reclen_offset + 2]
+                .copy_from_slice(&(bad_reclen as u16).to_ne_bytes());
+            let mut buf = dirents(&["0", "100"]);
+            buf.extend(&bad);
+            filter_dirent_structs(&mut buf, layout(), is_rd_fd);
+            let mut expected = dirents(&["0"]);
+            expected.extend(&bad);
+            assert_eq!(expected, buf);
+        }
+
+        // A truncated header is left alone.
+        let mut buf = dirents(&["100"]);
+        buf.extend(&[0u8; 8]);
+        filter_dirent_structs(&mut buf, layout(), is_rd_fd);
+        assert_eq!(vec![0u8; 8], buf);
     }
 }
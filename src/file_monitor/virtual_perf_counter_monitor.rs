@@ -1 +1,159 @@
-pub struct VirtualPerfCounterMonitor;
+use crate::{
+    bindings::perf_event::{
+        perf_event_attr,
+        PERF_EVENT_IOC_DISABLE,
+        PERF_EVENT_IOC_ENABLE,
+        PERF_EVENT_IOC_RESET,
+        PERF_FLAG_FD_CLOEXEC,
+    },
+    file_monitor::{FileMonitor, FileMonitorType, Range},
+    log::LogLevel::LogWarn,
+    perf_counters::PerfCounters,
+    session::task::{record_task::record_task::RecordTask, task_common::write_mem, Task},
+    taskish_uid::TaskUid,
+    ticks::Ticks,
+};
+use libc::{c_ulong, pid_t, ENOTTY};
+use std::cmp::min;
+
+/// A FileMonitor to emulate perf event fds that a tracee opened to count rd's
+/// ticks for a tracee. Opening a real counter would clash with the
+/// counters rd itself uses, and its values wouldn't be reproducible anyway.
+/// Instead the counter reports the target's tick count (which rd maintains
+/// from `PerfCounters::read_ticks()`), so it reads the same during record
+/// and replay.
+pub struct VirtualPerfCounterMonitor {
+    /// The target's tick count when the counter was last enabled or reset.
+    initial_ticks: Ticks,
+    /// Ticks counted before the counter was last disabled.
+    /// DIFF NOTE: rr keeps counting while the counter is disabled.
+    accumulated_ticks: Ticks,
+    target_tuid: TaskUid,
+    enabled: bool,
+}
+
+impl FileMonitor for VirtualPerfCounterMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::VirtualPerfCounter
+    }
+
+    fn emulate_ioctl(&mut self, t: &RecordTask, r: &mut u64) -> bool {
+        let now = target_tick_count(t, self.target_tuid);
+        match t.regs_ref().arg2() as c_ulong {
+            PERF_EVENT_IOC_ENABLE => {
+                if !self.enabled {
+                    self.initial_ticks = now.unwrap_or(0);
+                    self.enabled = true;
+                }
+            }
+            PERF_EVENT_IOC_DISABLE => {
+                if self.enabled {
+                    self.accumulated_ticks = self.value(now);
+                    self.enabled = false;
+                }
+            }
+            PERF_EVENT_IOC_RESET => {
+                self.accumulated_ticks = 0;
+                self.initial_ticks = now.unwrap_or(0);
+            }
+            request => {
+                // DIFF NOTE: rr asserts. The request is up to the tracee, so fail it
+                // like the kernel fails requests it doesn't know.
+                log!(
+                    LogWarn,
+                    "Unsupported perf event ioctl {:#x} on a virtual counter",
+                    request
+                );
+                *r = -ENOTTY as u64;
+                return true;
+            }
+        }
+        *r = 0;
+        true
+    }
+
+    fn emulate_read(&self, t: &mut RecordTask, vr: &Vec<Range>, l: &mut u64) -> bool {
+        let val = self.value(target_tick_count(t, self.target_tuid));
+        *l = write_ranges(t, vr, &val.to_ne_bytes()) as u64;
+        true
+    }
+}
+
+impl VirtualPerfCounterMonitor {
+    /// True if a perf_event_open() of `attr` with these `cpu`, `group_fd` and
+    /// `flags` arguments asks for something we can emulate. Reads of the counter
+    /// only return the bare value, so `attr` mustn't ask for anything else.
+    pub fn should_virtualize(attr: &perf_event_attr, cpu: i32, group_fd: i32, flags: u64) -> bool {
+        cpu == -1
+            && group_fd == -1
+            && attr.read_format == 0
+            && flags & !(PERF_FLAG_FD_CLOEXEC as u64) == 0
+            && PerfCounters::is_rd_ticks_attr(attr)
+    }
+
+    /// The task that a perf_event_open() by `t` with `pid` would monitor, if
+    /// it's one of ours.
+    /// DIFF NOTE: rr doesn't treat pid 0 as the calling task here.
+    pub fn target_tuid(t: &dyn Task, pid: pid_t) -> Option<TaskUid> {
+        if pid == 0 || pid == t.rec_tid {
+            Some(t.tuid())
+        } else {
+            t.session()
+                .find_task_from_rec_tid(pid)
+                .map(|target| target.borrow().tuid())
+        }
+    }
+
+    pub fn new(
+        t: &dyn Task,
+        target_tuid: TaskUid,
+        attr: &perf_event_attr,
+    ) -> VirtualPerfCounterMonitor {
+        ed_assert!(t, PerfCounters::is_rd_ticks_attr(attr));
+        let initial_ticks = target_tick_count(t, target_tuid);
+        ed_assert!(t, initial_ticks.is_some());
+        VirtualPerfCounterMonitor {
+            initial_ticks: initial_ticks.unwrap(),
+            accumulated_ticks: 0,
+            target_tuid,
+            enabled: attr.disabled() == 0,
+        }
+    }
+
+    /// The counter value if the target's tick count is `now` (None if the
+    /// target is gone).
+    fn value(&self, now: Option<Ticks>) -> Ticks {
+        match now {
+            Some(now) if self.enabled => self.accumulated_ticks + (now - self.initial_ticks),
+            _ => self.accumulated_ticks,
+        }
+    }
+}
+
+fn target_tick_count(t: &dyn Task, target_tuid: TaskUid) -> Option<Ticks> {
+    if target_tuid == t.tuid() {
+        return Some(t.tick_count());
+    }
+    let target = t.session().find_task_from_task_uid(target_tuid)?;
+    let target_ref = target.borrow();
+    if target_ref.tuid() == target_tuid {
+        Some(target_ref.tick_count())
+    } else {
+        None
+    }
+}
+
+/// Write `data` to the ranges `vr` of `t`'s memory, in order, until either
+/// runs out. Returns the number of bytes written.
+fn write_ranges(t: &mut RecordTask, vr: &[Range], data: &[u8]) -> usize {
+    let mut written = 0;
+    for r in vr {
+        if written == data.len() {
+            break;
+        }
+        let len = min(r.length, data.len() - written);
+        write_mem(t, r.data, &data[written..written + len], None);
+        written += len;
+    }
+    written
+}
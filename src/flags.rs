@@ -1,10 +1,13 @@
 use crate::{commands::rd_options::RdOptions, trace::trace_frame::FrameTime};
-use std::path::PathBuf;
-use structopt::StructOpt;
+use std::{
+    path::PathBuf,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
-lazy_static! {
-    static ref FLAGS: Flags = init_flags();
-}
+/// Set once, either explicitly via `Flags::init()` or to the defaults on the first
+/// `Flags::get()`, and never freed.
+static FLAGS: AtomicPtr<Flags> = AtomicPtr::new(ptr::null_mut());
 
 /// When to generate or check memory checksums. One of CHECKSUM_NONE,
 /// CHECKSUM_SYSCALL or CHECKSUM_ALL, or a positive integer representing the
@@ -24,7 +27,11 @@ pub enum DumpOn {
     DumpOnSyscall(i32),
 }
 
-#[derive(Clone)]
+/// Process-wide configuration that is not specific to any one command.
+///
+/// The `rd` binary builds this from its command line. Programs using rd as a library can call
+/// `Flags::init()` before doing anything else, or just rely on the defaults.
+#[derive(Clone, Default)]
 pub struct Flags {
     pub checksum: Option<Checksum>,
     pub dump_on: Option<DumpOn>,
@@ -42,6 +49,9 @@ pub struct Flags {
     pub suppress_environment_warnings: bool,
     /// Any warning or error that would be printed is treated as fatal
     pub fatal_errors_and_warnings: bool,
+    /// Only warn, instead of aborting, when a tracee's write to
+    /// RD_MAGIC_SAVE_DATA_FD during replay doesn't match the recording.
+    pub nonfatal_save_data_mismatch: bool,
     /// Pretend CPUID faulting support doesn't exist
     pub disable_cpuid_faulting: bool,
     /// Don't listen for PTRACE_EVENT_EXIT events, to test how rd handles
@@ -54,26 +64,65 @@ pub struct Flags {
 }
 
 impl Flags {
+    /// Install `flags` as the process-wide configuration.
+    ///
+    /// Must be called at most once, before anything has called `Flags::get()`.
+    pub fn init(flags: Flags) {
+        let new_flags = Box::into_raw(Box::new(flags));
+        if FLAGS
+            .compare_exchange(
+                ptr::null_mut(),
+                new_flags,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            // Safe: `new_flags` was never published.
+            drop(unsafe { Box::from_raw(new_flags) });
+            panic!("Flags::init() called after the flags were already initialized or in use");
+        }
+    }
+
     pub fn get() -> &'static Flags {
-        &*FLAGS
+        let flags = FLAGS.load(Ordering::Acquire);
+        if !flags.is_null() {
+            return unsafe { &*flags };
+        }
+
+        let default_flags = Box::into_raw(Box::new(Flags::default()));
+        match FLAGS.compare_exchange(
+            ptr::null_mut(),
+            default_flags,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => unsafe { &*default_flags },
+            Err(winner) => {
+                // Safe: `default_flags` was never published.
+                drop(unsafe { Box::from_raw(default_flags) });
+                unsafe { &*winner }
+            }
+        }
     }
 }
 
-pub fn init_flags() -> Flags {
-    let options = RdOptions::from_args();
-
-    Flags {
-        checksum: options.checksum,
-        dump_on: options.dump_on,
-        dump_at: options.dump_at,
-        force_things: options.force_things,
-        mark_stdio: options.mark_stdio,
-        check_cached_mmaps: options.check_cached_mmaps,
-        suppress_environment_warnings: options.suppress_environment_warnings,
-        fatal_errors_and_warnings: options.fatal_errors,
-        disable_cpuid_faulting: options.disable_cpuid_faulting,
-        disable_ptrace_exit_events: options.disable_ptrace_exit_events,
-        forced_uarch: options.microarch,
-        resource_path: options.resource_path,
+impl From<&RdOptions> for Flags {
+    fn from(options: &RdOptions) -> Flags {
+        Flags {
+            checksum: options.checksum,
+            dump_on: options.dump_on,
+            dump_at: options.dump_at,
+            force_things: options.force_things,
+            mark_stdio: options.mark_stdio,
+            check_cached_mmaps: options.check_cached_mmaps,
+            suppress_environment_warnings: options.suppress_environment_warnings,
+            fatal_errors_and_warnings: options.fatal_errors,
+            nonfatal_save_data_mismatch: options.nonfatal_save_data_mismatch,
+            disable_cpuid_faulting: options.disable_cpuid_faulting,
+            disable_ptrace_exit_events: options.disable_ptrace_exit_events,
+            forced_uarch: options.microarch.clone(),
+            resource_path: options.resource_path.clone(),
+        }
     }
 }
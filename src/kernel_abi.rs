@@ -187,19 +187,30 @@ fn get_syscall_instruction_arch(
     if !ok {
         return false;
     }
-    match t.arch() {
+    match syscall_instruction_arch(&code, t.arch()) {
+        Some(syscall_arch) => {
+            *arch = syscall_arch;
+            true
+        }
+        None => false,
+    }
+}
+
+/// The arch of the syscall table used by the syscall instruction `code`, executed by a task
+/// of arch `task_arch`, or `None` if `code` isn't a syscall instruction.
+pub fn syscall_instruction_arch(code: &[u8], task_arch: SupportedArch) -> Option<SupportedArch> {
+    match task_arch {
         // Compatibility mode switch can happen in user space (but even without
         // such tricks, int80, which uses the 32bit syscall table, can be invoked
         // from 64bit processes).
         SupportedArch::X86 | SupportedArch::X64 => {
             if code == INT80_INSN || code == SYSENTER_INSN {
-                *arch = SupportedArch::X86;
+                Some(SupportedArch::X86)
             } else if code == SYSCALL_INSN {
-                *arch = SupportedArch::X64;
+                Some(SupportedArch::X64)
             } else {
-                return false;
+                None
             }
-            return true;
         }
     }
 }
@@ -218,6 +229,17 @@ pub fn syscall_instruction(arch: SupportedArch) -> &'static [u8] {
     }
 }
 
+/// The AUDIT_ARCH_* value that identifies syscalls of `arch`, e.g. in the
+/// siginfo of a seccomp SIGSYS.
+pub fn to_audit_arch(arch: SupportedArch) -> u32 {
+    match arch {
+        // AUDIT_ARCH_I386
+        SupportedArch::X86 => 0x4000_0003,
+        // AUDIT_ARCH_X86_64
+        SupportedArch::X64 => 0xc000_003e,
+    }
+}
+
 /// Return the length of all invoke-syscall instructions. Currently,
 /// they must all have the same length!
 pub fn syscall_instruction_length(arch: SupportedArch) -> usize {
@@ -387,7 +409,7 @@ pub mod x64 {
 
     #[repr(C)]
     #[derive(Copy, Clone, Default)]
-    struct stat64 {
+    pub struct stat64 {
         pub st_dev: dev_t,
         pub st_ino: ino_t,
         pub st_nlink: nlink_t,
@@ -0,0 +1,200 @@
+//! rd is a record and replay debugger; this crate is also usable as a library for reading
+//! the traces it records.
+//!
+//! The stable part of the API is re-exported at the crate root. A minimal analyzer that walks
+//! every frame, the mappings created at each frame and the task events looks like:
+//!
+//! ```no_run
+//! use rd::{Flags, TraceReader, ValidateSourceFile};
+//!
+//! // Optional: configuration is otherwise defaulted, never read from argv.
+//! Flags::init(Flags::default());
+//!
+//! let mut trace = TraceReader::new(Some(&"/path/to/trace"));
+//! while !trace.at_end() {
+//!     let frame = trace.read_frame();
+//!     println!("{} {} ip:{}", frame.time(), frame.event(), frame.regs_ref().ip());
+//!     while let Some(km) = trace.read_mapped_region(
+//!         None,
+//!         Some(ValidateSourceFile::DontValidate),
+//!         None,
+//!         None,
+//!         None,
+//!     ) {
+//!         println!("  mmap {}", km);
+//!     }
+//! }
+//!
+//! trace.rewind();
+//! while let Some(task_event) = trace.read_task_event(None) {
+//!     println!("task event for tid {}", task_event.tid());
+//! }
+//! ```
+//!
+//! Passing `None` to `TraceReader::new()` opens the latest trace, as the `rd` binary does.
+//!
+//! To run a replay and inspect the tracees as it goes, implement `ReplayCallbacks` and hand it
+//! to a `Replayer`. This logs every write to stderr:
+//!
+//! ```no_run
+//! use rd::{ReplayCallbacks, ReplayFlags, Replayer, Task, TraceFrame};
+//!
+//! struct StderrWrites;
+//!
+//! impl ReplayCallbacks for StderrWrites {
+//!     fn on_syscall_exit(&mut self, t: &mut dyn Task, frame: &TraceFrame, _syscallno: i32) {
+//!         let regs = frame.regs_ref();
+//!         if frame.event().syscall_event().syscall_name() == "write" && regs.arg1() == 2 {
+//!             let mut buf = vec![0u8; regs.arg3()];
+//!             let _ = t.read_bytes_fallible(regs.arg2().into(), &mut buf);
+//!             println!("{}: {}", frame.time(), String::from_utf8_lossy(&buf));
+//!         }
+//!     }
+//! }
+//!
+//! let flags = ReplayFlags {
+//!     redirect_stdio: false,
+//!     share_private_mappings: false,
+//!     cpu_unbound: false,
+//! };
+//! Replayer::new(Some(&"/path/to/trace"), flags)
+//!     .run(&mut StderrWrites)
+//!     .unwrap();
+//! ```
+#![feature(get_mut_unchecked)]
+#![feature(map_first_last)]
+#![feature(llvm_asm)]
+#![feature(raw_ref_op)]
+// @TODO To many results for "never used". Disable for now.
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate raw_cpuid;
+#[macro_use]
+extern crate static_assertions;
+#[macro_use]
+extern crate memoffset;
+
+#[macro_use]
+mod log;
+#[macro_use]
+mod arch;
+#[macro_use]
+mod kernel_abi;
+#[macro_use]
+mod auto_remote_syscalls;
+mod bindings;
+mod flags;
+mod kernel_metadata;
+mod perf_counters;
+#[macro_use]
+mod registers;
+/// The `rd` binary's subcommands. Not part of the stable API.
+#[doc(hidden)]
+pub mod commands;
+mod core;
+mod cpuid_bug_detector;
+mod disassembler;
+mod divergence_bundle;
+mod elf_symbols;
+mod emu_fs;
+mod event;
+mod extra_registers;
+mod fast_forward;
+mod fd_table;
+mod file_monitor;
+mod gdb_register;
+mod gdb_server;
+mod kernel_supplement;
+mod monitored_shared_memory;
+mod monkey_patcher;
+mod rd;
+mod record_signal;
+mod record_syscall;
+mod remote_code_ptr;
+mod remote_ptr;
+mod replay_profiler;
+mod replay_syscall;
+mod replayer;
+mod scheduler;
+mod scoped_fd;
+mod seccomp_bpf;
+mod seccomp_filter_rewriter;
+mod session;
+mod taskish_uid;
+mod thread_group;
+mod ticks;
+mod trace;
+mod trace_capnp;
+mod unwinder;
+mod util;
+mod wait_status;
+mod weak_ptr_set;
+
+pub use crate::{
+    event::{Event, EventType, SignalEventData, SyscallEventData, SyscallState},
+    extra_registers::{ExtraRegisters, Format as ExtraRegistersFormat},
+    flags::{Checksum, DumpOn, Flags},
+    kernel_abi::SupportedArch,
+    perf_counters::TicksSemantics,
+    registers::Registers,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    replayer::{ReplayCallbacks, Replayer},
+    session::{
+        address_space::kernel_mapping::KernelMapping,
+        replay_session::Flags as ReplayFlags,
+        task::Task,
+    },
+    ticks::Ticks,
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::{RawData, TimeConstraint, TraceReader, ValidateSourceFile},
+        trace_stream::{MappedData, MappedDataSource, TraceRemoteFd},
+        trace_task_event::{
+            TraceTaskEvent,
+            TraceTaskEventClone,
+            TraceTaskEventExec,
+            TraceTaskEventExit,
+            TraceTaskEventType,
+            TraceTaskEventVariant,
+        },
+    },
+    util::CPUIDRecord,
+    wait_status::WaitStatus,
+};
+
+/// Used by the `rd` binary. Not part of the stable API.
+#[doc(hidden)]
+pub use crate::{perf_counters::init_pmu, util::raise_resource_limits};
+
+use nix::sys::utsname::uname;
+
+pub(crate) fn assert_prerequisites(maybe_use_syscall_buffer: Option<bool>) {
+    let use_syscall_buffer = maybe_use_syscall_buffer.unwrap_or(false);
+    let unm = uname();
+    let release = unm.release();
+    let parts: Vec<&str> = release.split('.').collect();
+    if parts.len() < 2 {
+        fatal!("Could not parse kernel version string. Got: `{}`", release);
+    }
+
+    let maybe_major = parts[0].parse::<u32>();
+    let maybe_minor = parts[1].parse::<u32>();
+    if maybe_major.is_err() || maybe_minor.is_err() {
+        fatal!("Could not parse kernel version string. Got: `{}`", release);
+    }
+
+    let (major, minor) = (maybe_major.unwrap(), maybe_minor.unwrap());
+    if (major, minor) < (3, 4) {
+        fatal!("Kernel doesn't support necessary ptrace functionality; need 3.4.0 or better.");
+    }
+
+    if use_syscall_buffer && (major, minor) < (3, 5) {
+        fatal!("Your kernel does not support syscall filtering; please use the -n option while recording");
+    }
+}
@@ -1,14 +1,24 @@
-use crate::kernel_metadata::errno_name;
+use crate::{kernel_metadata::errno_name, trace::trace_frame::FrameTime};
 use backtrace::Backtrace;
+use libc::pid_t;
 use nix::errno::errno;
+use serde_json::{json, Map, Value};
 use std::{
+    cell::{Cell, RefCell},
+    cmp::min,
     collections::HashMap,
     env,
     env::var_os,
-    fs::{File, OpenOptions},
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
     io::{self, BufWriter, Result, Write},
-    path::Path,
-    sync::{Mutex, MutexGuard},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        Mutex,
+        MutexGuard,
+    },
 };
 
 #[derive(Clone)]
@@ -29,15 +39,183 @@ pub enum LogLevel {
 use crate::session::task::Task;
 pub use LogLevel::*;
 
+/// Selected with the RD_LOG_FORMAT environment variable.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum LogFormat {
+    /// `[LEVEL function()] message` lines, like rr.
+    Text,
+    /// One JSON object per log record and line.
+    Json,
+}
+
 struct LogGlobals {
     level_map: HashMap<String, LogLevel>,
-    log_modules_cache: HashMap<String, LogModule>,
     logging_stream: String,
     // Possibly buffered
     log_file: Box<dyn Write + Send>,
     default_level: LogLevel,
 }
 
+/// Bumped whenever a log level changes, invalidating every thread's `LOG_MODULES_CACHE`.
+static LOG_LEVELS_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Filename -> LogModule, valid for the `LOG_LEVELS_GENERATION` it was filled in.
+    /// Per thread so deciding whether a record is enabled doesn't take `LOG_GLOBALS`.
+    static LOG_MODULES_CACHE: RefCell<(usize, HashMap<String, LogModule>)> =
+        RefCell::new((0, HashMap::new()));
+    /// The tid of the task being worked on and the trace time. Only reported in JSON records.
+    static TASK_CONTEXT: Cell<Option<(pid_t, FrameTime)>> = Cell::new(None);
+}
+
+/// Set the task and trace time that subsequent JSON log records on this thread refer to,
+/// until the returned guard is dropped.
+pub fn set_task_context(context: Option<(pid_t, FrameTime)>) -> TaskContextGuard {
+    TaskContextGuard {
+        previous: TASK_CONTEXT.with(|c| c.replace(context)),
+    }
+}
+
+/// Restores the previous task context when dropped, see `set_task_context()`.
+#[must_use]
+pub struct TaskContextGuard {
+    previous: Option<(pid_t, FrameTime)>,
+}
+
+impl Drop for TaskContextGuard {
+    fn drop(&mut self) {
+        TASK_CONTEXT.with(|c| c.set(self.previous));
+    }
+}
+
+/// Keeps the last few records logged at any level, whether or not they were written to the log,
+/// so they can be dumped when rd aborts. Each slot owns its record through an `AtomicPtr`, so
+/// recording never needs a lock.
+struct FlightRecorder {
+    slots: Vec<AtomicPtr<Vec<u8>>>,
+    next: AtomicUsize,
+}
+
+impl FlightRecorder {
+    fn new(size: usize) -> FlightRecorder {
+        FlightRecorder {
+            slots: (0..size).map(|_| AtomicPtr::new(ptr::null_mut())).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, record: Vec<u8>) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let new = Box::into_raw(Box::new(record));
+        let old = self.slots[index].swap(new, Ordering::AcqRel);
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    /// Empty the recorder, returning the records oldest first.
+    fn take_all(&self) -> Vec<Vec<u8>> {
+        let start = self.next.load(Ordering::Acquire);
+        let len = self.slots.len();
+        (0..len)
+            .filter_map(|i| {
+                let p = self.slots[(start + i) % len].swap(ptr::null_mut(), Ordering::AcqRel);
+                if p.is_null() {
+                    None
+                } else {
+                    Some(*unsafe { Box::from_raw(p) })
+                }
+            })
+            .collect()
+    }
+}
+
+lazy_static! {
+    /// On if RD_LOG_FLIGHT_RECORDER is set to the number of records to keep.
+    static ref FLIGHT_RECORDER: Option<FlightRecorder> = match env::var("RD_LOG_FLIGHT_RECORDER") {
+        Ok(size) => {
+            let size = size.parse::<usize>().expect(&format!("Error. Could not parse `{:?}' in environment var `RD_LOG_FLIGHT_RECORDER' as a number", size));
+            if size > 0 {
+                Some(FlightRecorder::new(size))
+            } else {
+                None
+            }
+        }
+        Err(_) => None,
+    };
+}
+
+/// A log file that is renamed to `<path>.1` once it would grow past `max_size` bytes. Older
+/// files are shifted up to `<path>.<max_files>`; anything beyond that is deleted.
+struct RotatingLogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingLogFile {
+    fn open(path: &OsStr, append: bool, max_size: u64, max_files: u32) -> Result<RotatingLogFile> {
+        let file = if append {
+            OpenOptions::new().append(true).create(true).open(path)?
+        } else {
+            File::create(path)?
+        };
+        Ok(RotatingLogFile {
+            path: PathBuf::from(path),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut len = buf.len();
+        if self.size > 0 && self.size + len as u64 > self.max_size {
+            // Try not to split log records between files: fill this file up to the last
+            // complete line that fits and rotate on the next write.
+            let room = min(self.max_size.saturating_sub(self.size) as usize, len);
+            match memchr::memrchr(b'\n', &buf[..room]) {
+                Some(pos) => len = pos + 1,
+                None => self.rotate()?,
+            }
+        }
+        let nwritten = self.file.write(&buf[..len])?;
+        self.size += nwritten as u64;
+        Ok(nwritten)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
 /// @TODO Will this work in all situations?
 /// Is this what we want?
 extern "C" fn flush_log_buffer() {
@@ -54,9 +232,19 @@ lazy_static! {
     static ref LOG_GLOBALS: Mutex<LogGlobals> = {
         let maybe_filename = var_os("RD_LOG_FILE");
         let maybe_append_filename = var_os("RD_APPEND_LOG_FILE");
+        let maybe_max_size = env::var("RD_LOG_FILE_MAX_SIZE").ok().map(|max_size| {
+            max_size.parse::<u64>().expect(&format!("Error. Could not parse `{:?}' in environment var `RD_LOG_FILE_MAX_SIZE' as a number", max_size))
+        });
+        let max_files = match env::var("RD_LOG_FILE_MAX_FILES") {
+            Ok(max_files) => max_files.parse::<u32>().expect(&format!("Error. Could not parse `{:?}' in environment var `RD_LOG_FILE_MAX_FILES' as a number", max_files)),
+            Err(_) => 5,
+        };
         // @TODO Ok to simply add Sync + Send?
         let mut f: Box<dyn Write + Sync + Send>;
-        if let Some(filename) = maybe_filename {
+        if let (Some(max_size), Some(filename)) = (maybe_max_size, maybe_filename.as_ref().or(maybe_append_filename.as_ref())) {
+            let append = maybe_filename.is_none();
+            f = Box::new(RotatingLogFile::open(filename, append, max_size, max_files).expect(&format!("Error. Could not open log file `{:?}'", filename)));
+        } else if let Some(filename) = maybe_filename {
             f = Box::new(File::create(&filename).expect(&format!("Error. Could not create filename `{:?}' specified in environment variable RD_LOG_FILE", filename)));
         } else if let Some(append_filename) = maybe_append_filename {
             f = Box::new(OpenOptions::new().append(true).create(true).open(&append_filename).expect(&format!("Error. Could not append to filename `{:?}' specified in env variable RD_APPEND_LOG_FILE", append_filename)));
@@ -83,13 +271,19 @@ lazy_static! {
 
         Mutex::new(LogGlobals {
             level_map,
-            log_modules_cache: HashMap::new(),
             logging_stream: String::new(),
             // Possibly buffered
             log_file: f,
             default_level,
         })
     };
+
+    static ref LOG_FORMAT: LogFormat = match env::var("RD_LOG_FORMAT") {
+        Ok(format) if format == "json" => LogFormat::Json,
+        Ok(format) if format == "text" => LogFormat::Text,
+        Ok(format) => panic!("Error. Unknown log format `{}' in environment var `RD_LOG_FORMAT'. Expected `text' or `json'", format),
+        Err(_) => LogFormat::Text,
+    };
 }
 
 fn log_level_string_to_level(log_level_string: &str) -> LogLevel {
@@ -144,28 +338,35 @@ fn filename_to_module_name(filename: &str) -> String {
 }
 
 /// Given the filename get the corresponding LogModule.
-fn get_log_module(filename: &str, l: &mut MutexGuard<LogGlobals>) -> LogModule {
-    let maybe_log_module = l.log_modules_cache.get(filename);
-    if let Some(log_module) = maybe_log_module {
-        log_module.to_owned()
-    } else {
+/// Only takes `LOG_GLOBALS` the first time this thread sees `filename` since the last level
+/// change.
+fn get_log_module(filename: &str) -> LogModule {
+    LOG_MODULES_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let generation = LOG_LEVELS_GENERATION.load(Ordering::Acquire);
+        if cache.0 != generation {
+            *cache = (generation, HashMap::new());
+        }
+        if let Some(log_module) = cache.1.get(filename) {
+            return log_module.to_owned();
+        }
         let name = filename_to_module_name(filename);
-        let level = get_log_level(&name, l);
+        let level = get_log_level(&name, &LOG_GLOBALS.lock().unwrap());
         let m = LogModule { level, name };
-        l.log_modules_cache.insert(filename.to_owned(), m.clone());
+        cache.1.insert(filename.to_owned(), m.clone());
         m
-    }
+    })
 }
 
 fn set_all_logging(level: LogLevel, l: &mut MutexGuard<LogGlobals>) {
     l.default_level = level;
     l.level_map.clear();
-    l.log_modules_cache.clear();
+    LOG_LEVELS_GENERATION.fetch_add(1, Ordering::AcqRel);
 }
 
 fn set_logging(module_name: &str, level: LogLevel, l: &mut MutexGuard<LogGlobals>) {
     l.level_map.insert(module_name.to_owned(), level);
-    l.log_modules_cache.clear();
+    LOG_LEVELS_GENERATION.fetch_add(1, Ordering::AcqRel);
 }
 
 fn log_name(level: LogLevel) -> String {
@@ -178,11 +379,48 @@ fn log_name(level: LogLevel) -> String {
     }
 }
 
+/// Everything but the message of a record that will be written as JSON.
+struct JsonRecord {
+    module: String,
+    filename: String,
+    line: u32,
+    func_name: String,
+    errno: i32,
+    task_context: Option<(pid_t, FrameTime)>,
+}
+
+impl JsonRecord {
+    fn to_line(&self, level: LogLevel, message: &[u8]) -> Vec<u8> {
+        let mut record = Map::new();
+        record.insert("level".into(), json!(log_name(level)));
+        record.insert("module".into(), json!(self.module));
+        record.insert("file".into(), json!(self.filename));
+        record.insert("line".into(), json!(self.line));
+        record.insert("function".into(), json!(self.func_name));
+        if level <= LogWarn && self.errno != 0 {
+            record.insert("errno".into(), json!(errno_name(self.errno)));
+        }
+        if let Some((tid, time)) = self.task_context {
+            record.insert("tid".into(), json!(tid));
+            record.insert("time".into(), json!(time));
+        }
+        let message = String::from_utf8_lossy(message);
+        record.insert("message".into(), json!(message.trim_end_matches('\n')));
+
+        let mut line = serde_json::to_vec(&Value::Object(record)).unwrap();
+        line.push(b'\n');
+        line
+    }
+}
+
 pub struct NewLineTerminatingOstream {
     enabled: bool,
     level: LogLevel,
     message: Vec<u8>,
-    lock: MutexGuard<'static, LogGlobals>,
+    /// Some(_) if the message is to be written as JSON.
+    json: Option<JsonRecord>,
+    /// The whole text record, if the flight recorder is on. Independent of `enabled`.
+    flight_record: Option<Vec<u8>>,
 }
 
 impl NewLineTerminatingOstream {
@@ -193,38 +431,61 @@ impl NewLineTerminatingOstream {
         func_name: &str,
         always_enabled: bool,
     ) -> NewLineTerminatingOstream {
-        let mut lock = LOG_GLOBALS.lock().unwrap();
-        let m = get_log_module(filename, &mut lock);
+        let err = errno();
+        let m = get_log_module(filename);
         let enabled = always_enabled || level <= m.level;
-        let mut this = NewLineTerminatingOstream {
-            message: Vec::new(),
-            enabled,
-            level,
-            lock,
+        let json = if enabled && *LOG_FORMAT == LogFormat::Json {
+            Some(JsonRecord {
+                module: m.name.clone(),
+                filename: filename.to_owned(),
+                line,
+                func_name: func_name.to_owned(),
+                errno: err,
+                task_context: TASK_CONTEXT.with(|c| c.get()),
+            })
+        } else {
+            None
         };
-        if enabled {
+        let text = enabled && json.is_none();
+        // Most records are disabled debug records, don't format a prefix for them.
+        let mut prefix: Vec<u8> = Vec::new();
+        if text || FLIGHT_RECORDER.is_some() {
             if level == LogDebug {
-                write!(this, "[{}] ", m.name).unwrap();
+                write!(prefix, "[{}] ", m.name).unwrap();
             } else {
-                write_prefix(&mut this, level, filename, line, func_name);
+                write_prefix(&mut prefix, level, filename, line, func_name);
             }
         }
-
-        this
+        NewLineTerminatingOstream {
+            message: if text { prefix.clone() } else { Vec::new() },
+            enabled,
+            level,
+            json,
+            flight_record: FLIGHT_RECORDER.as_ref().map(|_| prefix),
+        }
     }
 }
 
 /// Low level. Use is_logging!() macro instead.
 pub fn is_logging(level: LogLevel, filename: &str, _line: u32, _func_name: &str) -> bool {
-    let mut lock = LOG_GLOBALS.lock().unwrap();
-    let m = get_log_module(filename, &mut lock);
-    let enabled = level <= m.level;
-    enabled
+    level <= get_log_module(filename).level
 }
 
 impl Drop for NewLineTerminatingOstream {
     fn drop(&mut self) {
-        if self.enabled {
+        if let Some(mut flight_record) = self.flight_record.take() {
+            flight_record.push(b'\n');
+            FLIGHT_RECORDER.as_ref().unwrap().record(flight_record);
+        }
+        if let Some(record) = self.json.take() {
+            let line = record.to_line(self.level, &self.message);
+            LOG_GLOBALS
+                .lock()
+                .unwrap()
+                .log_file
+                .write_all(&line)
+                .unwrap_or(());
+        } else if self.enabled {
             self.write(b"\n").unwrap();
             // This flushes self.message *to* the log file
             // (which could be stderr or a log file or a buffered writer that wraps stderr
@@ -238,8 +499,17 @@ impl Drop for NewLineTerminatingOstream {
 impl Write for NewLineTerminatingOstream {
     /// Write the text stored in the `message` member to the log file.
     fn flush(&mut self) -> Result<()> {
+        // A JSON record can only be written once the whole message is known, when we're dropped.
+        if self.json.is_some() {
+            return Ok(());
+        }
+        // The log file is the only thing records share, so this is the only place we lock.
         if self.message.len() > 0 && self.enabled {
-            self.lock.log_file.write_all(&self.message)?;
+            LOG_GLOBALS
+                .lock()
+                .unwrap()
+                .log_file
+                .write_all(&self.message)?;
             // We DONT flush the log file. This is handled automatically.
         }
         self.message.clear();
@@ -250,6 +520,9 @@ impl Write for NewLineTerminatingOstream {
         if self.enabled {
             self.message.extend_from_slice(buf);
         }
+        if let Some(flight_record) = self.flight_record.as_mut() {
+            flight_record.extend_from_slice(buf);
+        }
 
         // Need to pretend these were written even if buffer was not enabled.
         // Otherwise we get a `Err` value
@@ -349,10 +622,46 @@ macro_rules! clean_fatal {
 /// Dump the stacktrace and abort.
 pub fn notifying_abort(bt: Backtrace) {
     // @TODO running under test monitor stuff.
+    dump_flight_recorder();
     dump_rd_stack(bt);
     std::process::abort();
 }
 
+/// Write the records held by the flight recorder, if it is on, to RD_LOG_FLIGHT_RECORDER_FILE
+/// or stderr.
+fn dump_flight_recorder() {
+    let recorder = match FLIGHT_RECORDER.as_ref() {
+        Some(recorder) => recorder,
+        None => return,
+    };
+    let records = recorder.take_all();
+    let mut out: Box<dyn Write> = match var_os("RD_LOG_FLIGHT_RECORDER_FILE") {
+        Some(filename) => match File::create(&filename) {
+            Ok(f) => {
+                write!(
+                    io::stderr(),
+                    "rd: flight recorder log written to {:?}\n",
+                    filename
+                )
+                .unwrap_or(());
+                Box::new(f)
+            }
+            Err(_) => Box::new(io::stderr()),
+        },
+        None => Box::new(io::stderr()),
+    };
+    write!(
+        out,
+        "=== Start rd flight recorder (last {} log records):\n",
+        records.len()
+    )
+    .unwrap_or(());
+    for record in records {
+        out.write_all(&record).unwrap_or(());
+    }
+    write!(out, "=== End rd flight recorder\n").unwrap_or(());
+}
+
 /// Write the backtrace to stderr.
 fn dump_rd_stack(bt: Backtrace) {
     write!(io::stderr(), "=== Start rd backtrace:\n").unwrap();
@@ -1,8 +1,8 @@
-//! Support tracees that share memory read-only with a non-tracee that
-//! writes to the memory. Currently this just supports limited cases that
-//! suffice for dconf: no remapping, coalescing or splitting of the memory is
-//! allowed (`subrange` below just asserts). It doesn't handle mappings where
-//! the mapping has more pages than the file.
+//! Support tracees that share memory with a non-tracee that writes to the
+//! memory, e.g. `MAP_SHARED` mappings of dconf's database. No remapping,
+//! coalescing or splitting of the memory is allowed (`subrange` below just
+//! asserts). It doesn't handle mappings where the mapping has more pages than
+//! the file.
 //!
 //! After such memory is mapped in the tracee, we also map it in rd at `real_mem`
 //! and replace the tracee's mapping with a "shadow buffer" that's only shared
@@ -10,36 +10,187 @@
 //! the shadow buffer, we update the shadow buffer with the new values and
 //! record that we did so.
 //!
-//! Currently we check the real memory after each syscall exit. This ensures
-//! that if the tracee is woken up by some IPC mechanism (or after sched_yield),
-//! it will get a chance to see updated memory values.
+//! DIFF NOTE: rr only monitors read-only mappings of dconf's database. We
+//! monitor any read-only shared mapping of a regular file, and io_uring rings
+//! (where the other party is the kernel, see `IoUringMonitor`).
+//!
+//! Writable shared mappings of files are only monitored after
+//! `RecordSession::set_monitor_writable_shared_memory()`. The stores the tracee
+//! made to the shadow buffer since the last check are copied to the real memory
+//! before we look for changes made by others (we keep `snapshot` to tell the two
+//! apart). That changes what the tracee and the other processes observe:
+//! - the other processes see the tracee's stores late, which can deadlock
+//!   protocols that wait for them, e.g. SQLite's locks or IPC handshakes,
+//! - if both sides store to the same byte between checks, the tracee's store
+//!   wins and the other one is lost,
+//! - futex wakeups don't work across the shadow buffer,
+//! - the whole mapping is compared at every check.
+//!
+//! We check the real memory after each syscall exit, before io_uring_enter(),
+//! when a timeslice expires outside a syscall and before a signal is delivered,
+//! i.e. right before every frame whose replay applies recorded data. This ensures
+//! that if the tracee is woken up by some IPC mechanism (or after sched_yield), or
+//! just spins on the memory, it will get a chance to see updated memory values.
+//! We don't check at task exit: re-establishing a local mapping there would
+//! need a remote syscall.
+//!
+//! `rd record --monitor-writable-shared-memory` turns on the monitoring of
+//! writable mappings.
 
-use crate::session::{address_space::address_space, task::record_task::record_task::RecordTask};
+use crate::{
+    auto_remote_syscalls::{AutoRemoteSyscalls, PreserveContents},
+    file_monitor::io_uring_monitor::IO_URING_FILE_NAME,
+    log::LogLevel::{LogDebug, LogWarn},
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{address_space, memory_range::MemoryRangeKey},
+        task::{record_task::record_task::RecordTask, Task},
+    },
+};
+use libc::{c_void, S_IFMT, S_IFREG};
+use nix::sys::mman::{munmap, MapFlags, ProtFlags};
 use std::{
     cell::RefCell,
+    cmp::min,
+    ffi::OsStr,
+    ops::Range,
+    os::unix::ffi::OsStrExt,
+    ptr,
     rc::{Rc, Weak},
+    slice,
 };
 
 pub type MonitoredSharedMemorySharedPtr = Rc<RefCell<MonitoredSharedMemory>>;
 pub type MonitoredSharedMemorySharedWeakPtr = Weak<RefCell<MonitoredSharedMemory>>;
 
+/// We compare memory in blocks of this many bytes and record whole changed
+/// blocks. This keeps the number of raw data records down when many scattered
+/// bytes change.
+const COMPARE_BLOCK_SIZE: usize = 64;
+
+/// Don't monitor mappings bigger than this. We compare the whole mapping after
+/// every syscall.
+const MAX_MONITORED_SIZE: usize = 64 * 1024 * 1024;
+
 pub struct MonitoredSharedMemory {
     real_mem: *mut [u8],
+    /// The contents of the shadow buffer as of the last check.
+    snapshot: Vec<u8>,
+    /// True if the tracee can write to its shadow buffer.
+    writable: bool,
+}
+
+impl Drop for MonitoredSharedMemory {
+    fn drop(&mut self) {
+        let size = self.size();
+        unsafe {
+            munmap(self.real_mem as *mut u8 as *mut c_void, size).unwrap();
+        }
+    }
 }
 
 impl MonitoredSharedMemory {
+    /// Start monitoring `m` if it's a shared mapping of a regular file (read-only,
+    /// unless the session opted in to writable ones) or of an io_uring, which `t`
+    /// has just mapped from `tracee_fd` at `offset` bytes.
     pub fn maybe_monitor(
-        _t: &RecordTask,
-        _filename: &str,
-        _m: &address_space::Mapping,
-        _tracee_fd: i32,
-        _offset: usize,
+        t: &mut RecordTask,
+        file_name: &OsStr,
+        m: &address_space::Mapping,
+        tracee_fd: i32,
+        offset: u64,
     ) {
-        unimplemented!()
+        if !m.map.flags().contains(MapFlags::MAP_SHARED)
+            || m.map.prot().contains(ProtFlags::PROT_EXEC)
+        {
+            return;
+        }
+        if file_name.as_bytes().starts_with(b"/dev/") {
+            // Device memory can't be snapshotted.
+            return;
+        }
+        let size = m.map.size();
+        if size > MAX_MONITORED_SIZE {
+            log!(
+                LogWarn,
+                "Not monitoring {:?}: shared mapping of {} bytes is too big",
+                file_name,
+                size
+            );
+            return;
+        }
+        let st = t.stat_fd(tracee_fd);
+        let is_io_uring = file_name == OsStr::new(IO_URING_FILE_NAME);
+        if !is_io_uring
+            && (st.st_mode & S_IFMT != S_IFREG || (st.st_size as u64) < offset + size as u64)
+        {
+            // Reading past the end of the file would SIGBUS us.
+            return;
+        }
+
+        let writable = m.map.prot().contains(ProtFlags::PROT_WRITE);
+        if writable
+            && !is_io_uring
+            && !t
+                .session()
+                .as_record()
+                .unwrap()
+                .monitor_writable_shared_memory()
+        {
+            return;
+        }
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let fd = remote.retrieve_fd(tracee_fd);
+        let prot = if writable {
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+        } else {
+            ProtFlags::PROT_READ
+        };
+        let real_mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                prot.bits(),
+                MapFlags::MAP_SHARED.bits(),
+                fd.as_raw(),
+                offset as _,
+            )
+        };
+        if real_mem == libc::MAP_FAILED {
+            log!(
+                LogWarn,
+                "Not monitoring {:?}: can't map it into rd",
+                file_name
+            );
+            return;
+        }
+
+        let real_mem = ptr::slice_from_raw_parts_mut(real_mem as *mut u8, size);
+        let snapshot = unsafe { (*real_mem).to_vec() };
+        let result = Rc::new(RefCell::new(MonitoredSharedMemory::new(
+            real_mem, snapshot, writable,
+        )));
+
+        let shared = remote.steal_mapping(m, Some(result.clone()));
+        // m may be invalid now
+        let local = unsafe {
+            slice::from_raw_parts_mut(shared.local_addr.unwrap().as_ptr() as *mut u8, size)
+        };
+        local.copy_from_slice(&result.borrow().snapshot);
+        log!(LogDebug, "Monitoring shared memory {:?}", file_name);
     }
 
-    pub fn check_all(_t: &RecordTask) {
-        unimplemented!()
+    pub fn check_all(t: &mut RecordTask) {
+        let addrs: Vec<RemotePtr<Void>> = t.vm().monitored_addrs().iter().copied().collect();
+        for a in addrs {
+            let maybe_monitored = t
+                .vm()
+                .mapping_of(a)
+                .and_then(|m| m.monitored_shared_memory.clone());
+            if let Some(monitored) = maybe_monitored {
+                monitored.borrow_mut().check_for_changes(t, a, &monitored);
+            }
+        }
     }
 
     /// This feature is currently unsupported
@@ -51,14 +202,151 @@ impl MonitoredSharedMemory {
         unimplemented!()
     }
 
-    fn check_for_changes(&self, _t: &RecordTask, _m: &address_space::Mapping) {
-        unimplemented!()
+    /// DIFF NOTE: Takes the start of the mapping instead of the mapping itself (which we
+    /// may have to recreate) and our own shared pointer to hand to the recreated mapping.
+    fn check_for_changes(
+        &mut self,
+        t: &mut RecordTask,
+        start: RemotePtr<Void>,
+        self_ptr: &MonitoredSharedMemorySharedPtr,
+    ) {
+        let size = self.size();
+        let (map, mut local_addr) = {
+            let m = t.vm().mapping_of(start).unwrap();
+            (m.map.clone(), m.local_addr)
+        };
+        ed_assert!(t, map.size() == size);
+        if local_addr.is_none() {
+            // reestablish local mapping after a fork or whatever
+            let mut remote = AutoRemoteSyscalls::new(t);
+            let new_addr = remote.recreate_shared_mmap(
+                MemoryRangeKey(*map),
+                Some(PreserveContents::DiscardContents),
+                Some(self_ptr.clone()),
+            );
+            local_addr = remote.vm().mapping_of(new_addr).unwrap().local_addr;
+            match local_addr {
+                // The tracee's view of the memory is what it saw at the last check.
+                Some(addr) => unsafe {
+                    slice::from_raw_parts_mut(addr.as_ptr() as *mut u8, size)
+                        .copy_from_slice(&self.snapshot);
+                },
+                // Failed to re-establish local mapping
+                None => return,
+            }
+        }
+
+        let local =
+            unsafe { slice::from_raw_parts_mut(local_addr.unwrap().as_ptr() as *mut u8, size) };
+        let real = unsafe { &mut *self.real_mem };
+
+        if self.writable {
+            // Publish the tracee's own stores first. They don't need to be recorded,
+            // replay will perform them. Only copy the bytes the tracee changed: the
+            // other side may have changed its neighbours, e.g. the kernel moving an
+            // io_uring's SQ head while the tracee moved the tail.
+            for r in changed_blocks(local, &self.snapshot) {
+                for i in r {
+                    if local[i] != self.snapshot[i] {
+                        real[i] = local[i];
+                        self.snapshot[i] = local[i];
+                    }
+                }
+            }
+        }
+
+        for r in changed_blocks(real, &self.snapshot) {
+            log!(
+                LogDebug,
+                "Shared memory at {} changed by another process: [{}, {})",
+                map.start(),
+                r.start,
+                r.end
+            );
+            local[r.clone()].copy_from_slice(&real[r.clone()]);
+            self.snapshot[r.clone()].copy_from_slice(&real[r.clone()]);
+            t.record_local(map.start() + r.start, &self.snapshot[r]);
+        }
+    }
+
+    fn size(&self) -> usize {
+        unsafe { (*self.real_mem).len() }
     }
 
     /// real_mem is pointer within rd's address space to the memory shared between
     /// the tracee (which just becomes a "shadow buffer") and the non-rd process.
     /// See description above.
-    fn new(real_mem: *mut [u8]) -> MonitoredSharedMemory {
-        MonitoredSharedMemory { real_mem }
+    fn new(real_mem: *mut [u8], snapshot: Vec<u8>, writable: bool) -> MonitoredSharedMemory {
+        MonitoredSharedMemory {
+            real_mem,
+            snapshot,
+            writable,
+        }
+    }
+}
+
+/// The ranges of `COMPARE_BLOCK_SIZE` blocks in which `a` and `b` differ, with
+/// adjacent changed blocks merged.
+fn changed_blocks(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    debug_assert_eq!(a.len(), b.len());
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut offset = 0;
+    while offset < a.len() {
+        let end = min(offset + COMPARE_BLOCK_SIZE, a.len());
+        if a[offset..end] != b[offset..end] {
+            match ranges.last_mut() {
+                Some(last) if last.end == offset => last.end = end,
+                _ => ranges.push(offset..end),
+            }
+        }
+        offset = end;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const B: usize = COMPARE_BLOCK_SIZE;
+
+    /// `b` with the bytes at `offsets` flipped.
+    fn changed(b: &[u8], offsets: &[usize]) -> Vec<u8> {
+        let mut a = b.to_vec();
+        for &offset in offsets {
+            a[offset] ^= 0xff;
+        }
+        a
+    }
+
+    #[test]
+    fn changed_blocks_test() {
+        let b = vec![0u8; 6 * B];
+        assert!(changed_blocks(&b, &b).is_empty());
+        assert_eq!(vec![0..B], changed_blocks(&changed(&b, &[0, B - 1]), &b));
+        // Adjacent changed blocks are merged, others aren't.
+        assert_eq!(
+            vec![B..3 * B, 4 * B..5 * B],
+            changed_blocks(&changed(&b, &[B + 5, 2 * B, 4 * B + 1]), &b)
+        );
+        assert_eq!(
+            vec![0..6 * B],
+            changed_blocks(&changed(&b, &[0, B, 2 * B, 3 * B, 4 * B, 5 * B]), &b)
+        );
+    }
+
+    #[test]
+    fn changed_blocks_partial_block_test() {
+        // The last block is shorter than the rest.
+        let b = vec![0u8; 2 * B + 3];
+        assert_eq!(
+            vec![2 * B..2 * B + 3],
+            changed_blocks(&changed(&b, &[2 * B + 2]), &b)
+        );
+        assert_eq!(
+            vec![B..2 * B + 3],
+            changed_blocks(&changed(&b, &[2 * B - 1, 2 * B]), &b)
+        );
+        assert!(changed_blocks(&[], &[]).is_empty());
     }
 }
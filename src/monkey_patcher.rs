@@ -1,20 +1,591 @@
-use crate::{arch::Architecture, session::task::record_task::record_task::RecordTask};
+//! The monkeypatcher redirects syscalls made by the tracee into the
+//! preload library, so that they can be buffered instead of stopping rd
+//! for every one of them.
+//!
+//! Right after exec we patch the vDSO's entry points to make real syscalls
+//! (so that they become visible to us at all) and, on x86, change
+//! `__kernel_vsyscall` to use `int $0x80` instead of `sysenter`. Once the
+//! preload library has initialized we learn where its syscall hooks live and
+//! from then on patch syscall instructions lazily, the first time a traced
+//! syscall is made from a given site.
+use crate::{
+    arch::{Architecture, X64Arch, X86Arch},
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    elf_symbols::ElfSymbols,
+    kernel_abi::{
+        common::preload_interface::syscall_patch_hook,
+        syscall_instruction_length,
+        SupportedArch,
+    },
+    kernel_metadata::syscall_name,
+    log::LogLevel::LogDebug,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{kernel_mapping::KernelMapping, MappingFlags},
+        task::{
+            record_task::record_task::RecordTask,
+            task_common::{read_mem, read_val_mem},
+            task_inner::task_inner::WriteFlags,
+            Task,
+        },
+    },
+    trace::trace_writer::{MappingOrigin, RecordInTrace},
+    util::page_size,
+};
+use nix::sys::mman::{MapFlags, ProtFlags};
+use std::{collections::HashSet, ffi::OsStr};
 
-#[derive(Clone)]
-pub struct MonkeyPatcher {}
+include!(concat!(env!("OUT_DIR"), "/assembly_templates_generated.rs"));
+
+/// A page of extended jump stubs allocated near patched syscall sites.
+#[derive(Copy, Clone)]
+struct ExtendedJumpPage {
+    addr: RemotePtr<u8>,
+    allocated: usize,
+}
+
+impl ExtendedJumpPage {
+    fn new(addr: RemotePtr<u8>) -> ExtendedJumpPage {
+        ExtendedJumpPage { addr, allocated: 0 }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MonkeyPatcher {
+    /// The hooks the preload library reported at `SYS_rdcall_init_preload`.
+    /// Empty until then, which disables syscall patching.
+    syscall_hooks: Vec<syscall_patch_hook>,
+    /// Sites we have already tried to patch, successfully or not. We never
+    /// try the same site twice.
+    tried_to_patch_syscall_addresses: HashSet<RemoteCodePtr>,
+    extended_jump_pages: Vec<ExtendedJumpPage>,
+    /// Address of `__kernel_vsyscall` in the vDSO. x86 only.
+    x86_vsyscall: Option<RemotePtr<u8>>,
+}
 
 impl MonkeyPatcher {
     pub fn new() -> MonkeyPatcher {
-        MonkeyPatcher {}
+        Self::default()
     }
-    pub fn patch_at_preload_init(&self, t: &RecordTask) {
+
+    /// Apply any necessary patching immediately after exec.
+    /// In this hook we patch everything that doesn't depend on the preload
+    /// library being loaded.
+    pub fn patch_after_exec(&mut self, t: &mut RecordTask) {
+        ed_assert!(
+            t,
+            t.vm().task_set().inner_hashset().len() == 1,
+            "Can't have multiple threads immediately after exec!"
+        );
+
+        let vdso = t.vm().vdso();
+        let image = read_mem(t, RemotePtr::<u8>::cast(vdso.start()), vdso.size(), None);
+        let syms = match ElfSymbols::parse(&image) {
+            Ok(syms) => syms,
+            Err(e) => fatal!("Could not read symbols from the vDSO: {:?}", e),
+        };
+
+        match t.arch() {
+            SupportedArch::X86 => self.patch_vdso_after_exec_x86(t, vdso.start(), &syms),
+            SupportedArch::X64 => patch_vdso_after_exec_x64(t, vdso.start(), &syms),
+        }
+    }
+
+    /// Apply any necessary patching once the preload library has been loaded
+    /// and initialized, i.e. at `SYS_rdcall_init_preload`.
+    pub fn patch_at_preload_init(&mut self, t: &mut RecordTask) {
         // NB: the tracee can't be interrupted with a signal while
         // we're processing the rdcall, because it's masked off all
         // signals.
         rd_arch_function_selfless!(patch_at_preload_init_arch, t.arch(), t, self);
     }
+
+    /// Try to patch the syscall instruction that `t` just entered a syscall
+    /// through. If this returns false, patching failed and the syscall should
+    /// be processed as normal. If this returns true, patching succeeded and
+    /// the syscall was aborted; ip() has been reset to the start of the
+    /// patched syscall, and execution should resume normally to execute the
+    /// patched code.
+    /// Zero or more mapping operations are also recorded to the trace and must
+    /// be replayed.
+    pub fn try_patch_syscall(&mut self, t: &mut RecordTask) -> bool {
+        if !self.may_patch_syscall_at(t) {
+            return false;
+        }
+
+        let r = t.regs_ref().clone();
+        let ip = r.ip();
+        // We could examine the current syscall number and if it's not one that
+        // we support syscall buffering for, refuse to patch the syscall instruction.
+        // This would, on the face of it, reduce overhead since patching the
+        // instruction just means a useless trip through the syscall buffering logic.
+        // However, it actually wouldn't help much since we'd still do a switch
+        // on the syscall number in this function instead, and due to context
+        // switching costs any overhead saved would be insignificant.
+        // Also, implementing that would require keeping a buffered-syscalls
+        // list in sync with the preload code, which is unnecessary complexity.
+        self.tried_to_patch_syscall_addresses.insert(ip);
+
+        let mut following_bytes = [0u8; 256];
+        let bytes_count = t
+            .read_bytes_fallible(ip.to_data_ptr::<Void>(), &mut following_bytes)
+            .unwrap_or(0);
+
+        let syscallno = r.original_syscallno() as i32;
+        for i in 0..self.syscall_hooks.len() {
+            let hook = self.syscall_hooks[i];
+            let next_len = hook.next_instruction_length as usize;
+            if bytes_count < next_len
+                || following_bytes[0..next_len] != hook.next_instruction_bytes[0..next_len]
+            {
+                continue;
+            }
+
+            // Search for a following short-jump instruction that targets an
+            // instruction after the syscall. False positives are OK.
+            // glibc-2.23.1-8.fc24.x86_64's __clock_nanosleep needs this.
+            let mut found_potential_interfering_branch = false;
+            for j in 0..bytes_count.saturating_sub(1) {
+                let b = following_bytes[j];
+                // Check for short conditional or unconditional jump
+                if b == 0xeb || (b >= 0x70 && b < 0x80) {
+                    let offset = j as isize + 2 + following_bytes[j + 1] as i8 as isize;
+                    let interferes = if hook.is_multi_instruction != 0 {
+                        offset >= 0 && offset < next_len as isize
+                    } else {
+                        offset == 0
+                    };
+                    if interferes {
+                        log!(
+                            LogDebug,
+                            "Found potential interfering branch at {}",
+                            ip.to_data_ptr::<u8>() + j
+                        );
+                        // We can't patch this because it would jump straight back into
+                        // the middle of our patch code.
+                        found_potential_interfering_branch = true;
+                    }
+                }
+            }
+
+            if found_potential_interfering_branch {
+                continue;
+            }
+
+            // The patch overwrites the syscall instruction and the instruction after it.
+            // Check this before leaving the syscall, which we can't undo.
+            let patch_start = ip.decrement_by_syscall_insn_length(t.arch());
+            let patch_end = RemoteCodePtr::from_val(ip.as_usize() + next_len);
+            if !safe_for_syscall_patching(t, patch_start, patch_end) {
+                log!(
+                    LogDebug,
+                    "Temporarily declining to patch syscall at {} because a different task has its ip in the patched range",
+                    ip
+                );
+                self.tried_to_patch_syscall_addresses.remove(&ip);
+                return false;
+            }
+
+            // Get out of executing the current syscall before we patch it.
+            if !t.exit_syscall_and_prepare_restart() {
+                return false;
+            }
+
+            if !self.patch_syscall_with_hook(t, &hook) {
+                return false;
+            }
+
+            log!(
+                LogDebug,
+                "Patched syscall at {} syscall {} tid {} bytes {:x?}",
+                ip,
+                syscall_name(syscallno, t.arch()),
+                t.tid,
+                &following_bytes[0..next_len]
+            );
+
+            // Return to caller, which will resume into the syscall hook
+            return true;
+        }
+
+        log!(
+            LogDebug,
+            "Failed to patch syscall at {} syscall {} tid {} bytes {:x?}",
+            ip,
+            syscall_name(syscallno, t.arch()),
+            t.tid,
+            &following_bytes[0..bytes_count.min(14)]
+        );
+        false
+    }
+
+    /// False if `try_patch_syscall()` is certain to fail for the syscall `t` just
+    /// entered, without trying to patch it.
+    pub fn may_patch_syscall_at(&self, t: &RecordTask) -> bool {
+        if self.syscall_hooks.is_empty() {
+            // Syscall hooks not set up yet. Don't spew warnings, and don't
+            // fill tried_to_patch_syscall_addresses with addresses that we might be
+            // able to patch later.
+            return false;
+        }
+        if t.emulated_ptracer.is_some() {
+            // Syscall patching can confuse ptracers, which may be surprised to see
+            // a syscall instruction at the current IP but then when running
+            // forwards, that the syscall occurs deep in the preload library instead.
+            return false;
+        }
+        !self
+            .tried_to_patch_syscall_addresses
+            .contains(&t.regs_ref().ip())
+    }
+
+    /// Return true if `p` is inside one of our extended jump stub pages.
+    pub fn is_jump_stub_instruction(&self, p: RemoteCodePtr) -> bool {
+        let pp = p.to_data_ptr::<u8>();
+        self.extended_jump_pages
+            .iter()
+            .any(|page| page.addr <= pp && pp < page.addr + page.allocated)
+    }
+
+    fn init_dynamic_syscall_patching(
+        &mut self,
+        t: &mut RecordTask,
+        syscall_patch_hooks: RemotePtr<syscall_patch_hook>,
+        syscall_patch_hook_count: usize,
+    ) {
+        if syscall_patch_hook_count > 0 {
+            self.syscall_hooks = read_mem(t, syscall_patch_hooks, syscall_patch_hook_count, None);
+        }
+    }
+
+    fn patch_vdso_after_exec_x86(
+        &mut self,
+        t: &mut RecordTask,
+        vdso_start: RemotePtr<Void>,
+        syms: &ElfSymbols,
+    ) {
+        let kernel_vsyscall = match vdso_symbol_address(vdso_start, syms, "__kernel_vsyscall") {
+            Some(addr) => addr,
+            None => fatal!("Failed to find __kernel_vsyscall in the vDSO"),
+        };
+        let impl_bytes = read_mem(
+            t,
+            kernel_vsyscall,
+            X86SysenterVsyscallImplementationAMD::SIZE,
+            None,
+        );
+        if !X86SysenterVsyscallImplementation::matches(&impl_bytes)
+            && !X86SysenterVsyscallImplementationAMD::matches(&impl_bytes)
+        {
+            fatal!(
+                "Unexpected __kernel_vsyscall implementation at {}: {:x?}",
+                kernel_vsyscall,
+                impl_bytes
+            );
+        }
+        self.x86_vsyscall = Some(kernel_vsyscall);
+
+        // Patch __kernel_vsyscall to use int 80 instead of sysenter.
+        // During replay we may remap the VDSO to a new address, and the sysenter
+        // instruction would return to the old address, which is not mapped.
+        let mut patch = [0u8; X86SysenterVsyscallUseInt80::SIZE];
+        X86SysenterVsyscallUseInt80::substitute(&mut patch);
+        write_and_record_bytes(t, kernel_vsyscall, &patch);
+
+        let syscalls_to_monkeypatch = [
+            ("__vdso_clock_gettime", X86Arch::CLOCK_GETTIME),
+            ("__vdso_gettimeofday", X86Arch::GETTIMEOFDAY),
+            ("__vdso_time", X86Arch::TIME),
+        ];
+        for &(name, syscallno) in syscalls_to_monkeypatch.iter() {
+            if let Some(addr) = vdso_symbol_address(vdso_start, syms, name) {
+                let mut patch = [0u8; X86VsyscallMonkeypatch::SIZE];
+                X86VsyscallMonkeypatch::substitute(&mut patch, syscallno as u32);
+                write_and_record_bytes(t, addr, &patch);
+                log!(LogDebug, "monkeypatched {} to syscall {}", name, syscallno);
+            }
+        }
+    }
+
+    fn patch_syscall_with_hook(&mut self, t: &mut RecordTask, hook: &syscall_patch_hook) -> bool {
+        match t.arch() {
+            SupportedArch::X86 => self.patch_syscall_with_hook_x86ish(
+                t,
+                hook,
+                X86SysenterVsyscallSyscallHook::SIZE,
+                X86SyscallStubExtendedJump::SIZE,
+            ),
+            SupportedArch::X64 => self.patch_syscall_with_hook_x86ish(
+                t,
+                hook,
+                X64JumpMonkeypatch::SIZE,
+                X64SyscallStubExtendedJump::SIZE,
+            ),
+        }
+    }
+
+    fn patch_syscall_with_hook_x86ish(
+        &mut self,
+        t: &mut RecordTask,
+        hook: &syscall_patch_hook,
+        jump_patch_size: usize,
+        extended_jump_patch_size: usize,
+    ) -> bool {
+        // We're patching in a relative jump, so we need to compute the offset from
+        // the end of the jump to our actual destination.
+        let jump_patch_start = t.regs_ref().ip().to_data_ptr::<u8>();
+        let jump_patch_end = jump_patch_start + jump_patch_size;
+        let return_addr = jump_patch_start
+            + syscall_instruction_length(t.arch())
+            + hook.next_instruction_length as usize;
+
+        let extended_jump_start = match allocate_extended_jump(
+            t,
+            &mut self.extended_jump_pages,
+            extended_jump_patch_size,
+        ) {
+            Some(addr) => addr,
+            None => return false,
+        };
+
+        let mut stub_patch = vec![0u8; extended_jump_patch_size];
+        match t.arch() {
+            SupportedArch::X86 => {
+                let offset = (hook.hook_address as usize).wrapping_sub(
+                    extended_jump_start.as_usize()
+                        + X86SyscallStubExtendedJump::TRAMPOLINE_RELATIVE_ADDR_END,
+                );
+                // An offset that appears to be > 2GB is OK here, since EIP will just
+                // wrap around.
+                X86SyscallStubExtendedJump::substitute(
+                    &mut stub_patch,
+                    return_addr.as_usize() as u32,
+                    offset as u32,
+                );
+            }
+            SupportedArch::X64 => {
+                let return_addr = return_addr.as_usize() as u64;
+                X64SyscallStubExtendedJump::substitute(
+                    &mut stub_patch,
+                    return_addr as u32,
+                    (return_addr >> 32) as u32,
+                    hook.hook_address,
+                );
+            }
+        }
+        write_and_record_bytes(t, extended_jump_start, &stub_patch);
+
+        let jump_offset = extended_jump_start.as_isize() - jump_patch_end.as_isize();
+        let jump_offset32 = jump_offset as i32;
+        ed_assert!(
+            t,
+            jump_offset32 as isize == jump_offset,
+            "allocate_extended_jump didn't work"
+        );
+
+        let mut jump_patch = vec![0u8; jump_patch_size];
+        match t.arch() {
+            SupportedArch::X86 => {
+                X86SysenterVsyscallSyscallHook::substitute(&mut jump_patch, jump_offset32 as u32)
+            }
+            SupportedArch::X64 => {
+                X64JumpMonkeypatch::substitute(&mut jump_patch, jump_offset32 as u32)
+            }
+        }
+        // Pad with NOPs to the next instruction
+        jump_patch.resize(return_addr - jump_patch_start, NOP);
+        write_and_record_bytes(t, jump_patch_start, &jump_patch);
+        true
+    }
+}
+
+const NOP: u8 = 0x90;
+
+/// True if no task sharing `t`'s address space, other than `t`, has its ip in
+/// [start, end). Patching code that another task is in the middle of would
+/// break it.
+fn safe_for_syscall_patching(t: &RecordTask, start: RemoteCodePtr, end: RemoteCodePtr) -> bool {
+    for task in t.vm().task_set().iter_except(t.weak_self_ptr()) {
+        let ip = task.borrow().ip();
+        if start <= ip && ip < end {
+            return false;
+        }
+    }
+    true
+}
+
+fn patch_at_preload_init_arch<Arch: Architecture>(t: &mut RecordTask, patcher: &mut MonkeyPatcher) {
+    let params = read_val_mem(
+        t,
+        RemotePtr::<Arch::rdcall_init_preload_params>::from(t.regs_ref().arg1()),
+        None,
+    );
+    if !Arch::rdcall_init_preload_params_syscallbuf_enabled(&params) {
+        return;
+    }
+
+    if Arch::arch() == SupportedArch::X86 {
+        let kernel_vsyscall = patcher.x86_vsyscall.unwrap();
+        // Luckily, linux is happy for us to scribble directly over
+        // the vdso mapping's bytes without mprotecting the region, so
+        // we don't need to prepare remote syscalls here.
+        let syscallhook_vsyscall_entry =
+            Arch::rdcall_init_preload_params_syscallhook_vsyscall_entry(&params);
+        let mut patch = [0u8; X86SysenterVsyscallSyscallHook::SIZE];
+        // We're patching in a relative jump, so we need to compute the offset from
+        // the end of the jump to our actual destination.
+        let offset = syscallhook_vsyscall_entry
+            .as_usize()
+            .wrapping_sub((kernel_vsyscall + patch.len()).as_usize());
+        X86SysenterVsyscallSyscallHook::substitute(&mut patch, offset as u32);
+        write_and_record_bytes(t, kernel_vsyscall, &patch);
+        log!(
+            LogDebug,
+            "monkeypatched __kernel_vsyscall to jump to {}",
+            syscallhook_vsyscall_entry
+        );
+    }
+
+    let (syscall_patch_hooks, syscall_patch_hook_count) =
+        Arch::rdcall_init_preload_params_syscall_patch_hooks(&params);
+    patcher.init_dynamic_syscall_patching(t, syscall_patch_hooks, syscall_patch_hook_count);
+}
+
+fn patch_vdso_after_exec_x64(t: &mut RecordTask, vdso_start: RemotePtr<Void>, syms: &ElfSymbols) {
+    let syscalls_to_monkeypatch = [
+        ("__vdso_clock_gettime", X64Arch::CLOCK_GETTIME),
+        ("__vdso_clock_getres", X64Arch::CLOCK_GETRES),
+        ("__vdso_gettimeofday", X64Arch::GETTIMEOFDAY),
+        ("__vdso_time", X64Arch::TIME),
+        ("__vdso_getcpu", X64Arch::GETCPU),
+    ];
+    for &(name, syscallno) in syscalls_to_monkeypatch.iter() {
+        if let Some(addr) = vdso_symbol_address(vdso_start, syms, name) {
+            let mut patch = [0u8; X64VsyscallMonkeypatch::SIZE];
+            X64VsyscallMonkeypatch::substitute(&mut patch, syscallno as u32);
+            write_and_record_bytes(t, addr, &patch);
+            log!(LogDebug, "monkeypatched {} to syscall {}", name, syscallno);
+        }
+    }
+}
+
+/// The vDSO is mapped in its entirety starting at file offset 0.
+fn vdso_symbol_address(
+    vdso_start: RemotePtr<Void>,
+    syms: &ElfSymbols,
+    name: &str,
+) -> Option<RemotePtr<u8>> {
+    syms.symbols()
+        .iter()
+        .find(|s| s.name == name)
+        .map(|s| RemotePtr::<u8>::cast(vdso_start) + s.file_offset as usize)
+}
+
+fn write_and_record_bytes(t: &mut RecordTask, child_addr: RemotePtr<u8>, buf: &[u8]) {
+    t.write_bytes_helper(child_addr, buf, None, WriteFlags::empty());
+    t.record_local(RemotePtr::cast(child_addr), buf);
 }
 
-fn patch_at_preload_init_arch<Arch: Architecture>(_t: &RecordTask, _patcher: &MonkeyPatcher) {
-    unimplemented!()
+/// Find room for `size` bytes of stub within 2GB of the current ip (so a 32-bit
+/// relative jump can reach it), mapping a new page of stubs if necessary.
+fn allocate_extended_jump(
+    t: &mut RecordTask,
+    pages: &mut Vec<ExtendedJumpPage>,
+    size: usize,
+) -> Option<RemotePtr<u8>> {
+    let ip = t.regs_ref().ip().to_data_ptr::<u8>();
+    let maybe_page_index = pages.iter().position(|p| {
+        let page_jump_start = p.addr + p.allocated;
+        let offset = page_jump_start.as_isize() - ip.as_isize();
+        offset as i32 as isize == offset && p.allocated + size <= page_size()
+    });
+
+    let page_index = match maybe_page_index {
+        Some(i) => i,
+        None => {
+            // We're looking for a gap of three pages --- one page to allocate and
+            // a page on each side as a guard page.
+            let required_space = 3 * page_size();
+            // Find free space after the patch site.
+            let after = t.vm().mapping_of(RemotePtr::cast(ip)).unwrap().map.start();
+            let free_mem = t.vm().find_free_memory(required_space, Some(after));
+
+            let addr = RemotePtr::<u8>::cast(free_mem + page_size());
+            let offset = addr.as_isize() - ip.as_isize();
+            if offset as i32 as isize != offset {
+                log!(
+                    LogDebug,
+                    "Can't allocate extended jump page: too far from {}",
+                    ip
+                );
+                return None;
+            }
+
+            {
+                let prot = ProtFlags::PROT_READ | ProtFlags::PROT_EXEC;
+                let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED;
+                let mut remote = AutoRemoteSyscalls::new(t);
+                remote.infallible_mmap_syscall(
+                    Some(RemotePtr::cast(addr)),
+                    page_size(),
+                    prot,
+                    flags,
+                    -1,
+                    0,
+                );
+                let recorded = KernelMapping::new_with_opts(
+                    RemotePtr::cast(addr),
+                    RemotePtr::cast(addr + page_size()),
+                    OsStr::new(""),
+                    KernelMapping::NO_DEVICE,
+                    KernelMapping::NO_INODE,
+                    prot,
+                    flags,
+                    0,
+                );
+                remote.vm().map(
+                    remote.task(),
+                    RemotePtr::cast(addr),
+                    page_size(),
+                    prot,
+                    flags,
+                    0,
+                    OsStr::new(""),
+                    KernelMapping::NO_DEVICE,
+                    KernelMapping::NO_INODE,
+                    None,
+                    Some(&recorded),
+                    None,
+                    None,
+                    None,
+                );
+                *remote.vm().mapping_flags_of_mut(RemotePtr::cast(addr)) |=
+                    MappingFlags::IS_PATCH_STUBS;
+                let rt = remote.task().as_record_task().unwrap();
+                let record_in_trace = rt
+                    .session()
+                    .as_record()
+                    .unwrap()
+                    .trace_writer_mut()
+                    .write_mapped_region(
+                        rt,
+                        &recorded,
+                        &recorded.fake_stat(),
+                        &[],
+                        Some(MappingOrigin::PatchMapping),
+                        None,
+                    );
+                ed_assert!(rt, record_in_trace == RecordInTrace::DontRecordInTrace);
+            }
+
+            pages.push(ExtendedJumpPage::new(addr));
+            pages.len() - 1
+        }
+    };
+
+    let page = &mut pages[page_index];
+    let jump_addr = page.addr + page.allocated;
+    page.allocated += size;
+    Some(jump_addr)
 }
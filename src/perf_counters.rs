@@ -328,6 +328,15 @@ struct PmuAttributes {
     minus_ticks_attr: Option<perf_event_attr>,
 }
 
+/// The name of this CPU's microarchitecture, as used by --microarch.
+pub fn cpu_microarch_name() -> &'static str {
+    let uarch = get_cpu_microarch();
+    PMU_CONFIGS
+        .iter()
+        .find(|config| config.uarch == uarch)
+        .map_or("unknown", |config| config.name)
+}
+
 /// Gets the values for the lazy_static! global PMU_ATTRIBUTES.
 fn get_init_attributes() -> PmuAttributes {
     let uarch = get_cpu_microarch();
@@ -0,0 +1,390 @@
+//! Recording-side signal handling: the signals rd raises itself (the desched
+//! signal of the syscallbuf, the time slice interrupt and the SIGSEGVs of
+//! trapped rdtsc/cpuid instructions) and the delivery of the tracees' own
+//! signals.
+//!
+//! Group stops of the tracees aren't emulated yet, so stop signals are
+//! discarded.
+use crate::{
+    bindings::{
+        perf_event::PERF_EVENT_IOC_DISABLE,
+        signal::{siginfo_t, POLL_IN},
+    },
+    event::{
+        DeschedEventData,
+        Event,
+        EventType,
+        SignalDeterministic,
+        SignalResolvedDisposition,
+        SyscallEventData,
+        SyscallState,
+    },
+    kernel_abi::common::preload_interface::{syscallbuf_hdr, syscallbuf_record},
+    kernel_metadata::{signal_name, syscall_name},
+    log::LogLevel::LogDebug,
+    monitored_shared_memory::MonitoredSharedMemory,
+    perf_counters::TIME_SLICE_SIGNAL,
+    remote_ptr::RemotePtr,
+    session::task::{
+        record_task::record_task::RecordTask,
+        task_common::read_val_mem,
+        task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+        Task,
+    },
+    util::{cpuid, rdtsc, trapped_instruction_at, trapped_instruction_len, TrappedInstruction},
+    wait_status::WaitStatus,
+};
+use libc::{ioctl, PR_TSC_SIGSEGV, SIGSEGV};
+use std::cmp::min;
+
+/// The most we record of a signal frame the kernel built on an alternate signal
+/// stack, where we can't tell its size from the old stack pointer. This is more
+/// than the largest x86-64 frame with a full xsave area.
+const MAX_SIGFRAME_SIZE: usize = 16 * 1024;
+
+/// What `t` should be resumed with after `handle_signal()`.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum SignalHandled {
+    /// The signal was rd's own business, was emulated or has no effect. Resume `t`
+    /// without it.
+    Discard,
+    /// The signal has been delivered and `t` is at the first instruction of its handler.
+    Delivered,
+    /// The signal is fatal. Resume `t` with it so that the kernel kills the task.
+    DeliverFatal(i32),
+}
+
+/// Prevent further desched notifications from firing for `t`.
+fn disarm_desched_event(t: &RecordTask) {
+    if t.desched_fd.is_open()
+        && 0 != unsafe { ioctl(t.desched_fd.as_raw(), PERF_EVENT_IOC_DISABLE as _, 0) }
+    {
+        fatal!("Failed to disarm desched event");
+    }
+}
+
+/// `t` is stopped with the desched signal `si`. Advance it to the syscall it
+/// was (re-)entering and, if that is the buffered syscall that blocked, push
+/// a `Desched` event and the `SyscallInterruption` for it. Otherwise push a
+/// `Noop` event: the desched can be ignored.
+pub fn handle_desched_event(t: &mut RecordTask, si: &siginfo_t) {
+    let desched_sig = t.session().as_record().unwrap().syscallbuf_desched_sig() as i32;
+    ed_assert!(
+        t,
+        desched_sig == si.si_signo
+            && si.si_code == POLL_IN as i32
+            && unsafe { si._sifields._sigpoll.si_fd } == t.desched_fd_child,
+        "Tracee is using {}??? (code={}, fd={})",
+        signal_name(si.si_signo),
+        si.si_code,
+        unsafe { si._sifields._sigpoll.si_fd }
+    );
+
+    // If the tracee isn't in the critical section where a desched
+    // event is relevant, we can ignore it.  See the long comments
+    // in syscall_buffer.c.
+    //
+    // It's OK if the tracee is in the critical section for a
+    // may-block syscall B, but this signal was delivered by an
+    // event programmed by a previous may-block syscall A.
+    //
+    // If we're running in a signal handler inside an interrupted syscallbuf
+    // system call, never do anything here. Syscall buffering is disabled and
+    // the desched_signal_may_be_relevant was set by the outermost syscallbuf
+    // invocation.
+    let syscallbuf_child = t.syscallbuf_child;
+    let may_be_relevant = read_val_mem(
+        t,
+        RemotePtr::<u8>::cast(syscallbuf_child)
+            + offset_of!(syscallbuf_hdr, desched_signal_may_be_relevant),
+        None,
+    );
+    if may_be_relevant == 0 || t.running_inside_desched() {
+        log!(LogDebug, "  (not entering may-block syscall; resuming)");
+        // We have to disarm the event just in case the tracee
+        // has cleared the relevancy flag, but not yet
+        // disarmed the event itself.
+        disarm_desched_event(t);
+        t.push_event(Event::noop());
+        return;
+    }
+
+    // The desched event just fired.  That implies that the
+    // arm-desched ioctl went into effect, and that the
+    // disarm-desched syscall didn't take effect.  Since a signal
+    // is pending for the tracee, then if the tracee was in a
+    // syscall, linux has exited it with an -ERESTART* error code.
+    // That means the tracee is about to (re-)enter either
+    //
+    //  1. buffered syscall
+    //  2. disarm-desched ioctl syscall
+    //
+    // We can figure out which one by simply issuing a
+    // ptrace(SYSCALL) and examining the tracee's registers.
+    //
+    // If the tracee enters the disarm-desched ioctl, it's going
+    // to commit a record of the buffered syscall to the
+    // syscallbuf, and we can safely send the tracee back on its
+    // way, ignoring the desched completely.
+    //
+    // If it enters the buffered syscall, then the desched event
+    // has served its purpose and we need to prepare the tracee
+    // to be context-switched.
+    //
+    // Sometimes the desched signal will interrupt the arm-desched
+    // syscall itself. Continuing to the next syscall boundary restarts
+    // the arm-desched syscall, so we advance to the next syscall
+    // boundary that's not an arm-desched ioctl.
+    loop {
+        // Prevent further desched notifications from firing
+        // while we're advancing the tracee.  We're going to
+        // leave it in a consistent state anyway, so the event
+        // is no longer useful.  We have to do this in each
+        // loop iteration because a restarted arm-desched
+        // syscall may have re-armed the event.
+        disarm_desched_event(t);
+
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeUnlimitedTicks,
+            None,
+        );
+        if t.is_dying() {
+            return;
+        }
+
+        if t.status().is_syscall() {
+            if t.is_arm_desched_event_syscall() {
+                continue;
+            }
+            break;
+        }
+
+        // Completely ignore spurious desched signals and
+        // signals that aren't going to be delivered to the
+        // tracee.
+        //
+        // Also ignore time-slice signals. If the tracee ends
+        // up at the disarm-desched ioctl, we'll reschedule it
+        // with the ticks interrupt still programmed.  At worst,
+        // the tracee will get an extra time-slice out of
+        // this, on average, so we don't worry too much about
+        // it.
+        let sig = t.maybe_stop_sig();
+        ed_assert!(t, sig.is_sig(), "expected stop-signal, got {}", t.status());
+        let sig = sig.unwrap_sig();
+        if sig == desched_sig || sig == TIME_SLICE_SIGNAL || t.is_sig_ignored(sig) {
+            log!(LogDebug, "  dropping ignored {}", signal_name(sig));
+            continue;
+        }
+
+        log!(LogDebug, "  stashing {}", signal_name(sig));
+        t.stash_sig();
+    }
+
+    if t.is_disarm_desched_event_syscall() {
+        log!(
+            LogDebug,
+            "  (at disarm-desched, so finished buffered syscall; resuming)"
+        );
+        t.push_event(Event::noop());
+        return;
+    }
+
+    // This prevents the syscallbuf record counter from being
+    // reset until we've finished guiding the tracee through this
+    // interrupted call.  We use the record counter for
+    // assertions.
+    ed_assert!(t, !t.delay_syscallbuf_reset_for_desched);
+    t.delay_syscallbuf_reset_for_desched = true;
+    log!(LogDebug, "Desched initiated");
+
+    // The tracee is (re-)entering the buffered syscall.  Stash
+    // away this breadcrumb so that we can figure out what syscall
+    // the tracee was in, and how much "scratch" space it carved
+    // off the syscallbuf, if needed.
+    let desched_rec = t.next_syscallbuf_record();
+    t.push_event(Event::new_desched_event(DeschedEventData {
+        rec: desched_rec,
+    }));
+    let call = read_val_mem(
+        t,
+        RemotePtr::<u16>::cast(
+            RemotePtr::<u8>::cast(desched_rec) + offset_of!(syscallbuf_record, syscallno),
+        ),
+        None,
+    ) as i32;
+
+    // The descheduled syscall was interrupted by a signal, like
+    // all other may-restart syscalls, with the exception that
+    // this one has no restart instruction to execute.
+    let arch = t.detect_syscall_arch();
+    let mut syscall_event = SyscallEventData::new(call, arch);
+    syscall_event.desched_rec = Some(desched_rec);
+    syscall_event.state = SyscallState::ProcessingSyscall;
+    syscall_event.regs = t.regs_ref().clone();
+    t.push_event(Event::new_syscall_interruption_event(syscall_event));
+
+    log!(
+        LogDebug,
+        "  resuming (and probably switching out) blocked `{}`",
+        syscall_name(call, arch)
+    );
+}
+
+/// `t` stopped with a SIGSEGV. If it is for an rdtsc or cpuid instruction that
+/// trapped only because rd made it trap, emulate the instruction, record an
+/// `InstructionTrap` event and return true. Replay makes the same instructions
+/// trap and restores the recorded registers.
+pub fn try_handle_trapped_instruction(t: &mut RecordTask, si: &siginfo_t) -> bool {
+    ed_assert!(t, si.si_signo == SIGSEGV);
+
+    let ip = t.ip();
+    let trapped_instruction = trapped_instruction_at(t, ip);
+    match trapped_instruction {
+        TrappedInstruction::Rdtsc | TrappedInstruction::Rdtscp => {
+            if t.tsc_mode == PR_TSC_SIGSEGV {
+                // The tracee asked for the SIGSEGV itself.
+                return false;
+            }
+        }
+        TrappedInstruction::CpuId => {
+            if t.cpuid_mode == 0 {
+                // The tracee asked for the SIGSEGV itself.
+                return false;
+            }
+        }
+        _ => return false,
+    }
+
+    let len = trapped_instruction_len(trapped_instruction);
+    let mut r = t.regs_ref().clone();
+    match trapped_instruction {
+        TrappedInstruction::Rdtsc | TrappedInstruction::Rdtscp => {
+            let current_time = rdtsc();
+            r.set_rdtsc_output(current_time);
+            if trapped_instruction == TrappedInstruction::Rdtscp {
+                // IA32_TSC_AUX. Pretend we're always on CPU 0.
+                r.set_cx(0);
+            }
+            log!(LogDebug, "  trapped for rdtsc: returning {}", current_time);
+        }
+        _ => {
+            let eax = r.syscallno() as u32;
+            let ecx = r.cx() as u32;
+            let mut cpuid_data = cpuid(eax, ecx);
+            t.session()
+                .as_record()
+                .unwrap()
+                .disable_cpuid_features()
+                .amend_cpuid_data(eax, ecx, &mut cpuid_data);
+            r.set_cpuid_output(
+                cpuid_data.eax,
+                cpuid_data.ebx,
+                cpuid_data.ecx,
+                cpuid_data.edx,
+            );
+            log!(LogDebug, "  trapped for cpuid: {:#x}:{:#x}", eax, ecx);
+        }
+    }
+    r.set_ip(r.ip() + len);
+    t.set_regs(&r);
+    t.record_event(&Event::instruction_trap(), None, None, None);
+    true
+}
+
+/// `t` stopped with the signal `si`, which isn't the desched signal. Record
+/// whatever replay needs to reproduce its effect and tell the caller how to resume
+/// `t`.
+pub fn handle_signal(
+    t: &mut RecordTask,
+    si: &siginfo_t,
+    deterministic: SignalDeterministic,
+) -> SignalHandled {
+    let sig = si.si_signo;
+    log!(
+        LogDebug,
+        "{}: handling signal {} (pevent: {}, event: {})",
+        t.tid,
+        signal_name(sig),
+        t.maybe_ptrace_event(),
+        t.ev()
+    );
+
+    if sig == TIME_SLICE_SIGNAL {
+        // Our ticks interrupt. Note where it happened so that replay can stop
+        // at the same point and switch tasks there too.
+        t.session()
+            .as_record()
+            .unwrap()
+            .scheduler_mut()
+            .expire_timeslice();
+        if t.ev().event_type() != EventType::EvSyscall {
+            // The tracee may have been spinning on shared memory all timeslice.
+            MonitoredSharedMemory::check_all(t);
+            t.record_event(&Event::sched(), None, None, None);
+        }
+        return SignalHandled::Discard;
+    }
+
+    if sig == SIGSEGV && try_handle_trapped_instruction(t, si) {
+        return SignalHandled::Discard;
+    }
+
+    if t.emulate_ptrace_stop(WaitStatus::for_stop_sig(sig), Some(si), None) {
+        // The emulated ptracer gets to decide what to do with the signal.
+        return SignalHandled::Discard;
+    }
+
+    let disposition = t.sig_resolved_disposition(sig, deterministic);
+    match disposition {
+        SignalResolvedDisposition::DispositionIgnored => {
+            log!(LogDebug, "  {} is ignored; discarding", signal_name(sig));
+            SignalHandled::Discard
+        }
+        SignalResolvedDisposition::DispositionFatal => {
+            MonitoredSharedMemory::check_all(t);
+            t.record_signal(si, deterministic);
+            t.record_signal_delivered();
+            SignalHandled::DeliverFatal(sig)
+        }
+        SignalResolvedDisposition::DispositionUserHandler => {
+            // The sender may have written shared memory before signalling us.
+            MonitoredSharedMemory::check_all(t);
+            t.record_signal(si, deterministic);
+            deliver_signal_to_handler(t, sig);
+            SignalHandled::Delivered
+        }
+    }
+}
+
+/// Have the kernel set up the frame of `t`'s handler for `sig`, stop at the
+/// handler's first instruction and record the frame and the registers there.
+fn deliver_signal_to_handler(t: &mut RecordTask, sig: i32) {
+    let old_sp = t.regs_ref().sp();
+    t.resume_execution(
+        ResumeRequest::ResumeSinglestep,
+        WaitRequest::ResumeWait,
+        TicksRequest::ResumeNoTicks,
+        Some(sig),
+    );
+    if t.is_dying() {
+        return;
+    }
+    let new_sp = t.regs_ref().sp();
+    let frame_size = if new_sp < old_sp && old_sp - new_sp <= MAX_SIGFRAME_SIZE {
+        old_sp - new_sp
+    } else {
+        // The frame is on an alternate signal stack.
+        MAX_SIGFRAME_SIZE
+    };
+    // Don't run off the end of the (alternate) stack's mapping.
+    let frame_end = match t.vm().mapping_of(new_sp) {
+        Some(m) => min(m.map.end(), new_sp + frame_size),
+        None => new_sp + frame_size,
+    };
+    t.record_remote_writable(new_sp, frame_end - new_sp);
+    t.record_signal_delivered();
+    t.clear_wait_status();
+}
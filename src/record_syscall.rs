@@ -0,0 +1,1911 @@
+//! Recording-side syscall handling.
+//!
+//! This covers the syscalls rd emulates outright (ptrace() between tracees, and
+//! the waits that report the resulting emulated stops), the syscalls that drive
+//! syscall buffering (the rdcalls made by the preload library and seccomp filter
+//! installation), task creation and exit, execve(), the memory map changes
+//! replay can't reproduce by re-executing the syscall (mmap(), brk(), mremap()),
+//! the opens, getdents, reads and ioctls that file monitors care about
+//! (including the perf_event_open() fds we emulate) and io_uring. clone3() and
+//! rseq() are made to fail so that the C library uses clone() and doesn't
+//! register restartable sequences.
+//!
+//! The outputs of other syscalls are recorded by `record_syscall_outputs()`,
+//! which knows about the common syscalls that write buffers and structs at their
+//! arguments. Outputs it doesn't know about (recvmsg(), most ioctls, the SysV
+//! IPC syscalls, ...) aren't recorded yet.
+use crate::{
+    arch::{Architecture, NativeArch},
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    bindings::{
+        perf_event::{perf_event_attr, PERF_FLAG_FD_CLOEXEC},
+        ptrace::{
+            PTRACE_ATTACH,
+            PTRACE_CONT,
+            PTRACE_DETACH,
+            PTRACE_GETEVENTMSG,
+            PTRACE_GETREGS,
+            PTRACE_GETSIGINFO,
+            PTRACE_OLDSETOPTIONS,
+            PTRACE_O_EXITKILL,
+            PTRACE_O_TRACECLONE,
+            PTRACE_O_TRACEEXEC,
+            PTRACE_O_TRACEEXIT,
+            PTRACE_O_TRACEFORK,
+            PTRACE_O_TRACESYSGOOD,
+            PTRACE_O_TRACEVFORK,
+            PTRACE_PEEKDATA,
+            PTRACE_PEEKTEXT,
+            PTRACE_PEEKUSER,
+            PTRACE_POKEDATA,
+            PTRACE_POKETEXT,
+            PTRACE_POKEUSER,
+            PTRACE_SEIZE,
+            PTRACE_SETOPTIONS,
+            PTRACE_SETREGS,
+            PTRACE_SINGLESTEP,
+            PTRACE_SYSCALL,
+            PTRACE_SYSEMU,
+            PTRACE_SYSEMU_SINGLESTEP,
+            PTRACE_TRACEME,
+        },
+        signal::siginfo_t,
+    },
+    event::{Event, EventType, OpenedFd, SignalDeterministic, SignalEventData, Switchable},
+    file_monitor::{
+        io_uring_monitor::IoUringMonitor,
+        proc_fd_dir_monitor::ProcFdDirMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+        FileMonitor,
+        FileMonitorType,
+        Range,
+    },
+    kernel_abi::{to_audit_arch, x64, x86, CloneTLSType, MmapCallingSemantics, SupportedArch},
+    kernel_metadata::{errno_name, ptrace_req_name, signal_name, syscall_name},
+    kernel_supplement::{
+        io_uring_params,
+        open_how,
+        IORING_SETUP_SQPOLL,
+        SECCOMP_RET_ACTION,
+        SECCOMP_RET_DATA,
+        SECCOMP_RET_ERRNO,
+        SECCOMP_RET_KILL,
+        SECCOMP_RET_TRAP,
+        SECCOMP_SET_MODE_FILTER,
+        SYS_SECCOMP,
+    },
+    log::LogLevel::{LogDebug, LogWarn},
+    monitored_shared_memory::MonitoredSharedMemory,
+    registers::Registers,
+    remote_ptr::{RemotePtr, Void},
+    seccomp_filter_rewriter::SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO,
+    session::{
+        address_space::{
+            address_space::AddressSpace,
+            kernel_map_iterator::KernelMapIterator,
+            kernel_mapping::KernelMapping,
+        },
+        task::{
+            record_task::{record_task::RecordTask, EmulatedStopType, WaitType},
+            task_common::{read_mem, read_val_mem, write_mem},
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+            Task,
+            TaskSharedPtr,
+        },
+    },
+    trace::{
+        trace_task_event::TraceTaskEvent,
+        trace_writer::{MappingOrigin, RecordInTrace},
+    },
+    util::{
+        ceil_page_size,
+        clone_flags_to_task_flags,
+        extract_clone_parameters,
+        is_proc_fd_dir,
+        page_size,
+        u8_raw_slice,
+        CloneParameters,
+    },
+    wait_status::WaitStatus,
+};
+use libc::{
+    pid_t,
+    CLONE_UNTRACED,
+    EINVAL,
+    EIO,
+    ENOSYS,
+    EPERM,
+    ESRCH,
+    FIONREAD,
+    O_ACCMODE,
+    O_CLOEXEC,
+    O_RDONLY,
+    PR_SET_SECCOMP,
+    SECCOMP_MODE_FILTER,
+    SIGKILL,
+    SIGSTOP,
+    SIGSYS,
+    S_IFMT,
+    S_IFREG,
+    TCGETS,
+    TIOCGWINSZ,
+    WNOWAIT,
+    WUNTRACED,
+};
+use nix::sys::{
+    mman::{MapFlags, ProtFlags},
+    stat::stat,
+};
+use std::{
+    cmp::min,
+    ffi::{OsStr, OsString},
+    fs,
+    mem::size_of,
+    os::unix::ffi::OsStrExt,
+};
+
+/// Only the options we know how to emulate.
+const PTRACE_O_MASK_SUPPORTED: u32 = PTRACE_O_TRACESYSGOOD
+    | PTRACE_O_TRACEFORK
+    | PTRACE_O_TRACEVFORK
+    | PTRACE_O_TRACECLONE
+    | PTRACE_O_TRACEEXEC
+    | PTRACE_O_TRACEEXIT
+    | PTRACE_O_EXITKILL;
+
+/// Size of each task's scratch memory.
+const SCRATCH_SIZE_PAGES: usize = 512;
+
+/// `struct user_desc`, the TLS argument of clone() on x86, is four ints.
+const USER_DESC_SIZE: usize = 16;
+
+/// Room for any `struct sockaddr` the kernel writes (`struct sockaddr_storage`).
+const SOCKADDR_STORAGE_SIZE: usize = 128;
+
+/// Size of the kernel struct `$t` for the architecture `$arch`.
+macro_rules! arch_size_of {
+    ($arch:ident, $t:ident) => {
+        match $arch::arch() {
+            SupportedArch::X86 => size_of::<x86::$t>(),
+            SupportedArch::X64 => size_of::<x64::$t>(),
+        }
+    };
+}
+
+/// State carried by a task from the entry to the exit of a syscall we emulate.
+#[derive(Default)]
+pub struct TaskSyscallState {
+    /// The registers at syscall entry, before we clobbered arguments to make the
+    /// kernel fail the syscall for us.
+    syscall_entry_registers: Option<Registers>,
+    /// Result to give the syscall at exit, if we emulated it.
+    /// DIFF NOTE: rr uses a separate `emulated` flag.
+    emulated_result: Option<isize>,
+    /// The tracee or child whose emulated stop a wait syscall reports.
+    /// DIFF NOTE: rr stores a RecordTask pointer. We store the rec_tid.
+    emulate_wait_for_child: Option<pid_t>,
+    /// True if we patched the syscall instruction instead of letting the syscall run.
+    syscall_patched: bool,
+}
+
+impl TaskSyscallState {
+    pub fn new() -> TaskSyscallState {
+        Self::default()
+    }
+
+    fn emulate_result(&mut self, result: isize) {
+        self.emulated_result = Some(result);
+    }
+
+    /// True if `rec_prepare_syscall()` patched the syscall instruction to call into the
+    /// preload library instead. The task has been taken out of the syscall and will run
+    /// the patched code when resumed, so there is no syscall exit to process.
+    pub fn syscall_patched(&self) -> bool {
+        self.syscall_patched
+    }
+}
+
+/// Call this when `t` has entered a syscall, before letting the kernel run it. If
+/// `syscall_state.syscall_patched()` afterwards, just resume `t`.
+pub fn rec_prepare_syscall(t: &mut RecordTask, syscall_state: &mut TaskSyscallState) -> Switchable {
+    rd_arch_function_selfless!(
+        rec_prepare_syscall_arch,
+        t.ev().syscall().arch(),
+        t,
+        syscall_state
+    )
+}
+
+/// Call this when `t` has exited the syscall prepared with `rec_prepare_syscall()`.
+pub fn rec_process_syscall(t: &mut RecordTask, syscall_state: &mut TaskSyscallState) {
+    rd_arch_function_selfless!(
+        rec_process_syscall_arch,
+        t.ev().syscall().arch(),
+        t,
+        syscall_state
+    );
+    MonitoredSharedMemory::check_all(t);
+    let fds = t.fd_table_shr_ptr();
+    fds.borrow().record_io_uring_completions(t);
+}
+
+fn rec_prepare_syscall_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let syscallno = t.ev().syscall().number;
+    syscall_state.syscall_patched = false;
+
+    if t.is_ptrace_seccomp_event() && prepare_seccomp_result::<Arch>(t, syscall_state) {
+        return Switchable::PreventSwitch;
+    }
+
+    if try_patch_syscall(t) {
+        syscall_state.syscall_entry_registers = None;
+        syscall_state.syscall_patched = true;
+        return Switchable::PreventSwitch;
+    }
+
+    syscall_state.syscall_entry_registers = Some(t.regs_ref().clone());
+
+    if syscallno == Arch::CLONE || syscallno == Arch::FORK || syscallno == Arch::VFORK {
+        return prepare_clone::<Arch>(t, syscall_state);
+    }
+
+    if syscallno == Arch::EXIT
+        || (syscallno == Arch::EXIT_GROUP && t.thread_group().task_set().len() == 1)
+    {
+        prepare_exit(t);
+        return Switchable::AllowSwitch;
+    }
+
+    if syscallno == Arch::RSEQ {
+        // We don't support restartable sequences. The C library copes without them.
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result(-ENOSYS as isize);
+        return Switchable::PreventSwitch;
+    }
+
+    if syscallno == Arch::PTRACE {
+        return prepare_ptrace::<Arch>(t, syscall_state);
+    }
+
+    if syscallno == Arch::WAITPID || syscallno == Arch::WAIT4 {
+        let pid = t.regs_ref().arg1_signed() as pid_t;
+        if pid < -1 {
+            t.in_wait_type = WaitType::WaitTypePgid;
+            t.in_wait_pid = -pid;
+        } else if pid == -1 {
+            t.in_wait_type = WaitType::WaitTypeAny;
+        } else if pid == 0 {
+            t.in_wait_type = WaitType::WaitTypeSamePgid;
+        } else {
+            t.in_wait_type = WaitType::WaitTypePid;
+            t.in_wait_pid = pid;
+        }
+        let options = t.regs_ref().arg3() as i32;
+        if maybe_emulate_wait(t, syscall_state, options) {
+            let mut r = t.regs_ref().clone();
+            // Set options to an invalid value to force syscall to fail
+            r.set_arg3(0xffffffff);
+            t.set_regs(&r);
+            return Switchable::PreventSwitch;
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if syscallno == Arch::PERF_EVENT_OPEN {
+        return prepare_perf_event_open::<Arch>(t);
+    }
+
+    if syscallno == Arch::MMAP2
+        || (syscallno == Arch::MMAP
+            && Arch::MMAP_SEMANTICS == MmapCallingSemantics::RegisterArguments)
+    {
+        prepare_mmap_register_params(t);
+        return Switchable::PreventSwitch;
+    }
+
+    if syscallno == Arch::CLONE3 {
+        // Make the C library fall back to clone().
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result(-ENOSYS as isize);
+        return Switchable::PreventSwitch;
+    }
+
+    if syscallno == Arch::IO_URING_ENTER {
+        // The kernel reads the SQEs and the SQ tail from the real ring memory,
+        // not from the tracee's copy.
+        MonitoredSharedMemory::check_all(t);
+        let maybe_monitor = t.fd_table().get_monitor(t.regs_ref().arg1() as i32);
+        if let Some(monitor) = maybe_monitor {
+            if let Some(io_uring_monitor) = monitor.borrow_mut().as_io_uring_monitor_mut() {
+                io_uring_monitor.will_enter(t);
+            }
+        }
+        // This can wait for completions indefinitely.
+        return Switchable::AllowSwitch;
+    }
+
+    if syscallno == Arch::IOCTL {
+        let fd = t.regs_ref().arg1_signed() as i32;
+        let mut result: u64 = 0;
+        let fds = t.fd_table_shr_ptr();
+        if fds.borrow().emulate_ioctl(fd, t, &mut result) {
+            // Don't perform this syscall.
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            syscall_state.emulate_result(result as isize);
+            return Switchable::PreventSwitch;
+        }
+    }
+
+    if syscallno == Arch::READ {
+        let fd = t.regs_ref().arg1_signed() as i32;
+        let ranges = vec![Range::new(
+            RemotePtr::from(t.regs_ref().arg2()),
+            t.regs_ref().arg3(),
+        )];
+        let mut result: u64 = 0;
+        let fds = t.fd_table_shr_ptr();
+        if fds.borrow().emulate_read(fd, t, &ranges, &mut result) {
+            // Don't perform this syscall.
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            record_ranges(t, &ranges, result as usize);
+            syscall_state.emulate_result(result as isize);
+            return Switchable::PreventSwitch;
+        }
+    }
+
+    if is_seccomp_filter_install::<Arch>(syscallno, t.regs_ref()) && t.session().done_initial_exec()
+    {
+        // Prevent the actual call from succeeding in the kernel. We install our
+        // patched version of the filter when the syscall exits. Before the
+        // initial exec this must be rd's own filter, so let it through.
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+    }
+
+    if may_block::<Arch>(syscallno) {
+        return Switchable::AllowSwitch;
+    }
+
+    Switchable::PreventSwitch
+}
+
+/// True if `syscallno` can wait for another task indefinitely. The scheduler
+/// must be free to run other tasks while `t` is in such a syscall, otherwise
+/// e.g. a parent in wait4() would deadlock with its child.
+fn may_block<Arch: Architecture>(syscallno: i32) -> bool {
+    [
+        Arch::ACCEPT,
+        Arch::ACCEPT4,
+        Arch::CLOCK_NANOSLEEP,
+        Arch::CONNECT,
+        Arch::EPOLL_PWAIT,
+        Arch::EPOLL_WAIT,
+        Arch::FLOCK,
+        Arch::FUTEX,
+        Arch::MSGRCV,
+        Arch::NANOSLEEP,
+        Arch::PAUSE,
+        Arch::POLL,
+        Arch::PPOLL,
+        Arch::PSELECT6,
+        Arch::READ,
+        Arch::READV,
+        Arch::RECVFROM,
+        Arch::RECVMSG,
+        Arch::RT_SIGSUSPEND,
+        Arch::RT_SIGTIMEDWAIT,
+        Arch::SELECT,
+        Arch::SEMOP,
+        Arch::SEMTIMEDOP,
+        Arch::SENDMSG,
+        Arch::SENDTO,
+        Arch::WAITID,
+        Arch::WRITE,
+        Arch::WRITEV,
+        Arch::_NEWSELECT,
+    ]
+    .contains(&syscallno)
+}
+
+/// Run the clone(), fork() or vfork() `t` just entered until the new task exists,
+/// then set up and record the new task. This must be kept in sync with
+/// replay_syscall's prepare_clone.
+fn prepare_clone<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let entry_regs = syscall_state.syscall_entry_registers.clone().unwrap();
+    let syscallno = entry_regs.original_syscallno() as i32;
+    let mut flags: i32 = 0;
+    let mut r = t.regs_ref().clone();
+    if syscallno == Arch::CLONE {
+        // If we allow CLONE_UNTRACED then the child would escape from rd control
+        // and we can't allow that.
+        flags = r.arg1() as i32;
+        r.set_arg1((flags & !CLONE_UNTRACED) as usize);
+        t.set_regs(&r);
+    }
+
+    let mut new_tid: Option<pid_t> = None;
+    loop {
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeNoTicks,
+            None,
+        );
+        if t.maybe_stop_sig().is_sig() {
+            // Deliver it after the syscall. The syscall restarts.
+            t.stash_sig();
+        } else if t.clone_syscall_is_complete(&mut new_tid, Arch::arch()) {
+            break;
+        } else if !t.regs_ref().syscall_may_restart() {
+            let result = t.regs_ref().syscall_result_signed();
+            log!(
+                LogDebug,
+                "clone failed, returning {}",
+                errno_name(-result as i32)
+            );
+            syscall_state.emulate_result(result);
+            t.ev_mut().syscall_mut().failed_during_preparation = true;
+            // The clone failed and we're out of the syscall. Enter a gettid() so
+            // that we're in the same state as after a successful clone.
+            let mut gettid_regs = r.clone();
+            gettid_regs.set_syscallno(Arch::GETTID as isize);
+            gettid_regs.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+            t.set_regs(&gettid_regs);
+            t.enter_syscall();
+            let mut restored = entry_regs.clone();
+            restored.set_ip(t.regs_ref().ip());
+            t.set_regs(&restored);
+            let arch = t.arch();
+            t.canonicalize_regs(arch);
+            return Switchable::AllowSwitch;
+        }
+        // Reset the registers and try again.
+        t.set_regs(&r);
+    }
+
+    let new_tid = new_tid.unwrap();
+    let params = if syscallno == Arch::CLONE {
+        extract_clone_parameters(t)
+    } else {
+        CloneParameters::default()
+    };
+    let session = t.session();
+    let new_task_shr_ptr = session.clone_task(
+        t,
+        clone_flags_to_task_flags(flags),
+        params.stack,
+        params.tls,
+        params.ctid,
+        new_tid,
+        Some(new_tid),
+    );
+
+    // Hide the flags we changed.
+    let mut r = t.regs_ref().clone();
+    r.set_original_syscallno(entry_regs.original_syscallno());
+    r.set_arg1(entry_regs.arg1());
+    t.set_regs(&r);
+
+    let mut new_task_ref = new_task_shr_ptr.borrow_mut();
+    let new_task = new_task_ref.as_record_task_mut().unwrap();
+    let mut new_r = new_task.regs_ref().clone();
+    new_r.set_original_syscallno(entry_regs.original_syscallno());
+    new_r.set_arg1(entry_regs.arg1());
+    new_task.set_regs(&new_r);
+    let new_task_arch = new_task.arch();
+    new_task.canonicalize_regs(new_task_arch);
+
+    if syscallno == Arch::CLONE {
+        t.record_remote_even_if_null_for(params.ptid);
+        if Arch::CLONE_TLS_TYPE == CloneTLSType::UserDescPointer {
+            t.record_remote_even_if_null(params.tls, USER_DESC_SIZE);
+            new_task.record_remote_even_if_null(params.tls, USER_DESC_SIZE);
+        }
+        new_task.record_remote_even_if_null_for(params.ptid);
+        new_task.record_remote_even_if_null_for(params.ctid);
+    }
+
+    session
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_task_event(&TraceTaskEvent::for_clone(
+            new_task.rec_tid,
+            t.rec_tid,
+            new_tid,
+            flags,
+        ));
+
+    init_scratch_memory(new_task);
+    // The new task is stopped and ready to be resumed.
+    new_task.clear_wait_status();
+
+    Switchable::AllowSwitch
+}
+
+/// `t` entered exit() (or the exit_group() of its last thread). Get rid of its
+/// syscall buffer, which the preload library can't do itself, before the task
+/// is gone. Replay does the same when it reaches the syscall.
+fn prepare_exit(t: &mut RecordTask) {
+    if !t.exit_syscall_and_prepare_restart() {
+        // The task exited anyway. The PTRACE_EVENT_EXIT will be processed as usual.
+        return;
+    }
+    t.destroy_buffers();
+    t.enter_syscall();
+}
+
+/// Map `t`'s scratch memory and record the mapping. This must be kept in sync
+/// with replay_syscall's init_scratch_memory.
+fn init_scratch_memory(t: &mut RecordTask) {
+    let size = SCRATCH_SIZE_PAGES * page_size();
+    let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+    let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS;
+    let scratch_ptr = {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        remote.infallible_mmap_syscall(None, size, prot, flags, -1, 0)
+    };
+    t.scratch_ptr = scratch_ptr;
+    t.scratch_size = size;
+    t.setup_preload_thread_locals();
+
+    let km = t.vm().map(
+        t,
+        scratch_ptr,
+        size,
+        prot,
+        flags,
+        0,
+        OsStr::new(""),
+        KernelMapping::NO_DEVICE,
+        KernelMapping::NO_INODE,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    let record_in_trace = t
+        .session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+    ed_assert!(t, record_in_trace == RecordInTrace::DontRecordInTrace);
+}
+
+/// `t` entered a syscall through a PTRACE_EVENT_SECCOMP stop. If the tracee's own seccomp
+/// filter (which `SeccompFilterRewriter` made return SECCOMP_RET_TRACE) decided the syscall
+/// mustn't run, make the kernel skip it, do what the filter asked for instead and return
+/// true.
+fn prepare_seccomp_result<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> bool {
+    let data = t.get_ptrace_eventmsg_seccomp_data();
+    if data as u32 == SECCOMP_RET_DATA {
+        // Traced by rd's own filter. The syscall runs as usual.
+        return false;
+    }
+    let maybe_real_result = t
+        .session()
+        .as_record()
+        .unwrap()
+        .seccomp_filter_rewriter()
+        .map_filter_data_to_real_result(data);
+    ed_assert!(
+        t,
+        maybe_real_result.is_some(),
+        "Unexpected seccomp data {:#x}",
+        data
+    );
+    let real_result = maybe_real_result.unwrap();
+    let real_data = real_result & SECCOMP_RET_DATA;
+
+    let mut r = t.regs_ref().clone();
+    let syscallno = r.original_syscallno() as i32;
+    log!(
+        LogDebug,
+        "seccomp filter result {:#x} for {}",
+        real_result,
+        syscall_name(syscallno, Arch::arch())
+    );
+    match real_result & SECCOMP_RET_ACTION {
+        SECCOMP_RET_ERRNO => syscall_state.emulate_result(-(real_data as isize)),
+        SECCOMP_RET_TRAP => {
+            let mut si: siginfo_t = Default::default();
+            si.si_signo = SIGSYS;
+            si.si_errno = real_data as i32;
+            si.si_code = SYS_SECCOMP as i32;
+            si._sifields._sigsys._arch = to_audit_arch(Arch::arch());
+            si._sifields._sigsys._syscall = syscallno;
+            // The kernel reports the address right after the syscall instruction.
+            si._sifields._sigsys._call_addr = r.ip().as_usize() as *mut _;
+            t.stash_synthetic_sig(&si, SignalDeterministic::DeterministicSig);
+            // The registers are left alone, so the syscall number stays in the result
+            // register.
+            syscall_state.emulate_result(syscallno as isize);
+        }
+        SECCOMP_RET_KILL => t.tgkill(SIGKILL),
+        _ => ed_assert!(t, false, "Seccomp result {:#x} not handled", real_result),
+    }
+
+    r.set_original_syscallno(SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO);
+    t.set_regs(&r);
+    true
+}
+
+/// Try to patch the syscall instruction `t` entered the syscall through to call into the
+/// preload library. If that works, `t` has been taken out of the syscall and we record
+/// that the syscall was patched.
+fn try_patch_syscall(t: &mut RecordTask) -> bool {
+    let vm = t.vm_shr_ptr();
+    // Most syscalls are at sites we've tried already, don't copy the monkeypatcher for them.
+    if !vm
+        .monkeypatcher()
+        .map_or(false, |m| m.may_patch_syscall_at(t))
+    {
+        return false;
+    }
+    let patched = vm
+        .with_monkeypatcher(|monkeypatcher| monkeypatcher.try_patch_syscall(t))
+        .unwrap_or(false);
+    if patched {
+        t.pop_syscall();
+        t.record_event(&Event::patch_syscall(), None, None, None);
+    }
+    patched
+}
+
+/// If `t` is opening a perf event counting rd's ticks for a tracee, turn the
+/// syscall into an inotify_init1(). This just gives us an allocated fd; syscalls
+/// using it will be emulated by a VirtualPerfCounterMonitor (except for close()).
+fn prepare_perf_event_open<Arch: Architecture>(t: &mut RecordTask) -> Switchable {
+    let regs = t.regs_ref().clone();
+    let flags = regs.arg5() as u64;
+    if VirtualPerfCounterMonitor::target_tuid(t, regs.arg2_signed() as pid_t).is_none() {
+        return Switchable::PreventSwitch;
+    }
+    let mut ok = true;
+    let attr = read_val_mem(
+        t,
+        RemotePtr::<perf_event_attr>::from(regs.arg1()),
+        Some(&mut ok),
+    );
+    if !ok
+        || !VirtualPerfCounterMonitor::should_virtualize(
+            &attr,
+            regs.arg3_signed() as i32,
+            regs.arg4_signed() as i32,
+            flags,
+        )
+    {
+        return Switchable::PreventSwitch;
+    }
+
+    let mut r = regs;
+    r.set_original_syscallno(Arch::INOTIFY_INIT1 as isize);
+    r.set_arg1(if flags & PERF_FLAG_FD_CLOEXEC as u64 != 0 {
+        O_CLOEXEC as usize
+    } else {
+        0
+    });
+    t.set_regs(&r);
+    Switchable::AllowSwitch
+}
+
+/// In chaos mode, pick a random address for a mapping whose placement is up to
+/// the kernel.
+fn prepare_mmap_register_params(t: &mut RecordTask) {
+    if !t.session().as_record().unwrap().enable_chaos() {
+        return;
+    }
+    let mut r = t.regs_ref().clone();
+    let flags = MapFlags::from_bits_truncate(r.arg4_signed() as i32);
+    if r.arg1() != 0
+        || flags.intersects(MapFlags::MAP_FIXED | MapFlags::MAP_32BIT | MapFlags::MAP_GROWSDOWN)
+    {
+        return;
+    }
+    let addr = AddressSpace::chaos_mode_find_free_memory(t, r.arg2());
+    if addr.is_null() {
+        return;
+    }
+    r.set_arg1(addr.as_usize());
+    r.set_arg4((flags | MapFlags::MAP_FIXED).bits() as usize);
+    t.set_regs(&r);
+}
+
+/// Undo `prepare_mmap_register_params()`. The tracee and the trace should see the
+/// arguments the tracee passed.
+fn restore_mmap_register_params(t: &mut RecordTask, entry_regs: &Registers) {
+    let mut r = t.regs_ref().clone();
+    r.set_arg1(entry_regs.arg1());
+    r.set_arg4(entry_regs.arg4());
+    t.set_regs(&r);
+}
+
+/// Record the first `num_bytes` bytes of the memory `ranges` of `t`.
+pub fn record_ranges(t: &mut RecordTask, ranges: &[Range], num_bytes: usize) {
+    let mut left = num_bytes;
+    for r in ranges {
+        let len = min(left, r.length);
+        t.record_remote(r.data, len);
+        left -= len;
+        if left == 0 {
+            break;
+        }
+    }
+}
+
+/// Return true if `regs` are for a prctl() or seccomp() that installs a
+/// seccomp-bpf filter.
+fn is_seccomp_filter_install<Arch: Architecture>(syscallno: i32, regs: &Registers) -> bool {
+    (syscallno == Arch::SECCOMP && regs.arg1() == SECCOMP_SET_MODE_FILTER as usize)
+        || (syscallno == Arch::PRCTL
+            && regs.arg1_signed() == PR_SET_SECCOMP as isize
+            && regs.arg2() == SECCOMP_MODE_FILTER as usize)
+}
+
+fn rec_process_syscall_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) {
+    let syscallno = t.ev().syscall().number;
+    let entry_regs = match syscall_state.syscall_entry_registers.take() {
+        Some(regs) => regs,
+        None => return,
+    };
+
+    if t.regs_ref().original_syscallno() == SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO {
+        // The tracee's seccomp filter decided against this syscall, see
+        // `prepare_seccomp_result()`.
+        if let Some(result) = syscall_state.emulated_result.take() {
+            let mut r = t.regs_ref().clone();
+            r.set_syscall_result_signed(result);
+            t.set_regs(&r);
+        }
+        return;
+    }
+
+    if syscallno == Arch::WAITPID || syscallno == Arch::WAIT4 {
+        t.in_wait_type = WaitType::WaitTypeNone;
+    }
+
+    if syscallno == Arch::RDCALL_INIT_BUFFERS {
+        t.init_buffers();
+        return;
+    }
+
+    if syscallno == Arch::RDCALL_INIT_PRELOAD {
+        t.at_preload_init();
+        return;
+    }
+
+    if syscallno == Arch::EXECVE || syscallno == Arch::EXECVEAT {
+        if !t.regs_ref().syscall_failed() {
+            process_execve::<Arch>(t);
+        }
+        return;
+    }
+
+    if syscallno == Arch::BRK {
+        process_brk(t);
+        return;
+    }
+
+    if syscallno == Arch::MREMAP {
+        if !t.regs_ref().syscall_failed() {
+            process_mremap(t);
+        }
+        return;
+    }
+
+    if syscallno == Arch::MMAP {
+        match Arch::MMAP_SEMANTICS {
+            MmapCallingSemantics::StructArguments => {
+                // Only x86's old mmap() takes its arguments in memory.
+                let args = read_val_mem(
+                    t,
+                    RemotePtr::<x86::mmap_args>::from(entry_regs.arg1()),
+                    None,
+                );
+                process_mmap(
+                    t,
+                    args.len as usize,
+                    args.prot,
+                    args.flags,
+                    args.fd,
+                    args.offset as u64,
+                );
+            }
+            MmapCallingSemantics::RegisterArguments => {
+                restore_mmap_register_params(t, &entry_regs);
+                let r = t.regs_ref().clone();
+                process_mmap(
+                    t,
+                    r.arg2(),
+                    r.arg3() as i32,
+                    r.arg4() as i32,
+                    r.arg5() as i32,
+                    r.arg6() as u64,
+                );
+            }
+        }
+        return;
+    }
+
+    if syscallno == Arch::MMAP2 {
+        restore_mmap_register_params(t, &entry_regs);
+        let r = t.regs_ref().clone();
+        process_mmap(
+            t,
+            r.arg2(),
+            r.arg3() as i32,
+            r.arg4() as i32,
+            r.arg5() as i32,
+            (r.arg6() * page_size()) as u64,
+        );
+        return;
+    }
+
+    if is_seccomp_filter_install::<Arch>(syscallno, &entry_regs) {
+        if t.session().done_initial_exec() {
+            // Put back the argument we clobbered in rec_prepare_syscall_arch().
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(entry_regs.arg1());
+            t.set_regs(&r);
+            let session = t.session();
+            session
+                .as_record()
+                .unwrap()
+                .seccomp_filter_rewriter_mut()
+                .install_patched_seccomp_filter(t);
+        }
+        return;
+    }
+
+    if syscallno == Arch::PERF_EVENT_OPEN {
+        if t.regs_ref().original_syscallno() == Arch::INOTIFY_INIT1 as isize {
+            // We turned this into an inotify_init1() in prepare_perf_event_open().
+            let fd = t.regs_ref().syscall_result_signed() as i32;
+            let mut r = t.regs_ref().clone();
+            r.set_original_syscallno(entry_regs.original_syscallno());
+            r.set_arg1(entry_regs.arg1());
+            t.set_regs(&r);
+            if fd >= 0 {
+                let attr = read_val_mem(
+                    t,
+                    RemotePtr::<perf_event_attr>::from(entry_regs.arg1()),
+                    None,
+                );
+                let target_tuid =
+                    VirtualPerfCounterMonitor::target_tuid(t, entry_regs.arg2_signed() as pid_t)
+                        .unwrap();
+                let monitor = VirtualPerfCounterMonitor::new(t, target_tuid, &attr);
+                t.fd_table_shr_ptr()
+                    .borrow_mut()
+                    .add_monitor(t, fd, Box::new(monitor));
+            }
+        }
+        return;
+    }
+
+    if syscallno == Arch::OPEN || syscallno == Arch::OPENAT || syscallno == Arch::OPENAT2 {
+        if !t.regs_ref().syscall_failed() {
+            let fd = t.regs_ref().syscall_result_signed() as i32;
+            let flags = if syscallno == Arch::OPEN {
+                t.regs_ref().arg2() as i32
+            } else if syscallno == Arch::OPENAT {
+                t.regs_ref().arg3() as i32
+            } else {
+                let how_ptr = RemotePtr::<open_how>::from(t.regs_ref().arg3());
+                read_val_mem(t, how_ptr, None).flags as i32
+            };
+            handle_opened_file(t, fd, flags);
+        }
+        return;
+    }
+
+    if syscallno == Arch::IO_URING_SETUP {
+        if !t.regs_ref().syscall_failed() {
+            let fd = t.regs_ref().syscall_result_signed() as i32;
+            let params_ptr = RemotePtr::<io_uring_params>::from(t.regs_ref().arg2());
+            let params = read_val_mem(t, params_ptr, None);
+            if params.flags & IORING_SETUP_SQPOLL != 0 {
+                log!(
+                    LogWarn,
+                    "io_uring with IORING_SETUP_SQPOLL; completions will only be seen at syscalls"
+                );
+            }
+            // The kernel filled in the ring sizes and offsets.
+            t.record_local_for(params_ptr, &params);
+            t.fd_table_shr_ptr().borrow_mut().add_monitor(
+                t,
+                fd,
+                Box::new(IoUringMonitor::new(params)),
+            );
+        }
+        return;
+    }
+
+    if syscallno == Arch::GETDENTS || syscallno == Arch::GETDENTS64 {
+        let fd = t.regs_ref().arg1_signed() as i32;
+        let fds = t.fd_table_shr_ptr();
+        fds.borrow().filter_getdents(fd, t);
+        record_syscall_outputs::<Arch>(t, syscallno);
+        return;
+    }
+
+    let result = match syscall_state.emulated_result.take() {
+        Some(result) => result,
+        None => {
+            record_syscall_outputs::<Arch>(t, syscallno);
+            return;
+        }
+    };
+
+    // Restore the arguments we clobbered and set the result we emulated.
+    let mut r = t.regs_ref().clone();
+    r.set_arg1(entry_regs.arg1());
+    r.set_arg3(entry_regs.arg3());
+    r.set_syscall_result_signed(result);
+    t.set_regs(&r);
+
+    if syscallno == Arch::WAITPID || syscallno == Arch::WAIT4 {
+        let tid = syscall_state.emulate_wait_for_child.take().unwrap();
+        let tracee_rc = t.session().find_task_from_rec_tid(tid).unwrap();
+        let mut tracee_ref = tracee_rc.borrow_mut();
+        let tracee = tracee_ref.as_record_task_mut().unwrap();
+        // Finish emulation of ptrace result or stop-signal
+        let status_ptr = RemotePtr::<i32>::from(entry_regs.arg2());
+        if !status_ptr.is_null() {
+            let status = tracee.emulated_stop_code.get();
+            write_mem(t, status_ptr, &[status], None);
+            t.record_local_for(status_ptr, &status);
+        }
+        if syscallno == Arch::WAIT4 {
+            let ru_ptr = RemotePtr::<u8>::from(entry_regs.arg4());
+            if !ru_ptr.is_null() {
+                // We don't have the tracee's resource usage. Zeroing it is as good as
+                // anything. struct rusage is 18 longs on every architecture we support.
+                let ru = vec![0u8; 18 * size_of::<Arch::unsigned_long>()];
+                write_mem(t, ru_ptr, &ru, None);
+                t.record_local(RemotePtr::cast(ru_ptr), &ru);
+            }
+        }
+        if entry_regs.arg3() as i32 & WNOWAIT == 0 {
+            tracee.emulated_stop_pending = false;
+        }
+    }
+}
+
+/// Record the memory the kernel wrote for the syscall `t` just exited, for the
+/// syscalls whose outputs are buffers and structs at their arguments.
+fn record_syscall_outputs<Arch: Architecture>(t: &mut RecordTask, syscallno: i32) {
+    let regs = t.regs_ref().clone();
+    // Out-parameters (address, length).
+    let mut outputs: Vec<(usize, usize)> = Vec::new();
+
+    // The remaining time is written when the sleep is interrupted, i.e. fails.
+    if syscallno == Arch::NANOSLEEP {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, timespec)));
+    } else if syscallno == Arch::CLOCK_NANOSLEEP {
+        outputs.push((regs.arg4(), arch_size_of!(Arch, timespec)));
+    }
+    if regs.syscall_failed() {
+        record_outputs(t, &outputs);
+        return;
+    }
+
+    let result = regs.syscall_result();
+    let is_x64 = Arch::arch() == SupportedArch::X64;
+    if syscallno == Arch::READ
+        || syscallno == Arch::PREAD64
+        || syscallno == Arch::GETDENTS
+        || syscallno == Arch::GETDENTS64
+        || syscallno == Arch::GETRANDOM
+    {
+        outputs.push((regs.arg2(), result));
+    } else if syscallno == Arch::READV || syscallno == Arch::PREADV || syscallno == Arch::PREADV2 {
+        let ranges = read_iovecs::<Arch>(t, regs.arg2(), regs.arg3());
+        record_ranges(t, &ranges, result);
+    } else if syscallno == Arch::RECVFROM {
+        outputs.push((regs.arg2(), result));
+        outputs.extend(sockaddr_output(t, regs.arg5(), regs.arg6()));
+    } else if syscallno == Arch::GETSOCKNAME || syscallno == Arch::GETPEERNAME {
+        outputs.extend(sockaddr_output(t, regs.arg2(), regs.arg3()));
+    } else if syscallno == Arch::GETSOCKOPT {
+        outputs.extend(sockaddr_output(t, regs.arg4(), regs.arg5()));
+    } else if (is_x64
+        && (syscallno == Arch::STAT || syscallno == Arch::LSTAT || syscallno == Arch::FSTAT))
+        || syscallno == Arch::STAT64
+        || syscallno == Arch::LSTAT64
+        || syscallno == Arch::FSTAT64
+    {
+        // x86-64's struct stat has the layout of x86's struct stat64.
+        outputs.push((regs.arg2(), arch_size_of!(Arch, stat64)));
+    } else if syscallno == Arch::FSTATAT64 {
+        outputs.push((regs.arg3(), arch_size_of!(Arch, stat64)));
+    } else if syscallno == Arch::STATX {
+        outputs.push((regs.arg5(), arch_size_of!(Arch, statx)));
+    } else if syscallno == Arch::STATFS || syscallno == Arch::FSTATFS {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, statfs)));
+    } else if syscallno == Arch::STATFS64 || syscallno == Arch::FSTATFS64 {
+        outputs.push((regs.arg3(), arch_size_of!(Arch, statfs64)));
+    } else if syscallno == Arch::UNAME {
+        outputs.push((regs.arg1(), arch_size_of!(Arch, utsname)));
+    } else if syscallno == Arch::CLOCK_GETTIME || syscallno == Arch::CLOCK_GETRES {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, timespec)));
+    } else if syscallno == Arch::GETTIMEOFDAY {
+        outputs.push((regs.arg1(), arch_size_of!(Arch, timeval)));
+        outputs.push((regs.arg2(), arch_size_of!(Arch, timezone)));
+    } else if syscallno == Arch::TIME {
+        outputs.push((regs.arg1(), arch_size_of!(Arch, time_t)));
+    } else if syscallno == Arch::PIPE || syscallno == Arch::PIPE2 {
+        outputs.push((regs.arg1(), 2 * size_of::<i32>()));
+    } else if syscallno == Arch::SOCKETPAIR {
+        outputs.push((regs.arg4(), 2 * size_of::<i32>()));
+    } else if syscallno == Arch::POLL || syscallno == Arch::PPOLL {
+        outputs.push((regs.arg1(), regs.arg2() * arch_size_of!(Arch, pollfd)));
+    } else if syscallno == Arch::SELECT || syscallno == Arch::_NEWSELECT {
+        // x86's old select() takes its arguments in memory, so SELECT is only the
+        // register-argument version on x86-64.
+        if syscallno == Arch::_NEWSELECT || is_x64 {
+            for &fds in &[regs.arg2(), regs.arg3(), regs.arg4()] {
+                outputs.push((fds, arch_size_of!(Arch, fd_set)));
+            }
+            outputs.push((regs.arg5(), arch_size_of!(Arch, timeval)));
+        }
+    } else if syscallno == Arch::PSELECT6 {
+        for &fds in &[regs.arg2(), regs.arg3(), regs.arg4()] {
+            outputs.push((fds, arch_size_of!(Arch, fd_set)));
+        }
+        outputs.push((regs.arg5(), arch_size_of!(Arch, timespec)));
+    } else if syscallno == Arch::EPOLL_WAIT || syscallno == Arch::EPOLL_PWAIT {
+        outputs.push((regs.arg2(), result * arch_size_of!(Arch, epoll_event)));
+    } else if syscallno == Arch::WAITPID || syscallno == Arch::WAIT4 {
+        outputs.push((regs.arg2(), size_of::<i32>()));
+        if syscallno == Arch::WAIT4 {
+            outputs.push((regs.arg4(), arch_size_of!(Arch, rusage)));
+        }
+    } else if syscallno == Arch::WAITID {
+        outputs.push((regs.arg3(), size_of::<Arch::siginfo_t>()));
+        outputs.push((regs.arg5(), arch_size_of!(Arch, rusage)));
+    } else if syscallno == Arch::RT_SIGACTION {
+        outputs.push((regs.arg3(), size_of::<Arch::kernel_sigaction>()));
+    } else if syscallno == Arch::RT_SIGPROCMASK {
+        outputs.push((regs.arg3(), regs.arg4()));
+    } else if syscallno == Arch::RT_SIGPENDING {
+        outputs.push((regs.arg1(), regs.arg2()));
+    } else if syscallno == Arch::RT_SIGTIMEDWAIT {
+        outputs.push((regs.arg2(), size_of::<Arch::siginfo_t>()));
+    } else if syscallno == Arch::GETRLIMIT || syscallno == Arch::UGETRLIMIT {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, rlimit)));
+    } else if syscallno == Arch::PRLIMIT64 {
+        outputs.push((regs.arg4(), arch_size_of!(Arch, rlimit64)));
+    } else if syscallno == Arch::GETRUSAGE {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, rusage)));
+    } else if syscallno == Arch::SYSINFO {
+        outputs.push((regs.arg1(), arch_size_of!(Arch, sysinfo)));
+    } else if syscallno == Arch::TIMES {
+        outputs.push((regs.arg1(), arch_size_of!(Arch, tms)));
+    } else if syscallno == Arch::GETITIMER {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, itimerval)));
+    } else if syscallno == Arch::TIMERFD_GETTIME {
+        outputs.push((regs.arg2(), arch_size_of!(Arch, itimerspec)));
+    } else if syscallno == Arch::GETCWD {
+        outputs.push((regs.arg1(), result));
+    } else if syscallno == Arch::READLINK {
+        outputs.push((regs.arg2(), result));
+    } else if syscallno == Arch::READLINKAT {
+        outputs.push((regs.arg3(), result));
+    } else if syscallno == Arch::GETGROUPS32 || (is_x64 && syscallno == Arch::GETGROUPS) {
+        outputs.push((regs.arg2(), result * size_of::<u32>()));
+    } else if syscallno == Arch::GETRESUID32
+        || syscallno == Arch::GETRESGID32
+        || (is_x64 && (syscallno == Arch::GETRESUID || syscallno == Arch::GETRESGID))
+    {
+        for &id in &[regs.arg1(), regs.arg2(), regs.arg3()] {
+            outputs.push((id, size_of::<u32>()));
+        }
+    } else if syscallno == Arch::SCHED_GETAFFINITY {
+        let pid = regs.arg1() as pid_t;
+        if pid == 0 || t.session().find_task_from_rec_tid(pid).is_some() {
+            // Report the CPUs we pretend the tracees have, see
+            // `Scheduler::pretend_affinity_mask()`. Replay reads it from the trace.
+            let mask = *t
+                .session()
+                .as_record()
+                .unwrap()
+                .scheduler()
+                .pretend_affinity_mask();
+            let bytes = unsafe { &*u8_raw_slice(&mask) };
+            let len = min(result, bytes.len());
+            write_mem(t, RemotePtr::<u8>::from(regs.arg3()), &bytes[..len], None);
+        }
+        outputs.push((regs.arg3(), result));
+    } else if syscallno == Arch::IOCTL {
+        let request = regs.arg2() as u64;
+        if request == TCGETS as u64 {
+            outputs.push((regs.arg3(), arch_size_of!(Arch, termios)));
+        } else if request == TIOCGWINSZ as u64 {
+            outputs.push((regs.arg3(), arch_size_of!(Arch, winsize)));
+        } else if request == FIONREAD as u64 {
+            outputs.push((regs.arg3(), size_of::<i32>()));
+        }
+    }
+    record_outputs(t, &outputs);
+}
+
+fn record_outputs(t: &mut RecordTask, outputs: &[(usize, usize)]) {
+    for &(addr, len) in outputs {
+        if len > 0 {
+            t.record_remote(RemotePtr::from(addr), len);
+        }
+    }
+}
+
+/// The `struct iovec`s at `addr`.
+fn read_iovecs<Arch: Architecture>(t: &mut RecordTask, addr: usize, count: usize) -> Vec<Range> {
+    let word_size = size_of::<Arch::unsigned_word>();
+    let words = read_mem(t, RemotePtr::<u8>::from(addr), 2 * word_size * count, None);
+    let word = |i: usize| {
+        let mut bytes = [0u8; size_of::<usize>()];
+        bytes[0..word_size].copy_from_slice(&words[i * word_size..(i + 1) * word_size]);
+        usize::from_le_bytes(bytes)
+    };
+    (0..count)
+        .map(|i| Range::new(RemotePtr::from(word(2 * i)), word(2 * i + 1)))
+        .collect()
+}
+
+/// The outputs of a syscall that writes a socket address (or option) of at most
+/// `*len_addr` bytes to `addr` and its real length to `len_addr`.
+fn sockaddr_output(t: &mut RecordTask, addr: usize, len_addr: usize) -> Vec<(usize, usize)> {
+    if addr == 0 || len_addr == 0 {
+        return Vec::new();
+    }
+    let len = read_val_mem(t, RemotePtr::<u32>::from(len_addr), None) as usize;
+    vec![
+        (len_addr, size_of::<u32>()),
+        (addr, min(len, SOCKADDR_STORAGE_SIZE)),
+    ]
+}
+
+/// Record the new address space of `t`, which just execed. This must be kept in
+/// sync with replay_syscall's process_execve: the stack comes first, the
+/// scratch memory last.
+fn process_execve<Arch: Architecture>(t: &mut RecordTask) {
+    Task::post_exec_syscall(t);
+    let fds = t.fd_table_shr_ptr();
+    let fds_to_close = fds.borrow_mut().fds_to_close_after_exec(t);
+    t.ev_mut().syscall_mut().exec_fds_to_close = fds_to_close;
+
+    let kms: Vec<KernelMapping> = KernelMapIterator::new(t).collect();
+    for km in kms.iter().filter(|km| km.is_stack()) {
+        record_exec_mapping(t, km);
+    }
+    for km in kms.iter().filter(|km| km.is_vvar()) {
+        // The vdso is patched to make real syscalls, and replay can't reproduce
+        // the kernel's time data anyway.
+        let mut remote = AutoRemoteSyscalls::new(t);
+        rd_infallible_syscall!(remote, Arch::MUNMAP, km.start().as_usize(), km.size());
+        remote
+            .task()
+            .vm_shr_ptr()
+            .unmap(remote.task(), km.start(), km.size());
+    }
+
+    let exe_name = t.vm().exe_image().to_owned();
+    let mut exe_base = RemotePtr::null();
+    for km in &kms {
+        if km.start() == AddressSpace::rd_page_start()
+            || km.start() == AddressSpace::preload_thread_locals_start()
+            || km.is_stack()
+            || km.is_vsyscall()
+            || km.is_vvar()
+        {
+            continue;
+        }
+        if exe_base.is_null() && km.fsname() == exe_name {
+            exe_base = km.start();
+        }
+        record_exec_mapping(t, km);
+    }
+    ed_assert!(t, !exe_base.is_null(), "No mapping of {:?}", exe_name);
+
+    let cmd_line: Vec<OsString> = fs::read(format!("/proc/{}/cmdline", t.tid))
+        .unwrap_or_default()
+        .split(|&c| c == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| OsStr::from_bytes(arg).to_owned())
+        .collect();
+    let exec_event = TraceTaskEvent::for_exec(t.rec_tid, &exe_name, &cmd_line, exe_base);
+    t.trace_writer_mut().write_task_event(&exec_event);
+
+    init_scratch_memory(t);
+
+    // The kernel zeroes the rest of the last page of each file-backed segment,
+    // which replay doesn't get from the file.
+    for km in &kms {
+        if !km.is_real_device() || !km.prot().contains(ProtFlags::PROT_WRITE) {
+            continue;
+        }
+        if let Ok(st) = stat(km.fsname()) {
+            let file_end = km.start().as_usize() as u64 + st.st_size as u64
+                - min(st.st_size as u64, km.file_offset_bytes());
+            if file_end > km.start().as_usize() as u64 && file_end < km.end().as_usize() as u64 {
+                let file_end = file_end as usize;
+                let len = ceil_page_size(file_end) - file_end;
+                t.record_remote(RemotePtr::from(file_end), len);
+            }
+        }
+    }
+
+    t.vm_shr_ptr().save_auxv(t);
+    let vm = t.vm_shr_ptr();
+    vm.with_monkeypatcher(|monkeypatcher| monkeypatcher.patch_after_exec(t));
+}
+
+/// Record `km`, one of the mappings `t` got from the kernel at exec.
+fn record_exec_mapping(t: &mut RecordTask, km: &KernelMapping) {
+    let st = if km.fsname().as_bytes().starts_with(b"/") {
+        stat(km.fsname()).unwrap_or_else(|_| km.fake_stat())
+    } else {
+        km.fake_stat()
+    };
+    let record_in_trace = t
+        .session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, km, &st, &[], Some(MappingOrigin::ExecMapping), None);
+    if record_in_trace == RecordInTrace::RecordInTrace {
+        let size = if st.st_ino != 0 && !km.is_stack() {
+            let file_end = (st.st_size as u64).saturating_sub(km.file_offset_bytes()) as usize;
+            min(file_end, km.size())
+        } else {
+            km.size()
+        };
+        t.record_remote(km.start(), size);
+    }
+}
+
+/// Record the heap change of the brk() `t` just exited. This must be kept in sync
+/// with replay_syscall's process_brk.
+fn process_brk(t: &mut RecordTask) {
+    let result = RemotePtr::<Void>::from(t.regs_ref().syscall_result());
+    let old_brk = ceil_page_size(t.vm().current_brk());
+    let new_brk = ceil_page_size(result);
+    let km = if old_brk < new_brk {
+        // Read the kernel's mapping. There doesn't seem to be any other way to get
+        // the correct prot bits for heaps. Usually it's READ|WRITE but there seem
+        // to be exceptions depending on system settings.
+        let kernel_info = AddressSpace::read_kernel_mapping(t, old_brk);
+        ed_assert!(t, kernel_info.device() == KernelMapping::NO_DEVICE);
+        ed_assert!(t, kernel_info.inode() == KernelMapping::NO_INODE);
+        kernel_info.subrange(old_brk, new_brk)
+    } else {
+        // A dummy mapping with no flags stands for an unmap (or no change).
+        KernelMapping::new_with_opts(
+            new_brk,
+            old_brk,
+            OsStr::new(""),
+            KernelMapping::NO_DEVICE,
+            KernelMapping::NO_INODE,
+            ProtFlags::empty(),
+            MapFlags::empty(),
+            0,
+        )
+    };
+    let record_in_trace = t
+        .session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+    ed_assert!(t, record_in_trace == RecordInTrace::DontRecordInTrace);
+    t.vm().brk(t, result, km.prot());
+}
+
+/// Tell the address space about the mremap() `t` just exited and record the
+/// moved and the new parts of the mapping. This must be kept in sync with
+/// replay_syscall's process_mremap.
+fn process_mremap(t: &mut RecordTask) {
+    let r = t.regs_ref().clone();
+    let old_addr = RemotePtr::<Void>::from(r.arg1());
+    let old_size = ceil_page_size(r.arg2());
+    let new_addr = RemotePtr::<Void>::from(r.syscall_result());
+    let new_size = ceil_page_size(r.arg3());
+    t.vm().remap(t, old_addr, old_size, new_addr, new_size);
+
+    let m = t.vm().mapping_of(new_addr).unwrap().clone();
+    let km = m.map.subrange(new_addr, new_addr + min(old_size, new_size));
+    let st = m.mapped_file_stat.unwrap_or_else(|| km.fake_stat());
+    t.session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &st, &[], Some(MappingOrigin::RemapMapping), None);
+    if new_size <= old_size {
+        return;
+    }
+
+    let km = m.map.subrange(new_addr + old_size, new_addr + new_size);
+    let record_in_trace = t
+        .session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &st, &[], None, None);
+    if record_in_trace == RecordInTrace::RecordInTrace {
+        let file_end = (st.st_size as u64).saturating_sub(km.file_offset_bytes()) as usize;
+        t.record_remote(km.start(), min(file_end, km.size()));
+    }
+}
+
+/// Attach a FileMonitor to `fd`, which `t` just opened, if the file needs one,
+/// and note the opened file in the syscall event so that replay attaches the
+/// same monitor.
+/// Also capture the file if it was opened read-only and we're capturing files.
+/// This must be kept in sync with replay_syscall's handle_opened_files.
+fn handle_opened_file(t: &mut RecordTask, fd: i32, flags: i32) {
+    let pathname = t.file_name_of_fd(fd);
+    let st = t.stat_fd(fd);
+    if flags & O_ACCMODE == O_RDONLY && st.st_mode & S_IFMT == S_IFREG {
+        let readable_path = format!("/proc/{}/fd/{}", t.tid, fd);
+        t.session()
+            .as_record()
+            .unwrap()
+            .trace_writer_mut()
+            .capture_file(&pathname, OsStr::new(&readable_path), &st);
+    }
+
+    let file_monitor: Box<dyn FileMonitor> = if is_proc_fd_dir(&pathname) {
+        Box::new(ProcFdDirMonitor::new(t, &pathname))
+    } else {
+        return;
+    };
+
+    t.ev_mut().syscall_mut().opened.push(OpenedFd {
+        path: pathname,
+        fd,
+        device: st.st_dev,
+        inode: st.st_ino,
+    });
+    t.fd_table_shr_ptr()
+        .borrow_mut()
+        .add_monitor(t, fd, file_monitor);
+}
+
+/// Tell the address space about the mapping `t` just created with mmap(), record
+/// it and its contents if they can't be recovered from the file at replay
+/// time, and start monitoring it if it's shared with other processes.
+fn process_mmap(t: &mut RecordTask, length: usize, prot: i32, flags: i32, fd: i32, offset: u64) {
+    if t.regs_ref().syscall_failed() {
+        return;
+    }
+
+    let size = ceil_page_size(length);
+    let addr = RemotePtr::<Void>::from(t.regs_ref().syscall_result());
+    let prot = ProtFlags::from_bits_truncate(prot);
+    let flags = MapFlags::from_bits_truncate(flags);
+    if flags.contains(MapFlags::MAP_ANONYMOUS) {
+        let km = if !flags.contains(MapFlags::MAP_SHARED) {
+            // Anonymous mappings are by definition not backed by any file-like
+            // object, and are initialized to zero, so there's no nondeterminism
+            // to record.
+            t.vm().map(
+                t,
+                addr,
+                size,
+                prot,
+                flags,
+                0,
+                OsStr::new(""),
+                KernelMapping::NO_DEVICE,
+                KernelMapping::NO_INODE,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        } else {
+            ed_assert!(t, !flags.contains(MapFlags::MAP_GROWSDOWN));
+            // Read the kernel's mapping. There doesn't seem to be any other way to
+            // get the correct device/inode numbers. Fortunately anonymous shared
+            // mappings are rare.
+            let kernel_info = AddressSpace::read_kernel_mapping(t, addr);
+            t.vm().map(
+                t,
+                addr,
+                size,
+                prot,
+                flags,
+                0,
+                kernel_info.fsname(),
+                kernel_info.device(),
+                kernel_info.inode(),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        let record_in_trace = t
+            .session()
+            .as_record()
+            .unwrap()
+            .trace_writer_mut()
+            .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+        ed_assert!(t, record_in_trace == RecordInTrace::DontRecordInTrace);
+        return;
+    }
+
+    ed_assert!(t, fd >= 0, "Valid fd required for file mapping");
+    ed_assert!(t, !flags.contains(MapFlags::MAP_GROWSDOWN));
+
+    let km = AddressSpace::read_kernel_mapping(t, addr);
+    let st = t.stat_fd(fd);
+    let io_uring_monitor = t
+        .fd_table()
+        .get_monitor(fd)
+        .filter(|m| m.borrow().file_monitor_type() == FileMonitorType::IoUring);
+    // An io_uring's fd doesn't behave like a file that was mapped, so don't have
+    // replay monitor it as one.
+    let record_in_trace = t
+        .session()
+        .as_record()
+        .unwrap()
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &st, &[], None, Some(io_uring_monitor.is_some()));
+    if record_in_trace == RecordInTrace::RecordInTrace {
+        if io_uring_monitor.is_some() {
+            // The kernel has already initialized the ring. There is no file size.
+            t.record_remote(addr, km.size());
+        } else {
+            let file_end = (st.st_size as u64).saturating_sub(km.file_offset_bytes()) as usize;
+            t.record_remote(addr, min(file_end, km.size()));
+        }
+    }
+    t.vm().map(
+        t,
+        addr,
+        size,
+        prot,
+        flags,
+        offset,
+        km.fsname(),
+        km.device(),
+        km.inode(),
+        Some(st),
+        Some(&km),
+        None,
+        None,
+        None,
+    );
+
+    if let Some(monitor) = io_uring_monitor {
+        monitor
+            .borrow_mut()
+            .as_io_uring_monitor_mut()
+            .unwrap()
+            .did_map(addr, offset);
+    }
+
+    let file_name = t.file_name_of_fd(fd);
+    let m = t.vm().mapping_of(addr).unwrap().clone();
+    MonitoredSharedMemory::maybe_monitor(t, &file_name, &m, fd, offset);
+}
+
+/// Look for a tracee (or, with WUNTRACED, a group-stopped child) whose pending emulated
+/// stop `t`'s wait can report right away.
+fn maybe_emulate_wait(t: &RecordTask, syscall_state: &mut TaskSyscallState, options: i32) -> bool {
+    for tracee in t.emulated_ptrace_tracees.iter() {
+        let tracee_ref = tracee.borrow();
+        let rtracee = tracee_ref.as_record_task().unwrap();
+        if t.is_waiting_for_ptrace(rtracee) && rtracee.emulated_stop_pending {
+            emulate_wait_for(syscall_state, rtracee.tid);
+            return true;
+        }
+    }
+    if options & WUNTRACED != 0 {
+        for child_tg in t.thread_group().children().iter() {
+            for child in child_tg.borrow().task_set().iter() {
+                let child_ref = child.borrow();
+                let rchild = child_ref.as_record_task().unwrap();
+                if rchild.emulated_stop_type == EmulatedStopType::GroupStop
+                    && rchild.emulated_stop_pending
+                    && t.is_waiting_for(rchild)
+                {
+                    emulate_wait_for(syscall_state, rchild.tid);
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn emulate_wait_for(syscall_state: &mut TaskSyscallState, tid: pid_t) {
+    log!(LogDebug, "Emulating wait for {}", tid);
+    syscall_state.emulate_wait_for_child = Some(tid);
+    syscall_state.emulate_result(tid as isize);
+}
+
+/// The tracee `tracer` is allowed to issue a ptrace request against, if any.
+fn verify_ptrace_target(
+    tracer: &RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    pid: pid_t,
+) -> Option<TaskSharedPtr> {
+    if pid == tracer.rec_tid {
+        // A task can't be its own tracee. (It's also already borrowed.)
+        syscall_state.emulate_result(-ESRCH as isize);
+        return None;
+    }
+    if let Some(tracee) = tracer.session().find_task_from_rec_tid(pid) {
+        let is_stopped_tracee = {
+            let tracee_ref = tracee.borrow();
+            let rtracee = tracee_ref.as_record_task().unwrap();
+            rtracee
+                .emulated_ptracer
+                .as_ref()
+                .map_or(false, |p| p.ptr_eq(&tracer.weak_self_ptr()))
+                && rtracee.emulated_stop_type != EmulatedStopType::NotStopped
+        };
+        if is_stopped_tracee {
+            return Some(tracee);
+        }
+    }
+    syscall_state.emulate_result(-ESRCH as isize);
+    None
+}
+
+fn prepare_ptrace_attach(
+    t: &RecordTask,
+    pid: pid_t,
+    syscall_state: &mut TaskSyscallState,
+) -> Option<TaskSharedPtr> {
+    if pid == t.rec_tid {
+        // Can't ptrace ourselves. Checked before looking up the tracee because
+        // `t` is already borrowed.
+        syscall_state.emulate_result(-EPERM as isize);
+        return None;
+    }
+    let tracee = match t.session().find_task_from_rec_tid(pid) {
+        Some(tracee) => tracee,
+        None => {
+            syscall_state.emulate_result(-ESRCH as isize);
+            return None;
+        }
+    };
+    let already_traced = {
+        let tracee_ref = tracee.borrow();
+        let rtracee = tracee_ref.as_record_task().unwrap();
+        rtracee.emulated_ptracer.is_some() || rtracee.tgid() == t.tgid()
+    };
+    if already_traced {
+        // Can't ptrace a task twice, or a task in our own thread group.
+        syscall_state.emulate_result(-EPERM as isize);
+        return None;
+    }
+    Some(tracee)
+}
+
+/// Emulate a resume of `tracee` by its ptracer, delivering `sig` if nonzero.
+fn prepare_ptrace_cont(tracee: &mut RecordTask, sig: i32, command: u32) -> bool {
+    if sig < 0 || sig >= 0x80 {
+        return false;
+    }
+    if sig != 0 {
+        let si: siginfo_t = tracee.take_ptrace_signal_siginfo(sig);
+        log!(
+            LogDebug,
+            "Doing ptrace resume with signal {}",
+            signal_name(sig)
+        );
+        // Treat signal as nondeterministic; it won't happen just by
+        // replaying the tracee.
+        let disposition =
+            tracee.sig_resolved_disposition(sig, SignalDeterministic::NondeterministicSig);
+        tracee.push_event(Event::new_signal_event(
+            EventType::EvSignal,
+            SignalEventData::new(&si, SignalDeterministic::NondeterministicSig, disposition),
+        ));
+    }
+
+    tracee.emulated_stop_type = EmulatedStopType::NotStopped;
+    tracee.emulated_stop_pending = false;
+    tracee.emulated_stop_code = WaitStatus::default();
+    tracee.emulated_ptrace_cont_command = Some(command);
+    true
+}
+
+fn prepare_ptrace<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let pid = t.regs_ref().arg2_signed() as pid_t;
+    let request = t.regs_ref().arg1() as u32;
+    let addr = t.regs_ref().arg3();
+    let data = t.regs_ref().arg4();
+    let word_size = size_of::<Arch::unsigned_word>();
+    log!(
+        LogDebug,
+        "{}: {} of {}",
+        t.tid,
+        ptrace_req_name(request),
+        pid
+    );
+
+    let mut emulate = true;
+    match request {
+        PTRACE_TRACEME => {
+            // Acquire our parent as tracer. This only works if the parent is being recorded.
+            let ppid = t.get_parent_pid();
+            match t.session().find_task_from_rec_tid(ppid) {
+                Some(tracer) if t.emulated_ptracer.is_none() => {
+                    t.set_emulated_ptracer(tracer.borrow_mut().as_record_task_mut().unwrap());
+                    t.emulated_ptrace_seized = false;
+                    t.emulated_ptrace_options = Some(0);
+                    syscall_state.emulate_result(0);
+                }
+                _ => syscall_state.emulate_result(-EPERM as isize),
+            }
+        }
+        PTRACE_ATTACH => {
+            if let Some(tracee) = prepare_ptrace_attach(t, pid, syscall_state) {
+                let mut tracee_ref = tracee.borrow_mut();
+                let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                rtracee.set_emulated_ptracer(t);
+                rtracee.emulated_ptrace_seized = false;
+                rtracee.emulated_ptrace_options = Some(0);
+                syscall_state.emulate_result(0);
+                if rtracee.emulated_stop_type == EmulatedStopType::NotStopped {
+                    // Send SIGSTOP to the tracee. It turns into an emulated ptrace stop
+                    // when rd sees it.
+                    rtracee.tgkill(SIGSTOP);
+                } else {
+                    // The tracee is already in a group stop, so a SIGSTOP wouldn't
+                    // do anything. Stop it for us directly.
+                    let mut si: siginfo_t = Default::default();
+                    si.si_signo = SIGSTOP;
+                    rtracee.save_ptrace_signal_siginfo(&si);
+                    rtracee.force_emulate_ptrace_stop(WaitStatus::for_stop_sig(SIGSTOP));
+                    drop(tracee_ref);
+                    t.send_synthetic_sigchld_if_necessary();
+                }
+            }
+        }
+        PTRACE_SEIZE => {
+            if let Some(tracee) = prepare_ptrace_attach(t, pid, syscall_state) {
+                if addr != 0 {
+                    syscall_state.emulate_result(-EIO as isize);
+                } else if data as u32 & !PTRACE_O_MASK_SUPPORTED != 0 {
+                    syscall_state.emulate_result(-EINVAL as isize);
+                } else {
+                    let mut tracee_ref = tracee.borrow_mut();
+                    let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                    rtracee.set_emulated_ptracer(t);
+                    rtracee.emulated_ptrace_seized = true;
+                    rtracee.emulated_ptrace_options = Some(data as u32);
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_OLDSETOPTIONS | PTRACE_SETOPTIONS => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                if data as u32 & !PTRACE_O_MASK_SUPPORTED != 0 {
+                    syscall_state.emulate_result(-EINVAL as isize);
+                } else {
+                    let mut tracee_ref = tracee.borrow_mut();
+                    tracee_ref
+                        .as_record_task_mut()
+                        .unwrap()
+                        .emulated_ptrace_options = Some(data as u32);
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_GETEVENTMSG => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                let msg = tracee
+                    .borrow()
+                    .as_record_task()
+                    .unwrap()
+                    .emulated_ptrace_event_msg
+                    .to_le_bytes();
+                write_word(t, data, &msg[0..word_size]);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_GETSIGINFO => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                if Arch::arch() != NativeArch::arch() {
+                    log!(
+                        LogDebug,
+                        "PTRACE_GETSIGINFO for a non-native tracer isn't supported yet"
+                    );
+                    syscall_state.emulate_result(-EIO as isize);
+                } else {
+                    let si: siginfo_t = tracee
+                        .borrow()
+                        .as_record_task()
+                        .unwrap()
+                        .get_saved_ptrace_siginfo()
+                        .clone();
+                    write_word(t, data, unsafe { &*u8_raw_slice(&si) });
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_GETREGS => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                let regs = tracee.borrow().regs_ref().get_ptrace_for_arch(Arch::arch());
+                ed_assert!(t, regs.len() == size_of::<Arch::user_regs_struct>());
+                write_word(t, data, &regs);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_SETREGS => {
+            if verify_ptrace_target(t, syscall_state, pid).is_some() {
+                // The actual register effects are performed by
+                // task_common::on_syscall_exit_arch().
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_PEEKUSER => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                // The actual syscall returns the data via the 'data' out-parameter.
+                // The behavior of returning the data as the system call result is
+                // provided by the glibc wrapper.
+                if addr & (word_size - 1) != 0 || addr >= size_of::<Arch::user>() {
+                    syscall_state.emulate_result(-EIO as isize);
+                } else {
+                    let value = peek_user::<Arch>(&tracee, addr);
+                    write_word(t, data, &value.to_le_bytes()[0..word_size]);
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_POKEUSER => {
+            if verify_ptrace_target(t, syscall_state, pid).is_some() {
+                if addr & (word_size - 1) != 0 || addr >= size_of::<Arch::user>() {
+                    syscall_state.emulate_result(-EIO as isize);
+                } else {
+                    // The actual register effects are performed by
+                    // task_common::on_syscall_exit_arch().
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                // The actual syscall returns the data via the 'data' out-parameter.
+                // The behavior of returning the data as the system call result is
+                // provided by the glibc wrapper.
+                let mut ok = true;
+                let v = read_mem(
+                    tracee.borrow_mut().as_mut(),
+                    RemotePtr::<u8>::from(addr),
+                    word_size,
+                    Some(&mut ok),
+                );
+                if ok {
+                    write_word(t, data, &v);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result(-EIO as isize);
+                }
+            }
+        }
+        PTRACE_POKETEXT | PTRACE_POKEDATA => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                let mut tracee_ref = tracee.borrow_mut();
+                let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                let word = &data.to_le_bytes()[0..word_size];
+                let mut ok = true;
+                let addr = RemotePtr::<u8>::from(addr);
+                write_mem(rtracee, addr, word, Some(&mut ok));
+                if ok {
+                    // Since we're recording data that might not be for `t`, we have to
+                    // handle this specially during replay.
+                    rtracee.record_local(RemotePtr::cast(addr), word);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result(-EIO as isize);
+                }
+            }
+        }
+        PTRACE_CONT
+        | PTRACE_SYSCALL
+        | PTRACE_SINGLESTEP
+        | PTRACE_SYSEMU
+        | PTRACE_SYSEMU_SINGLESTEP => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                let mut tracee_ref = tracee.borrow_mut();
+                let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                if prepare_ptrace_cont(rtracee, data as i32, request) {
+                    rtracee.set_syscallbuf_locked(request != PTRACE_CONT);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result(-EIO as isize);
+                }
+            }
+        }
+        PTRACE_DETACH => {
+            if let Some(tracee) = verify_ptrace_target(t, syscall_state, pid) {
+                let mut tracee_ref = tracee.borrow_mut();
+                let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                rtracee.set_syscallbuf_locked(false);
+                rtracee.emulated_ptrace_options = None;
+                rtracee.emulated_ptrace_cont_command = None;
+                if prepare_ptrace_cont(rtracee, data as i32, request) {
+                    rtracee.emulated_ptrace_cont_command = None;
+                    rtracee.clear_emulated_ptracer(t);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result(-EIO as isize);
+                }
+            }
+        }
+        _ => {
+            // Let the kernel fail the request for us: the tracee isn't really
+            // being ptraced by `t`.
+            emulate = false;
+        }
+    }
+
+    if emulate {
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+    }
+    Switchable::PreventSwitch
+}
+
+/// PTRACE_PEEKUSER of a word in `tracee`'s `struct user` at `offset`.
+fn peek_user<Arch: Architecture>(tracee: &TaskSharedPtr, offset: usize) -> usize {
+    let word_size = size_of::<Arch::unsigned_word>();
+    let tracee_ref = tracee.borrow();
+    let regs_size = size_of::<Arch::user_regs_struct>();
+    // The registers are at the start of `struct user`, the debug registers at the end.
+    let debugreg_offset = size_of::<Arch::user>() - 8 * word_size;
+    if offset < regs_size {
+        let regs = tracee_ref.regs_ref().get_ptrace_for_arch(Arch::arch());
+        let mut word = [0u8; size_of::<usize>()];
+        let end = min(offset + word_size, regs.len());
+        word[0..end - offset].copy_from_slice(&regs[offset..end]);
+        usize::from_le_bytes(word)
+    } else if offset >= debugreg_offset {
+        tracee_ref.get_debug_reg((offset - debugreg_offset) / word_size)
+    } else {
+        0
+    }
+}
+
+/// Write ptrace() output `bytes` to the tracer's `data` argument and record it.
+fn write_word(t: &mut RecordTask, data: usize, bytes: &[u8]) {
+    let dest = RemotePtr::<u8>::from(data);
+    write_mem(t, dest, bytes, None);
+    t.record_local(RemotePtr::<Void>::cast(dest), bytes);
+}
@@ -1,5 +1,6 @@
 use crate::{
     bindings::kernel::user_regs_struct as native_user_regs_struct,
+    divergence_bundle::write_divergence_bundle,
     gdb_register::*,
     kernel_abi::{x64, x86, SupportedArch, RD_NATIVE_ARCH},
     kernel_supplement::{ERESTARTNOHAND, ERESTARTNOINTR, ERESTARTSYS, ERESTART_RESTARTBLOCK},
@@ -282,6 +283,9 @@ impl Registers {
             mismatch_behavior,
         );
         if let Some(t) = maybe_t {
+            if bail_error && !match_ {
+                write_divergence_bundle(t, "register mismatch", &[(name1, regs1), (name2, regs2)]);
+            }
             ed_assert!(
                 t,
                 !bail_error || match_,
@@ -316,6 +320,19 @@ impl Registers {
         )
     }
 
+    /// The name and value of every readable register, in gdb register order.
+    pub fn register_values(&self) -> Vec<(&'static str, u64)> {
+        let mut result = Vec::new();
+        for rv in self.get_regs_info().values() {
+            match (self, rv.nbytes) {
+                (_, 0) => (),
+                (X86(regs), _) => result.push((rv.name, rv.u32_into_x86(regs) as u64)),
+                (X64(regs), _) => result.push((rv.name, rv.u64_into_x64(regs))),
+            }
+        }
+        result
+    }
+
     /// Write the value for register `regno` into `buf`, which should
     /// be large enough to hold any register supported by the target.
     /// Return the size of the register in bytes. If None is returned it
@@ -0,0 +1,178 @@
+//! Accounting of where replay time goes, for `rd replay -a --profile`.
+//!
+//! Instrumented code holds on to a `timer()` for as long as the work it wants to measure takes,
+//! or calls `count_singlestep()` etc. for things not worth timing. Unless `enable()` has been
+//! called these do nothing; in particular the name closure passed to `timer()` is not run.
+//!
+//! Timers nest, e.g. a replay step contains the ptrace requests it makes, so the totals of
+//! different categories overlap and should not be added up.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ProfileCategory {
+    /// One `ReplayTraceStepType` step in `ReplaySession::try_one_trace_step()`.
+    ReplayStep,
+    /// Replaying the exit of a recorded syscall in `rep_process_syscall()`.
+    Syscall,
+    /// A syscall injected into the tracee by `AutoRemoteSyscalls`.
+    RemoteSyscall,
+    Ptrace,
+    FastForward,
+    /// Reading and decompressing blocks of the trace in `CompressedReader`.
+    TraceRead,
+}
+
+const CATEGORIES: [(ProfileCategory, &str); 6] = [
+    (ProfileCategory::ReplayStep, "Replay steps"),
+    (ProfileCategory::Syscall, "Replayed syscalls"),
+    (
+        ProfileCategory::RemoteSyscall,
+        "Remote syscalls (AutoRemoteSyscalls)",
+    ),
+    (ProfileCategory::Ptrace, "Ptrace requests"),
+    (ProfileCategory::FastForward, "Fast forward"),
+    (ProfileCategory::TraceRead, "Trace reading"),
+];
+
+#[derive(Copy, Clone, Default)]
+struct Stat {
+    count: u64,
+    total: Duration,
+}
+
+struct Profile {
+    started: Instant,
+    stats: HashMap<(ProfileCategory, String), Stat>,
+    singlesteps: u64,
+    decompressed_bytes: u64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static PROFILE: RefCell<Option<Profile>> = RefCell::new(None);
+}
+
+/// Start profiling. Everything before this isn't accounted for.
+pub fn enable() {
+    PROFILE.with(|p| {
+        *p.borrow_mut() = Some(Profile {
+            started: Instant::now(),
+            stats: HashMap::new(),
+            singlesteps: 0,
+            decompressed_bytes: 0,
+        })
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn with_profile<F: FnOnce(&mut Profile)>(f: F) {
+    PROFILE.with(|p| {
+        if let Some(profile) = p.borrow_mut().as_mut() {
+            f(profile)
+        }
+    });
+}
+
+/// Accounts the time until it is dropped to `category`/`name`.
+pub struct ProfileTimer {
+    category: ProfileCategory,
+    name: String,
+    start: Instant,
+}
+
+impl Drop for ProfileTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let key = (self.category, std::mem::take(&mut self.name));
+        with_profile(|profile| {
+            let stat = profile.stats.entry(key).or_default();
+            stat.count += 1;
+            stat.total += elapsed;
+        });
+    }
+}
+
+pub fn timer<F: FnOnce() -> String>(category: ProfileCategory, name: F) -> Option<ProfileTimer> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(ProfileTimer {
+        category,
+        name: name(),
+        start: Instant::now(),
+    })
+}
+
+pub fn count_singlestep() {
+    if is_enabled() {
+        with_profile(|profile| profile.singlesteps += 1);
+    }
+}
+
+pub fn count_decompressed_bytes(bytes: usize) {
+    if is_enabled() {
+        with_profile(|profile| profile.decompressed_bytes += bytes as u64);
+    }
+}
+
+/// Write everything accounted since `enable()`, most expensive first within each category.
+pub fn write_report(out: &mut dyn Write) -> io::Result<()> {
+    let mut result = Ok(());
+    with_profile(|profile| result = write_profile(profile, out));
+    result
+}
+
+fn write_profile(profile: &Profile, out: &mut dyn Write) -> io::Result<()> {
+    let wall = profile.started.elapsed();
+    write!(
+        out,
+        "[Profile] wall time {:.3}s, {} singlesteps, {} bytes of trace decompressed\n",
+        wall.as_secs_f64(),
+        profile.singlesteps,
+        profile.decompressed_bytes
+    )?;
+    for &(category, title) in CATEGORIES.iter() {
+        let mut rows: Vec<(&str, Stat)> = profile
+            .stats
+            .iter()
+            .filter(|((c, _), _)| *c == category)
+            .map(|((_, name), stat)| (name.as_str(), *stat))
+            .collect();
+        if rows.is_empty() {
+            continue;
+        }
+        rows.sort_by(|a, b| b.1.total.cmp(&a.1.total));
+
+        write!(
+            out,
+            "\n{:<40} {:>10} {:>12} {:>12} {:>7}\n",
+            title, "count", "total(ms)", "mean(us)", "%wall"
+        )?;
+        for (name, stat) in rows {
+            let total_secs = stat.total.as_secs_f64();
+            write!(
+                out,
+                "  {:<38} {:>10} {:>12.3} {:>12.3} {:>6.1}%\n",
+                name,
+                stat.count,
+                total_secs * 1e3,
+                total_secs * 1e6 / stat.count as f64,
+                100.0 * total_secs / wall.as_secs_f64()
+            )?;
+        }
+    }
+    Ok(())
+}
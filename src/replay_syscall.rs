@@ -13,6 +13,7 @@ use crate::{
     },
     bindings::{
         kernel::{user_desc, SHMAT, SHMDT},
+        perf_event::perf_event_attr,
         ptrace::{
             PTRACE_CONT,
             PTRACE_DETACH,
@@ -25,13 +26,16 @@ use crate::{
             PTRACE_SYSEMU_SINGLESTEP,
         },
     },
+    divergence_bundle::write_divergence_bundle,
     emu_fs::EmuFileSharedPtr,
     file_monitor::{
         base_file_monitor::BaseFileMonitor,
+        io_uring_monitor::IoUringMonitor,
         mmapped_file_monitor::MmappedFileMonitor,
         proc_fd_dir_monitor::ProcFdDirMonitor,
         proc_mem_monitor::ProcMemMonitor,
         stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
         FileMonitor,
         FileMonitorType,
     },
@@ -51,10 +55,11 @@ use crate::{
         RD_NATIVE_ARCH,
     },
     kernel_metadata::{is_sigreturn, shm_flags_to_mmap_prot, syscall_name},
-    kernel_supplement::{ARCH_GET_CPUID, ARCH_SET_CPUID},
+    kernel_supplement::{io_uring_params, open_how, ARCH_GET_CPUID, ARCH_SET_CPUID},
     log::LogLevel::LogDebug,
     registers::{with_converted_registers, Registers},
     remote_ptr::{RemotePtr, Void},
+    replay_profiler::{self, ProfileCategory},
     scoped_fd::ScopedFd,
     seccomp_filter_rewriter::SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO,
     session::{
@@ -216,6 +221,18 @@ fn __ptrace_cont(
 
     // check if we are synchronized with the trace -- should never fail
     let current_syscall = t.regs_ref().original_syscallno() as i32;
+    if current_syscall != expect_syscallno && current_syscall != expect_syscallno2 {
+        let rec_regs = t.current_trace_frame().regs_ref().clone();
+        write_divergence_bundle(
+            t,
+            &format!(
+                "expected syscall {}, but at {}",
+                syscall_name(expect_syscallno, syscall_arch),
+                syscall_name(current_syscall, syscall_arch)
+            ),
+            &[("replaying", t.regs_ref()), ("recorded", &rec_regs)],
+        );
+    }
     // DIFF NOTE: Minor differences arising out of maybe_dump_written_string() behavior.
     ed_assert!(
         t,
@@ -604,6 +621,9 @@ pub fn rep_process_syscall(t: &mut ReplayTask, step: &mut ReplayTraceStep) {
         arch = trace_frame.event().syscall_event().arch();
         trace_regs = trace_frame.regs_ref().clone()
     }
+    let _timer = replay_profiler::timer(ProfileCategory::Syscall, || {
+        syscall_name(t.current_trace_frame().event().syscall_event().number, arch)
+    });
     with_converted_registers(&trace_regs, arch, |converted_regs| {
         rd_arch_function_selfless!(rep_process_syscall_arch, arch, t, step, converted_regs)
     })
@@ -804,7 +824,30 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     }
 
     if nsys == Arch::PERF_EVENT_OPEN {
-        unimplemented!();
+        let fd = trace_regs.syscall_result_signed() as i32;
+        let maybe_target_tuid = if fd >= 0 {
+            VirtualPerfCounterMonitor::target_tuid(t, trace_regs.arg2_signed() as pid_t)
+        } else {
+            None
+        };
+        if let Some(target_tuid) = maybe_target_tuid {
+            let attr = read_val_mem(
+                t,
+                RemotePtr::<perf_event_attr>::from(trace_regs.arg1()),
+                None,
+            );
+            if VirtualPerfCounterMonitor::should_virtualize(
+                &attr,
+                trace_regs.arg3_signed() as i32,
+                trace_regs.arg4_signed() as i32,
+                trace_regs.arg5() as u64,
+            ) {
+                let monitor = VirtualPerfCounterMonitor::new(t, target_tuid, &attr);
+                t.fd_table_shr_ptr()
+                    .borrow_mut()
+                    .add_monitor(t, fd, Box::new(monitor));
+            }
+        }
     }
 
     if nsys == Arch::PERF_EVENT_OPEN
@@ -827,6 +870,28 @@ fn rep_process_syscall_arch<Arch: Architecture>(
         return;
     }
 
+    if nsys == Arch::OPENAT2 {
+        let how_ptr = RemotePtr::<open_how>::from(trace_regs.arg3());
+        let how = read_val_mem(t, how_ptr, None);
+        handle_opened_files(t, how.flags as i32);
+        return;
+    }
+
+    // io_uring_enter() and io_uring_register() need nothing special. What the
+    // kernel wrote to the rings and to the operations' buffers is in the data
+    // records.
+    if nsys == Arch::IO_URING_SETUP {
+        // We need the io_uring_params the kernel filled in.
+        t.apply_all_data_records_from_trace();
+        let params_ptr = RemotePtr::<io_uring_params>::from(trace_regs.arg2());
+        let params = read_val_mem(t, params_ptr, None);
+        let fd = trace_regs.syscall_result_signed() as i32;
+        t.fd_table_shr_ptr()
+            .borrow_mut()
+            .add_monitor(t, fd, Box::new(IoUringMonitor::new(params)));
+        return;
+    }
+
     if nsys == Arch::WRITE || nsys == Arch::WRITEV {
         // write*() can be desched'd, but don't use scratch,
         // so we might have saved 0 bytes of scratch after a
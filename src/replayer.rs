@@ -0,0 +1,161 @@
+//! A small driver for replaying a trace from other Rust programs.
+//!
+//! `Replayer` owns a `ReplaySession` and replays it one trace frame at a time, calling back into
+//! a `ReplayCallbacks` implementation as frames complete. The callbacks get the live tracee as a
+//! `&mut dyn Task`, so they can look at registers and read tracee memory.
+
+use crate::{
+    assert_prerequisites,
+    event::{EventType, SyscallState},
+    kernel_abi::{
+        is_clone3_syscall,
+        is_clone_syscall,
+        is_execve_syscall,
+        is_fork_syscall,
+        is_vfork_syscall,
+    },
+    perf_counters::init_pmu,
+    session::{
+        replay_session::{Flags, ReplaySession, ReplayStatus},
+        session_inner::RunCommand,
+        task::Task,
+        Session,
+        SessionSharedPtr,
+    },
+    trace::trace_frame::{FrameTime, TraceFrame},
+    util::raise_resource_limits,
+};
+use libc::pid_t;
+use std::{
+    ffi::OsStr,
+    io::{self, ErrorKind},
+};
+
+/// Hooks called by `Replayer`. Every method has an empty default implementation so
+/// implementors only need to override what they are interested in.
+///
+/// Unless noted otherwise a hook is called after its frame has been replayed, when the task is
+/// in the state the recording was in at the end of the event.
+pub trait ReplayCallbacks {
+    /// Called for every trace frame, after the more specific hooks below. Frames that end with
+    /// their task gone (e.g. the task's exit) only get `on_exit`.
+    fn on_frame(&mut self, _t: &mut dyn Task, _frame: &TraceFrame) {}
+
+    /// A syscall has just returned. `syscallno` is for `frame.event().syscall_event().arch()`.
+    fn on_syscall_exit(&mut self, _t: &mut dyn Task, _frame: &TraceFrame, _syscallno: i32) {}
+
+    /// A signal `sig` has been received by `t`.
+    fn on_signal(&mut self, _t: &mut dyn Task, _frame: &TraceFrame, _sig: i32) {}
+
+    /// `t` has successfully exec'd; its address space is the new image.
+    fn on_exec(&mut self, _t: &mut dyn Task, _frame: &TraceFrame) {}
+
+    /// `t` has created a new task or process whose recorded tid is `child_rec_tid`.
+    fn on_clone(&mut self, _t: &mut dyn Task, _frame: &TraceFrame, _child_rec_tid: pid_t) {}
+
+    /// `t` is about to exit. Called *before* the frame is replayed, since the task no longer
+    /// exists afterwards.
+    fn on_exit(&mut self, _t: &mut dyn Task, _frame: &TraceFrame) {}
+}
+
+pub struct Replayer {
+    session: SessionSharedPtr,
+}
+
+impl Replayer {
+    /// Start replaying the trace in `trace_dir`, or the latest trace if `None`.
+    ///
+    /// This spawns the initial tracee. The caller should have checked that the trace can be
+    /// replayed on this machine; problems are reported the same way `rd replay` reports them.
+    pub fn new<T: AsRef<OsStr>>(trace_dir: Option<&T>, flags: Flags) -> Replayer {
+        assert_prerequisites(None);
+        init_pmu();
+        let session = ReplaySession::create(trace_dir, flags);
+        // Now that we've spawned the replay, raise our resource limits if possible.
+        raise_resource_limits();
+        Replayer { session }
+    }
+
+    pub fn session(&self) -> &ReplaySession {
+        self.session.as_replay().unwrap()
+    }
+
+    /// The time of the next frame to be replayed.
+    pub fn time(&self) -> FrameTime {
+        self.session().trace_reader().time()
+    }
+
+    /// Replay until the current frame is complete, calling the appropriate hooks.
+    /// Returns false once the end of the trace has been reached.
+    ///
+    /// Frames are replayed with `RunContinue`, so a breakpoint the caller set through the
+    /// session stops the replay in the middle of a frame. That is reported as an error.
+    pub fn step(&mut self, callbacks: &mut dyn ReplayCallbacks) -> io::Result<bool> {
+        let replay_session = self.session.as_replay().unwrap();
+        let frame: TraceFrame = replay_session.current_trace_frame().clone();
+        let before_time = replay_session.trace_reader().time();
+
+        if frame.event().event_type() == EventType::EvExit {
+            if let Some(t) = replay_session.find_task_from_rec_tid(frame.tid()) {
+                callbacks.on_exit(t.borrow_mut().as_mut(), &frame);
+            }
+        }
+
+        loop {
+            let result = replay_session.replay_step(RunCommand::RunContinue);
+            if result.status == ReplayStatus::ReplayExited {
+                return Ok(false);
+            }
+            if result.break_status.breakpoint_hit {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("Hit a breakpoint while replaying event {}", before_time),
+                ));
+            }
+            if replay_session.trace_reader().time() > before_time {
+                break;
+            }
+        }
+
+        let maybe_task = replay_session.find_task_from_rec_tid(frame.tid());
+        let t = match maybe_task {
+            Some(t) => t,
+            // Exited tasks. `on_exit` was already called above.
+            None => return Ok(true),
+        };
+        let mut task = t.borrow_mut();
+        let event = frame.event();
+        match event.event_type() {
+            EventType::EvSyscall if event.syscall_event().state == SyscallState::ExitingSyscall => {
+                let syscall = event.syscall_event();
+                let (no, arch) = (syscall.number, syscall.arch());
+                callbacks.on_syscall_exit(task.as_mut(), &frame, no);
+                let result = frame.regs_ref().syscall_result_signed();
+                if result >= 0 && !syscall.failed_during_preparation {
+                    if is_execve_syscall(no, arch) {
+                        callbacks.on_exec(task.as_mut(), &frame);
+                    } else if result > 0
+                        && (is_clone_syscall(no, arch)
+                            || is_clone3_syscall(no, arch)
+                            || is_fork_syscall(no, arch)
+                            || is_vfork_syscall(no, arch))
+                    {
+                        callbacks.on_clone(task.as_mut(), &frame, result as pid_t);
+                    }
+                }
+            }
+            EventType::EvSignal => {
+                callbacks.on_signal(task.as_mut(), &frame, event.signal_event().siginfo.si_signo);
+            }
+            _ => (),
+        }
+        callbacks.on_frame(task.as_mut(), &frame);
+        Ok(true)
+    }
+
+    /// Replay the rest of the trace.
+    pub fn run(&mut self, callbacks: &mut dyn ReplayCallbacks) -> io::Result<()> {
+        while self.step(callbacks)? {}
+        Ok(())
+    }
+}
@@ -18,11 +18,11 @@
 //! The scheduler only runs during recording. During replay we're just replaying
 //! the recorded scheduling decisions.
 //!
-//! The main interface to the scheduler is `get_next_thread`. This gets called
+//! The main interface to the scheduler is `reschedule`. This gets called
 //! after every rd event to decide which task to run next.
 //!
 //! The scheduler gives the current task a 'timeslice', a ticks deadline after
-//! which we will try to switch to another task. So `get_next_thread` first
+//! which we will try to switch to another task. So `reschedule` first
 //! checks whether the currently running task has exceeded that deadline. If
 //! not, and the current task is runnable, we schedule it again. If it's blocked
 //! or has exceeded its deadline, we search for another task to run:
@@ -34,22 +34,113 @@
 //!
 //! The main parameter to the scheduler is `max_ticks`, which controls the
 //! length of each timeslice.
+//!
+//! In chaos mode rd tries to provoke the schedules and memory layouts that
+//! cause rare bugs:
+//! -- Every so often, tasks are given random priorities: most get the normal
+//! priority, a few the low priority. This overrides setpriority(2).
+//! -- During randomly placed "high priority only" intervals, only the tasks
+//! with the highest priority run while any of them is runnable, starving the
+//! others.
+//! -- Timeslices have random lengths, some of them very short.
+//! -- The tracees are told they have a random number of cores (see
+//! `pretend_num_cores` and `pretend_affinity_mask`).
+//! -- mmaps are placed at random addresses (see
+//! `AddressSpace::chaos_mode_find_free_memory()`).
+//!
+//! The random choices come from one generator whose seed is saved in the trace
+//! metadata. That tells you how a chaos recording was set up, but doesn't make
+//! it reproducible: tracees make random and timing dependent choices of their
+//! own.
 
-use crate::{session::task::record_task::record_task::RecordTask, ticks::Ticks};
-use libc::cpu_set_t;
+use crate::{
+    event::Switchable,
+    log::LogLevel::{LogDebug, LogWarn},
+    session::{
+        record_session::RecordSession,
+        task::{
+            record_task::{record_task::RecordTask, EmulatedStopType},
+            Task,
+            TaskSharedPtr,
+            TaskSharedWeakPtr,
+        },
+        Session,
+    },
+    ticks::Ticks,
+    util::monotonic_now_sec,
+    wait_status::WaitStatus,
+    weak_ptr_set::WeakPtrWrap,
+};
+use libc::{
+    cpu_set_t,
+    pid_t,
+    sched_getaffinity,
+    sysconf,
+    waitpid,
+    EINTR,
+    _SC_NPROCESSORS_CONF,
+    __WALL,
+    CPU_ISSET,
+    CPU_SET,
+    CPU_ZERO,
+};
+use nix::errno::errno;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use std::{
-    cell::RefCell,
+    cmp::max,
     collections::{BTreeSet, VecDeque},
-    rc::{Rc, Weak},
+    mem::{size_of, zeroed},
+    rc::Rc,
 };
 
 // Tasks sorted by priority.
-type TaskPrioritySet = BTreeSet<(i32, Weak<RefCell<RecordTask>>)>;
-type TaskQueue = VecDeque<Weak<RefCell<RecordTask>>>;
+type TaskPrioritySet = BTreeSet<(i32, WeakPtrWrap<Box<dyn Task>>)>;
+type TaskQueue = VecDeque<TaskSharedWeakPtr>;
+
+/// The most cores we pretend to have in chaos mode.
+const CHAOS_MAX_PRETEND_NUM_CORES: u32 = 8;
+
+/// In chaos mode, the longest we keep the random task priorities, in seconds.
+const PRIORITIES_REFRESH_MAX_INTERVAL: f64 = 20.0;
+/// In chaos mode, the probability that a thread group's main thread gets the low
+/// priority. Main threads often coordinate the others, so starving them is
+/// interesting.
+const MAIN_THREAD_LOW_PRIORITY_PROBABILITY: f64 = 0.3;
+/// In chaos mode, the probability that any other task gets the low priority.
+const LOW_PRIORITY_PROBABILITY: f64 = 0.1;
+/// The priority given to tasks in chaos mode. Lower values run first.
+const CHAOS_NORMAL_PRIORITY: i32 = 0;
+const CHAOS_LOW_PRIORITY: i32 = 1;
 
+/// In chaos mode, the longest we keep the same high-priority-only intervals, in
+/// seconds.
+const HIGH_PRIORITY_ONLY_INTERVALS_REFRESH_MAX_INTERVAL: f64 = 10.0;
+/// The shortest high-priority-only interval, in seconds. Longer ones are this
+/// times a random power of 2, up to 2^`HIGH_PRIORITY_ONLY_DURATION_STEPS` - 1.
+const MIN_HIGH_PRIORITY_ONLY_DURATION: f64 = 0.001;
+const HIGH_PRIORITY_ONLY_DURATION_STEPS: u32 = 12;
+/// The high-priority-only intervals take at most this fraction of the time.
+const HIGH_PRIORITY_ONLY_FRACTION: f64 = 0.2;
+/// The period is the shortest it can be for `HIGH_PRIORITY_ONLY_FRACTION`
+/// times a random power of 2, up to 2^`HIGH_PRIORITY_ONLY_PERIOD_STEPS` - 1.
+const HIGH_PRIORITY_ONLY_PERIOD_STEPS: u32 = 6;
+
+/// What `Scheduler::reschedule()` did.
+#[derive(Copy, Clone, Default)]
+pub struct Rescheduled {
+    /// waitpid() was interrupted by a signal sent to rd, e.g. the user pressed Ctrl-C.
+    /// There is no current task to run.
+    pub interrupted_by_signal: bool,
+    /// The current task has a new status from waitpid() to process.
+    pub by_waitpid: bool,
+    pub started_new_timeslice: bool,
+}
+
+/// DIFF NOTE: rr's Scheduler holds a reference to its RecordSession. Here the
+/// RecordSession owns the Scheduler in a RefCell, so the methods that need to
+/// wait for tasks, which can reenter the scheduler through
+/// `expire_timeslice()`, take the session instead of `&mut self`.
 pub struct Scheduler {
-    // @TODO figure this out. Currently Session owns a scheduler
-    // session: RecordSession,
     /// Every task of this session is either in task_priority_set
     /// (when in_round_robin_queue is false), or in task_round_robin_queue
     /// (when in_round_robin_queue is true).
@@ -62,7 +153,7 @@ pub struct Scheduler {
 
     /// The currently scheduled task. This may be `None` if the last scheduled
     /// task has been destroyed.
-    current_: Option<Rc<RefCell<RecordTask>>>,
+    current_: Option<TaskSharedWeakPtr>,
     current_timeslice_end_: Ticks,
 
     /// At this time (or later) we should refresh these values.
@@ -75,7 +166,8 @@ pub struct Scheduler {
 
     max_ticks_: Ticks,
 
-    must_run_task: RecordTask,
+    /// If set, the next scheduling decision must pick this task.
+    must_run_task: Option<TaskSharedWeakPtr>,
 
     pretend_affinity_mask_: cpu_set_t,
     pretend_num_cores_: u32,
@@ -88,6 +180,13 @@ pub struct Scheduler {
 
     enable_poll: bool,
     last_reschedule_in_high_priority_only_interval: bool,
+
+    /// The CPU recording is bound to, if any. See `regenerate_affinity_mask()`.
+    bound_cpu: Option<u32>,
+    /// Source of every random decision in chaos mode.
+    random: StdRng,
+    /// The seed `random` was initialized with when chaos mode was enabled.
+    chaos_seed: Option<u64>,
 }
 
 /// Like most task schedulers, there are conflicting goals to balance. Lower
@@ -112,7 +211,608 @@ enum TickHowMany {
 }
 
 impl Scheduler {
+    /// `bound_cpu` is the CPU the tracees are bound to, if any.
+    pub fn new(bound_cpu: Option<u32>) -> Scheduler {
+        let mut scheduler = Scheduler {
+            task_priority_set: TaskPrioritySet::new(),
+            task_round_robin_queue: TaskQueue::new(),
+            current_: None,
+            current_timeslice_end_: 0,
+            high_priority_only_intervals_refresh_time: 0.0,
+            high_priority_only_intervals_start: 0.0,
+            high_priority_only_intervals_duration: 0.0,
+            high_priority_only_intervals_period: 0.0,
+            priorities_refresh_time: 0.0,
+            max_ticks_: TickHowMany::DefaultMaxTicks as Ticks,
+            must_run_task: None,
+            pretend_affinity_mask_: unsafe { zeroed() },
+            pretend_num_cores_: 1,
+            always_switch: false,
+            enable_chaos: false,
+            enable_poll: false,
+            last_reschedule_in_high_priority_only_interval: false,
+            bound_cpu,
+            random: StdRng::from_entropy(),
+            chaos_seed: None,
+        };
+        scheduler.regenerate_affinity_mask();
+        scheduler
+    }
+
+    /// Turn chaos mode on or off. When turning it on, the random decisions are made with a
+    /// generator seeded with `seed`, or a random seed if `None`. The seed used can be
+    /// retrieved with `chaos_seed()` so it can be saved with the trace.
+    pub fn set_enable_chaos(&mut self, enable_chaos: bool, seed: Option<u64>) {
+        self.enable_chaos = enable_chaos;
+        if enable_chaos {
+            let seed = seed.unwrap_or_else(rand::random);
+            log!(LogDebug, "Chaos mode enabled with seed {}", seed);
+            self.random = StdRng::seed_from_u64(seed);
+            self.chaos_seed = Some(seed);
+            // Pretend to have 1-8 cores at random.
+            self.pretend_num_cores_ = self.random.gen_range(1, CHAOS_MAX_PRETEND_NUM_CORES + 1);
+        } else {
+            self.chaos_seed = None;
+            self.pretend_num_cores_ = 1;
+        }
+        self.regenerate_affinity_mask();
+    }
+
+    pub fn is_chaos_enabled(&self) -> bool {
+        self.enable_chaos
+    }
+
+    pub fn chaos_seed(&self) -> Option<u64> {
+        self.chaos_seed
+    }
+
+    pub fn set_max_ticks(&mut self, max_ticks: Ticks) {
+        self.max_ticks_ = max_ticks;
+    }
+
+    pub fn max_ticks(&self) -> Ticks {
+        self.max_ticks_
+    }
+
+    pub fn set_always_switch(&mut self, always_switch: bool) {
+        self.always_switch = always_switch;
+    }
+
+    pub fn set_enable_poll(&mut self, enable_poll: bool) {
+        self.enable_poll = enable_poll;
+    }
+
+    /// The number of cores the tracees should think they have, e.g. for sysconf and
+    /// /proc/cpuinfo.
+    pub fn pretend_num_cores(&self) -> u32 {
+        self.pretend_num_cores_
+    }
+
+    /// The affinity mask to report to tracees calling sched_getaffinity.
+    pub fn pretend_affinity_mask(&self) -> &cpu_set_t {
+        &self.pretend_affinity_mask_
+    }
+
+    pub fn current(&self) -> Option<TaskSharedPtr> {
+        self.current_.as_ref().and_then(|w| w.upgrade())
+    }
+
+    /// Make `t` the current task, e.g. a task that has to be dealt with right
+    /// away whatever the scheduling policy says.
+    pub fn set_current(&mut self, t: &RecordTask) {
+        self.current_ = Some(t.weak_self_ptr());
+    }
+
+    pub fn current_timeslice_end(&self) -> Ticks {
+        self.current_timeslice_end_
+    }
+
     pub fn expire_timeslice(&mut self) {
         self.current_timeslice_end_ = 0;
     }
+
+    /// Register the newly created task `t`.
+    pub fn on_create(&mut self, t: &RecordTask) {
+        debug_assert!(!t.in_round_robin_queue);
+        self.task_priority_set
+            .insert((t.priority, WeakPtrWrap(t.weak_self_ptr())));
+    }
+
+    /// Forget the tasks that have been destroyed.
+    ///
+    /// DIFF NOTE: rr passes the task being destroyed. Here it is already gone
+    /// when the session hears about it, so we drop every dead entry instead.
+    pub fn on_destroy(&mut self) {
+        self.task_priority_set
+            .retain(|(_, w)| w.upgrade().is_some());
+        self.task_round_robin_queue
+            .retain(|w| w.upgrade().is_some());
+        if self.current().is_none() {
+            self.current_ = None;
+        }
+        if self
+            .must_run_task
+            .as_ref()
+            .map_or(false, |w| w.upgrade().is_none())
+        {
+            self.must_run_task = None;
+        }
+    }
+
+    /// Set the priority of `t` to `value` and update the scheduling order.
+    pub fn update_task_priority(&mut self, t: &mut RecordTask, value: i32) {
+        if t.priority == value {
+            return;
+        }
+        if t.in_round_robin_queue {
+            t.priority = value;
+            return;
+        }
+        let weak = WeakPtrWrap(t.weak_self_ptr());
+        self.task_priority_set.remove(&(t.priority, weak.clone()));
+        t.priority = value;
+        self.task_priority_set.insert((t.priority, weak));
+    }
+
+    /// Decide which task runs next and make it `current()`. If the current task
+    /// is running and `switchable` is `PreventSwitch`, wait for it instead. If
+    /// no task is runnable, wait for any task to change state.
+    ///
+    /// The task that is made current has stopped, with a new status to process
+    /// if `by_waitpid` is set in the result, unless `interrupted_by_signal` is.
+    pub fn reschedule(session: &RecordSession, switchable: Switchable) -> Rescheduled {
+        let mut result = Rescheduled::default();
+        session.scheduler_mut().must_run_task = None;
+        if session.scheduler().enable_chaos {
+            let now = monotonic_now_sec();
+            let mut scheduler = session.scheduler_mut();
+            scheduler.maybe_reset_priorities(now);
+            scheduler.maybe_reset_high_priority_only_intervals(now);
+            scheduler.last_reschedule_in_high_priority_only_interval =
+                scheduler.in_high_priority_only_interval(now);
+        }
+
+        let maybe_current = session.scheduler().current();
+        if let Some(current_rc) = maybe_current.as_ref() {
+            if switchable == Switchable::PreventSwitch {
+                let mut current = current_rc.borrow_mut();
+                log!(
+                    LogDebug,
+                    "  ({} is un-switchable at {})",
+                    current.tid,
+                    current.as_record_task().unwrap().ev()
+                );
+                if current.is_running() {
+                    log!(LogDebug, "  and running; waiting for state change");
+                    current.wait(None);
+                    result.by_waitpid = true;
+                    log!(LogDebug, "  new status is {}", current.status());
+                }
+                return result;
+            }
+        }
+
+        let next = loop {
+            log!(LogDebug, "Scheduling next task");
+            if let Some(next) = Self::find_next_runnable_task(session, &mut result.by_waitpid) {
+                break next;
+            }
+
+            log!(
+                LogDebug,
+                "  all tasks blocked, waiting for runnable ({} total)",
+                session.tasks().len()
+            );
+            let mut raw_status: i32 = 0;
+            let tid: pid_t = unsafe { waitpid(-1, &mut raw_status, __WALL) };
+            if tid < 0 {
+                if errno() == EINTR {
+                    log!(LogDebug, "  interrupted by signal");
+                    result.interrupted_by_signal = true;
+                    return result;
+                }
+                fatal!("Failed to waitpid()");
+            }
+            let status = WaitStatus::new(raw_status);
+            log!(LogDebug, "  {} changed status to {}", tid, status);
+            // Recorded tids are the real tids.
+            match session.find_task_from_rec_tid(tid) {
+                Some(next_rc) => {
+                    {
+                        let mut next = next_rc.borrow_mut();
+                        ed_assert!(
+                            &**next,
+                            next.unstable.get()
+                                || next.as_record_task().unwrap().may_be_blocked()
+                                || status.maybe_ptrace_event().is_ptrace_event(),
+                            "Scheduled task should have been blocked"
+                        );
+                        next.did_waitpid(status);
+                    }
+                    result.by_waitpid = true;
+                    session.scheduler_mut().must_run_task = Some(Rc::downgrade(&next_rc));
+                    break next_rc;
+                }
+                // A task we've already destroyed, being reaped.
+                None => log!(LogDebug, "    ... but it's dead"),
+            }
+        };
+
+        {
+            let mut t = next.borrow_mut();
+            if t.is_running() {
+                // `next` was resumed at its last event and isn't blocked, so
+                // this must be the previous current task. Wait for its next stop.
+                t.wait(None);
+                result.by_waitpid = true;
+            }
+        }
+
+        let same_as_current = maybe_current
+            .as_ref()
+            .map_or(false, |current| Rc::ptr_eq(current, &next));
+        let mut scheduler = session.scheduler_mut();
+        if !same_as_current {
+            log!(LogDebug, "  switching to {}", next.borrow().tid);
+        }
+        let tick_count = next.borrow().tick_count();
+        if !same_as_current || tick_count >= scheduler.current_timeslice_end_ {
+            scheduler.setup_new_timeslice(tick_count);
+            result.started_new_timeslice = true;
+        }
+        scheduler.current_ = Some(Rc::downgrade(&next));
+        result
+    }
+
+    fn setup_new_timeslice(&mut self, tick_count: Ticks) {
+        let mut max_timeslice_duration = self.max_ticks_;
+        if self.enable_chaos {
+            // Hypothesis: some bugs require short timeslices to expose. But we don't
+            // want the average timeslice to be too small. So make 10% of timeslices
+            // very short, 10% short-ish, and the rest uniformly distributed between 0
+            // and `max_ticks_`.
+            let timeslice_kind_frac: f64 = self.random.gen();
+            if timeslice_kind_frac < 0.1 {
+                max_timeslice_duration /= 100;
+            } else if timeslice_kind_frac < 0.2 {
+                max_timeslice_duration /= 10;
+            }
+            max_timeslice_duration = self.random.gen_range(1, max(2, max_timeslice_duration));
+        }
+        self.current_timeslice_end_ = tick_count + max_timeslice_duration;
+    }
+
+    /// In chaos mode, give every task a new random priority from time to time.
+    fn maybe_reset_priorities(&mut self, now: f64) {
+        if !self.enable_chaos || self.priorities_refresh_time > now {
+            return;
+        }
+        // Reset task priorities again at some point in the future.
+        self.priorities_refresh_time =
+            now + self.random.gen::<f64>() * PRIORITIES_REFRESH_MAX_INTERVAL;
+        let tasks: Vec<TaskSharedPtr> = self
+            .task_priority_set
+            .iter()
+            .filter_map(|(_, w)| w.upgrade())
+            .chain(
+                self.task_round_robin_queue
+                    .iter()
+                    .filter_map(|w| w.upgrade()),
+            )
+            .collect();
+        for t_rc in tasks {
+            let mut t_ref = t_rc.borrow_mut();
+            let t = t_ref.as_record_task_mut().unwrap();
+            let priority = self.choose_random_priority(t);
+            self.update_task_priority(t, priority);
+        }
+    }
+
+    fn choose_random_priority(&mut self, t: &RecordTask) -> i32 {
+        let prob = if t.tgid() == t.tid {
+            MAIN_THREAD_LOW_PRIORITY_PROBABILITY
+        } else {
+            LOW_PRIORITY_PROBABILITY
+        };
+        if self.random.gen::<f64>() < prob {
+            CHAOS_LOW_PRIORITY
+        } else {
+            CHAOS_NORMAL_PRIORITY
+        }
+    }
+
+    /// In chaos mode, pick new high-priority-only intervals from time to time:
+    /// windows of `high_priority_only_intervals_duration` seconds every
+    /// `high_priority_only_intervals_period` seconds, from
+    /// `high_priority_only_intervals_start`.
+    fn maybe_reset_high_priority_only_intervals(&mut self, now: f64) {
+        if !self.enable_chaos || self.high_priority_only_intervals_refresh_time > now {
+            return;
+        }
+        let duration_step = self.random.gen_range(0, HIGH_PRIORITY_ONLY_DURATION_STEPS);
+        self.high_priority_only_intervals_duration =
+            MIN_HIGH_PRIORITY_ONLY_DURATION * f64::from(1u32 << duration_step);
+        let period_step = self.random.gen_range(0, HIGH_PRIORITY_ONLY_PERIOD_STEPS);
+        self.high_priority_only_intervals_period = self.high_priority_only_intervals_duration
+            / HIGH_PRIORITY_ONLY_FRACTION
+            * f64::from(1u32 << period_step);
+        self.high_priority_only_intervals_start =
+            now + self.random.gen::<f64>() * self.high_priority_only_intervals_period;
+        self.high_priority_only_intervals_refresh_time =
+            now + self.random.gen::<f64>() * HIGH_PRIORITY_ONLY_INTERVALS_REFRESH_MAX_INTERVAL;
+    }
+
+    fn in_high_priority_only_interval(&self, now: f64) -> bool {
+        if !self.enable_chaos || now < self.high_priority_only_intervals_start {
+            return false;
+        }
+        let offset = (now - self.high_priority_only_intervals_start)
+            % self.high_priority_only_intervals_period;
+        offset < self.high_priority_only_intervals_duration
+    }
+
+    /// Find the task to run next: a runnable task of higher priority than the
+    /// current task, else the current task if its timeslice hasn't expired,
+    /// else the next runnable task after the current task in priority and then
+    /// round-robin order.
+    ///
+    /// In a chaos mode high-priority-only interval, only the tasks with the
+    /// highest priority are considered first.
+    fn find_next_runnable_task(
+        session: &RecordSession,
+        by_waitpid: &mut bool,
+    ) -> Option<TaskSharedPtr> {
+        let (candidates, maybe_current, timeslice_end, always_switch, high_priority_only) = {
+            let scheduler = session.scheduler();
+            let candidates: Vec<(i32, TaskSharedPtr)> = scheduler
+                .task_priority_set
+                .iter()
+                .filter_map(|(priority, w)| w.upgrade().map(|t| (*priority, t)))
+                .collect();
+            (
+                candidates,
+                scheduler.current(),
+                scheduler.current_timeslice_end_,
+                scheduler.always_switch,
+                scheduler.last_reschedule_in_high_priority_only_interval,
+            )
+        };
+
+        if high_priority_only && candidates.len() > 1 {
+            let top_priority = candidates[0].0;
+            let high_priority: Vec<(i32, TaskSharedPtr)> = candidates
+                .iter()
+                .take_while(|(p, _)| *p == top_priority)
+                .cloned()
+                .collect();
+            log!(
+                LogDebug,
+                "  in high-priority-only interval, trying {} task(s) of priority {}",
+                high_priority.len(),
+                top_priority
+            );
+            let next = Self::find_next_runnable_task_among(
+                session,
+                &high_priority,
+                maybe_current.as_ref(),
+                timeslice_end,
+                always_switch,
+                by_waitpid,
+            );
+            // DIFF NOTE: rr waits for the interval to end when all the high priority
+            // tasks are blocked. We let the other tasks run then.
+            if next.is_some() {
+                return next;
+            }
+        }
+        Self::find_next_runnable_task_among(
+            session,
+            &candidates,
+            maybe_current.as_ref(),
+            timeslice_end,
+            always_switch,
+            by_waitpid,
+        )
+    }
+
+    /// `find_next_runnable_task()` for the tasks in `candidates`, which are sorted by
+    /// priority.
+    fn find_next_runnable_task_among(
+        session: &RecordSession,
+        candidates: &[(i32, TaskSharedPtr)],
+        maybe_current: Option<&TaskSharedPtr>,
+        timeslice_end: Ticks,
+        always_switch: bool,
+        by_waitpid: &mut bool,
+    ) -> Option<TaskSharedPtr> {
+        // The current task only counts if it is one of the candidates.
+        let maybe_current =
+            maybe_current.filter(|c| candidates.iter().any(|(_, t)| Rc::ptr_eq(t, c)));
+        let current_priority = maybe_current.map(|c| c.borrow().as_record_task().unwrap().priority);
+        // Tasks of higher priority than the current task first.
+        if let Some(priority) = current_priority {
+            for (_, t) in candidates.iter().filter(|(p, _)| *p < priority) {
+                if Self::is_task_runnable(session, t, by_waitpid) {
+                    return Some(t.clone());
+                }
+            }
+        }
+        if let Some(current) = maybe_current {
+            if !always_switch
+                && current.borrow().tick_count() < timeslice_end
+                && Self::is_task_runnable(session, current, by_waitpid)
+            {
+                log!(LogDebug, "  Carrying on with current task");
+                return Some(current.clone());
+            }
+        }
+
+        // Then every task in priority order. Tasks with the current task's
+        // priority are tried in round-robin order, starting after it.
+        let mut order: Vec<&TaskSharedPtr> = Vec::with_capacity(candidates.len());
+        let mut i = 0;
+        while i < candidates.len() {
+            let priority = candidates[i].0;
+            let group: Vec<&TaskSharedPtr> = candidates[i..]
+                .iter()
+                .take_while(|(p, _)| *p == priority)
+                .map(|(_, t)| t)
+                .collect();
+            i += group.len();
+            let start = match (current_priority, maybe_current) {
+                (Some(p), Some(current)) if p == priority => group
+                    .iter()
+                    .position(|t| Rc::ptr_eq(t, current))
+                    .map_or(0, |pos| pos + 1),
+                _ => 0,
+            };
+            order.extend(group[start..].iter().chain(group[..start].iter()));
+        }
+        for t in order {
+            if Self::is_task_runnable(session, t, by_waitpid) {
+                return Some(t.clone());
+            }
+        }
+        None
+    }
+
+    /// Returns true if `t_rc` can run now. This may have to `try_wait()` for it, in
+    /// which case `by_waitpid` is set and it must run next.
+    fn is_task_runnable(
+        session: &RecordSession,
+        t_rc: &TaskSharedPtr,
+        by_waitpid: &mut bool,
+    ) -> bool {
+        let mut t_ref = t_rc.borrow_mut();
+        let tid = t_ref.tid;
+        {
+            let t = t_ref.as_record_task().unwrap();
+            if t.unstable.get() {
+                log!(LogDebug, "  {} is unstable", tid);
+                return true;
+            }
+            if !t.may_be_blocked() {
+                log!(LogDebug, "  {} isn't blocked", tid);
+                return true;
+            }
+            if t.emulated_stop_type != EmulatedStopType::NotStopped {
+                log!(LogDebug, "  {} is stopped by ptrace or signal", tid);
+                return false;
+            }
+            log!(
+                LogDebug,
+                "  {} is blocked on {}; checking status ...",
+                tid,
+                t.ev()
+            );
+        }
+        if t_ref.try_wait() {
+            *by_waitpid = true;
+            session.scheduler_mut().must_run_task = Some(Rc::downgrade(t_rc));
+            log!(LogDebug, "  ready with status {}", t_ref.status());
+            return true;
+        }
+        log!(LogDebug, "  still blocked");
+        false
+    }
+
+    /// A uniformly distributed random number for chaos mode decisions made outside the
+    /// scheduler, e.g. `AddressSpace::chaos_mode_find_free_memory()`, so that they are
+    /// determined by the chaos seed too.
+    pub fn chaos_random(&mut self) -> u64 {
+        self.random.gen()
+    }
+
+    /// Compute an affinity mask to report via sched_getaffinity.
+    /// This mask should include whatever CPU number the task is
+    /// actually running on, otherwise we may confuse applications.
+    /// The mask should also match the number of CPUs we're pretending
+    /// to have.
+    fn regenerate_affinity_mask(&mut self) {
+        let ret = unsafe {
+            sched_getaffinity(0, size_of::<cpu_set_t>(), &mut self.pretend_affinity_mask_)
+        };
+        if ret != 0 {
+            fatal!("Failed sched_getaffinity");
+        }
+
+        let cpu = match self.bound_cpu {
+            Some(cpu) => cpu as usize,
+            // We only run one thread at a time but we're not limiting
+            // where that thread can run, so report all available CPUs
+            // in the affinity mask even though that doesn't match
+            // pretend_num_cores. We only run unbound during tests or
+            // when explicitly requested by the user.
+            None => return,
+        };
+        if !unsafe { CPU_ISSET(cpu, &self.pretend_affinity_mask_) } {
+            log!(LogWarn, "Bound CPU {} not in affinity mask", cpu);
+            // Use the original affinity mask since something strange is
+            // going on.
+            return;
+        }
+        // Try to limit the CPU numbers we generate to the ones that
+        // actually exist on this system, but don't fail if there aren't
+        // enough CPUs.
+        let faked_num_cpus = max(
+            unsafe { sysconf(_SC_NPROCESSORS_CONF) } as usize,
+            self.pretend_num_cores_ as usize,
+        );
+        // Generate random CPU numbers that fit into the CPU mask.
+        let mut other_cpus: Vec<usize> = (0..faked_num_cpus).filter(|&i| i != cpu).collect();
+        other_cpus.shuffle(&mut self.random);
+        unsafe {
+            CPU_ZERO(&mut self.pretend_affinity_mask_);
+            CPU_SET(cpu, &mut self.pretend_affinity_mask_);
+            for &other in other_cpus.iter().take(self.pretend_num_cores_ as usize - 1) {
+                CPU_SET(other, &mut self.pretend_affinity_mask_);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chaos_timeslices_test() {
+        let mut scheduler = Scheduler::new(None);
+        scheduler.setup_new_timeslice(100);
+        assert_eq!(
+            100 + scheduler.max_ticks(),
+            scheduler.current_timeslice_end()
+        );
+
+        scheduler.set_enable_chaos(true, Some(1));
+        let mut short = 0;
+        for _ in 0..1000 {
+            scheduler.setup_new_timeslice(100);
+            let timeslice = scheduler.current_timeslice_end() - 100;
+            assert!(timeslice >= 1 && timeslice < scheduler.max_ticks());
+            if timeslice < scheduler.max_ticks() / 10 {
+                short += 1;
+            }
+        }
+        // About 20% are short or very short, plus 10% of the rest.
+        assert!(short > 150 && short < 450, "{} short timeslices", short);
+    }
+
+    #[test]
+    fn chaos_high_priority_only_intervals_test() {
+        let mut scheduler = Scheduler::new(None);
+        scheduler.maybe_reset_high_priority_only_intervals(0.0);
+        assert!(!scheduler.in_high_priority_only_interval(1.0));
+
+        scheduler.set_enable_chaos(true, Some(1));
+        scheduler.maybe_reset_high_priority_only_intervals(0.0);
+        let start = scheduler.high_priority_only_intervals_start;
+        let duration = scheduler.high_priority_only_intervals_duration;
+        let period = scheduler.high_priority_only_intervals_period;
+        assert!(duration <= period * HIGH_PRIORITY_ONLY_FRACTION + 1e-12);
+        assert!(!scheduler.in_high_priority_only_interval(start - duration / 2.0));
+        assert!(scheduler.in_high_priority_only_interval(start + duration / 2.0));
+        assert!(!scheduler.in_high_priority_only_interval(start + (duration + period) / 2.0));
+        assert!(scheduler.in_high_priority_only_interval(start + period + duration / 2.0));
+    }
 }
@@ -1,3 +1,22 @@
+use crate::{
+    arch::Architecture,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
+    bindings::kernel::{sock_filter, BPF_K, BPF_RET},
+    kernel_supplement::{SECCOMP_RET_ALLOW, SECCOMP_RET_DATA, SECCOMP_RET_TRACE},
+    log::LogLevel::LogDebug,
+    remote_ptr::RemotePtr,
+    seccomp_bpf::SeccompFilter,
+    session::{
+        address_space::{address_space::AddressSpace, Privileged},
+        task::{
+            record_task::record_task::RecordTask,
+            task_common::{read_mem, read_val_mem, write_mem, write_val_mem},
+            Task,
+        },
+    },
+};
+use std::{collections::HashMap, mem::size_of};
+
 /// When seccomp decides not to execute a syscall the kernel returns to userspace
 /// without modifying the registers. There is no negative return value to
 /// indicate that whatever side effects the syscall would happen did not take
@@ -15,4 +34,169 @@
 /// kernel itself.
 pub const SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO: isize = -2;
 
-pub struct SeccompFilterRewriter;
+/// Object to support install_patched_seccomp_filter.
+#[derive(Default)]
+pub struct SeccompFilterRewriter {
+    /// Seccomp filters can return 32-bit result values. We need to map all of
+    /// them into a single 16 bit data field. Fortunately (so far) all the
+    /// filters we've seen return constants, so there aren't too many distinct
+    /// values we need to deal with. For each constant value that gets returned,
+    /// we'll add it as the key in `result_to_index`, with the corresponding
+    /// value being the 16-bit data value that our rewritten filter returns.
+    result_to_index: HashMap<u32, u16>,
+    index_to_result: Vec<u32>,
+}
+
+impl SeccompFilterRewriter {
+    pub fn new() -> SeccompFilterRewriter {
+        Self::default()
+    }
+
+    /// Assuming `t` is set up for a prctl or seccomp syscall that
+    /// installs a seccomp-bpf filter, patch the filter to signal the tracer
+    /// instead of silently delivering an errno, and install it.
+    /// Syscalls made from the privileged rd page syscall stubs are let
+    /// through without tracing.
+    pub fn install_patched_seccomp_filter(&mut self, t: &mut RecordTask) {
+        rd_arch_function_selfless!(
+            install_patched_seccomp_filter_arch,
+            t.arch(),
+            t,
+            &mut self.result_to_index,
+            &mut self.index_to_result
+        )
+    }
+
+    /// The original result of the filter for the 16-bit data value `value`
+    /// that our patched filter returned, or `None` if it isn't one of ours.
+    pub fn map_filter_data_to_real_result(&self, value: u16) -> Option<u32> {
+        self.index_to_result.get(value as usize).copied()
+    }
+}
+
+fn bpf_class(code: u16) -> u32 {
+    code as u32 & 0x07
+}
+
+fn bpf_rval(code: u16) -> u32 {
+    code as u32 & 0x18
+}
+
+fn set_syscall_result(t: &mut RecordTask, ret: isize) {
+    let mut r = t.regs_ref().clone();
+    r.set_syscall_result_signed(ret);
+    t.set_regs(&r);
+}
+
+fn pass_through_seccomp_filter(t: &mut RecordTask) {
+    let regs = t.regs_ref().clone();
+    let ret = {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        rd_syscall!(
+            remote,
+            regs.original_syscallno() as i32,
+            regs.arg1(),
+            regs.arg2(),
+            regs.arg3()
+        )
+    };
+    set_syscall_result(t, ret);
+    ed_assert!(t, t.regs_ref().syscall_failed());
+}
+
+fn install_patched_seccomp_filter_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    result_to_index: &mut HashMap<u32, u16>,
+    index_to_result: &mut Vec<u32>,
+) {
+    // Take advantage of the fact that the filter program is arg3() in both
+    // prctl and seccomp syscalls.
+    let mut ok = true;
+    let mut prog = read_val_mem(
+        t,
+        RemotePtr::<Arch::sock_fprog>::from(t.regs_ref().arg3()),
+        Some(&mut ok),
+    );
+    if !ok {
+        // We'll probably return EFAULT but a kernel that doesn't support
+        // seccomp(2) should return ENOSYS instead, so just run the original
+        // system call to get the correct error.
+        pass_through_seccomp_filter(t);
+        return;
+    }
+    let (filter, len) = Arch::get_sock_fprog(&prog);
+    let mut code: Vec<sock_filter> = read_mem(t, filter, len, Some(&mut ok));
+    if !ok {
+        pass_through_seccomp_filter(t);
+        return;
+    }
+
+    // Convert all returns to TRACE returns so that rd can handle them.
+    for u in code.iter_mut() {
+        if bpf_class(u.code) != BPF_RET {
+            continue;
+        }
+        ed_assert!(
+            t,
+            bpf_rval(u.code) == BPF_K,
+            "seccomp-bpf program uses BPF_RET with A/X register, not supported"
+        );
+        if u.k == SECCOMP_RET_ALLOW {
+            continue;
+        }
+        let index = match result_to_index.get(&u.k) {
+            Some(&index) => index,
+            None => {
+                ed_assert!(
+                    t,
+                    index_to_result.len() < SECCOMP_RET_DATA as usize,
+                    "Too many distinct constants!"
+                );
+                let index = index_to_result.len() as u16;
+                result_to_index.insert(u.k, index);
+                index_to_result.push(u.k);
+                index
+            }
+        };
+        u.k = index as u32 | SECCOMP_RET_TRACE;
+    }
+
+    let mut f = SeccompFilter::new();
+    for e in AddressSpace::rd_page_syscalls() {
+        if e.privileged == Privileged::Privileged {
+            let ip = AddressSpace::rd_page_syscall_exit_point(e.traced, e.privileged, e.enabled);
+            f.allow_syscalls_from_callsite(ip);
+        }
+    }
+    f.filters.extend_from_slice(&code);
+
+    let regs = t.regs_ref().clone();
+    let ret = {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let mut mem = AutoRestoreMem::new(
+            &mut remote,
+            None,
+            size_of::<Arch::sock_fprog>() + f.filters.len() * size_of::<sock_filter>(),
+        );
+        let code_ptr = RemotePtr::<sock_filter>::cast(mem.get().unwrap());
+        write_mem(mem.task_mut(), code_ptr, &f.filters, None);
+        Arch::set_sock_fprog(&mut prog, code_ptr, f.filters.len());
+        let prog_ptr = RemotePtr::<Arch::sock_fprog>::cast(code_ptr + f.filters.len());
+        write_val_mem(mem.task_mut(), prog_ptr, &prog, None);
+
+        rd_syscall!(
+            mem,
+            regs.original_syscallno() as i32,
+            regs.arg1(),
+            regs.arg2(),
+            prog_ptr.as_usize()
+        )
+    };
+    set_syscall_result(t, ret);
+    log!(
+        LogDebug,
+        "Installed patched seccomp filter with {} instructions, result {}",
+        f.filters.len(),
+        ret
+    );
+}
@@ -658,7 +658,7 @@ pub mod address_space {
         /// First mapped byte of the vdso.
         vdso_start_addr: Cell<RemotePtr<Void>>,
         /// The monkeypatcher that's handling this address space.
-        monkeypatch_state: Option<MonkeyPatcher>,
+        monkeypatch_state: Option<RefCell<MonkeyPatcher>>,
         /// The watchpoints set for tasks in this VM.  Watchpoints are
         /// programmed per Task, but we track them per address space on
         /// behalf of debuggers that assume that model.
@@ -1699,8 +1699,22 @@ pub mod address_space {
             *self.child_mem_fd.borrow_mut() = fd;
         }
 
-        pub fn monkeypatcher(&self) -> Option<&MonkeyPatcher> {
-            self.monkeypatch_state.as_ref()
+        pub fn monkeypatcher(&self) -> Option<Ref<MonkeyPatcher>> {
+            self.monkeypatch_state.as_ref().map(|m| m.borrow())
+        }
+        pub fn monkeypatcher_mut(&self) -> Option<RefMut<MonkeyPatcher>> {
+            self.monkeypatch_state.as_ref().map(|m| m.borrow_mut())
+        }
+
+        /// Run `f` on a copy of the monkeypatcher and store the copy back afterwards, or
+        /// return None if there is no monkeypatcher. Patching runs the task, and stopping
+        /// the task looks at the monkeypatcher, so we can't hold on to its borrow while
+        /// `f` runs.
+        pub fn with_monkeypatcher<R, F: FnOnce(&mut MonkeyPatcher) -> R>(&self, f: F) -> Option<R> {
+            let mut patcher = self.monkeypatcher()?.clone();
+            let result = f(&mut patcher);
+            *self.monkeypatcher_mut().unwrap() = patcher;
+            Some(result)
         }
 
         pub fn at_preload_init(&self, t: &mut dyn Task) {
@@ -1888,9 +1902,126 @@ pub mod address_space {
             8 * 1024 * 1024
         }
 
-        // @TODO Deliberately left unimplemented. We don't support chaos mode for now
-        pub fn chaos_mode_find_free_memory(_t: &RecordTask, _len: usize) -> RemotePtr<Void> {
-            unimplemented!()
+        /// In chaos mode, choose where a new mapping of `len` bytes goes instead of letting the
+        /// kernel do it, so that bugs depending on the relative placement of mappings show up.
+        /// Half the time we start looking at a random address, the other half at a random
+        /// existing mapping, and then search up or down (at random) for a free range.
+        ///
+        /// Returns null if no free range was found. The random choices come from the
+        /// session's Scheduler, which draws them from the chaos seed.
+        pub fn chaos_mode_find_free_memory(t: &RecordTask, len: usize) -> RemotePtr<Void> {
+            let (random_start, random_addr, random_map, random_direction) = {
+                let session = t.session();
+                let mut scheduler = session.as_record().unwrap().scheduler_mut();
+                (
+                    scheduler.chaos_random(),
+                    scheduler.chaos_random(),
+                    scheduler.chaos_random(),
+                    scheduler.chaos_random(),
+                )
+            };
+            let (usable_end, address_mask): (usize, u64) = match t.arch() {
+                SupportedArch::X86 => (0xc000_0000, 0xffff_ffff),
+                SupportedArch::X64 => (0x7fff_ffff_f000, (1 << 47) - 1),
+            };
+            // Reserve 3 pages at the end of userspace in case MonkeyPatcher wants
+            // to allocate something there. Stay clear of the lowest addresses too, which
+            // mmap_min_addr usually forbids.
+            let addr_space_start = RemotePtr::<Void>::from(0x10000usize);
+            let addr_space_end = RemotePtr::<Void>::from(usable_end - 3 * page_size());
+            let len = ceil_page_size(len);
+            if len > addr_space_end - addr_space_start {
+                return RemotePtr::null();
+            }
+
+            let vm = t.vm();
+            // Never hand out the rd page or the thread locals after it, and leave room for
+            // the stack to grow.
+            let mut exclusions = vec![MemoryRange::from_range(
+                Self::rd_page_start(),
+                Self::preload_thread_locals_start() + PRELOAD_THREAD_LOCALS_SIZE,
+            )];
+            let mut start = RemotePtr::<Void>::null();
+            {
+                let maps = vm.maps();
+                for (_, m) in &maps {
+                    if m.map.is_stack() {
+                        let stack_growth = Self::chaos_mode_min_stack_size() as usize;
+                        let bottom = if m.map.start().as_usize() > stack_growth {
+                            m.map.start() - stack_growth
+                        } else {
+                            RemotePtr::null()
+                        };
+                        exclusions.push(MemoryRange::from_range(bottom, m.map.end()));
+                    }
+                }
+                if random_start % 2 == 0 {
+                    // Some of these addresses will not be usable.
+                    start = floor_page_size(RemotePtr::from((random_addr & address_mask) as usize));
+                } else {
+                    let count = (&maps).into_iter().count();
+                    ed_assert!(t, count > 0);
+                    let index = (random_map % count as u64) as usize;
+                    start = (&maps).into_iter().nth(index).unwrap().1.map.start();
+                }
+            }
+            if start < addr_space_start || start > addr_space_end - len {
+                start = addr_space_start;
+            }
+
+            // Search the address space in one direction all the way to the end,
+            // then in the other direction.
+            let mut direction_up = random_direction % 2 == 0;
+            for _ in 0..2 {
+                // Invariant: [addr, addr+len) is always in the usable address space
+                // [addr_space_start, addr_space_end).
+                let mut addr = if direction_up {
+                    start
+                } else if start < addr_space_start + len {
+                    direction_up = !direction_up;
+                    continue;
+                } else {
+                    start - len
+                };
+                loop {
+                    let range = MemoryRange::new_range(addr, len);
+                    // Look for any reserved address space that overlaps [addr, addr+len). If
+                    // several do, we just pick one arbitrarily.
+                    let mut overlapping: Option<MemoryRange> = None;
+                    {
+                        let maps = vm.maps_containing_or_after(addr);
+                        if let Some((_, m)) = (&maps).into_iter().next() {
+                            if m.map.start() < range.end() {
+                                overlapping =
+                                    Some(MemoryRange::from_range(m.map.start(), m.map.end()));
+                            }
+                        }
+                    }
+                    if overlapping.is_none() {
+                        overlapping = exclusions.iter().find(|e| e.intersects(&range)).copied();
+                    }
+                    let overlapping = match overlapping {
+                        None => return addr,
+                        Some(overlapping) => overlapping,
+                    };
+                    if direction_up {
+                        // Try moving up above the overlapping range.
+                        if overlapping.end() > addr_space_end - len {
+                            break;
+                        }
+                        addr = overlapping.end();
+                    } else {
+                        // Try moving down below the overlapping range.
+                        if overlapping.start() < addr_space_start + len {
+                            break;
+                        }
+                        addr = floor_page_size(overlapping.start() - len);
+                    }
+                }
+                direction_up = !direction_up;
+            }
+
+            RemotePtr::null()
         }
 
         /// We assume this method always succeeds
@@ -2105,7 +2236,7 @@ pub mod address_space {
             exec_count: u32,
         ) -> AddressSpace {
             let patcher = if t.session().is_recording() {
-                Some(MonkeyPatcher::new())
+                Some(RefCell::new(MonkeyPatcher::new()))
             } else {
                 None
             };
@@ -2963,10 +3094,10 @@ pub mod address_space {
             self.syscallbuf_enabled_.set(true);
 
             if t.session().is_recording() {
-                self.monkeypatch_state
-                    .as_ref()
-                    .unwrap()
-                    .patch_at_preload_init(t.as_record_task().unwrap());
+                self.with_monkeypatcher(|patcher| {
+                    patcher.patch_at_preload_init(t.as_record_task_mut().unwrap())
+                })
+                .unwrap();
             }
         }
 
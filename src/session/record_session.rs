@@ -1,23 +1,62 @@
-use super::session_common::kill_all_tasks;
+use super::{
+    session_common::kill_all_tasks,
+    task::{
+        record_task::record_task::RecordTask,
+        task_inner::{
+            ResumeRequest,
+            SaveTraceeFdNumber,
+            TaskInner,
+            TicksRequest,
+            WaitRequest,
+            MAX_TICKS_REQUEST,
+        },
+    },
+    SessionSharedPtr,
+};
 use crate::{
-    event::Switchable,
+    bindings::ptrace::{PTRACE_EVENT_EXEC, PTRACE_EVENT_EXIT, PTRACE_EVENT_SECCOMP},
+    event::{Event, EventType, Switchable, SyscallState},
     kernel_abi::SupportedArch,
+    log::LogLevel::LogDebug,
+    record_signal::{handle_desched_event, handle_signal, SignalHandled},
+    record_syscall::{rec_prepare_syscall, rec_process_syscall},
     scheduler::Scheduler,
+    scoped_fd::ScopedFd,
     seccomp_filter_rewriter::SeccompFilterRewriter,
     session::{
-        session_inner::session_inner::SessionInner,
+        session_inner::session_inner::{PtraceSyscallSeccompOrdering, SessionInner},
         task::{Task, TaskSharedPtr},
         Session,
     },
     taskish_uid::TaskUid,
     thread_group::ThreadGroupSharedPtr,
-    trace::{trace_stream::TraceStream, trace_writer::TraceWriter},
-    util::{good_random, CPUIDData, CPUID_GETEXTENDEDFEATURES, CPUID_GETFEATURES, CPUID_GETXSAVE},
+    ticks::Ticks,
+    trace::{
+        trace_stream::TraceStream,
+        trace_task_event::TraceTaskEvent,
+        trace_writer::{CloseStatus, TraceWriter},
+    },
+    util::{
+        choose_cpu,
+        good_random,
+        is_deterministic_signal,
+        set_cpu_affinity,
+        BindCPU,
+        CPUIDData,
+        CPUID_GETEXTENDEDFEATURES,
+        CPUID_GETFEATURES,
+        CPUID_GETXSAVE,
+    },
+    wait_status::WaitStatus,
 };
-use libc::pid_t;
+use libc::{pid_t, SIGPWR};
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
+    cmp::{max, min},
+    ffi::{OsStr, OsString},
+    io,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
 #[derive(Clone, Eq, PartialEq)]
@@ -110,18 +149,28 @@ impl TraceUuid {
     }
 }
 
+/// What `RecordSession::record_step()` did.
+pub enum RecordResult {
+    /// Recording continues. Call `record_step()` again.
+    StepContinue,
+    /// All the tracees have exited. This is how the last one exited.
+    StepExited(WaitStatus),
+}
+
 pub struct RecordSession {
     session_inner: SessionInner,
-    trace_out: TraceWriter,
+    trace_out: RefCell<TraceWriter>,
     scheduler_: RefCell<Scheduler>,
-    initial_thread_group: ThreadGroupSharedPtr,
-    seccomp_filter_rewriter_: SeccompFilterRewriter,
+    initial_thread_group: RefCell<Option<ThreadGroupSharedPtr>>,
+    seccomp_filter_rewriter_: RefCell<SeccompFilterRewriter>,
     // DIFF NOTE: This is a unique_ptr in rr
     trace_id: TraceUuid,
     disable_cpuid_features_: DisableCPUIDFeatures,
     ignore_sig: i32,
     continue_through_sig: i32,
-    last_task_switchable: Switchable,
+    last_task_switchable: Cell<Switchable>,
+    /// How the last tracee to exit exited.
+    last_exit_status: Cell<Option<WaitStatus>>,
     syscall_buffer_size_: usize,
     syscallbuf_desched_sig_: u8,
     use_syscall_buffer_: bool,
@@ -129,21 +178,348 @@ pub struct RecordSession {
     use_file_cloning_: bool,
     use_read_cloning_: bool,
     /// When true, try to increase the probability of finding bugs.
-    enable_chaos_: bool,
+    enable_chaos_: Cell<bool>,
     asan_active_: bool,
     /// When true, wait for all tracees to exit before finishing recording.
     wait_for_all_: bool,
+    /// When true, also monitor writable shared mappings of files. See
+    /// `MonitoredSharedMemory`.
+    monitor_writable_shared_memory_: Cell<bool>,
 
-    output_trace_dir: String,
+    output_trace_dir: OsString,
 }
 
 impl Drop for RecordSession {
     fn drop(&mut self) {
-        unimplemented!()
+        self.kill_all_tasks();
     }
 }
 
 impl RecordSession {
+    /// Spawn `exe_path` with `argv` and `env` under ptrace and get ready to record it
+    /// into `output_trace_dir`, or into a new directory in the trace save directory if
+    /// `None`. Nothing runs until the first `record_step()`.
+    ///
+    /// DIFF NOTE: rd doesn't inject its preload library yet, so the syscall buffer
+    /// is always disabled and every syscall the tracees make stops in rd.
+    pub fn create(
+        exe_path: &OsStr,
+        argv: &[OsString],
+        env: &[OsString],
+        bind_cpu: BindCPU,
+        output_trace_dir: Option<&OsStr>,
+    ) -> SessionSharedPtr {
+        let bind_to_cpu = choose_cpu(bind_cpu);
+        if let Some(cpu) = bind_to_cpu {
+            // Bind rd itself; the tracees inherit the affinity.
+            if !set_cpu_affinity(cpu) {
+                fatal!("Can't bind to requested CPU {}", cpu);
+            }
+        }
+
+        let mut session_inner = SessionInner::default();
+        // DIFF NOTE: rr probes the kernel for the order of the syscall-entry and
+        // seccomp stops. We assume a 4.8 or later kernel, which reports the
+        // syscall-entry stop first.
+        session_inner
+            .syscall_seccomp_ordering_
+            .set(PtraceSyscallSeccompOrdering::SyscallBeforeSeccomp);
+        let disable_cpuid_features = DisableCPUIDFeatures::new();
+        let mut trace_out = TraceWriter::new(
+            exe_path,
+            bind_to_cpu,
+            output_trace_dir.unwrap_or_else(|| OsStr::new("")),
+            session_inner.ticks_semantics_,
+        );
+        trace_out.setup_cpuid_records(SessionInner::has_cpuid_faulting(), &disable_cpuid_features);
+        if output_trace_dir.is_none() {
+            trace_out.make_latest_trace();
+        }
+
+        let mut session = RecordSession {
+            session_inner,
+            trace_out: RefCell::new(trace_out),
+            scheduler_: RefCell::new(Scheduler::new(bind_to_cpu)),
+            initial_thread_group: Default::default(),
+            seccomp_filter_rewriter_: RefCell::new(SeccompFilterRewriter::new()),
+            trace_id: TraceUuid::new(),
+            disable_cpuid_features_: disable_cpuid_features,
+            ignore_sig: 0,
+            continue_through_sig: 0,
+            last_task_switchable: Cell::new(Switchable::AllowSwitch),
+            last_exit_status: Cell::new(None),
+            syscall_buffer_size_: 0,
+            syscallbuf_desched_sig_: SIGPWR as u8,
+            use_syscall_buffer_: false,
+            use_file_cloning_: false,
+            use_read_cloning_: false,
+            enable_chaos_: Cell::new(false),
+            asan_active_: false,
+            wait_for_all_: false,
+            monitor_writable_shared_memory_: Cell::new(false),
+            output_trace_dir: output_trace_dir.map_or_else(OsString::new, |d| d.to_owned()),
+        };
+
+        let error_fd: ScopedFd = session.create_spawn_task_error_pipe();
+        let sock_fd_out = session.tracee_socket_fd();
+
+        let mut rc: SessionSharedPtr = Rc::new(Box::new(session));
+        let weak_self = Rc::downgrade(&rc);
+        // We never change the weak_self pointer so its a good idea to use
+        // a bit of unsafe here.
+        unsafe { Rc::get_mut_unchecked(&mut rc) }.weak_self = weak_self;
+        // The recorded tid of a task is its real tid, so `rec_tid` is ignored here.
+        let t = TaskInner::spawn(
+            (*rc).as_ref(),
+            &error_fd,
+            sock_fd_out,
+            SaveTraceeFdNumber::SaveToSession,
+            exe_path,
+            argv,
+            env,
+            0,
+        );
+        let tg = t.borrow().thread_group_shr_ptr();
+        rc.on_create(t);
+        *rc.as_record().unwrap().initial_thread_group.borrow_mut() = Some(tg);
+
+        rc
+    }
+
+    /// Run the tracees until the next event we have to record, or the next time
+    /// we have to let some other tracee run, and record it.
+    pub fn record_step(&self) -> RecordResult {
+        if self.tasks().is_empty() {
+            return RecordResult::StepExited(
+                self.last_exit_status
+                    .get()
+                    .unwrap_or_else(|| WaitStatus::for_exit_code(0)),
+            );
+        }
+
+        let rescheduled = Scheduler::reschedule(self, self.last_task_switchable.get());
+        if rescheduled.interrupted_by_signal {
+            // The user wants to stop recording. Let our caller decide.
+            return RecordResult::StepContinue;
+        }
+        let rc_t = match self.scheduler().current() {
+            Some(rc_t) => rc_t,
+            None => return RecordResult::StepContinue,
+        };
+
+        let mut switchable = Switchable::AllowSwitch;
+        {
+            let mut tb = rc_t.borrow_mut();
+            let t = tb.as_record_task_mut().unwrap();
+            if rescheduled.by_waitpid {
+                switchable = self.process_status(t);
+            } else {
+                // `t` is stopped at an event we've already recorded.
+                self.resume(t);
+            }
+        }
+
+        self.last_task_switchable.set(switchable);
+        RecordResult::StepContinue
+    }
+
+    /// Resume `t`, which is stopped outside of any syscall, with the rest of its
+    /// timeslice. Deliver the next signal it has stashed first.
+    fn resume(&self, t: &mut RecordTask) {
+        let mut maybe_sig = None;
+        if t.ev().event_type() != EventType::EvSyscall {
+            if let Some(i) = t.peek_stashed_sig_to_deliver() {
+                let stashed = t.pop_stash_sig(i);
+                t.stashed_signal_processed();
+                if let SignalHandled::DeliverFatal(sig) =
+                    handle_signal(t, &stashed.siginfo, stashed.deterministic)
+                {
+                    maybe_sig = Some(sig);
+                }
+                if t.is_dying() {
+                    return;
+                }
+            }
+        }
+
+        let timeslice_end: Ticks = self.scheduler().current_timeslice_end();
+        let ticks = min(
+            max(1, timeslice_end.saturating_sub(t.tick_count())),
+            MAX_TICKS_REQUEST,
+        );
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeNonblocking,
+            TicksRequest::ResumeWithTicksRequest(ticks),
+            maybe_sig,
+        );
+    }
+
+    /// `t` has a new status from waitpid(). Record what it means and either resume
+    /// `t` or leave it stopped for the next `record_step()`.
+    fn process_status(&self, t: &mut RecordTask) -> Switchable {
+        log!(LogDebug, "{}: processing status {}", t.tid, t.status());
+        let in_syscall = t.ev().event_type() == EventType::EvSyscall;
+        let event = t.maybe_ptrace_event();
+        if event == PTRACE_EVENT_EXIT {
+            self.task_exit(t);
+            return Switchable::AllowSwitch;
+        }
+        if event == PTRACE_EVENT_EXEC {
+            t.post_exec();
+            self.resume_syscall(t);
+            return Switchable::PreventSwitch;
+        }
+        if event == PTRACE_EVENT_SECCOMP {
+            return self.syscall_entry(t);
+        }
+        if t.status().is_syscall() {
+            if in_syscall {
+                self.syscall_exit(t);
+                return Switchable::AllowSwitch;
+            }
+            if t.seccomp_bpf_enabled {
+                // Our seccomp filter traces every syscall, so the seccomp stop
+                // that follows this syscall-entry stop is where we handle it.
+                self.resume_syscall(t);
+                return Switchable::PreventSwitch;
+            }
+            // No seccomp filter yet, before the initial exec.
+            return self.syscall_entry(t);
+        }
+        if event.is_ptrace_event() {
+            log!(LogDebug, "  ignoring {}", event);
+            if in_syscall {
+                self.resume_syscall(t);
+            }
+            return Switchable::AllowSwitch;
+        }
+        if t.maybe_group_stop_sig().is_sig() {
+            // DIFF NOTE: rr emulates group stops. We just let `t` carry on.
+            return Switchable::AllowSwitch;
+        }
+
+        let sig = t.maybe_stop_sig().unwrap_sig();
+        if sig == self.syscallbuf_desched_sig() as i32 {
+            let si = *t.get_siginfo();
+            handle_desched_event(t, &si);
+            return Switchable::AllowSwitch;
+        }
+        if in_syscall {
+            // Deliver it after the syscall exits.
+            t.stash_sig();
+            self.resume_syscall(t);
+            return Switchable::AllowSwitch;
+        }
+        let si = *t.get_siginfo();
+        let deterministic = is_deterministic_signal(t);
+        if let SignalHandled::DeliverFatal(sig) = handle_signal(t, &si, deterministic) {
+            t.resume_execution(
+                ResumeRequest::ResumeSyscall,
+                WaitRequest::ResumeNonblocking,
+                TicksRequest::ResumeNoTicks,
+                Some(sig),
+            );
+        }
+        Switchable::AllowSwitch
+    }
+
+    fn resume_syscall(&self, t: &mut RecordTask) {
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeNonblocking,
+            TicksRequest::ResumeNoTicks,
+            None,
+        );
+    }
+
+    /// `t` is entering a syscall. Record the syscall entry and let the kernel run it.
+    fn syscall_entry(&self, t: &mut RecordTask) -> Switchable {
+        let syscallno = t.regs_ref().original_syscallno() as i32;
+        t.push_syscall_event(syscallno);
+        let arch = t.ev().syscall().arch();
+        t.canonicalize_regs(arch);
+        t.ev_mut().syscall_mut().state = SyscallState::EnteringSyscall;
+
+        let mut syscall_state = std::mem::take(&mut t.syscall_state);
+        let switchable = rec_prepare_syscall(t, &mut syscall_state);
+        let syscall_patched = syscall_state.syscall_patched();
+        t.syscall_state = syscall_state;
+        if syscall_patched {
+            // `t` will run the patched code instead. Nothing to record.
+            t.pop_syscall();
+            return Switchable::AllowSwitch;
+        }
+        if t.is_dying() {
+            return Switchable::AllowSwitch;
+        }
+
+        t.ev_mut().syscall_mut().switchable = switchable;
+        t.record_current_event();
+        t.ev_mut().syscall_mut().state = SyscallState::ProcessingSyscall;
+        self.resume_syscall(t);
+        switchable
+    }
+
+    /// `t` has exited the syscall at the top of its event stack. Record its outputs
+    /// and the syscall exit.
+    ///
+    /// DIFF NOTE: rr restarts syscalls interrupted by signals as the same syscall
+    /// event. We record an interrupted syscall as an ordinary exit and the restarted
+    /// one as a new syscall.
+    fn syscall_exit(&self, t: &mut RecordTask) {
+        t.ev_mut().syscall_mut().state = SyscallState::ExitingSyscall;
+        let mut syscall_state = std::mem::take(&mut t.syscall_state);
+        rec_process_syscall(t, &mut syscall_state);
+        t.syscall_state = syscall_state;
+
+        let (syscallno, arch) = {
+            let syscall = t.ev().syscall();
+            (syscall.number, syscall.arch())
+        };
+        let regs = t.regs_ref().clone();
+        t.on_syscall_exit(syscallno, arch, &regs);
+        t.record_current_event();
+        t.pop_syscall();
+    }
+
+    /// `t` is at its PTRACE_EVENT_EXIT stop. Record its exit and forget it.
+    fn task_exit(&self, t: &mut RecordTask) {
+        let status = t.get_ptrace_eventmsg_exit_status();
+        log!(LogDebug, "{}: exiting with {}", t.tid, status);
+        if t.ev().event_type() == EventType::EvSyscall {
+            // The exit() or exit_group() `t` was in.
+            t.pop_syscall();
+        }
+        let tid = t.rec_tid;
+        t.trace_writer_mut()
+            .write_task_event(&TraceTaskEvent::for_exit(tid, status));
+        t.record_event(&Event::exit(), None, None, None);
+        if self.tasks().len() == 1 {
+            self.last_exit_status.set(Some(status));
+        }
+        // The task is dropped, and detached, once the last reference to it is gone.
+        t.destroy();
+    }
+
+    /// Kill the tracees, e.g. because the user interrupted recording, and finish the trace.
+    pub fn terminate_recording(&self) {
+        log!(LogDebug, "Terminating recording");
+        self.kill_all_tasks();
+        self.close_trace_writer(CloseStatus::CloseOk);
+    }
+
+    /// Write the trace header. The trace can't be replayed until this is called.
+    pub fn close_trace_writer(&self, status: CloseStatus) {
+        self.trace_out
+            .borrow_mut()
+            .close(status, Some(self.trace_id.clone()));
+    }
+
+    pub fn disable_cpuid_features(&self) -> &DisableCPUIDFeatures {
+        &self.disable_cpuid_features_
+    }
+
     pub fn scheduler(&self) -> Ref<'_, Scheduler> {
         self.scheduler_.borrow()
     }
@@ -151,6 +527,76 @@ impl RecordSession {
         self.scheduler_.borrow_mut()
     }
 
+    pub fn enable_chaos(&self) -> bool {
+        self.enable_chaos_.get()
+    }
+
+    /// Turn chaos mode on or off, see `Scheduler`. The seed for its random decisions is
+    /// `seed`, or random if `None`, and is saved with the trace.
+    pub fn set_enable_chaos(&self, enable_chaos: bool, seed: Option<u64>) -> io::Result<()> {
+        self.enable_chaos_.set(enable_chaos);
+        let mut scheduler = self.scheduler_.borrow_mut();
+        scheduler.set_enable_chaos(enable_chaos, seed);
+        self.trace_out
+            .borrow()
+            .write_chaos_seed(scheduler.chaos_seed())
+    }
+
+    /// Name, describe and tag the trace, as `rd tag` would afterwards.
+    pub fn set_trace_metadata(
+        &self,
+        name: Option<String>,
+        description: Option<String>,
+        tags: Vec<(String, String)>,
+    ) -> io::Result<()> {
+        self.trace_out.borrow().update_metadata(|metadata| {
+            if name.is_some() {
+                metadata.name = name;
+            }
+            if description.is_some() {
+                metadata.description = description;
+            }
+            metadata.tags.extend(tags);
+        })
+    }
+
+    /// Copy the files the tracees open read-only or map into the trace, so
+    /// that it can be replayed without them. See `FileSnapshotStore`.
+    pub fn enable_file_capture(&self) {
+        self.trace_out.borrow_mut().enable_file_capture();
+    }
+
+    /// Opt in to recording writes other processes make to files the tracees
+    /// have mapped shared and writable. This has costs, see
+    /// `MonitoredSharedMemory`.
+    pub fn set_monitor_writable_shared_memory(&self, monitor: bool) {
+        self.monitor_writable_shared_memory_.set(monitor);
+    }
+
+    pub fn monitor_writable_shared_memory(&self) -> bool {
+        self.monitor_writable_shared_memory_.get()
+    }
+
+    pub fn trace_writer(&self) -> Ref<'_, TraceWriter> {
+        self.trace_out.borrow()
+    }
+
+    pub fn trace_writer_mut(&self) -> RefMut<'_, TraceWriter> {
+        self.trace_out.borrow_mut()
+    }
+
+    pub fn seccomp_filter_rewriter(&self) -> Ref<'_, SeccompFilterRewriter> {
+        self.seccomp_filter_rewriter_.borrow()
+    }
+
+    pub fn seccomp_filter_rewriter_mut(&self) -> RefMut<'_, SeccompFilterRewriter> {
+        self.seccomp_filter_rewriter_.borrow_mut()
+    }
+
+    pub fn syscall_buffer_size(&self) -> usize {
+        self.syscall_buffer_size_
+    }
+
     pub fn syscallbuf_desched_sig(&self) -> u8 {
         self.syscallbuf_desched_sig_
     }
@@ -161,12 +607,6 @@ impl RecordSession {
     pub fn use_syscall_buffer(&self) -> bool {
         self.use_syscall_buffer_
     }
-    pub fn trace_stream(&self) -> Option<&TraceStream> {
-        Some(&self.trace_out)
-    }
-    pub fn trace_stream_mut(&mut self) -> Option<&mut TraceStream> {
-        Some(&mut self.trace_out)
-    }
 }
 
 impl Deref for RecordSession {
@@ -190,7 +630,7 @@ impl Session for RecordSession {
     }
 
     fn on_destroy_task(&self, _t: TaskUid) {
-        unimplemented!()
+        self.scheduler_mut().on_destroy();
     }
 
     fn as_session_inner(&self) -> &SessionInner {
@@ -203,15 +643,92 @@ impl Session for RecordSession {
 
     fn new_task(
         &self,
-        _tid: pid_t,
+        tid: pid_t,
         _rec_tid: Option<pid_t>,
-        _serial: u32,
-        _a: SupportedArch,
+        serial: u32,
+        a: SupportedArch,
     ) -> Box<dyn Task> {
-        unimplemented!()
+        Box::new(RecordTask::new(self, tid, serial, a))
+    }
+
+    fn on_create(&self, t: TaskSharedPtr) {
+        let rec_tid = t.borrow().rec_tid;
+        self.task_map.borrow_mut().insert(rec_tid, t.clone());
+        self.scheduler_mut()
+            .on_create(t.borrow().as_record_task().unwrap());
+    }
+
+    fn as_record(&self) -> Option<&RecordSession> {
+        Some(self)
+    }
+
+    fn trace_stream(&self) -> Option<Ref<'_, TraceStream>> {
+        let w = self.trace_out.borrow();
+        Some(Ref::map(w, |t| t.deref()))
+    }
+
+    fn trace_stream_mut(&self) -> Option<RefMut<'_, TraceStream>> {
+        let w = self.trace_out.borrow_mut();
+        Some(RefMut::map(w, |t| t.deref_mut()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        perf_counters::init_pmu,
+        replayer::{ReplayCallbacks, Replayer},
+        session::replay_session::Flags as ReplayFlags,
+        trace::trace_frame::TraceFrame,
+    };
+    use std::{env, fs, process};
+
+    #[derive(Default)]
+    struct CountExits(usize);
+
+    impl ReplayCallbacks for CountExits {
+        fn on_exit(&mut self, _t: &mut dyn Task, _frame: &TraceFrame) {
+            self.0 += 1;
+        }
     }
 
-    fn on_create(&self, _t: TaskSharedPtr) {
-        unimplemented!()
+    /// Needs ptrace and working performance counters, which most CI machines don't have.
+    #[test]
+    #[ignore]
+    fn record_and_replay_true() {
+        init_pmu();
+        let dir = env::temp_dir().join(format!("rd-record-test-{}", process::id()));
+        let exe = OsString::from("/bin/true");
+        {
+            let session = RecordSession::create(
+                &exe,
+                &[exe.clone()],
+                &[],
+                BindCPU::UnboundCPU,
+                Some(dir.as_os_str()),
+            );
+            let record_session = session.as_record().unwrap();
+            let status = loop {
+                match record_session.record_step() {
+                    RecordResult::StepContinue => (),
+                    RecordResult::StepExited(status) => break status,
+                }
+            };
+            assert_eq!(Some(0), status.exit_code());
+            record_session.close_trace_writer(CloseStatus::CloseOk);
+        }
+
+        let flags = ReplayFlags {
+            redirect_stdio: false,
+            share_private_mappings: false,
+            cpu_unbound: true,
+        };
+        let mut callbacks = CountExits::default();
+        Replayer::new(Some(&dir), flags)
+            .run(&mut callbacks)
+            .unwrap();
+        assert_eq!(1, callbacks.0);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
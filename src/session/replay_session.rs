@@ -15,6 +15,7 @@ use crate::{
         signal::siginfo_t,
     },
     cpuid_bug_detector::CPUIDBugDetector,
+    divergence_bundle::write_divergence_bundle,
     emu_fs::{EmuFs, EmuFsSharedPtr},
     event::{Event, EventType, SignalDeterministic, SignalEventData, SyscallState},
     fast_forward::{fast_forward_through_instruction, FastForwardStatus},
@@ -26,12 +27,16 @@ use crate::{
         SupportedArch,
     },
     kernel_metadata::{signal_name, syscall_name},
-    log::LogLevel::{LogDebug, LogError},
+    log::{
+        self,
+        LogLevel::{LogDebug, LogError},
+    },
     perf_counters,
     perf_counters::{PerfCounters, TIME_SLICE_SIGNAL},
     registers::{MismatchBehavior, Registers},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::RemotePtr,
+    replay_profiler::{self, ProfileCategory},
     replay_syscall::{
         rep_after_enter_syscall,
         rep_prepare_run_to_syscall,
@@ -614,6 +619,7 @@ impl ReplaySession {
             result.break_status.task = Some(rc_t.borrow().weak_self.clone());
             let mut dt = rc_t.borrow_mut();
             let t = dt.as_replay_task_mut().unwrap();
+            let _task_context = log::set_task_context(Some((t.tid, self.trace_reader().time())));
             // Advance towards fulfilling `current_step`.
             if self.try_one_trace_step(t, &constraints) == Completion::Incomplete {
                 if EventType::EvTraceTermination == self.current_trace_frame().event().event_type()
@@ -1757,25 +1763,43 @@ impl ReplaySession {
             // Unfortunately we can't do this for TSTEP_FLUSH_SYSCALLBUF
             // because its tick count can't be trusted.
             // cont_syscall_boundary handles the ticks constraint for those cases.
+            let _timer = replay_profiler::timer(ProfileCategory::ReplayStep, || {
+                "advance_to_ticks_target".into()
+            });
             return self.advance_to_ticks_target(t, &constraints);
         }
 
+        let _timer = replay_profiler::timer(ProfileCategory::ReplayStep, || {
+            format!("{:?}", self.current_step.get().action)
+        });
         match self.current_step.get().action {
             ReplayTraceStepType::TstepRetire => Completion::Complete,
             ReplayTraceStepType::TstepEnterSyscall => self.enter_syscall(t, &constraints),
             ReplayTraceStepType::TstepExitSyscall => self.exit_syscall(t),
-            ReplayTraceStepType::TstepDeterministicSignal => self.emulate_deterministic_signal(
-                t,
-                self.current_step.get().target().signo,
-                &constraints,
-            ),
+            ReplayTraceStepType::TstepDeterministicSignal => {
+                let complete = self.emulate_deterministic_signal(
+                    t,
+                    self.current_step.get().target().signo,
+                    &constraints,
+                );
+                if complete == Completion::Complete {
+                    // Monitored shared memory changes recorded at the signal.
+                    t.apply_all_data_records_from_trace();
+                }
+                complete
+            }
             ReplayTraceStepType::TstepProgramAsyncSignalInterrupt => {
                 // @TODO Ok to have an unwrap here?
-                self.emulate_async_signal(
+                let complete = self.emulate_async_signal(
                     t,
                     &constraints,
                     self.current_step.get().target().ticks.unwrap(),
-                )
+                );
+                if complete == Completion::Complete {
+                    // Monitored shared memory changes recorded at the sched or signal.
+                    t.apply_all_data_records_from_trace();
+                }
+                complete
             }
             ReplayTraceStepType::TstepDeliverSignal => {
                 self.emulate_signal_delivery(t, self.current_step.get().target().signo)
@@ -2109,14 +2133,21 @@ fn guard_unexpected_signal(t: &mut ReplayTask) {
             t.maybe_stop_sig()
         );
     } else if t.status().is_syscall() {
+        let name = syscall_name(
+            t.regs_ref().original_syscallno().try_into().unwrap(),
+            t.arch(),
+        );
+        let rec_regs = t.current_trace_frame().regs_ref().clone();
+        write_divergence_bundle(
+            t,
+            &format!("unrecorded syscall {} while awaiting signal", name),
+            &[("replaying", t.regs_ref()), ("recorded", &rec_regs)],
+        );
         ed_assert!(
             t,
             false,
             "Replay got unrecorded syscall {} while awaiting signal",
-            syscall_name(
-                t.regs_ref().original_syscallno().try_into().unwrap(),
-                t.arch()
-            )
+            name
         );
     }
 }
@@ -2210,6 +2241,17 @@ fn guard_overshoot(
                 MismatchBehavior::LogMismatches,
             );
         }
+        write_divergence_bundle(
+            t,
+            &format!(
+                "overshot target ticks={} by {}",
+                target_ticks, -remaining_ticks
+            ),
+            &[
+                ("replaying", t.regs_ref()),
+                ("recorded", closest_matching_regs.unwrap_or(target_regs)),
+            ],
+        );
         ed_assert!(
             t,
             false,
@@ -83,7 +83,7 @@ pub trait Task: DerefMut<Target = TaskInner> {
     /// DIFF NOTE: @TODO method is protected in rr
     ///
     /// Internal method called after the first wait() during a clone().
-    fn post_wait_clone(&self, _t: &dyn Task, _flags: CloneFlags) {
+    fn post_wait_clone(&mut self, _t: &dyn Task, _flags: CloneFlags) {
         // Do nothing by default. Can be overridden in trait impl-s.
     }
 
@@ -146,7 +146,7 @@ pub trait Task: DerefMut<Target = TaskInner> {
 
     /// Hook called by `resume_execution`.
     fn will_resume_execution(
-        &self,
+        &mut self,
         _resume_req: ResumeRequest,
         _wait_req: WaitRequest,
         _ticks_req: TicksRequest,
@@ -227,7 +227,7 @@ pub trait Task: DerefMut<Target = TaskInner> {
             {
                 continue;
             }
-            self.as_record_task().unwrap().stash_sig();
+            self.as_record_task_mut().unwrap().stash_sig();
         }
     }
 
@@ -271,7 +271,7 @@ pub trait Task: DerefMut<Target = TaskInner> {
                 continue;
             }
             ed_assert!(self, self.session().is_recording());
-            self.as_record_task().unwrap().stash_sig();
+            self.as_record_task_mut().unwrap().stash_sig();
         }
         true
     }
@@ -335,8 +335,26 @@ pub trait Task: DerefMut<Target = TaskInner> {
 
     /// Return true if the status of this has changed, but don't
     /// block.
-    fn try_wait(&self) -> bool {
-        unimplemented!()
+    fn try_wait(&mut self) -> bool {
+        if self.wait_unexpected_exit() {
+            return true;
+        }
+
+        let mut raw_status: i32 = 0;
+        let ret = unsafe {
+            waitpid(
+                self.tid,
+                &mut raw_status,
+                libc::WNOHANG | libc::__WALL | libc::WSTOPPED,
+            )
+        };
+        log!(LogDebug, "waitpid({}, NOHANG) returns {}", self.tid, ret);
+        ed_assert!(self, 0 <= ret, "waitpid({}, NOHANG) failed with {}", self.tid, ret);
+        if ret == self.tid {
+            self.did_waitpid(WaitStatus::new(raw_status));
+            return true;
+        }
+        false
     }
 
     /// Block until the status of this changes. wait() expects the wait to end
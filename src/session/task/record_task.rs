@@ -192,6 +192,7 @@ pub enum AddSysgoodFlag {
     UseSysgood,
 }
 
+#[derive(Copy, Clone, Default)]
 pub struct SyscallbufCodeLayout {
     pub syscallbuf_code_start: RemoteCodePtr,
     pub syscallbuf_code_end: RemoteCodePtr,
@@ -210,39 +211,97 @@ pub enum SignalDisposition {
 pub mod record_task {
     use super::*;
     use crate::{
-        bindings::{kernel::user_desc, signal::siginfo_t},
-        event::{Event, EventType, SignalDeterministic, SignalResolvedDisposition},
-        kernel_abi::{common::preload_interface::syscallbuf_record, SupportedArch},
+        auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
+        bindings::{
+            kernel::user_desc,
+            perf_event::{PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE},
+            ptrace::{
+                PTRACE_EVENT_CLONE,
+                PTRACE_EVENT_FORK,
+                PTRACE_EVENT_VFORK,
+                PTRACE_GETEVENTMSG,
+                PTRACE_SETSIGINFO,
+                PTRACE_SETSIGMASK,
+            },
+            signal::{siginfo_t, SI_QUEUE},
+        },
+        event::{
+            Event,
+            EventType,
+            SignalDeterministic,
+            SignalEventData,
+            SignalResolvedDisposition,
+            SyscallEventData,
+            SyscallState,
+            SyscallbufFlushEventData,
+        },
+        extra_registers::ExtraRegisters,
+        file_monitor::preserve_file_monitor::PreserveFileMonitor,
+        kernel_abi::{
+            common::preload_interface::{
+                mprotect_record,
+                preload_globals,
+                syscallbuf_hdr,
+                syscallbuf_record,
+            },
+            is_ioctl_syscall,
+            is_restart_syscall_syscall,
+            is_wait4_syscall,
+            is_waitid_syscall,
+            is_waitpid_syscall,
+            syscall_number_for_execve,
+            syscall_number_for_gettid,
+            syscall_number_for_rt_sigprocmask,
+            SupportedArch,
+        },
+        kernel_metadata::{signal_name, syscall_name},
         kernel_supplement::sig_set_t,
-        registers::Registers,
+        log::LogLevel::LogDebug,
+        perf_counters::TIME_SLICE_SIGNAL,
+        record_syscall::TaskSyscallState,
+        registers::{with_converted_registers, Registers},
         remote_code_ptr::RemoteCodePtr,
         remote_ptr::{RemotePtr, Void},
         scoped_fd::ScopedFd,
         session::{
-            address_space::memory_range::MemoryRange,
+            address_space::{
+                address_space::AddressSpace,
+                memory_range::MemoryRange,
+                BreakpointType,
+                Enabled,
+                Privileged,
+                Traced,
+            },
             record_session::RecordSession,
             task::{
                 task_common::{
+                    at_preload_init_common,
                     compute_trap_reasons,
                     destroy_buffers,
                     did_waitpid,
                     next_syscallbuf_record,
+                    on_syscall_exit,
                     open_mem_fd,
                     post_exec_for_exe,
                     post_exec_syscall,
+                    post_vm_clone_common,
                     read_bytes_fallible,
                     read_bytes_helper,
                     read_bytes_helper_for,
                     read_c_str,
+                    read_mem,
+                    read_val_mem,
                     resume_execution,
                     set_thread_area,
                     stored_record_size,
                     syscallbuf_data_size,
+                    task_drop_common,
                     write_bytes,
                     write_bytes_helper,
+                    write_val_mem,
                 },
                 task_inner::{
-                    task_inner::{CloneReason, TaskInner, WriteFlags},
+                    task_inner::{CloneReason, PtraceData, TaskInner, WriteFlags},
                     CloneFlags,
                     ResumeRequest,
                     TicksRequest,
@@ -250,24 +309,101 @@ pub mod record_task {
                     WaitRequest,
                 },
                 Task,
+                TaskSharedPtr,
+                TaskSharedWeakPtr,
             },
+            Session,
+            SessionSharedPtr,
         },
         ticks::Ticks,
-        trace::{trace_frame::FrameTime, trace_writer::TraceWriter},
+        trace::{
+            trace_frame::FrameTime,
+            trace_writer::{MappingOrigin, RecordInTrace, TraceWriter},
+        },
+        util::{
+            default_action,
+            good_random,
+            is_deterministic_signal,
+            is_unstoppable_signal,
+            read_proc_status_fields,
+            signal_bit,
+            u8_raw_slice,
+            u8_raw_slice_mut,
+            SignalAction,
+        },
         wait_status::WaitStatus,
+        weak_ptr_set::WeakPtrSet,
+    };
+    use libc::{
+        c_ulong,
+        pid_t,
+        EIO,
+        ESRCH,
+        PR_TSC_ENABLE,
+        SIGCHLD,
+        SIGCONT,
+        SIGKILL,
+        SIGSTOP,
+        SIGTSTP,
+        SIGTTIN,
+        SIGTTOU,
+        SIG_UNBLOCK,
     };
-    use libc::pid_t;
+    use nix::sys::mman::ProtFlags;
+    use owning_ref::OwningHandle;
     use std::{
-        cell::RefCell,
-        collections::{HashSet, VecDeque},
+        cell::{Ref, RefCell, RefMut},
+        cmp::min,
+        collections::VecDeque,
         ffi::{CString, OsStr},
+        fs::{read_dir, read_link},
+        mem::size_of,
         ops::{Deref, DerefMut},
+        path::Path,
         rc::{Rc, Weak},
     };
 
+    /// si_value of the SIGCHLDs we queue to wake up tasks waiting for an emulated stop, see
+    /// `send_synthetic_sigchld_if_necessary()`.
+    pub const SIGCHLD_SYNTHETIC: i32 = 0xbeadf00du32 as i32;
+
+    /// si_code of the TIME_SLICE_SIGNALs we queue to kick a task out of a blocking syscall.
+    /// Only the kernel can send positive codes.
+    pub const SYNTHETIC_TIME_SLICE_SI_CODE: i32 = -9999;
+
+    /// Returns the parent pid of `pid`, or None if it has already gone away.
+    fn get_ppid(pid: pid_t) -> Option<pid_t> {
+        read_proc_status_fields(pid, &[b"PPid"])
+            .ok()
+            .and_then(|v| v.get(0).and_then(|p| p.to_str()?.trim().parse().ok()))
+    }
+
+    pub fn is_synthetic_sigchld(si: &siginfo_t) -> bool {
+        si.si_signo == SIGCHLD
+            && si.si_code == SI_QUEUE
+            && unsafe { si._sifields._rt.si_sigval.sival_int } == SIGCHLD_SYNTHETIC
+    }
+
+    /// Run `f` on `t`, or on `borrowed` instead if that's the same task (and so `t` can't be
+    /// borrowed). Returns None if `t` is mutably borrowed elsewhere.
+    fn with_record_task<R>(
+        t: &TaskSharedPtr,
+        borrowed: Option<&RecordTask>,
+        f: impl FnOnce(&RecordTask) -> R,
+    ) -> Option<R> {
+        if let Some(rt) = borrowed {
+            if rt.weak_self_ptr().ptr_eq(&Rc::downgrade(t)) {
+                return Some(f(rt));
+            }
+        }
+        let t_ref = t.try_borrow().ok()?;
+        Some(f(t_ref.as_record_task().unwrap()))
+    }
+
+    #[derive(Clone)]
     pub struct StashedSignal {
-        siginfo: siginfo_t,
-        deterministic: SignalDeterministic,
+        pub siginfo: siginfo_t,
+        pub deterministic: SignalDeterministic,
     }
 
     #[derive(Copy, Clone, Eq, PartialEq)]
@@ -308,8 +444,8 @@ pub mod record_task {
         /// ptrace emulation state
 
         /// Task for which we're emulating ptrace of this task, or null
-        pub emulated_ptracer: Option<RecordTaskSharedWeakPtr>,
-        pub emulated_ptrace_tracees: HashSet<RecordTaskSharedWeakPtr>,
+        pub emulated_ptracer: Option<TaskSharedWeakPtr>,
+        pub emulated_ptrace_tracees: WeakPtrSet<Box<dyn Task>>,
         pub emulated_ptrace_event_msg: usize,
         /// Saved emulated-ptrace signals
         pub saved_ptrace_siginfos: Vec<siginfo_t>,
@@ -425,6 +561,10 @@ pub mod record_task {
         pub next_pmc_interrupt_is_for_user: bool,
 
         pub did_record_robust_futex_changes: bool,
+
+        /// What `rec_prepare_syscall()` noted about the syscall this task is in, for
+        /// `rec_process_syscall()`.
+        pub syscall_state: TaskSyscallState,
     }
 
     impl Deref for RecordTask {
@@ -441,6 +581,12 @@ pub mod record_task {
         }
     }
 
+    impl Drop for RecordTask {
+        fn drop(&mut self) {
+            task_drop_common(self);
+        }
+    }
+
     impl Task for RecordTask {
         /// Forwarded method
         fn destroy_buffers(&mut self) {
@@ -494,16 +640,161 @@ pub mod record_task {
             Some(self)
         }
 
-        fn on_syscall_exit(&mut self, _syscallno: i32, _arch: SupportedArch, _regs: &Registers) {
-            unimplemented!()
+        fn on_syscall_exit(&mut self, syscallno: i32, arch: SupportedArch, regs: &Registers) {
+            with_converted_registers(regs, arch, |regs| {
+                on_syscall_exit(self, syscallno, arch, regs);
+                rd_arch_function!(self, on_syscall_exit_arch, arch, syscallno, regs)
+            })
+        }
+
+        fn will_resume_execution(
+            &mut self,
+            _resume_req: ResumeRequest,
+            _wait_req: WaitRequest,
+            ticks_req: TicksRequest,
+            maybe_sig: Option<i32>,
+        ) {
+            // We may execute user code, which could lead to an RDTSC or grow-map
+            // operation which unblocks SIGSEGV, and we'll need to know whether to
+            // re-block it. So we need our cached sigmask to be up to date.
+            // We don't need to this if we're not going to execute user code
+            // (i.e. ticks_req == ResumeNoTicks) except that did_wait can't
+            // easily check for that and may restore blocked_sigs so it had better be
+            // accurate.
+            self.get_sigmask();
+
+            if self.stashed_signals_blocking_more_signals {
+                // A stashed signal we have already accepted for this task may
+                // have a sigaction::sa_mask that would block the next signal to be
+                // delivered and cause it to be delivered to a different task. If we allow
+                // such a signal to be delivered to this task then we run the risk of never
+                // being able to process the signal (if it stays blocked indefinitely).
+                // To prevent this, block any further signal delivery as long as there are
+                // stashed signals.
+                // We leave rd signals unblocked. TIME_SLICE_SIGNAL has to be unblocked
+                // because blocking it seems to cause problems for some hardware/kernel
+                // configurations, causing them to stop counting events.
+                let desched_sig =
+                    self.session().as_record().unwrap().syscallbuf_desched_sig() as i32;
+                let mut sigset: sig_set_t =
+                    !(signal_bit(desched_sig) | signal_bit(TIME_SLICE_SIGNAL));
+                if let Some(sig) = maybe_sig {
+                    // We're injecting a signal, so make sure that signal is unblocked.
+                    sigset &= !signal_bit(sig);
+                }
+                let ret = self.fallible_ptrace(
+                    PTRACE_SETSIGMASK,
+                    RemotePtr::from(size_of::<sig_set_t>()),
+                    PtraceData::ReadFrom(u8_raw_slice(&sigset)),
+                );
+                if ret < 0 {
+                    if errno() == EIO {
+                        fatal!("PTRACE_SETSIGMASK not supported; rd requires Linux kernel >= 3.11");
+                    }
+                    ed_assert!(self, errno() == EINVAL);
+                } else {
+                    log!(
+                        LogDebug,
+                        "Set signal mask to block all signals (bar \
+                         SYSCALLBUF_DESCHED_SIGNAL/TIME_SLICE_SIGNAL) while we \
+                         have a stashed signal"
+                    );
+                }
+            }
+
+            // ResumeNoTicks means that tracee code is not going to run so there's no
+            // need to set breakpoints and in fact they might interfere with rd
+            // processing.
+            if ticks_req != TicksRequest::ResumeNoTicks {
+                if !self.at_may_restart_syscall() {
+                    // If the tracee has SIGTRAP blocked or ignored and we hit one of these
+                    // breakpoints, the kernel will automatically unblock the signal and set
+                    // its disposition to DFL, effects which we ought to undo to keep these
+                    // SIGTRAPs invisible to tracees. Fixing the sigmask happens
+                    // automatically in did_wait().
+                    //
+                    // Set breakpoints at untraced syscalls to catch us entering an untraced
+                    // syscall. If we have an interrupted syscall that we may restart, don't
+                    // set the breakpoints because we should restart the syscall instead
+                    // of breaking and delivering signals. The syscallbuf code doesn't
+                    // (and must not) perform more than one blocking syscall for any given
+                    // buffered syscall.
+                    for p in self.syscallbuf_syscall_entry_breakpoints() {
+                        self.vm_shr_ptr()
+                            .add_breakpoint(self, p, BreakpointType::BkptInternal);
+                    }
+                }
+                if self.break_at_syscallbuf_final_instruction {
+                    let addr = self
+                        .syscallbuf_code_layout
+                        .syscallbuf_final_exit_instruction;
+                    self.vm_shr_ptr()
+                        .add_breakpoint(self, addr, BreakpointType::BkptInternal);
+                }
+            }
         }
 
         fn did_wait(&mut self) {
-            unimplemented!()
+            for p in self.syscallbuf_syscall_entry_breakpoints() {
+                self.vm_shr_ptr()
+                    .remove_breakpoint(p, BreakpointType::BkptInternal, self);
+            }
+            if self.break_at_syscallbuf_final_instruction {
+                let addr = self
+                    .syscallbuf_code_layout
+                    .syscallbuf_final_exit_instruction;
+                self.vm_shr_ptr()
+                    .remove_breakpoint(addr, BreakpointType::BkptInternal, self);
+            }
+            if self.stashed_signals_blocking_more_signals {
+                // Saved 'blocked_sigs' must still be correct regardless of syscallbuf
+                // state, because we do not allow stashed_signals_blocking_more_signals
+                // to hold across syscalls (traced or untraced) that change the signal mask.
+                ed_assert!(self, !self.blocked_sigs_dirty);
+                let blocked_sigs = self.blocked_sigs;
+                self.xptrace(
+                    PTRACE_SETSIGMASK,
+                    RemotePtr::from(size_of::<sig_set_t>()),
+                    PtraceData::ReadFrom(u8_raw_slice(&blocked_sigs)),
+                );
+            } else if !self.syscallbuf_child.is_null() {
+                let child = self.syscallbuf_child;
+                let syscallbuf = read_val_mem(self, child, None);
+                if syscallbuf.in_sigprocmask_critical_section != 0 {
+                    // `blocked_sigs` may have been updated but the syscall not yet issued.
+                    // Use the kernel's value.
+                    self.invalidate_sigmask();
+                } else {
+                    let syscallbuf_generation = syscallbuf.blocked_sigs_generation;
+                    if syscallbuf_generation > self.syscallbuf_blocked_sigs_generation {
+                        self.syscallbuf_blocked_sigs_generation = syscallbuf_generation;
+                        self.blocked_sigs = syscallbuf.blocked_sigs;
+                    }
+                }
+            }
+        }
+
+        fn post_wait_clone(&mut self, cloned_from: &dyn Task, flags: CloneFlags) {
+            let rt = cloned_from.as_record_task().unwrap();
+            self.priority = rt.priority;
+            self.syscallbuf_code_layout = rt.syscallbuf_code_layout;
+            self.prctl_seccomp_status = rt.prctl_seccomp_status;
+            self.robust_futex_list = rt.robust_futex_list;
+            self.robust_futex_list_len = rt.robust_futex_list_len;
+            self.tsc_mode = rt.tsc_mode;
+            self.cpuid_mode = rt.cpuid_mode;
+            if flags.contains(CloneFlags::CLONE_SHARE_SIGHANDLERS) {
+                self.sighandlers = rt.sighandlers.clone();
+            } else {
+                let sh = rt.sighandlers.borrow().clone();
+                self.sighandlers = Rc::new(RefCell::new(sh));
+            }
+            self.update_own_namespace_tid();
         }
 
         fn at_preload_init(&mut self) {
-            unimplemented!()
+            at_preload_init_common(self);
+            rd_arch_function!(self, at_preload_init_arch, self.arch())
         }
 
         /// Forwarded method
@@ -567,11 +858,30 @@ pub mod record_task {
 
         fn post_vm_clone(
             &mut self,
-            _reason: CloneReason,
-            _flags: CloneFlags,
-            _origin: &mut dyn Task,
+            reason: CloneReason,
+            flags: CloneFlags,
+            origin: &mut dyn Task,
         ) -> bool {
-            unimplemented!()
+            if post_vm_clone_common(self, reason, flags, origin) {
+                let preload_thread_locals_mapping = self
+                    .vm()
+                    .mapping_of(AddressSpace::preload_thread_locals_start())
+                    .unwrap()
+                    .map
+                    .clone();
+                let mode = self.trace_writer_mut().write_mapped_region(
+                    self,
+                    &preload_thread_locals_mapping,
+                    &preload_thread_locals_mapping.fake_stat(),
+                    &[],
+                    Some(MappingOrigin::RdBufferMapping),
+                    None,
+                );
+                ed_assert!(self, mode == RecordInTrace::DontRecordInTrace);
+                true
+            } else {
+                false
+            }
         }
 
         // Forwarded method
@@ -584,23 +894,118 @@ pub mod record_task {
         /// Every Task owned by a RecordSession is a RecordTask. Functionality that
         /// only applies during recording belongs here.
         pub fn new(
-            _session: &RecordSession,
-            _tid: pid_t,
-            _serial: u32,
-            _a: SupportedArch,
+            session: &RecordSession,
+            tid: pid_t,
+            serial: u32,
+            a: SupportedArch,
         ) -> RecordTask {
-            unimplemented!()
+            let sighandlers = Rc::new(RefCell::new(Sighandlers::new()));
+            if session.tasks().is_empty() {
+                // Initial tracee. It inherited its state from this process, so set it up.
+                // The very first task we fork inherits the signal
+                // dispositions of the current OS process (which should all be
+                // default at this point, but ...).  From there on, new tasks
+                // will transitively inherit from this first task.
+                sighandlers.borrow_mut().init_from_current_process();
+            }
+            let mut t = RecordTask {
+                task_inner: TaskInner::new(session, tid, None, serial, a),
+                ticks_at_last_recorded_syscall_exit: 0,
+                registers_at_start_of_last_timeslice: Registers::new(a),
+                time_at_start_of_last_timeslice: 0,
+                priority: 0,
+                in_round_robin_queue: false,
+                emulated_ptracer: None,
+                emulated_ptrace_tracees: WeakPtrSet::new(),
+                emulated_ptrace_event_msg: 0,
+                saved_ptrace_siginfos: Vec::new(),
+                emulated_stop_code: Default::default(),
+                emulated_ptrace_options: None,
+                emulated_ptrace_cont_command: None,
+                emulated_stop_pending: false,
+                emulated_ptrace_sigchld_pending: false,
+                emulated_sigchld_pending: false,
+                emulated_ptrace_seized: false,
+                emulated_ptrace_queued_exit_stop: false,
+                in_wait_type: WaitType::WaitTypeNone,
+                in_wait_pid: 0,
+                sighandlers,
+                emulated_stop_type: EmulatedStopType::NotStopped,
+                blocked_sigs_dirty: true,
+                blocked_sigs: 0,
+                syscallbuf_blocked_sigs_generation: 0,
+                syscallbuf_code_layout: Default::default(),
+                desched_fd: ScopedFd::new(),
+                flushed_num_rec_bytes: 0,
+                flushed_syscallbuf: false,
+                delay_syscallbuf_reset_for_desched: false,
+                delay_syscallbuf_reset_for_seccomp_trap: false,
+                prctl_seccomp_status: 0,
+                robust_futex_list: RemotePtr::null(),
+                robust_futex_list_len: 0,
+                tid_futex: RemotePtr::null(),
+                own_namespace_rec_tid: 0,
+                exit_code: 0,
+                termination_signal: None,
+                tsc_mode: PR_TSC_ENABLE,
+                cpuid_mode: 1,
+                pending_events: VecDeque::new(),
+                stashed_signals: VecDeque::new(),
+                stashed_signals_blocking_more_signals: false,
+                stashed_group_stop: false,
+                break_at_syscallbuf_traced_syscalls: false,
+                break_at_syscallbuf_untraced_syscalls: false,
+                break_at_syscallbuf_final_instruction: false,
+                next_pmc_interrupt_is_for_user: false,
+                did_record_robust_futex_changes: false,
+                syscall_state: TaskSyscallState::new(),
+            };
+            t.push_event(Event::sentinel());
+            t
         }
 
-        // @TODO clone_task() ??
         pub fn syscallbuf_syscall_entry_breakpoints(&self) -> Vec<RemoteCodePtr> {
-            unimplemented!()
+            let mut result = Vec::new();
+            if self.break_at_syscallbuf_untraced_syscalls {
+                result.push(AddressSpace::rd_page_syscall_entry_point(
+                    Traced::Untraced,
+                    Privileged::Unpriviledged,
+                    Enabled::RecordingOnly,
+                    self.arch(),
+                ));
+                result.push(AddressSpace::rd_page_syscall_entry_point(
+                    Traced::Untraced,
+                    Privileged::Unpriviledged,
+                    Enabled::RecordingAndReplay,
+                    self.arch(),
+                ));
+            }
+            if self.break_at_syscallbuf_traced_syscalls {
+                result.push(AddressSpace::rd_page_syscall_entry_point(
+                    Traced::Traced,
+                    Privileged::Unpriviledged,
+                    Enabled::RecordingAndReplay,
+                    self.arch(),
+                ));
+            }
+            result
         }
+
         pub fn is_at_syscallbuf_syscall_entry_breakpoint(&self) -> bool {
-            unimplemented!()
+            let i = self.ip().decrement_by_bkpt_insn_length(self.arch());
+            self.syscallbuf_syscall_entry_breakpoints()
+                .iter()
+                .any(|&p| p == i)
         }
+
         pub fn is_at_syscallbuf_final_instruction_breakpoint(&self) -> bool {
-            unimplemented!()
+            if !self.break_at_syscallbuf_final_instruction {
+                return false;
+            }
+            let i = self.ip().decrement_by_bkpt_insn_length(self.arch());
+            i == self
+                .syscallbuf_code_layout
+                .syscallbuf_final_exit_instruction
         }
 
         /// Initialize tracee buffers in this, i.e., implement
@@ -608,20 +1013,94 @@ pub mod record_task {
         /// of *exit from* the rrcall.  Registers will be updated with
         /// the return value from the rrcall, which is also returned
         /// from this call.
-        pub fn init_buffers(&self) {
-            unimplemented!()
+        pub fn init_buffers(&mut self) {
+            rd_arch_function!(self, init_buffers_arch, self.arch())
+        }
+
+        /// Call this when the task has a PTRACE_EVENT_EXEC.
+        pub fn post_exec(&mut self) {
+            // Change syscall number to execve *for the new arch*. If we don't do this,
+            // and the arch changes, then the syscall number for execve in the old arch/
+            // is treated as the syscall we're executing in the new arch, with hilarious
+            // results.
+            let arch = self.arch();
+            let new_syscallno = syscall_number_for_execve(arch);
+            self.registers
+                .set_original_syscallno(new_syscallno as isize);
+            // Fix event architecture and syscall number
+            self.ev_mut().syscall_event_mut().number = new_syscallno;
+            self.ev_mut().syscall_event_mut().set_arch(arch);
+
+            // The signal mask is inherited across execve so we don't need to invalidate.
+            let exe_path = match read_link(format!("/proc/{}/exe", self.tid)) {
+                Ok(path) => path.into_os_string(),
+                Err(e) => {
+                    fatal!("Could not read exe path of {}: {:?}", self.tid, e);
+                    unreachable!()
+                }
+            };
+            self.post_exec_for_exe(&exe_path);
+            if let Some(ptracer) = self.emulated_ptracer.as_ref().and_then(|p| p.upgrade()) {
+                ed_assert!(
+                    self,
+                    !(ptracer.borrow().arch() == SupportedArch::X86 && arch == SupportedArch::X64),
+                    "We don't support a 32-bit process tracing a 64-bit process"
+                );
+            }
+
+            // Clear robust_list state to match kernel state. If this task is cloned
+            // soon after exec, we must not do a bogus set_robust_list syscall for
+            // the clone.
+            self.set_robust_list(RemotePtr::null(), 0);
+            let mut sh = self.sighandlers.borrow().clone();
+            sh.reset_user_handlers(arch);
+            self.sighandlers = Rc::new(RefCell::new(sh));
+
+            // Newly execed tasks always have non-faulting mode (from their point of
+            // view, even if rd is secretly causing faults).
+            self.cpuid_mode = 1;
         }
-        pub fn post_exec(&self) {
-            unimplemented!()
+
+        pub fn trace_writer(&self) -> OwningHandle<SessionSharedPtr, Ref<'_, TraceWriter>> {
+            let sess = self.session();
+            let owning_handle = OwningHandle::new_with_fn(sess, |o| {
+                unsafe { (*o).as_record() }.unwrap().trace_writer()
+            });
+            owning_handle
         }
 
-        pub fn trace_writer(&self) -> &TraceWriter {
-            unimplemented!()
+        pub fn trace_writer_mut(&self) -> OwningHandle<SessionSharedPtr, RefMut<'_, TraceWriter>> {
+            let sess = self.session();
+            let owning_handle = OwningHandle::new_with_fn(sess, |o| {
+                unsafe { (*o).as_record() }.unwrap().trace_writer_mut()
+            });
+            owning_handle
         }
 
         /// Emulate 'tracer' ptracing this task.
-        pub fn set_emulated_ptracer(&self, _tracer: &RecordTask) {
-            unimplemented!()
+        pub fn set_emulated_ptracer(&mut self, tracer: &mut RecordTask) {
+            ed_assert!(self, self.emulated_ptracer.is_none());
+            self.emulated_ptracer = Some(tracer.weak_self_ptr());
+            tracer.emulated_ptrace_tracees.insert(self.weak_self_ptr());
+        }
+
+        /// Stop emulating 'tracer' ptracing this task.
+        /// DIFF NOTE: rr passes a null tracer to set_emulated_ptracer(). We need the tracer
+        /// itself as it's usually borrowed by our caller, e.g. during its PTRACE_DETACH.
+        pub fn clear_emulated_ptracer(&mut self, tracer: &mut RecordTask) {
+            ed_assert!(
+                self,
+                self.emulated_ptracer
+                    .as_ref()
+                    .map_or(false, |p| p.ptr_eq(&tracer.weak_self_ptr()))
+            );
+            ed_assert!(
+                self,
+                self.emulated_stop_type == EmulatedStopType::NotStopped
+                    || self.emulated_stop_type == EmulatedStopType::GroupStop
+            );
+            tracer.emulated_ptrace_tracees.erase(self.weak_self_ptr());
+            self.emulated_ptracer = None;
         }
 
         /// Call this when an event occurs that should stop a ptraced task.
@@ -631,17 +1110,71 @@ pub mod record_task {
         /// make one up based on the status (unless the status is an exit code).
         /// Returns true if the task is stopped-for-emulated-ptrace, false otherwise.
         pub fn emulate_ptrace_stop(
-            &self,
-            _status: WaitStatus,
-            _siginfo: Option<&siginfo_t>,
-            _si_code: Option<i32>,
+            &mut self,
+            status: WaitStatus,
+            siginfo: Option<&siginfo_t>,
+            si_code: Option<i32>,
         ) -> bool {
-            unimplemented!()
+            ed_assert!(
+                self,
+                self.emulated_stop_type == EmulatedStopType::NotStopped
+            );
+            if self.emulated_ptracer.is_none() {
+                return false;
+            }
+            match siginfo {
+                Some(si) => {
+                    ed_assert!(self, status.ptrace_signal() == Some(si.si_signo));
+                    self.save_ptrace_signal_siginfo(si);
+                }
+                None => {
+                    let mut si: siginfo_t = Default::default();
+                    si.si_signo = status.ptrace_signal().unwrap_or(0);
+                    if status.maybe_ptrace_event().is_ptrace_event() || status.is_syscall() {
+                        si.si_code = status.get() >> 8;
+                    } else {
+                        si.si_code = si_code.unwrap_or(0);
+                    }
+                    self.save_ptrace_signal_siginfo(&si);
+                }
+            }
+            self.force_emulate_ptrace_stop(status);
+            true
         }
 
         /// Force the ptrace-stop state no matter what state the task is currently in.
-        pub fn force_emulate_ptrace_stopstatus(&self) -> WaitStatus {
-            unimplemented!()
+        pub fn force_emulate_ptrace_stop(&mut self, status: WaitStatus) {
+            self.emulated_stop_type = if status.maybe_group_stop_sig().is_sig() {
+                EmulatedStopType::GroupStop
+            } else {
+                EmulatedStopType::SignalDeliveryStop
+            };
+            self.emulated_stop_code = status;
+            self.emulated_stop_pending = true;
+            self.emulated_ptrace_sigchld_pending = true;
+
+            let ptracer = self
+                .emulated_ptracer
+                .as_ref()
+                .and_then(|p| p.upgrade())
+                .unwrap();
+            // If our ptracer is borrowed it's the task rd is currently processing (e.g. it's
+            // PTRACE_ATTACHing us right now), so it isn't blocked in a wait. It picks up the
+            // pending stop the next time it waits, see `maybe_emulate_wait()`.
+            if let Ok(mut ptracer_ref) = ptracer.try_borrow_mut() {
+                ptracer_ref
+                    .as_record_task_mut()
+                    .unwrap()
+                    .send_synthetic_sigchld_for(Some(self));
+            }
+            // The SIGCHLD will eventually be reported to rd via a ptrace stop,
+            // interrupting wake_task's syscall (probably a waitpid) if necessary. At
+            // that point, we'll fix up the siginfo data with values that match what
+            // the kernel would have delivered for a real ptracer's SIGCHLD. When the
+            // signal handler (if any) returns, if wake_task was in a blocking wait that
+            // wait will be resumed, at which point rec_prepare_syscall_arch will
+            // discover the pending ptrace result and emulate the wait syscall to
+            // return that result immediately.
         }
 
         /// Called when we're about to deliver a signal to this task. If it's a
@@ -651,8 +1184,63 @@ pub mod record_task {
         /// Note that we can't set the correct siginfo when we send the signal, because
         /// it requires us to set information only the kernel has permission to set.
         /// Returns false if this signal should be deferred.
-        pub fn set_siginfo_for_synthetic_sigchld(&self, _si: &siginfo_t) -> bool {
-            unimplemented!()
+        pub fn set_siginfo_for_synthetic_sigchld(&mut self, si: &mut siginfo_t) -> bool {
+            if !is_synthetic_sigchld(si) {
+                return true;
+            }
+
+            if self.ev().event_type() == EventType::EvSyscallInterruption {
+                let syscallno = self.ev().syscall_event().number;
+                let syscall_arch = self.ev().syscall_event().arch();
+                if is_waitpid_syscall(syscallno, syscall_arch)
+                    || is_waitid_syscall(syscallno, syscall_arch)
+                    || is_wait4_syscall(syscallno, syscall_arch)
+                {
+                    // Wait-like syscalls always check for notifications from waited-for
+                    // processes before they check for pending signals. So, if the tracee has a
+                    // pending notification that also generated a signal, the wait syscall will
+                    // return normally rather than returning with ERESTARTSYS etc. (The signal
+                    // will be dequeued and any handler run on the return to userspace,
+                    // however.) We need to emulate this by deferring our synthetic ptrace
+                    // signal until after the wait syscall has returned.
+                    log!(LogDebug, "Deferring signal because we're in a wait");
+                    // Return false to tell the caller to defer the signal and resume
+                    // the syscall.
+                    return false;
+                }
+            }
+
+            let native_si = unsafe {
+                &mut *(si as *mut siginfo_t as *mut <NativeArch as Architecture>::siginfo_t)
+            };
+            let tracees: Vec<TaskSharedPtr> = self.emulated_ptrace_tracees.iter().collect();
+            for tracee in tracees {
+                let mut tracee_ref = tracee.borrow_mut();
+                let rtracee = tracee_ref.as_record_task_mut().unwrap();
+                if rtracee.emulated_ptrace_sigchld_pending {
+                    rtracee.emulated_ptrace_sigchld_pending = false;
+                    rtracee.set_siginfo_for_waited_task::<NativeArch>(native_si);
+                    si._sifields._rt.si_sigval.sival_int = 0;
+                    return true;
+                }
+            }
+
+            let children: Vec<_> = self.thread_group().children().iter().collect();
+            for child_tg in children {
+                let child_tasks: Vec<_> = child_tg.borrow().task_set().iter().collect();
+                for child in child_tasks {
+                    let mut child_ref = child.borrow_mut();
+                    let rchild = child_ref.as_record_task_mut().unwrap();
+                    if rchild.emulated_sigchld_pending {
+                        rchild.emulated_sigchld_pending = false;
+                        rchild.set_siginfo_for_waited_task::<NativeArch>(native_si);
+                        si._sifields._rt.si_sigval.sival_int = 0;
+                        return true;
+                    }
+                }
+            }
+
+            true
         }
 
         pub fn set_siginfo_for_waited_task<Arch: Architecture>(&self, si: &mut Arch::siginfo_t) {
@@ -669,124 +1257,376 @@ pub mod record_task {
         /// Return a reference to the saved siginfo record for the stop-signal
         /// that we're currently in a ptrace-stop for.
         pub fn get_saved_ptrace_siginfo(&self) -> &siginfo_t {
-            unimplemented!()
+            let sig = self.emulated_stop_code.ptrace_signal().unwrap_or(0);
+            ed_assert!(self, sig > 0);
+            match self
+                .saved_ptrace_siginfos
+                .iter()
+                .find(|si| si.si_signo == sig)
+            {
+                Some(si) => si,
+                None => {
+                    ed_assert!(self, false, "No saved siginfo found for stop-signal???");
+                    unreachable!()
+                }
+            }
         }
 
         /// When emulating a ptrace-continue with a signal number, extract the siginfo
         /// that was saved by `save_ptrace_signal_siginfo`. If no such siginfo was
         /// saved, make one up.
-        pub fn take_ptrace_signal_siginfo(&self, _sig: i32) -> siginfo_t {
-            unimplemented!()
+        pub fn take_ptrace_signal_siginfo(&mut self, sig: i32) -> siginfo_t {
+            match self
+                .saved_ptrace_siginfos
+                .iter()
+                .position(|si| si.si_signo == sig)
+            {
+                Some(i) => self.saved_ptrace_siginfos.remove(i),
+                None => {
+                    let mut si: siginfo_t = Default::default();
+                    si.si_signo = sig;
+                    si
+                }
+            }
         }
 
         /// Returns true if this task is in a waitpid or similar that would return
         /// when t's status changes due to a ptrace event.
-        pub fn is_waiting_for_ptrace(&self, _t: &RecordTask) -> bool {
-            unimplemented!()
+        pub fn is_waiting_for_ptrace(&self, t: &RecordTask) -> bool {
+            // This task's process must be a ptracer of t.
+            // Compare thread groups without borrowing the ptracer: it may well be us.
+            match &t.emulated_ptracer {
+                Some(ptracer) if self.thread_group().task_set().has(ptracer.clone()) => (),
+                _ => return false,
+            }
+            // XXX need to check `options` to make sure this task is eligible!!
+            match self.in_wait_type {
+                WaitType::WaitTypeNone => false,
+                WaitType::WaitTypeAny => true,
+                WaitType::WaitTypeSamePgid => unsafe {
+                    libc::getpgid(t.tgid()) == libc::getpgid(self.tgid())
+                },
+                WaitType::WaitTypePgid => unsafe { libc::getpgid(t.tgid()) == self.in_wait_pid },
+                // When waiting for a ptracee, a specific pid is interpreted as the
+                // exact tid.
+                WaitType::WaitTypePid => t.tid == self.in_wait_pid,
+            }
         }
 
         /// Returns true if this task is in a waitpid or similar that would return
         /// when t's status changes due to a regular event (exit).
-        pub fn is_waiting_for(&self, _t: &RecordTask) -> bool {
-            unimplemented!()
+        pub fn is_waiting_for(&self, t: &RecordTask) -> bool {
+            // t must be a child of this task.
+            match t.thread_group().parent() {
+                Some(parent) if Rc::ptr_eq(&parent, &self.thread_group_shr_ptr()) => (),
+                _ => return false,
+            }
+            match self.in_wait_type {
+                WaitType::WaitTypeNone => false,
+                WaitType::WaitTypeAny => true,
+                WaitType::WaitTypeSamePgid => unsafe {
+                    libc::getpgid(t.tgid()) == libc::getpgid(self.tgid())
+                },
+                WaitType::WaitTypePgid => unsafe { libc::getpgid(t.tgid()) == self.in_wait_pid },
+                WaitType::WaitTypePid => t.tgid() == self.in_wait_pid,
+            }
         }
 
         /// Call this to force a group stop for this task with signal 'sig',
         /// notifying ptracer if necessary.
-        pub fn apply_group_stop(&self, _sig: i32) {
-            unimplemented!()
+        pub fn apply_group_stop(&mut self, sig: i32) {
+            if self.emulated_stop_type != EmulatedStopType::NotStopped {
+                return;
+            }
+            log!(
+                LogDebug,
+                "setting {} to GROUP_STOP due to signal {}",
+                self.tid,
+                signal_name(sig)
+            );
+            let status = WaitStatus::for_group_sig(sig, self);
+            if !self.emulate_ptrace_stop(status, None, None) {
+                self.emulated_stop_type = EmulatedStopType::GroupStop;
+                self.emulated_stop_code = status;
+                self.emulated_stop_pending = true;
+                self.emulated_sigchld_pending = true;
+                let ppid = self.get_parent_pid();
+                if let Some(parent) = self.session().find_task_from_rec_tid(ppid) {
+                    parent
+                        .borrow_mut()
+                        .as_record_task_mut()
+                        .unwrap()
+                        .send_synthetic_sigchld_for(Some(self));
+                }
+            }
         }
 
         /// Call this after `sig` is delivered to this task.  Emulate
         /// sighandler updates induced by the signal delivery.
-        pub fn signal_delivered(&self, _sig: i32) {
-            unimplemented!()
+        pub fn signal_delivered(&mut self, sig: i32) {
+            let arch = self.arch();
+            let disposition = {
+                let mut sighandlers = self.sighandlers.borrow_mut();
+                let h = sighandlers.get_mut(sig as usize);
+                if h.resethand {
+                    reset_handler(h, arch);
+                }
+                h.disposition()
+            };
+
+            if !self.is_sig_ignored(sig) {
+                match sig {
+                    SIGTSTP | SIGTTIN | SIGTTOU
+                        if disposition == SignalDisposition::SignalHandler => {}
+                    SIGTSTP | SIGTTIN | SIGTTOU | SIGSTOP => {
+                        // All threads in the process are stopped.
+                        self.apply_group_stop(sig);
+                        for t in self.thread_group_siblings() {
+                            t.borrow_mut()
+                                .as_record_task_mut()
+                                .unwrap()
+                                .apply_group_stop(sig);
+                        }
+                    }
+                    SIGCONT => self.emulate_sigcont(),
+                    _ => (),
+                }
+            }
+
+            self.send_synthetic_sigchld_if_necessary();
         }
 
         /// Return true if `sig` is pending but hasn't been reported to ptrace yet
-        pub fn is_signal_pending(&self, _sig: i32) -> bool {
-            unimplemented!()
+        pub fn is_signal_pending(&self, sig: i32) -> bool {
+            match self.read_sigsets(&[b"SigPnd", b"ShdPnd"]) {
+                Some(sets) => (sets[0] | sets[1]) & signal_bit(sig) != 0,
+                None => false,
+            }
         }
 
         /// Return true if there are any signals pending that are not blocked.
         pub fn has_any_actionable_signal(&self) -> bool {
-            unimplemented!()
+            match self.read_sigsets(&[b"SigPnd", b"ShdPnd", b"SigBlk"]) {
+                Some(sets) => (sets[0] | sets[1]) & !sets[2] != 0,
+                None => false,
+            }
         }
 
         /// Get all threads out of an emulated GROUP_STOP
-        pub fn emulate_sigcont(&self) {
-            unimplemented!()
+        pub fn emulate_sigcont(&mut self) {
+            self.leave_group_stop();
+            for t in self.thread_group_siblings() {
+                t.borrow_mut()
+                    .as_record_task_mut()
+                    .unwrap()
+                    .leave_group_stop();
+            }
+        }
+
+        fn leave_group_stop(&mut self) {
+            log!(
+                LogDebug,
+                "setting {} to NOT_STOPPED due to SIGCONT",
+                self.tid
+            );
+            self.clear_stashed_group_stop();
+            self.emulated_stop_pending = false;
+            self.emulated_stop_type = EmulatedStopType::NotStopped;
         }
 
         /// Return true if the disposition of `sig` in `table` isn't
         /// SIG_IGN or SIG_DFL, that is, if a user sighandler will be
         /// invoked when `sig` is received.
-        pub fn signal_has_user_handler(&self, _sig: i32) -> bool {
-            unimplemented!()
+        pub fn signal_has_user_handler(&self, sig: i32) -> bool {
+            self.sig_disposition(sig) == SignalDisposition::SignalHandler
         }
 
         /// If signal_has_user_handler(sig) is true, return the address of the
         /// user handler, otherwise return null.
-        pub fn get_signal_user_handler(&self, _sig: i32) -> RemoteCodePtr {
-            unimplemented!()
+        pub fn get_signal_user_handler(&self, sig: i32) -> RemoteCodePtr {
+            self.sighandlers
+                .borrow()
+                .get(sig as usize)
+                .get_user_handler()
+                .unwrap_or_else(RemoteCodePtr::null)
         }
 
         /// Return true if the signal handler for `sig` takes a &siginfo_t
         /// parameter.
-        pub fn signal_handler_takes_siginfo(&self, _sig: i32) -> bool {
-            unimplemented!()
+        pub fn signal_handler_takes_siginfo(&self, sig: i32) -> bool {
+            self.sighandlers.borrow().get(sig as usize).takes_siginfo
         }
 
         /// Return `sig`'s current sigaction. Returned as raw bytes since the
         /// data is architecture-dependent.
-        pub fn signal_action(&self, _sig: i32) -> &[u8] {
-            unimplemented!()
+        /// DIFF NOTE: Returns a `Ref` as the table is behind a `RefCell`.
+        pub fn signal_action(&self, sig: i32) -> Ref<'_, [u8]> {
+            Ref::map(self.sighandlers.borrow(), |sighandlers| {
+                sighandlers.get(sig as usize).sa.as_slice()
+            })
         }
 
         /// Return true iff `sig` is blocked for this.
-        pub fn is_sig_blocked(&self, _sig: i32) -> bool {
-            unimplemented!()
+        pub fn is_sig_blocked(&mut self, sig: i32) -> bool {
+            if is_unstoppable_signal(sig) {
+                // These can never be blocked
+                return false;
+            }
+            self.get_sigmask() & signal_bit(sig) != 0
         }
 
         /// Return true iff `sig` is SIG_IGN, or it's SIG_DFL and the
         /// default disposition is "ignore".
-        pub fn is_sig_ignored(&self, _sig: i32) -> bool {
-            unimplemented!()
+        pub fn is_sig_ignored(&self, sig: i32) -> bool {
+            if is_unstoppable_signal(sig) {
+                // These can never be ignored
+                return false;
+            }
+            match self.sig_disposition(sig) {
+                SignalDisposition::SignalIgnore => true,
+                SignalDisposition::SignalDefault => default_action(sig) == SignalAction::Ignore,
+                SignalDisposition::SignalHandler => false,
+            }
         }
 
         /// Return the applications current disposition of `sig`.
-        pub fn sig_disposition(&self, _sig: i32) -> SignalDisposition {
-            unimplemented!()
+        pub fn sig_disposition(&self, sig: i32) -> SignalDisposition {
+            self.sighandlers.borrow().get(sig as usize).disposition()
         }
 
         /// Return the resolved disposition --- what this signal will actually do,
         /// taking into account the default behavior.
         pub fn sig_resolved_disposition(
-            &self,
-            _sig: i32,
-            _deterministic: SignalDeterministic,
+            &mut self,
+            sig: i32,
+            deterministic: SignalDeterministic,
         ) -> SignalResolvedDisposition {
-            unimplemented!()
+            if self.is_fatal_signal(sig, deterministic) {
+                return SignalResolvedDisposition::DispositionFatal;
+            }
+            if self.signal_has_user_handler(sig) && !self.is_sig_blocked(sig) {
+                return SignalResolvedDisposition::DispositionUserHandler;
+            }
+            SignalResolvedDisposition::DispositionIgnored
         }
 
         /// Set the siginfo for the signal-stop of this.
-        pub fn set_siginfo(&self, _si: &siginfo_t) {
-            unimplemented!()
+        pub fn set_siginfo(&mut self, si: &siginfo_t) {
+            self.pending_siginfo = *si;
+            self.ptrace_if_alive(
+                PTRACE_SETSIGINFO,
+                RemotePtr::null(),
+                PtraceData::ReadFrom(u8_raw_slice(si)),
+            );
         }
 
         /// Note that the task sigmask needs to be refetched.
-        pub fn invalidate_sigmask(&self) {
-            unimplemented!()
+        pub fn invalidate_sigmask(&mut self) {
+            self.blocked_sigs_dirty = true;
         }
 
         /// Reset the signal handler for this signal to the default.
-        pub fn did_set_sig_handler_default(&self, _sig: i32) {
-            unimplemented!()
+        pub fn did_set_sig_handler_default(&mut self, sig: i32) {
+            let arch = self.arch();
+            reset_handler(self.sighandlers.borrow_mut().get_mut(sig as usize), arch);
         }
 
         /// Check that our status for `sig` matches what's in /proc/<pid>/status.
-        pub fn verify_signal_states(&self) {
-            unimplemented!()
+        pub fn verify_signal_states(&mut self) {
+            if !cfg!(debug_assertions) {
+                return;
+            }
+            if self.ev().is_syscall_event() {
+                // If the syscall event is on the event stack with PROCESSING or EXITING
+                // states, we won't have applied the signal-state updates yet while the
+                // kernel may have.
+                return;
+            }
+            let sets = match self.read_sigsets(&[b"SigBlk", b"SigIgn", b"SigCgt"]) {
+                Some(sets) => sets,
+                None => return,
+            };
+            let (blocked, ignored, caught) = (sets[0], sets[1], sets[2]);
+            for sig in 1.._NSIG as i32 {
+                let mask = signal_bit(sig);
+                if is_unstoppable_signal(sig) {
+                    ed_assert!(
+                        self,
+                        blocked & mask == 0,
+                        "Expected {} to not be blocked, but it is",
+                        signal_name(sig)
+                    );
+                    ed_assert!(
+                        self,
+                        ignored & mask == 0,
+                        "Expected {} to not be ignored, but it is",
+                        signal_name(sig)
+                    );
+                    ed_assert!(
+                        self,
+                        caught & mask == 0,
+                        "Expected {} to not be caught, but it is",
+                        signal_name(sig)
+                    );
+                } else {
+                    let is_blocked = self.is_sig_blocked(sig);
+                    ed_assert!(
+                        self,
+                        (blocked & mask != 0) == is_blocked,
+                        "{} {}",
+                        signal_name(sig),
+                        if blocked & mask != 0 {
+                            "is blocked"
+                        } else {
+                            "is not blocked"
+                        }
+                    );
+                    let disposition = self.sig_disposition(sig);
+                    ed_assert!(
+                        self,
+                        (ignored & mask != 0) == (disposition == SignalDisposition::SignalIgnore),
+                        "{} {}",
+                        signal_name(sig),
+                        if ignored & mask != 0 {
+                            "is ignored"
+                        } else {
+                            "is not ignored"
+                        }
+                    );
+                    ed_assert!(
+                        self,
+                        (caught & mask != 0) == (disposition == SignalDisposition::SignalHandler),
+                        "{} {}",
+                        signal_name(sig),
+                        if caught & mask != 0 {
+                            "is caught"
+                        } else {
+                            "is not caught"
+                        }
+                    );
+                }
+            }
+        }
+
+        /// Read the given hex sigset fields of /proc/<tid>/status. Returns `None` if they
+        /// couldn't be read, e.g. because the task is gone.
+        fn read_sigsets(&self, fields: &[&[u8]]) -> Option<Vec<sig_set_t>> {
+            let values = read_proc_status_fields(self.tid, fields).ok()?;
+            if values.len() != fields.len() {
+                return None;
+            }
+            values
+                .iter()
+                .map(|v| sig_set_t::from_str_radix(v.to_str()?.trim(), 16).ok())
+                .collect()
+        }
+
+        /// The other tasks in our thread group.
+        fn thread_group_siblings(&self) -> Vec<TaskSharedPtr> {
+            self.thread_group()
+                .task_set()
+                .iter_except(self.weak_self_ptr())
+                .collect()
         }
 
         /// Stashed-signal API: if a signal becomes pending at an
@@ -807,48 +1647,196 @@ pub mod record_task {
         ///
         /// If the process unexpectedly died (due to SIGKILL), we don't
         /// stash anything.
-        pub fn stash_sig(&self) {
-            unimplemented!()
+        ///
+        /// DIFF NOTE: Stashed signals are identified by their index in `stashed_signals`
+        /// rather than by pointer.
+        pub fn stash_sig(&mut self) {
+            let sig = self.maybe_stop_sig().unwrap_sig();
+            // Callers should avoid passing the desched signal in here.
+            ed_assert!(
+                self,
+                sig != self.session().as_record().unwrap().syscallbuf_desched_sig() as i32
+            );
+            // multiple non-RT signals coalesce
+            if sig < 32 && self.has_stashed_sig(sig) {
+                log!(
+                    LogDebug,
+                    "discarding stashed signal {} since we already have one pending",
+                    sig
+                );
+                return;
+            }
+
+            let siginfo = *self.get_siginfo();
+            let deterministic = is_deterministic_signal(self);
+            self.stashed_signals.push_back(StashedSignal {
+                siginfo,
+                deterministic,
+            });
+            self.block_more_signals_until_stash_processed();
+        }
+
+        pub fn stash_synthetic_sig(&mut self, si: &siginfo_t, deterministic: SignalDeterministic) {
+            let sig = si.si_signo;
+            debug_assert!(sig != 0);
+            // Callers should avoid passing the desched signal in here.
+            ed_assert!(
+                self,
+                sig != self.session().as_record().unwrap().syscallbuf_desched_sig() as i32
+            );
+            // multiple non-RT signals coalesce
+            if sig < 32 {
+                if let Some(i) = self
+                    .stashed_signals
+                    .iter()
+                    .position(|s| s.siginfo.si_signo == sig)
+                {
+                    if deterministic == SignalDeterministic::DeterministicSig
+                        && self.stashed_signals[i].deterministic
+                            == SignalDeterministic::NondeterministicSig
+                    {
+                        self.stashed_signals.remove(i);
+                    } else {
+                        return;
+                    }
+                }
+            }
+
+            self.stashed_signals.push_front(StashedSignal {
+                siginfo: *si,
+                deterministic,
+            });
+            self.block_more_signals_until_stash_processed();
         }
-        pub fn stash_synthetic_sig(&self, _si: &siginfo_t, _deterministic: SignalDeterministic) {
-            unimplemented!()
+
+        /// Once we've stashed a signal, stop at the next traced/untraced syscall to
+        /// check whether we need to process the signal before it runs.
+        fn block_more_signals_until_stash_processed(&mut self) {
+            self.stashed_signals_blocking_more_signals = true;
+            self.break_at_syscallbuf_final_instruction = true;
+            self.break_at_syscallbuf_traced_syscalls = true;
+            self.break_at_syscallbuf_untraced_syscalls = true;
         }
+
         pub fn has_any_stashed_sig(&self) -> bool {
-            unimplemented!()
-        }
-        pub fn stashed_sig_not_synthetic_sigchld(&self) -> &siginfo_t {
-            unimplemented!()
-        }
-        pub fn has_stashed_sig(&self, _sig: i32) -> bool {
-            unimplemented!()
-        }
-        pub fn peek_stashed_sig_to_deliver(&self) -> &StashedSignal {
-            unimplemented!()
-        }
-        pub fn pop_stash_sig(&self, _stashed: &StashedSignal) {
-            unimplemented!()
+            !self.stashed_signals.is_empty()
+        }
+        pub fn stashed_sig_not_synthetic_sigchld(&self) -> Option<&StashedSignal> {
+            self.stashed_signals
+                .iter()
+                .find(|s| !is_synthetic_sigchld(&s.siginfo))
+        }
+        pub fn has_stashed_sig(&self, sig: i32) -> bool {
+            self.stashed_signals
+                .iter()
+                .any(|s| s.siginfo.si_signo == sig)
+        }
+        /// Returns the index in `stashed_signals` of the signal to deliver next, if any.
+        pub fn peek_stashed_sig_to_deliver(&self) -> Option<usize> {
+            if self.stashed_signals.is_empty() {
+                return None;
+            }
+            // Choose the first non-synthetic-SIGCHLD signal so that if a syscall should
+            // be interrupted, we'll interrupt it.
+            Some(
+                self.stashed_signals
+                    .iter()
+                    .position(|s| !is_synthetic_sigchld(&s.siginfo))
+                    .unwrap_or(0),
+            )
+        }
+        pub fn pop_stash_sig(&mut self, index: usize) -> StashedSignal {
+            match self.stashed_signals.remove(index) {
+                Some(stashed) => stashed,
+                None => {
+                    ed_assert!(self, false, "signal not found");
+                    unreachable!()
+                }
+            }
         }
-        pub fn stashed_signal_processed(&self) {
-            unimplemented!()
+        pub fn stashed_signal_processed(&mut self) {
+            let has_stashed = self.has_any_stashed_sig();
+            self.break_at_syscallbuf_final_instruction = has_stashed;
+            self.break_at_syscallbuf_traced_syscalls = has_stashed;
+            self.break_at_syscallbuf_untraced_syscalls = has_stashed;
+            self.stashed_signals_blocking_more_signals = has_stashed;
         }
 
         /// If a group-stop occurs at an inconvenient time, stash it and
         /// process it later.
-        pub fn stash_group_stop(&self) {
-            unimplemented!()
+        pub fn stash_group_stop(&mut self) {
+            self.stashed_group_stop = true;
         }
-        pub fn clear_stashed_group_stop(&self) {
-            unimplemented!()
+        pub fn clear_stashed_group_stop(&mut self) {
+            self.stashed_group_stop = false;
         }
         pub fn has_stashed_group_stop(&self) -> bool {
-            unimplemented!()
+            self.stashed_group_stop
         }
 
         /// Return true if the current state of this looks like the
         /// interrupted syscall at the top of our event stack, if there
         /// is one.
         pub fn is_syscall_restart(&self) -> bool {
-            unimplemented!()
+            if EventType::EvSyscallInterruption != self.ev().event_type() {
+                return false;
+            }
+
+            let mut syscallno = self.regs_ref().original_syscallno() as i32;
+            let syscall_arch = self.ev().syscall_event().arch();
+            let call_name = syscall_name(syscallno, syscall_arch);
+            log!(
+                LogDebug,
+                "  is syscall interruption of recorded {}? (now {})",
+                self.ev(),
+                call_name
+            );
+
+            // It's possible for the tracee to resume after a sighandler
+            // with a fresh syscall that happens to be the same as the one
+            // that was interrupted.  So we check here if the args are the
+            // same.
+            //
+            // Of course, it's possible (but less likely) for the tracee
+            // to incidentally resume with a fresh syscall that just
+            // happens to have the same *arguments* too.  But in that
+            // case, we would usually set up scratch buffers etc the same
+            // was as for the original interrupted syscall, so we just
+            // save a step here.
+            //
+            // @TODO: it's possible for arg structures to be mutated
+            // between the original call and restarted call in such a way
+            // that it might change the scratch allocation decisions.
+            if is_restart_syscall_syscall(syscallno, syscall_arch) {
+                syscallno = self.ev().syscall_event().number;
+                log!(LogDebug, "  (SYS_restart_syscall)");
+            }
+            if self.ev().syscall_event().number != syscallno {
+                log!(LogDebug, "  interrupted {} != {}", self.ev(), call_name);
+                return false;
+            }
+
+            let old_regs = &self.ev().syscall_event().regs;
+            let regs = self.regs_ref();
+            if !(old_regs.arg1() == regs.arg1()
+                && old_regs.arg2() == regs.arg2()
+                && old_regs.arg3() == regs.arg3()
+                && old_regs.arg4() == regs.arg4()
+                && old_regs.arg5() == regs.arg5()
+                && old_regs.arg6() == regs.arg6())
+            {
+                log!(
+                    LogDebug,
+                    "  regs different at interrupted {}: {} vs {}",
+                    call_name,
+                    old_regs,
+                    regs
+                );
+                return false;
+            }
+
+            log!(LogDebug, "  restart of {}", call_name);
+            true
         }
 
         /// Return true iff this is at an execution state where
@@ -860,17 +1848,34 @@ pub mod record_task {
         /// then delivering the signal may restart the first syscall
         /// and this method will return true.
         pub fn at_may_restart_syscall(&self) -> bool {
-            unimplemented!()
+            let depth = self.pending_events.len();
+            let maybe_prev_ev = if depth > 2 {
+                Some(&self.pending_events[depth - 2])
+            } else {
+                None
+            };
+            EventType::EvSyscallInterruption == self.ev().event_type()
+                || (EventType::EvSignalDelivery == self.ev().event_type()
+                    && maybe_prev_ev.map_or(false, |prev_ev| {
+                        EventType::EvSyscallInterruption == prev_ev.event_type()
+                    }))
         }
 
         /// Return true if this is at an arm-desched-event syscall.
         pub fn is_arm_desched_event_syscall(&self) -> bool {
-            unimplemented!()
+            self.is_desched_event_syscall()
+                && PERF_EVENT_IOC_ENABLE as usize == self.regs_ref().arg2()
         }
 
         /// Return true if this is at a disarm-desched-event syscall.
         pub fn is_disarm_desched_event_syscall(&self) -> bool {
-            unimplemented!()
+            self.is_desched_event_syscall()
+                && PERF_EVENT_IOC_DISABLE as usize == self.regs_ref().arg2()
+        }
+
+        fn is_desched_event_syscall(&self) -> bool {
+            is_ioctl_syscall(self.regs_ref().original_syscallno() as i32, self.arch())
+                && self.desched_fd_child as isize == self.regs_ref().arg1_signed()
         }
 
         /// Return true if `t` may not be immediately runnable,
@@ -880,19 +1885,61 @@ pub mod record_task {
         /// that the task is no longer possibly-blocked before resuming
         /// its execution.
         pub fn may_be_blocked(&self) -> bool {
-            unimplemented!()
+            (EventType::EvSyscall == self.ev().event_type()
+                && SyscallState::ProcessingSyscall == self.ev().syscall_event().state)
+                || self.emulated_stop_type != EmulatedStopType::NotStopped
+                || (EventType::EvSignalDelivery == self.ev().event_type()
+                    && SignalResolvedDisposition::DispositionFatal
+                        == self.ev().signal_event().disposition)
         }
 
         /// Returns true if it looks like this task has been spinning on an atomic
         /// access/lock.
         pub fn maybe_in_spinlock(&self) -> bool {
-            unimplemented!()
+            self.time_at_start_of_last_timeslice == self.trace_writer().time()
+                && self
+                    .regs_ref()
+                    .matches(&self.registers_at_start_of_last_timeslice)
         }
 
         /// Return true if this is within the syscallbuf library.  This
         /// *does not* imply that $ip is at a buffered syscall.
-        pub fn is_in_syscallbuf(&self) -> bool {
-            unimplemented!()
+        pub fn is_in_syscallbuf(&mut self) -> bool {
+            if !self.vm().syscallbuf_enabled() {
+                // Even if we're in the rd page, if syscallbuf isn't enabled then the
+                // rd page is not being used by syscallbuf.
+                return false;
+            }
+
+            let mut p = self.ip();
+            let layout = &self.syscallbuf_code_layout;
+            let (syscallbuf_code_start, syscallbuf_code_end) =
+                (layout.syscallbuf_code_start, layout.syscallbuf_code_end);
+            if self.is_in_rd_page()
+                || (layout.get_pc_thunks_start <= p && p < layout.get_pc_thunks_end)
+            {
+                // Look at the caller to see if we're in the syscallbuf or not.
+                let mut ok = true;
+                let sp = self.regs_ref().sp();
+                let caller = match self.arch() {
+                    SupportedArch::X86 => {
+                        read_val_mem(self, RemotePtr::<u32>::cast(sp), Some(&mut ok)) as usize
+                    }
+                    SupportedArch::X64 => {
+                        read_val_mem(self, RemotePtr::<u64>::cast(sp), Some(&mut ok)) as usize
+                    }
+                };
+                if !ok {
+                    return false;
+                }
+                p = RemoteCodePtr::from_val(caller);
+            }
+
+            let is_jump_stub = self
+                .vm()
+                .monkeypatcher()
+                .map_or(false, |m| m.is_jump_stub_instruction(p));
+            is_jump_stub || (syscallbuf_code_start <= p && p < syscallbuf_code_end)
         }
 
         /// Shortcut to the most recent `pending_event->desched.rec` when
@@ -900,16 +1947,47 @@ pub mod record_task {
         /// Exists just so that clients don't need to dig around in the
         /// event stack to find this record.
         pub fn desched_rec(&self) -> RemotePtr<syscallbuf_record> {
-            unimplemented!()
+            let ev = self.ev();
+            if ev.is_syscall_event() {
+                ev.syscall_event().desched_rec.unwrap_or(RemotePtr::null())
+            } else if ev.event_type() == EventType::EvDesched {
+                ev.desched_event().rec
+            } else {
+                RemotePtr::null()
+            }
         }
 
         /// Returns true when the task is in a signal handler in an interrupted
         /// system call being handled by syscall buffering.
         pub fn running_inside_desched(&self) -> bool {
-            unimplemented!()
+            for e in self.pending_events.iter() {
+                if e.event_type() == EventType::EvDesched {
+                    return e.desched_event().rec != self.desched_rec();
+                }
+            }
+            false
         }
+        /// The SECCOMP_RET_DATA part of the filter result at a
+        /// PTRACE_EVENT_SECCOMP stop.
         pub fn get_ptrace_eventmsg_seccomp_data(&self) -> u16 {
-            unimplemented!()
+            let mut data: c_ulong = 0;
+            self.xptrace(
+                PTRACE_GETEVENTMSG,
+                RemotePtr::null(),
+                PtraceData::WriteInto(u8_raw_slice_mut(&mut data)),
+            );
+            data as u16
+        }
+
+        /// The exit status of this task at a PTRACE_EVENT_EXIT stop.
+        pub fn get_ptrace_eventmsg_exit_status(&self) -> WaitStatus {
+            let mut data: c_ulong = 0;
+            self.xptrace(
+                PTRACE_GETEVENTMSG,
+                RemotePtr::null(),
+                PtraceData::WriteInto(u8_raw_slice_mut(&mut data)),
+            );
+            WaitStatus::new(data as i32)
         }
 
         /// Save tracee data to the trace.  `addr` is the address in
@@ -920,106 +1998,223 @@ pub mod record_task {
         /// If 'addr' is null then no record is written.
         /// DIFF NOTE: @TODO In the rr implementation ssize_t is being used instead of size_t
         /// for the record_* methods in many places. Why??
-        pub fn record_local(&self, _addr: RemotePtr<Void>, _buf: &[u8]) {
-            unimplemented!()
+        pub fn record_local(&mut self, addr: RemotePtr<Void>, buf: &[u8]) {
+            self.maybe_flush_syscallbuf();
+            if addr.is_null() {
+                return;
+            }
+            self.session()
+                .as_record()
+                .unwrap()
+                .trace_writer_mut()
+                .write_raw(self.rec_tid, buf, addr);
         }
-        pub fn record_local_for<T>(_addr: RemotePtr<T>, _data: &T) {
-            unimplemented!()
+        pub fn record_local_for<T>(&mut self, addr: RemotePtr<T>, data: &T) {
+            self.record_local(RemotePtr::cast(addr), unsafe { &*u8_raw_slice(data) });
         }
-        pub fn record_local_for_slice<T>(_addr: RemotePtr<T>, _buf: &[T]) {
-            unimplemented!()
+        pub fn record_local_for_slice<T>(&mut self, addr: RemotePtr<T>, buf: &[T]) {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * size_of::<T>())
+            };
+            self.record_local(RemotePtr::cast(addr), bytes);
         }
 
-        pub fn record_remote(&self, _addr: RemotePtr<Void>, _num_bytes: usize) {
-            unimplemented!()
+        pub fn record_remote(&mut self, addr: RemotePtr<Void>, num_bytes: usize) {
+            if addr.is_null() {
+                return;
+            }
+            let buf = read_mem(self, addr.cast::<u8>(), num_bytes, None);
+            self.record_local(addr, &buf);
         }
-        pub fn record_remote_for<T>(_addr: RemotePtr<T>) {
-            unimplemented!()
+        pub fn record_remote_for<T>(&mut self, addr: RemotePtr<T>) {
+            self.record_remote(RemotePtr::cast(addr), size_of::<T>());
         }
-        pub fn record_remote_range(&mut self, _range: MemoryRange) {
-            unimplemented!()
+        pub fn record_remote_range(&mut self, range: MemoryRange) {
+            self.record_remote(range.start(), range.size());
         }
-        pub fn record_remote_range_fallible(&mut self, _range: MemoryRange) -> Result<usize, ()> {
-            unimplemented!()
+        pub fn record_remote_range_fallible(&mut self, range: MemoryRange) -> Result<usize, ()> {
+            self.record_remote_fallible(range.start(), range.size())
         }
 
         /// Record as much as we can of the bytes in this range. Will record only
         /// contiguous mapped data starting at `addr`.
         pub fn record_remote_fallible(
             &mut self,
-            _addr: RemotePtr<Void>,
-            _num_bytes: usize,
+            addr: RemotePtr<Void>,
+            num_bytes: usize,
         ) -> Result<usize, ()> {
-            unimplemented!()
+            self.maybe_flush_syscallbuf();
+            let mut buf = vec![0u8; num_bytes];
+            let mut nread = 0;
+            if !addr.is_null() {
+                if self.record_remote_by_local_map(addr, num_bytes) {
+                    return Ok(num_bytes);
+                }
+                nread = self.read_bytes_fallible(addr, &mut buf).unwrap_or(0);
+            }
+            let rec_tid = self.rec_tid;
+            self.trace_writer_mut()
+                .write_raw(rec_tid, &buf[0..nread], addr);
+            Ok(nread)
         }
 
         /// Record as much as we can of the bytes in this range. Will record only
         /// contiguous mapped-writable data starting at `addr`.
-        pub fn record_remote_writable(&self, _addr: RemotePtr<Void>, _num_bytes: usize) {
-            unimplemented!()
+        pub fn record_remote_writable(&mut self, addr: RemotePtr<Void>, num_bytes: usize) {
+            let mut p = addr;
+            let mut seen_rd_mapping = false;
+            let mut mapping_count = 0;
+            while p < addr + num_bytes {
+                let vm = self.vm_shr_ptr();
+                let m = match vm.mapping_of(p) {
+                    Some(m) => m,
+                    None => break,
+                };
+                mapping_count += 1;
+                if !m.flags.is_empty() {
+                    seen_rd_mapping = true;
+                }
+                if !m.map.prot().contains(ProtFlags::PROT_WRITE)
+                    || (seen_rd_mapping && mapping_count > 1)
+                {
+                    break;
+                }
+                p = m.map.end();
+            }
+            let num_bytes = min(num_bytes, p - addr);
+            self.record_remote(addr, num_bytes);
         }
 
         /// Simple helper that attempts to use the local mapping to record if one
         /// exists
         pub fn record_remote_by_local_map(
-            &self,
-            _addr: RemotePtr<Void>,
-            _num_bytes: usize,
+            &mut self,
+            addr: RemotePtr<Void>,
+            num_bytes: usize,
         ) -> bool {
-            unimplemented!()
+            let vm = self.vm_shr_ptr();
+            match vm.local_mapping(addr, num_bytes) {
+                Some(local) => {
+                    self.record_local(addr, &local[0..num_bytes]);
+                    true
+                }
+                None => false,
+            }
         }
 
         /// Save tracee data to the trace.  `addr` is the address in
         /// the address space of this task.
         /// If 'addr' is null then a zero-length record is written.
-        pub fn record_remote_even_if_null(&self, _addr: RemotePtr<Void>, _num_bytes: usize) {
-            unimplemented!()
+        pub fn record_remote_even_if_null(&mut self, addr: RemotePtr<Void>, num_bytes: usize) {
+            self.maybe_flush_syscallbuf();
+            if addr.is_null() {
+                let rec_tid = self.rec_tid;
+                self.trace_writer_mut().write_raw(rec_tid, &[], addr);
+                return;
+            }
+            if self.record_remote_by_local_map(addr, num_bytes) {
+                return;
+            }
+            let buf = read_mem(self, addr.cast::<u8>(), num_bytes, None);
+            let rec_tid = self.rec_tid;
+            self.trace_writer_mut().write_raw(rec_tid, &buf, addr);
         }
-        pub fn record_remote_even_if_null_for<T>(_addr: RemotePtr<T>) {
-            unimplemented!()
+        pub fn record_remote_even_if_null_for<T>(&mut self, addr: RemotePtr<T>) {
+            self.record_remote_even_if_null(RemotePtr::cast(addr), size_of::<T>());
         }
 
         /// Manage pending events.  `push_event()` pushes the given
         /// event onto the top of the event stack.  The `pop_*()`
         /// helpers pop the event at top of the stack, which must be of
         /// the specified type.
-        pub fn push_event(&self, _ev: &Event) {
-            unimplemented!()
-        }
-        pub fn push_syscall_eventsyscallno(&self, _no: i32) {
-            unimplemented!()
-        }
-        pub fn pop_eventexpected_type(&self) -> EventType {
-            unimplemented!()
+        pub fn push_event(&mut self, ev: Event) {
+            self.pending_events.push_back(ev);
+        }
+        pub fn push_syscall_event(&mut self, no: i32) {
+            let arch = self.detect_syscall_arch();
+            self.push_event(Event::new_syscall_event(SyscallEventData::new(no, arch)));
+        }
+        pub fn pop_event(&mut self, expected_type: EventType) {
+            ed_assert!(
+                self,
+                self.ev().event_type() == expected_type,
+                "Expected {} at the top of the event stack but got {}",
+                expected_type,
+                self.ev()
+            );
+            self.pending_events.pop_back();
         }
-        pub fn pop_noop(&self) {
-            unimplemented!()
+        pub fn pop_noop(&mut self) {
+            self.pop_event(EventType::EvNoop)
         }
-        pub fn pop_desched(&self) {
-            unimplemented!()
+        pub fn pop_desched(&mut self) {
+            self.pop_event(EventType::EvDesched)
         }
-        pub fn pop_seccomp_trap(&self) {
-            unimplemented!()
+        pub fn pop_seccomp_trap(&mut self) {
+            self.pop_event(EventType::EvSeccompTrap)
         }
-        pub fn pop_signal_delivery(&self) {
-            unimplemented!()
+        pub fn pop_signal_delivery(&mut self) {
+            self.pop_event(EventType::EvSignalDelivery)
         }
-        pub fn pop_signal_handler(&self) {
-            unimplemented!()
+        pub fn pop_signal_handler(&mut self) {
+            self.pop_event(EventType::EvSignalHandler)
         }
-        pub fn pop_syscall(&self) {
-            unimplemented!()
+        pub fn pop_syscall(&mut self) {
+            self.pop_event(EventType::EvSyscall)
         }
-        pub fn pop_syscall_interruption(&self) {
-            unimplemented!()
+        pub fn pop_syscall_interruption(&mut self) {
+            self.pop_event(EventType::EvSyscallInterruption)
         }
         /// Return the event at the top of this's stack.
         pub fn ev(&self) -> &Event {
-            unimplemented!()
+            self.pending_events.back().unwrap()
         }
 
         pub fn ev_mut(&mut self) -> &mut Event {
-            unimplemented!()
+            self.pending_events.back_mut().unwrap()
+        }
+
+        /// Push a `Signal` event for `si` on the event stack and record it. This is where
+        /// replay has to deliver the signal. The resolved disposition of the signal is
+        /// computed now, while the sighandler table and sigmask are what the signal will
+        /// see.
+        ///
+        /// The event stays on the stack as a `SignalDelivery` to be finished by
+        /// `record_signal_delivered()` once the kernel has delivered the signal.
+        pub fn record_signal(&mut self, si: &siginfo_t, deterministic: SignalDeterministic) {
+            let disposition = self.sig_resolved_disposition(si.si_signo, deterministic);
+            log!(
+                LogDebug,
+                "{}: recording {} ({:?})",
+                self.tid,
+                signal_name(si.si_signo),
+                si
+            );
+            self.push_event(Event::new_signal_event(
+                EventType::EvSignal,
+                SignalEventData::new(si, deterministic, disposition),
+            ));
+            self.record_current_event();
+            self.ev_mut().transform(EventType::EvSignalDelivery);
+        }
+
+        /// Finish the signal at the top of the event stack (see `record_signal()`) after
+        /// the kernel delivered it. If a user handler is now running we record a
+        /// `SignalHandler` event with the registers at handler entry, otherwise a
+        /// `SignalDelivery`.
+        pub fn record_signal_delivered(&mut self) {
+            let sig_ev = self.ev().signal_event().clone();
+            let sig = sig_ev.siginfo.si_signo;
+            if sig_ev.disposition == SignalResolvedDisposition::DispositionUserHandler {
+                self.ev_mut().transform(EventType::EvSignalHandler);
+                self.signal_delivered(sig);
+                self.record_current_event();
+                self.pop_signal_handler();
+            } else {
+                self.signal_delivered(sig);
+                self.record_current_event();
+                self.pop_signal_delivery();
+            }
         }
 
         /// Call this before recording events or data.  Records
@@ -1033,15 +2228,96 @@ pub mod record_task {
         /// a chance to reset the syscallbuf (i.e. record some other kind of event)
         /// before the tracee runs again in a way that might append another buffered
         /// syscall --- so we can't flush too early
-        pub fn maybe_flush_syscallbuf(&self) {
-            unimplemented!()
+        pub fn maybe_flush_syscallbuf(&mut self) {
+            if self.ev().event_type() == EventType::EvSyscallbufFlush {
+                // Already flushing.
+                return;
+            }
+            if self.syscallbuf_child.is_null() {
+                return;
+            }
+
+            // This can be called while the task is not stopped, when we prematurely
+            // terminate the trace. In that case, the tracee could be concurrently
+            // modifying the header. We'll take a snapshot of the header now.
+            // The syscallbuf code ensures that *num_rec_bytes* can only increase
+            // after the buffer is reset, so we should be fine.
+            let syscallbuf_child = self.syscallbuf_child;
+            let hdr = read_val_mem(self, syscallbuf_child, None);
+            let num_rec_bytes = hdr.num_rec_bytes;
+            let mprotect_record_count = hdr.mprotect_record_count;
+            if num_rec_bytes == 0 || self.flushed_syscallbuf {
+                // no records, or we've already flushed.
+                return;
+            }
+
+            self.push_event(Event::new_syscallbuf_flush_event(
+                SyscallbufFlushEventData::new(),
+            ));
+
+            // Apply buffered mprotect operations and flush the buffer in the tracee.
+            if mprotect_record_count > 0 {
+                let records_ptr = RemotePtr::<mprotect_record>::cast(
+                    RemotePtr::<u8>::cast(self.preload_globals.unwrap())
+                        + offset_of!(preload_globals, mprotect_records),
+                );
+                let records: Vec<mprotect_record> =
+                    read_mem(self, records_ptr, mprotect_record_count as usize, None);
+                for r in &records {
+                    self.vm().protect(
+                        self,
+                        RemotePtr::from(r.start as usize),
+                        r.size as usize,
+                        ProtFlags::from_bits_truncate(r.prot),
+                    );
+                }
+                self.ev_mut().syscallbuf_flush_event_mut().mprotect_records = records;
+            }
+
+            // Write the entire buffer in one shot without parsing it,
+            // because replay will take care of that.
+            if self.is_running() {
+                let mut buf = vec![0u8; size_of::<syscallbuf_hdr>() + num_rec_bytes as usize];
+                buf[0..size_of::<syscallbuf_hdr>()]
+                    .copy_from_slice(unsafe { &*u8_raw_slice(&hdr) });
+                read_bytes_helper(
+                    self,
+                    RemotePtr::cast(syscallbuf_child + 1usize),
+                    &mut buf[size_of::<syscallbuf_hdr>()..],
+                    None,
+                );
+                self.record_local(RemotePtr::cast(syscallbuf_child), &buf);
+            } else {
+                let num_bytes = self.syscallbuf_data_size();
+                self.record_remote(RemotePtr::cast(syscallbuf_child), num_bytes);
+            }
+            self.record_current_event();
+            self.pop_event(EventType::EvSyscallbufFlush);
+
+            self.flushed_syscallbuf = true;
+            self.flushed_num_rec_bytes = num_rec_bytes;
+
+            log!(
+                LogDebug,
+                "Syscallbuf flushed with num_rec_bytes={}",
+                num_rec_bytes
+            );
         }
 
         /// Call this after recording an event when it might be safe to reset the
         /// syscallbuf. It must be after recording an event to ensure during replay
         /// we run past any syscallbuf after-syscall code that uses the buffer data.
-        pub fn maybe_reset_syscallbuf(&self) {
-            unimplemented!()
+        pub fn maybe_reset_syscallbuf(&mut self) {
+            if self.flushed_syscallbuf
+                && !self.delay_syscallbuf_reset_for_desched
+                && !self.delay_syscallbuf_reset_for_seccomp_trap
+            {
+                self.flushed_syscallbuf = false;
+                log!(LogDebug, "Syscallbuf reset");
+                self.reset_syscallbuf();
+                self.syscallbuf_blocked_sigs_generation = 0;
+                self.record_event(&Event::syscallbuf_reset(), None, None, None);
+            }
         }
 
         /// Record an event on behalf of this.  Record the registers of
@@ -1050,111 +2326,293 @@ pub mod record_task {
         /// and meaningful at this's current execution point.
         /// `record_current_event()` record `this->ev()`, and
         /// `record_event()` records the specified event.
-        pub fn record_current_event(&self) {
-            unimplemented!()
+        pub fn record_current_event(&mut self) {
+            let ev = self.ev().clone();
+            self.record_event(&ev, None, None, None);
         }
+        /// `flush` defaults to `FlushSyscallbuf` and `reset` to `AllowResetSyscallbuf`.
         pub fn record_event(
-            &self,
-            _ev: &Event,
-            _flush: Option<FlushSyscallbuf>,
-            _reset: Option<AllowSyscallbufReset>,
-            _registers: Option<&Registers>,
+            &mut self,
+            ev: &Event,
+            flush: Option<FlushSyscallbuf>,
+            reset: Option<AllowSyscallbufReset>,
+            registers: Option<&Registers>,
         ) {
-            unimplemented!()
+            if flush.unwrap_or(FlushSyscallbuf::FlushSyscallbuf) == FlushSyscallbuf::FlushSyscallbuf
+            {
+                self.maybe_flush_syscallbuf();
+            }
+
+            let mut maybe_registers = None;
+            let mut maybe_extra_registers: Option<ExtraRegisters> = None;
+            if ev.record_regs() {
+                maybe_registers = Some(registers.unwrap_or(self.regs_ref()).clone());
+                if ev.record_extra_regs() {
+                    maybe_extra_registers = Some(self.extra_regs_ref().clone());
+                }
+            }
+
+            if ev.is_syscall_event() && ev.syscall_event().state == SyscallState::ExitingSyscall {
+                self.ticks_at_last_recorded_syscall_exit = self.tick_count();
+            }
+
+            let session = self.session();
+            let mut trace_writer = session.as_record().unwrap().trace_writer_mut();
+            let current_time = trace_writer.time();
+            trace_writer.write_frame(
+                self,
+                ev,
+                maybe_registers.as_ref(),
+                maybe_extra_registers.as_ref(),
+            );
+            drop(trace_writer);
+            log!(LogDebug, "Wrote event {} for time {}", ev, current_time);
+
+            if !ev.has_ticks_slop()
+                && reset.unwrap_or(AllowSyscallbufReset::AllowResetSyscallbuf)
+                    == AllowSyscallbufReset::AllowResetSyscallbuf
+            {
+                // After we've output an event, it's safe to reset the syscallbuf (if not
+                // explicitly delayed) since we will have exited the syscallbuf code that
+                // consumed the syscallbuf data.
+                self.maybe_reset_syscallbuf();
+            }
         }
 
-        pub fn is_fatal_signal(&self, _sig: i32, _deterministic: SignalDeterministic) -> bool {
-            unimplemented!()
+        pub fn is_fatal_signal(&self, sig: i32, deterministic: SignalDeterministic) -> bool {
+            if self.thread_group().received_sigframe_sigsegv {
+                // Can't be blocked, caught or ignored
+                return true;
+            }
+
+            let action = default_action(sig);
+            if action != SignalAction::DumpCore && action != SignalAction::Terminate {
+                // If the default action doesn't kill the process, it won't die.
+                return false;
+            }
+
+            if self.is_sig_ignored(sig) {
+                // Deterministic fatal signals can't be ignored.
+                return deterministic == SignalDeterministic::DeterministicSig;
+            }
+            // If there's a signal handler, the signal won't be fatal.
+            !self.signal_has_user_handler(sig)
         }
 
         /// Return the pid of the newborn thread created by this task.
         /// Called when this task has a PTRACE_CLONE_EVENT with CLONE_THREAD.
         pub fn find_newborn_thread(&self) -> pid_t {
-            unimplemented!()
+            ed_assert!(
+                self,
+                self.maybe_ptrace_event().get_raw_repr() == PTRACE_EVENT_CLONE
+            );
+
+            let hint = self.get_ptrace_eventmsg_pid();
+            // This should always succeed, but may fail in old kernels due to
+            // a kernel bug.
+            if self.session().find_task_from_rec_tid(hint).is_none()
+                && Path::new(&format!("/proc/{}/task/{}", self.tid, hint)).exists()
+            {
+                return hint;
+            }
+
+            let dir = format!("/proc/{}/task", self.tid);
+            self.find_untracked_pid_in(&dir, |_| true)
+        }
+
+        /// Returns the first numeric entry of `dir` that isn't one of our tasks and
+        /// satisfies `pred`.
+        fn find_untracked_pid_in(&self, dir: &str, pred: impl Fn(pid_t) -> bool) -> pid_t {
+            let entries = match read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    ed_assert!(self, false, "Could not read {}: {:?}", dir, e);
+                    unreachable!()
+                }
+            };
+            for entry in entries.flatten() {
+                let maybe_pid = entry.file_name().to_str().and_then(|n| n.parse().ok());
+                if let Some(pid) = maybe_pid {
+                    if self.session().find_task_from_rec_tid(pid).is_none() && pred(pid) {
+                        return pid;
+                    }
+                }
+            }
+            ed_assert!(self, false, "Could not find newborn task in {}", dir);
+            unreachable!()
         }
 
         /// Return the pid of the newborn process (whose parent has pid `parent_pid`,
         /// which need not be the same as the current task's pid, due to CLONE_PARENT)
         /// created by this task. Called when this task has a PTRACE_CLONE_EVENT
         /// without CLONE_THREAD, or PTRACE_FORK_EVENT.
-        pub fn find_newborn_process(&self, _child_parent: pid_t) -> pid_t {
-            unimplemented!()
+        pub fn find_newborn_process(&self, child_parent: pid_t) -> pid_t {
+            let event = self.maybe_ptrace_event().get_raw_repr();
+            ed_assert!(
+                self,
+                event == PTRACE_EVENT_CLONE
+                    || event == PTRACE_EVENT_VFORK
+                    || event == PTRACE_EVENT_FORK
+            );
+
+            let hint = self.get_ptrace_eventmsg_pid();
+            // This should always succeed, but may fail in old kernels due to
+            // a kernel bug.
+            if self.session().find_task_from_rec_tid(hint).is_none()
+                && get_ppid(hint) == Some(child_parent)
+            {
+                return hint;
+            }
+
+            self.find_untracked_pid_in("/proc", |pid| get_ppid(pid) == Some(child_parent))
         }
 
         /// Do a tgkill to send a specific signal to this task.
-        pub fn tgkill(&self, _sig: i32) {
-            unimplemented!()
+        pub fn tgkill(&self, sig: i32) {
+            log!(LogDebug, "Sending {} to tid {}", signal_name(sig), self.tid);
+            let ret = unsafe { libc::syscall(libc::SYS_tgkill, self.real_tgid(), self.tid, sig) };
+            ed_assert!(self, ret == 0);
         }
 
         /// If the process looks alive, kill it. It is recommended to call try_wait(),
         /// on this task before, to make sure liveness is correctly reflected when
         /// making this decision
         pub fn kill_if_alive(&self) {
-            unimplemented!()
+            if !self.is_dying() {
+                self.tgkill(SIGKILL);
+            }
         }
 
         pub fn robust_list(&self) -> RemotePtr<Void> {
-            unimplemented!()
+            self.robust_futex_list
         }
         pub fn robust_list_len(&self) -> usize {
-            unimplemented!()
+            self.robust_futex_list_len
         }
 
         /// Uses /proc so not trivially cheap.
         pub fn get_parent_pid(&self) -> pid_t {
-            unimplemented!()
+            match get_ppid(self.tid) {
+                Some(ppid) => ppid,
+                None => {
+                    ed_assert!(self, false, "Could not read PPid of {}", self.tid);
+                    unreachable!()
+                }
+            }
         }
 
         /// Return true if this is a "clone child" per the wait(2) man page.
         pub fn is_clone_child(&self) -> bool {
-            unimplemented!()
+            self.termination_signal != Some(SIGCHLD)
         }
 
-        pub fn set_termination_signal(&self, _sig: i32) {
-            unimplemented!()
+        pub fn set_termination_signal(&mut self, sig: i32) {
+            self.termination_signal = Some(sig);
         }
 
         /// When a signal triggers an emulated a ptrace-stop for this task,
         /// save the siginfo so a later emulated ptrace-continue with this signal
         /// number can use it.
-        pub fn save_ptrace_signal_siginfo(&self, _si: &siginfo_t) {
-            unimplemented!()
+        pub fn save_ptrace_signal_siginfo(&mut self, si: &siginfo_t) {
+            self.saved_ptrace_siginfos
+                .retain(|saved| saved.si_signo != si.si_signo);
+            self.saved_ptrace_siginfos.push(si.clone());
         }
 
         /// Tasks normally can't change their tid. There is one very special situation
         /// where they can: when a non-main-thread does an execve, its tid changes
         /// to the tid of the thread-group leader.
-        pub fn set_tid_and_update_serial(&self, _tid: pid_t, _own_namespace_tid: pid_t) {
-            unimplemented!()
+        pub fn set_tid_and_update_serial(&mut self, tid: pid_t, own_namespace_tid: pid_t) {
+            self.hpc.set_tid(tid);
+            self.tid = tid;
+            self.rec_tid = tid;
+            self.serial = self.session().next_task_serial();
+            self.own_namespace_rec_tid = own_namespace_tid;
         }
 
         /// Return our cached copy of the signal mask, updating it if necessary.
-        pub fn get_sigmask(&self) -> sig_set_t {
-            unimplemented!()
+        pub fn get_sigmask(&mut self) -> sig_set_t {
+            if self.blocked_sigs_dirty {
+                self.blocked_sigs = self.read_sigmask_from_process();
+                log!(LogDebug, "Refreshed sigmask, now {:#x}", self.blocked_sigs);
+                self.blocked_sigs_dirty = false;
+            }
+            self.blocked_sigs
         }
 
         /// Just get the signal mask of the process.
         pub fn read_sigmask_from_process(&self) -> sig_set_t {
-            unimplemented!()
+            // We can't use PTRACE_GETSIGMASK: during a syscall that temporarily changes
+            // the sigmask (e.g. sigsuspend) it returns the mask that will be restored,
+            // not the one in effect.
+            match self.read_sigsets(&[b"SigBlk"]) {
+                Some(sets) => sets[0],
+                None => {
+                    ed_assert!(self, false, "Could not read SigBlk of {}", self.tid);
+                    unreachable!()
+                }
+            }
         }
 
         /// Unblock the signal for the process.
-        pub fn unblock_signal(&self, _sig: i32) {
-            unimplemented!()
+        pub fn unblock_signal(&mut self, sig: i32) {
+            let mask: sig_set_t = signal_bit(sig);
+            {
+                let mut remote = AutoRemoteSyscalls::new(self);
+                let arch = remote.arch();
+                let mut mem = AutoRestoreMem::new(
+                    &mut remote,
+                    Some(&mask.to_le_bytes()),
+                    size_of::<sig_set_t>(),
+                );
+                let addr = mem.get().unwrap();
+                rd_infallible_syscall!(
+                    mem,
+                    syscall_number_for_rt_sigprocmask(arch),
+                    SIG_UNBLOCK,
+                    addr.as_usize(),
+                    0,
+                    size_of::<sig_set_t>()
+                );
+            }
+            self.invalidate_sigmask();
         }
 
         /// Set the signal handler to default for the process.
-        pub fn set_sig_handler_default(&self, _sig: i32) {
-            unimplemented!()
+        pub fn set_sig_handler_default(&mut self, sig: i32) {
+            self.did_set_sig_handler_default(sig);
+            let sa = self.signal_action(sig).to_vec();
+            let mut remote = AutoRemoteSyscalls::new(self);
+            let arch = remote.arch();
+            let mut mem = AutoRestoreMem::new(&mut remote, Some(&sa), sa.len());
+            let addr = mem.get().unwrap();
+            rd_infallible_syscall!(
+                mem,
+                syscall_number_for_rt_sigaction(arch),
+                sig,
+                addr.as_usize(),
+                0,
+                size_of::<sig_set_t>()
+            );
         }
 
-        pub fn maybe_restore_original_syscall_registers(&self) {
-            unimplemented!()
-        }
+        /// The kernel clobbers the first syscall argument register with the result on
+        /// architectures that don't keep a separate copy of it. x86 and x86-64 keep the
+        /// original registers intact, so there's nothing to restore.
+        pub fn maybe_restore_original_syscall_registers(&self) {}
 
         /// Retrieve the tid of this task from the tracee and store it
-        fn update_own_namespace_tid(&self) {
-            unimplemented!()
+        fn update_own_namespace_tid(&mut self) {
+            let mut remote = AutoRemoteSyscalls::new(self);
+            let arch = remote.arch();
+            let ret = remote.syscall(syscall_number_for_gettid(arch), &[]);
+            drop(remote);
+            // The task may have died under us, see `kill_if_alive()`.
+            self.own_namespace_rec_tid = if ret == -(ESRCH as isize) {
+                -1
+            } else {
+                ret as pid_t
+            };
         }
 
         /// Wait for `futex` in this address space to have the value
@@ -1163,8 +2621,33 @@ pub mod record_task {
         /// WARNING: this implementation semi-busy-waits for the value
         /// change.  This must only be used in contexts where the futex
         /// will change "soon".
-        fn futex_wait(&self, _futex: RemotePtr<i32>, _val: i32, _ok: Option<&mut bool>) {
-            unimplemented!()
+        pub fn futex_wait(&mut self, futex: RemotePtr<i32>, val: i32, mut ok: Option<&mut bool>) {
+            // Wait for *sync_addr == sync_val.  This implementation isn't
+            // pretty, but it's pretty much the best we can do with
+            // available kernel tools.
+            //
+            // @TODO: find clever way to avoid busy-waiting.
+            loop {
+                let mut read_ok = true;
+                let mem: i32 = read_val_mem(self, futex, Some(&mut read_ok));
+                if !read_ok {
+                    match ok.as_mut() {
+                        Some(ok) => {
+                            **ok = false;
+                            return;
+                        }
+                        None => {
+                            ed_assert!(self, false, "Could not read futex at {}", futex);
+                        }
+                    }
+                }
+                if val == mem {
+                    break;
+                }
+                // Try to give our scheduling slot to the kernel
+                // thread that's going to write sync_addr.
+                unsafe { libc::sched_yield() };
+            }
         }
 
         /// Called when this task is able to receive a SIGCHLD (e.g. because
@@ -1172,26 +2655,303 @@ pub mod record_task {
         /// SIGCHLD to the task if there are still tasks that need a SIGCHLD
         /// sent for them.
         /// May queue signals for specific tasks.
-        fn send_synthetic_sigchld_if_necessary(&self) {
-            unimplemented!()
+        pub fn send_synthetic_sigchld_if_necessary(&mut self) {
+            self.send_synthetic_sigchld_for(None)
+        }
+
+        /// Like `send_synthetic_sigchld_if_necessary()`, but `stopped` is a task up the call
+        /// stack, and so already mutably borrowed, that has just entered an emulated stop.
+        /// Other tasks we can't borrow are busy being processed by rd and so can't be waiting.
+        fn send_synthetic_sigchld_for(&mut self, stopped: Option<&RecordTask>) {
+            let mut need_signal = false;
+            let mut wake_task: Option<pid_t> = None;
+            let tracees: Vec<TaskSharedPtr> = self.emulated_ptrace_tracees.iter().collect();
+            for tracee in tracees {
+                let waiting = with_record_task(&tracee, stopped, |rtracee| {
+                    if rtracee.emulated_ptrace_sigchld_pending {
+                        need_signal = true;
+                        // check to see if any thread in the ptracer process is in a waitpid
+                        // that could read the status of 'tracee'. If it is, we should wake
+                        // up that thread. Otherwise we send SIGCHLD to the ptracer thread.
+                        self.is_waiting_for_ptrace(rtracee)
+                    } else {
+                        false
+                    }
+                });
+                if waiting == Some(true) {
+                    wake_task = Some(self.tid);
+                    break;
+                }
+            }
+
+            if !need_signal {
+                // Check whether any thread of ours is in a waitpid that could read the status
+                // of a child with an emulated stop pending. If so we wake up that thread,
+                // otherwise we send SIGCHLD to the process.
+                let siblings = self.thread_group_siblings();
+                let children: Vec<_> = self.thread_group().children().iter().collect();
+                'children: for child_tg in children {
+                    let child_tasks: Vec<_> = child_tg.borrow().task_set().iter().collect();
+                    for child in child_tasks {
+                        let found = with_record_task(&child, stopped, |rchild| {
+                            if !rchild.emulated_sigchld_pending {
+                                return None;
+                            }
+                            need_signal = true;
+                            if self.is_waiting_for(rchild) {
+                                return Some(self.tid);
+                            }
+                            siblings.iter().find_map(|t| {
+                                let t_ref = t.try_borrow().ok()?;
+                                let rt = t_ref.as_record_task().unwrap();
+                                if rt.is_waiting_for(rchild) {
+                                    Some(rt.tid)
+                                } else {
+                                    None
+                                }
+                            })
+                        });
+                        if let Some(Some(tid)) = found {
+                            wake_task = Some(tid);
+                            break 'children;
+                        }
+                    }
+                }
+            }
+            if !need_signal {
+                return;
+            }
+
+            // We can't set all the siginfo values to their correct values here, so
+            // we'll patch this up when the signal is received.
+            // If there's already a pending SIGCHLD, this signal will be ignored,
+            // but at some point the pending SIGCHLD will be delivered and then
+            // send_synthetic_sigchld_if_necessary will be called again to deliver a new
+            // SIGCHLD if necessary.
+            let mut si: siginfo_t = Default::default();
+            si.si_code = SI_QUEUE;
+            si._sifields._rt.si_sigval.sival_int = SIGCHLD_SYNTHETIC;
+            let tgid = self.tgid();
+            match wake_task {
+                Some(tid) => {
+                    log!(LogDebug, "Sending synthetic SIGCHLD to tid {}", tid);
+                    // We must use the raw SYS_rt_tgsigqueueinfo syscall here to ensure the
+                    // signal is sent to the correct thread by tid.
+                    let ret = unsafe {
+                        libc::syscall(libc::SYS_rt_tgsigqueueinfo, tgid, tid, SIGCHLD, &si)
+                    };
+                    ed_assert!(self, ret == 0);
+                    let sigchld_blocked = if tid == self.tid {
+                        self.is_sig_blocked(SIGCHLD)
+                    } else {
+                        let t = self.session().find_task_from_rec_tid(tid).unwrap();
+                        let mut t_ref = t.borrow_mut();
+                        t_ref.as_record_task_mut().unwrap().is_sig_blocked(SIGCHLD)
+                    };
+                    if sigchld_blocked {
+                        log!(
+                            LogDebug,
+                            "SIGCHLD is blocked, kicking it out of the syscall"
+                        );
+                        // Just sending SIGCHLD won't wake it up. Send it a TIME_SLICE_SIGNAL
+                        // as well to make sure it exits a blocking syscall. We ensure those
+                        // can never be blocked.
+                        si.si_code = SYNTHETIC_TIME_SLICE_SI_CODE;
+                        let ret = unsafe {
+                            libc::syscall(
+                                libc::SYS_rt_tgsigqueueinfo,
+                                tgid,
+                                tid,
+                                TIME_SLICE_SIGNAL,
+                                &si,
+                            )
+                        };
+                        ed_assert!(self, ret == 0);
+                    }
+                }
+                None => {
+                    // Send the signal to the process as a whole and let the kernel
+                    // decide which thread gets it.
+                    let ret =
+                        unsafe { libc::syscall(libc::SYS_rt_sigqueueinfo, tgid, SIGCHLD, &si) };
+                    ed_assert!(self, ret == 0);
+                    log!(LogDebug, "Sending synthetic SIGCHLD to pid {}", tgid);
+                }
+            }
         }
 
         /// Call this when SYS_sigaction is finishing with `regs`.
-        fn update_sigaction(&self, _regs: &Registers) {
-            unimplemented!()
+        fn update_sigaction(&mut self, regs: &Registers) {
+            rd_arch_function!(self, update_sigaction_arch, regs.arch(), regs)
         }
 
         /// Update the futex robust list head pointer to `list` (which
         /// is of size `len`).
-        fn set_robust_list(&self, _list: RemotePtr<Void>, _len: usize) {
-            unimplemented!()
+        fn set_robust_list(&mut self, list: RemotePtr<Void>, len: usize) {
+            self.robust_futex_list = list;
+            self.robust_futex_list_len = len;
+        }
+
+        fn init_buffers_arch<Arch: Architecture>(&mut self) {
+            // NB: the tracee can't be interrupted with a signal while
+            // we're processing the rdcall, because it's masked off all
+            // signals.
+            let child_args =
+                RemotePtr::<Arch::rdcall_init_buffers_params>::from(self.regs_ref().arg1());
+
+            let mut args = read_val_mem(self, child_args, None);
+            let scratch_ptr = self.scratch_ptr;
+            let usable_scratch_size = self.usable_scratch_size();
+            let syscallbuf_enabled = self.vm().syscallbuf_enabled();
+
+            let mut remote = AutoRemoteSyscalls::new(self);
+            if syscallbuf_enabled {
+                let syscallbuf_size = remote
+                    .task()
+                    .session()
+                    .as_record()
+                    .unwrap()
+                    .syscall_buffer_size();
+                remote.task_mut().syscallbuf_size = syscallbuf_size;
+                let syscallbuf_km = TaskInner::init_syscall_buffer(&mut remote, None);
+
+                let desched_fd_child = Arch::rdcall_init_buffers_params_desched_counter_fd(&args);
+                {
+                    let t = remote.task_mut();
+                    t.desched_fd_child = desched_fd_child;
+                    // Prevent the child from closing this fd
+                    let fds = t.fd_table_shr_ptr();
+                    fds.borrow_mut().add_monitor(
+                        t,
+                        desched_fd_child,
+                        Box::new(PreserveFileMonitor::new()),
+                    );
+                }
+                let desched_fd = remote.retrieve_fd(desched_fd_child);
+
+                let rt = remote.task_mut().as_record_task_mut().unwrap();
+                rt.desched_fd = desched_fd;
+                let record_in_trace = rt
+                    .session()
+                    .as_record()
+                    .unwrap()
+                    .trace_writer_mut()
+                    .write_mapped_region(
+                        rt,
+                        &syscallbuf_km,
+                        &syscallbuf_km.fake_stat(),
+                        &[],
+                        Some(MappingOrigin::RdBufferMapping),
+                        None,
+                    );
+                ed_assert!(rt, record_in_trace == RecordInTrace::DontRecordInTrace);
+
+                Arch::set_rdcall_init_buffers_params(
+                    &mut args,
+                    RemotePtr::cast(rt.syscallbuf_child),
+                    syscallbuf_size,
+                    scratch_ptr,
+                    usable_scratch_size,
+                );
+            } else {
+                Arch::set_rdcall_init_buffers_params(
+                    &mut args,
+                    RemotePtr::null(),
+                    0,
+                    scratch_ptr,
+                    usable_scratch_size,
+                );
+            }
+
+            // Return the mapped buffers to the child.
+            write_val_mem(remote.task_mut(), child_args, &args, None);
+
+            // The tracee doesn't need this addr returned, because it's
+            // already written to the inout `args` param, but we stash it
+            // away in the return value slot so that we can easily check
+            // that we map the segment at the same addr during replay.
+            let syscallbuf_child = remote.task().syscallbuf_child;
+            remote
+                .initial_regs_mut()
+                .set_syscall_result(syscallbuf_child.as_usize());
+        }
+
+        fn at_preload_init_arch<Arch: Architecture>(&mut self) {
+            let addr_val = self.regs_ref().arg1();
+            let params = read_val_mem(
+                self,
+                RemotePtr::<Arch::rdcall_init_preload_params>::from(addr_val),
+                None,
+            );
+            self.syscallbuf_code_layout = Arch::rdcall_init_preload_params_code_layout(&params);
+
+            let (in_chaos, desched_sig, pretend_num_cores) = {
+                let session = self.session();
+                let record_session = session.as_record().unwrap();
+                let pretend_num_cores = record_session.scheduler().pretend_num_cores();
+                (
+                    record_session.enable_chaos() as u8,
+                    record_session.syscallbuf_desched_sig(),
+                    pretend_num_cores as i32,
+                )
+            };
+            let mut random_seed = [0u8; size_of::<u64>()];
+            good_random(&mut random_seed);
+            let random_seed = u64::from_le_bytes(random_seed);
+
+            let globals = self.preload_globals.unwrap();
+            self.write_and_record_global(globals, offset_of!(preload_globals, in_chaos), &in_chaos);
+            self.write_and_record_global(
+                globals,
+                offset_of!(preload_globals, desched_sig),
+                &desched_sig,
+            );
+            self.write_and_record_global(
+                globals,
+                offset_of!(preload_globals, pretend_num_cores),
+                &pretend_num_cores,
+            );
+            self.write_and_record_global(
+                globals,
+                offset_of!(preload_globals, random_seed),
+                &random_seed,
+            );
         }
 
-        fn init_buffers_arch<Arch>(&self) {
-            unimplemented!()
+        /// Write `val` to the field at `offset` in the tracee's preload_globals and
+        /// record it, so replay sees the same value.
+        fn write_and_record_global<T: Copy + 'static>(
+            &mut self,
+            globals: RemotePtr<preload_globals>,
+            offset: usize,
+            val: &T,
+        ) {
+            let addr = RemotePtr::<T>::cast(RemotePtr::<u8>::cast(globals) + offset);
+            write_val_mem(self, addr, val, None);
+            self.record_local_for(addr, val);
         }
-        fn on_syscall_exit_arch<Arch>(&self, _syscallno: i32, _regs: &Registers) {
-            unimplemented!()
+        fn on_syscall_exit_arch<Arch: Architecture>(&mut self, syscallno: i32, regs: &Registers) {
+            if regs.syscall_failed() {
+                return;
+            }
+            if syscallno == Arch::SET_ROBUST_LIST {
+                self.set_robust_list(regs.arg1().into(), regs.arg2());
+            } else if syscallno == Arch::SIGACTION || syscallno == Arch::RT_SIGACTION {
+                // TODO: SYS_signal
+                self.update_sigaction(regs);
+            } else if syscallno == Arch::SET_TID_ADDRESS {
+                self.set_tid_addr(regs.arg1().into());
+            } else if syscallno == Arch::SIGSUSPEND
+                || syscallno == Arch::RT_SIGSUSPEND
+                || syscallno == Arch::SIGPROCMASK
+                || syscallno == Arch::RT_SIGPROCMASK
+                || syscallno == Arch::PSELECT6
+                || syscallno == Arch::PSELECT6_TIME64
+                || syscallno == Arch::PPOLL
+                || syscallno == Arch::PPOLL_TIME64
+            {
+                self.invalidate_sigmask();
+            }
         }
 
         /// Helper function for update_sigaction.
@@ -1219,8 +2979,9 @@ pub mod record_task {
         }
 
         /// Update the clear-tid futex to `tid_addr`.
-        fn set_tid_addr(&self, _tid_addr: RemotePtr<i32>) {
-            unimplemented!()
+        fn set_tid_addr(&mut self, tid_addr: RemotePtr<i32>) {
+            log!(LogDebug, "updating cleartid futex to {}", tid_addr);
+            self.tid_futex = tid_addr;
         }
     }
 }
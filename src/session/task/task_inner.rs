@@ -152,6 +152,7 @@ pub mod task_inner {
         flags::Flags,
         kernel_abi::{
             common::preload_interface::{preload_globals, syscallbuf_hdr},
+            syscall_instruction_arch,
             SupportedArch,
             RD_NATIVE_ARCH,
         },
@@ -163,6 +164,7 @@ pub mod task_inner {
         registers::Registers,
         remote_code_ptr::RemoteCodePtr,
         remote_ptr::{RemotePtr, Void},
+        replay_profiler::{self, ProfileCategory},
         scoped_fd::ScopedFd,
         seccomp_bpf::SeccompFilter,
         session::{
@@ -186,6 +188,7 @@ pub mod task_inner {
         util::{
             choose_cpu,
             has_effective_caps,
+            page_size,
             restore_initial_resource_limits,
             running_under_rd,
             set_cpu_affinity,
@@ -1149,8 +1152,38 @@ pub mod task_inner {
         /// recorded in the syscall buffer. This makes for more deterministic behavior
         /// especially during replay, where during checkpointing we only save and
         /// restore the recorded data area.
+        /// DIFF NOTE: rr writes the header fields through the tracee's memory. The
+        /// syscallbuf is always mapped locally as well so we write it directly.
         pub fn reset_syscallbuf(&self) {
-            unimplemented!()
+            if self.syscallbuf_child.is_null() {
+                return;
+            }
+
+            let hdr_bytes = self
+                .vm()
+                .local_mapping_mut(
+                    RemotePtr::cast(self.syscallbuf_child),
+                    size_of::<syscallbuf_hdr>(),
+                )
+                .unwrap();
+            let hdr = unsafe { &mut *(hdr_bytes.as_mut_ptr() as *mut syscallbuf_hdr) };
+            let num_rec_bytes = hdr.num_rec_bytes as usize;
+            if num_rec_bytes > 0 {
+                let recs = self
+                    .vm()
+                    .local_mapping_mut(
+                        RemotePtr::cast(self.syscallbuf_child + 1usize),
+                        num_rec_bytes,
+                    )
+                    .unwrap();
+                for b in recs.iter_mut() {
+                    *b = 0;
+                }
+            }
+            hdr.num_rec_bytes = 0;
+            hdr.mprotect_record_count = 0;
+            hdr.mprotect_record_count_completed = 0;
+            hdr.blocked_sigs_generation = 0;
         }
 
         /// Return the virtual memory mapping (address space) of this
@@ -1184,8 +1217,25 @@ pub mod task_inner {
             getuid().as_raw()
         }
 
+        /// The arch of the syscall table used by the syscall instruction this task
+        /// has just executed.
         pub fn detect_syscall_arch(&self) -> SupportedArch {
-            unimplemented!()
+            let arch = self.arch();
+            let syscall_ip = self.ip().decrement_by_syscall_insn_length(arch);
+            let mut code = [0u8; 2];
+            let nread = self.read_bytes_ptrace(syscall_ip.to_data_ptr::<Void>(), &mut code);
+            let maybe_syscall_arch = if nread == code.len() {
+                syscall_instruction_arch(&code, arch)
+            } else {
+                None
+            };
+            ed_assert!(
+                self,
+                maybe_syscall_arch.is_some(),
+                "No syscall instruction at {}",
+                syscall_ip
+            );
+            maybe_syscall_arch.unwrap()
         }
 
         /// Call this when performing a clone syscall in this task. Returns
@@ -1275,8 +1325,10 @@ pub mod task_inner {
             self.address_of_last_execution_resume
         }
 
-        pub fn usable_scratch_size(&self) {
-            unimplemented!()
+        /// The part of the scratch area the syscallbuf may use. The last page is
+        /// reserved for the syscallbuf stubs' alternate stack.
+        pub fn usable_scratch_size(&self) -> usize {
+            self.scratch_size.saturating_sub(page_size())
         }
         pub fn syscallbuf_alt_stack(&self) -> RemotePtr<Void> {
             if self.scratch_ptr.is_null() {
@@ -1450,6 +1502,11 @@ pub mod task_inner {
             addr: RemotePtr<Void>,
             data: PtraceData,
         ) -> isize {
+            if request == PTRACE_SINGLESTEP || request == PTRACE_SYSEMU_SINGLESTEP {
+                replay_profiler::count_singlestep();
+            }
+            let _timer =
+                replay_profiler::timer(ProfileCategory::Ptrace, || ptrace_req_name(request));
             let res =
                 unsafe { ptrace(request, self.tid, addr.as_usize(), data.get_addr()) } as isize;
             res
@@ -1587,12 +1644,45 @@ pub mod task_inner {
         /// to be mapped --- and this is asserted --- or nullptr if
         /// there are no expectations.
         /// Initializes syscallbuf_child.
+        ///
+        /// DIFF NOTE: Takes no `self` as the task is borrowed by `remote`.
         pub(in super::super::super) fn init_syscall_buffer(
-            &self,
-            _remote: &AutoRemoteSyscalls,
-            _map_hint: RemotePtr<Void>,
+            remote: &mut AutoRemoteSyscalls,
+            map_hint: Option<RemotePtr<Void>>,
         ) -> KernelMapping {
-            unimplemented!()
+            let name = format!("syscallbuf.{}", remote.task().rec_tid);
+            let syscallbuf_size = remote.task().syscallbuf_size;
+            let km = remote.create_shared_mmap(
+                syscallbuf_size,
+                map_hint,
+                OsStr::new(&name),
+                None,
+                None,
+                None,
+            );
+            if km.size() == 0 {
+                return km;
+            }
+            *remote.vm().mapping_flags_of_mut(km.start()) |= MappingFlags::IS_SYSCALLBUF;
+
+            let t = remote.task_mut();
+            ed_assert!(
+                t,
+                t.syscallbuf_child.is_null(),
+                "Should not already have syscallbuf initialized!"
+            );
+            t.syscallbuf_child = RemotePtr::cast(km.start());
+
+            // No entries to begin with.
+            let hdr = t
+                .vm()
+                .local_mapping_mut(km.start(), size_of::<syscallbuf_hdr>())
+                .unwrap();
+            for b in hdr.iter_mut() {
+                *b = 0;
+            }
+
+            km
         }
 
         /// Make the OS-level calls to create a new fork or clone that
@@ -1623,7 +1713,7 @@ pub mod task_inner {
             let shr_ptr = self.session();
             let owning_handle =
                 OwningHandle::new_with_fn(shr_ptr, |s| match unsafe { (*s).as_record() } {
-                    Some(_rec_sess) => unimplemented!(),
+                    Some(rec_sess) => Ref::map(rec_sess.trace_writer(), |tw| tw.deref()),
                     None => match unsafe { (*s).as_replay() } {
                         Some(rep_sess) => Ref::map(rep_sess.trace_reader(), |tr| tr.deref()),
                         None => unreachable!(),
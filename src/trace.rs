@@ -1,6 +1,9 @@
 pub mod compressed_reader;
 pub mod compressed_writer;
+pub mod file_snapshot_store;
+pub mod trace_dir;
 pub mod trace_frame;
+pub mod trace_metadata;
 pub mod trace_reader;
 pub mod trace_stream;
 pub mod trace_task_event;
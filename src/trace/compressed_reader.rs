@@ -1,4 +1,5 @@
 use crate::{
+    replay_profiler::{self, ProfileCategory},
     scoped_fd::{ScopedFd, ScopedFdSharedPtr},
     trace::compressed_writer::BlockHeader,
     util::read_to_end,
@@ -216,6 +217,7 @@ impl CompressedReader {
     }
 
     fn refill_buffer(&mut self) -> io::Result<()> {
+        let read_timer = replay_profiler::timer(ProfileCategory::TraceRead, || "read".into());
         let mut header_vec: Vec<u8> = Vec::with_capacity(size_of::<BlockHeader>());
         header_vec.resize(size_of::<BlockHeader>(), 0u8);
         if false
@@ -266,7 +268,10 @@ impl CompressedReader {
             Ok(_) => false,
             Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
         };
+        drop(read_timer);
 
+        let _decompress_timer =
+            replay_profiler::timer(ProfileCategory::TraceRead, || "decompress".into());
         self.buffer.resize(header.uncompressed_length as usize, 0);
         self.buffer_read_pos = 0;
         if !do_decompress(compressed_buf.as_slice(), &mut self.buffer) {
@@ -275,6 +280,7 @@ impl CompressedReader {
                 "Decompression Error. @TODO",
             ));
         }
+        replay_profiler::count_decompressed_bytes(self.buffer.len());
 
         Ok(())
     }
@@ -0,0 +1,149 @@
+//! Copies of the files a recording depends on, kept in the trace directory so
+//! the trace can be replayed on a machine that doesn't have them (or has
+//! different versions of them).
+//!
+//! This is optional, see `RecordSession::enable_file_capture()`. While it's on,
+//! every regular file a tracee opens read-only and every mapped file that
+//! isn't already stored in the trace is copied to `files/` in the trace
+//! directory. Copies are named after a hash of their contents and size, so a
+//! file opened many times, or under several names, is stored once, while a
+//! file that changes during recording gets a copy per version.
+//!
+//! The mapped-region record of a mapping of a captured file names the copy
+//! that was current when the mapping was made, so replay maps that copy
+//! instead of the live file. The `files/index` JSON file lists the copies
+//! made of each path the tracee used, for people and tools looking at the
+//! trace; replay doesn't need it.
+
+use crate::log::LogLevel::{LogDebug, LogWarn};
+use libc::{dev_t, ino_t, time_t};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs,
+    fs::File,
+    hash::Hasher,
+    io,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Directory of the copies, relative to the trace directory.
+const FILES_DIR: &str = "files";
+
+/// The index, relative to `FILES_DIR`.
+const INDEX_FILE: &str = "index";
+
+#[derive(Clone, Default)]
+pub struct FileSnapshotStore {
+    /// The `FILES_DIR` of the trace.
+    dir: PathBuf,
+    /// Path as seen by the tracee -> names of its copies in `dir`, oldest first.
+    index: BTreeMap<PathBuf, Vec<String>>,
+    /// Copies already made during this recording, by (device, inode, size,
+    /// mtime in seconds and nanoseconds) of the original. Lets us skip hashing
+    /// files we've seen before.
+    captured: HashMap<(dev_t, ino_t, i64, time_t, i64), String>,
+}
+
+impl FileSnapshotStore {
+    /// Start an empty store in `trace_dir` for recording.
+    pub fn create(trace_dir: &OsStr) -> io::Result<FileSnapshotStore> {
+        let dir = Path::new(trace_dir).join(FILES_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(FileSnapshotStore {
+            dir,
+            ..Default::default()
+        })
+    }
+
+    /// Copy the file that the tracee knows as `path` into the store, unless an
+    /// identical copy is already there. `readable_path` is where rd can read
+    /// it, e.g. through /proc/<pid>/root or /proc/<pid>/fd. `stat` is the
+    /// original's.
+    /// Returns the name of the copy relative to the trace directory, or None
+    /// if the file wasn't captured.
+    pub fn capture(
+        &mut self,
+        path: &OsStr,
+        readable_path: &OsStr,
+        stat: &libc::stat,
+    ) -> io::Result<Option<OsString>> {
+        let path = Path::new(path);
+        if path.to_str().is_none() {
+            // The index is JSON.
+            log!(LogWarn, "Not capturing {:?}: name isn't UTF-8", path);
+            return Ok(None);
+        }
+
+        let key = (
+            stat.st_dev,
+            stat.st_ino,
+            stat.st_size as i64,
+            stat.st_mtime,
+            stat.st_mtime_nsec as i64,
+        );
+        let name = match self.captured.get(&key) {
+            Some(name) => name.clone(),
+            None => {
+                let name = self.copy_in(readable_path)?;
+                log!(LogDebug, "Captured {:?} as {}", path, name);
+                self.captured.insert(key, name.clone());
+                name
+            }
+        };
+        let names = self.index.entry(path.to_owned()).or_default();
+        if !names.contains(&name) {
+            names.push(name.clone());
+        }
+        Ok(Some(Path::new(FILES_DIR).join(name).into_os_string()))
+    }
+
+    /// True if `backing_file_name`, from a mapped-region record, names one of
+    /// our copies.
+    pub fn is_snapshot_name(backing_file_name: &[u8]) -> bool {
+        backing_file_name.starts_with(FILES_DIR.as_bytes())
+            && backing_file_name.get(FILES_DIR.len()) == Some(&b'/')
+    }
+
+    /// Write the index. Call this at the end of recording.
+    pub fn save_index(&self) -> io::Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let json = serde_json::to_vec_pretty(&self.index)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Copy `readable_path` into the store and return the name of the copy.
+    fn copy_in(&self, readable_path: &OsStr) -> io::Result<String> {
+        let mut src = File::open(readable_path)?;
+        let tmp_path = self.dir.join("capture.tmp");
+        let mut dest = File::create(&tmp_path)?;
+        // Hashes only need to agree within one recording, so DefaultHasher
+        // not being stable across Rust versions doesn't matter.
+        let mut hasher = DefaultHasher::new();
+        let mut size: u64 = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let nread = src.read(&mut buf)?;
+            if nread == 0 {
+                break;
+            }
+            hasher.write(&buf[0..nread]);
+            dest.write_all(&buf[0..nread])?;
+            size += nread as u64;
+        }
+        drop(dest);
+
+        let name = format!("{:016x}-{}", hasher.finish(), size);
+        let final_path = self.dir.join(&name);
+        if final_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        } else {
+            fs::rename(&tmp_path, &final_path)?;
+        }
+        Ok(name)
+    }
+}
@@ -0,0 +1,281 @@
+//! Finding, describing and deleting trace directories, for `rd ls`, `rd rm` and `rd gc`.
+
+use crate::{
+    log::LogLevel::LogWarn,
+    session::record_session::TraceUuid,
+    trace::{
+        compressed_reader::CompressedReader,
+        trace_metadata::TraceMetadata,
+        trace_stream::{latest_trace_symlink, Substream, TraceStream, TRACE_VERSION},
+    },
+    trace_capnp::{header, task_event},
+};
+use capnp::{message::ReaderOptions, serialize_packed::read_message};
+use std::{
+    convert::TryInto,
+    ffi::{OsStr, OsString},
+    fs,
+    fs::File,
+    io,
+    io::{BufRead, BufReader},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TraceDirStatus {
+    /// Recording finished normally.
+    Ok,
+    /// rd crashed out during recording, e.g. due to a fatal assertion.
+    Crashed,
+    /// Recording is still in progress, or rd was killed before it could finish.
+    Incomplete,
+    /// Recorded by a version of rd with a different trace format.
+    IncompatibleVersion(u32),
+    /// The version file, the metadata or the trace header could not be read or
+    /// parsed.
+    Unreadable,
+}
+
+pub struct TraceDirInfo {
+    pub path: PathBuf,
+    pub status: TraceDirStatus,
+    /// Only available for traces we can open, i.e. with status `Ok` or `Crashed`.
+    pub uuid: Option<TraceUuid>,
+    /// The command line of the first exec in the trace, if we could read it.
+    pub cmd_line: Vec<OsString>,
+    /// When recording finished, or when it last made progress for incomplete traces.
+    pub modified: SystemTime,
+    /// Space used on disk by all the files in the trace directory.
+    pub size: u64,
+    pub metadata: TraceMetadata,
+}
+
+fn version_file(dir: &Path) -> PathBuf {
+    dir.join("version")
+}
+
+fn incomplete_version_file(dir: &Path) -> PathBuf {
+    dir.join("incomplete")
+}
+
+/// A directory is considered to be a trace if it has a version file, complete or not.
+pub fn is_trace_dir(dir: &Path) -> bool {
+    dir.is_dir() && (version_file(dir).is_file() || incomplete_version_file(dir).is_file())
+}
+
+fn read_trace_version(dir: &Path) -> Option<u32> {
+    let mut line = String::new();
+    let mut reader = BufReader::new(File::open(version_file(dir)).ok()?);
+    reader.read_line(&mut line).ok()?;
+    line.trim().parse::<u32>().ok()
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Whether recording of the trace in `dir` finished normally, and its uuid.
+/// Unlike `TraceReader::new()`, this returns an error rather than aborting if
+/// the header is damaged.
+fn read_trace_header(dir: &Path) -> io::Result<(bool, TraceUuid)> {
+    let mut reader = BufReader::new(File::open(version_file(dir))?);
+    // Skip the version line.
+    reader.read_line(&mut String::new())?;
+    let header_msg = read_message(&mut reader, ReaderOptions::new()).map_err(invalid_data)?;
+    let header = header_msg
+        .get_root::<header::Reader>()
+        .map_err(invalid_data)?;
+    let bytes = header
+        .get_uuid()
+        .map_err(invalid_data)?
+        .try_into()
+        .map_err(|_| invalid_data("Invalid UUID length"))?;
+    Ok((header.get_ok(), TraceUuid { bytes }))
+}
+
+/// The command line of the first exec in the trace in `dir`, if there is one.
+fn read_first_exec_cmd_line(dir: &Path) -> io::Result<Vec<OsString>> {
+    let stream = TraceStream::new(dir.as_os_str(), 1);
+    let mut tasks = CompressedReader::new(&stream.path(Substream::Tasks));
+    while !tasks.at_end() {
+        let task_msg = read_message(&mut tasks, ReaderOptions::new()).map_err(invalid_data)?;
+        let task = task_msg
+            .get_root::<task_event::Reader>()
+            .map_err(invalid_data)?;
+        if let task_event::Exec(r) = task.which().map_err(invalid_data)? {
+            let mut cmd_line = Vec::new();
+            for arg in r.get_cmd_line().map_err(invalid_data)?.iter() {
+                cmd_line.push(OsStr::from_bytes(arg.map_err(invalid_data)?).to_os_string());
+            }
+            return Ok(cmd_line);
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            size += disk_usage(&entry?.path())?;
+        }
+    }
+    Ok(size)
+}
+
+/// When recording of the trace in `dir` finished, or when it last made progress for
+/// incomplete traces.
+fn modified_time(dir: &Path) -> io::Result<SystemTime> {
+    if version_file(dir).is_file() {
+        fs::metadata(version_file(dir))?.modified()
+    } else {
+        fs::metadata(incomplete_version_file(dir))?.modified()
+    }
+}
+
+/// Describe the trace in `dir`, which must satisfy `is_trace_dir()`. A trace
+/// whose files are damaged is described with status `Unreadable`.
+pub fn trace_dir_info(dir: &Path) -> io::Result<TraceDirInfo> {
+    let complete = version_file(dir).is_file();
+    let mut info = TraceDirInfo {
+        path: dir.to_owned(),
+        status: TraceDirStatus::Incomplete,
+        uuid: None,
+        cmd_line: Vec::new(),
+        modified: modified_time(dir)?,
+        size: disk_usage(dir)?,
+        metadata: TraceMetadata::default(),
+    };
+    match TraceMetadata::load(dir) {
+        Ok(metadata) => info.metadata = metadata,
+        Err(e) => {
+            log!(LogWarn, "Can't read metadata of {:?}: {}", dir, e);
+            info.status = TraceDirStatus::Unreadable;
+            return Ok(info);
+        }
+    }
+    if !complete {
+        return Ok(info);
+    }
+
+    match read_trace_version(dir) {
+        None => info.status = TraceDirStatus::Unreadable,
+        Some(version) if version != TRACE_VERSION => {
+            info.status = TraceDirStatus::IncompatibleVersion(version)
+        }
+        Some(_) => match read_trace_header(dir) {
+            Ok((ok, uuid)) => {
+                info.status = if ok {
+                    TraceDirStatus::Ok
+                } else {
+                    TraceDirStatus::Crashed
+                };
+                info.uuid = Some(uuid);
+                // The command line is nice to have, don't give up on the trace
+                // without it.
+                match read_first_exec_cmd_line(dir) {
+                    Ok(cmd_line) => info.cmd_line = cmd_line,
+                    Err(e) => log!(LogWarn, "Can't read the tasks of {:?}: {}", dir, e),
+                }
+            }
+            Err(e) => {
+                log!(LogWarn, "Can't read the header of {:?}: {}", dir, e);
+                info.status = TraceDirStatus::Unreadable;
+            }
+        },
+    }
+    Ok(info)
+}
+
+/// All the traces directly inside `dir`, oldest first. Traces we can't
+/// describe are listed with status `Unreadable`.
+pub fn list_traces(dir: &Path) -> io::Result<Vec<TraceDirInfo>> {
+    let mut traces = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Skip `latest-trace` and any other symlinks so that no trace is listed twice.
+        if entry.file_type()?.is_symlink() {
+            continue;
+        }
+        let path = entry.path();
+        if is_trace_dir(&path) {
+            let info = trace_dir_info(&path).unwrap_or_else(|e| {
+                log!(LogWarn, "Can't read {:?}: {}", path, e);
+                TraceDirInfo {
+                    path: path.clone(),
+                    status: TraceDirStatus::Unreadable,
+                    uuid: None,
+                    cmd_line: Vec::new(),
+                    modified: modified_time(&path).unwrap_or(UNIX_EPOCH),
+                    size: disk_usage(&path).unwrap_or(0),
+                    metadata: TraceMetadata::default(),
+                }
+            });
+            traces.push(info);
+        }
+    }
+    traces.sort_by_key(|t| t.modified);
+    Ok(traces)
+}
+
+/// The most recent trace directly inside `dir` whose metadata gives it the name `name`.
+/// Unlike `list_traces()` this doesn't open the traces themselves.
+pub fn find_trace_by_name(dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    let mut found: Option<(SystemTime, PathBuf)> = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_symlink() || !is_trace_dir(&path) {
+            continue;
+        }
+        // A trace with unreadable metadata can't be the one we're looking for.
+        let metadata = TraceMetadata::load(&path).unwrap_or_default();
+        if metadata.name.as_deref() != Some(name) {
+            continue;
+        }
+        let modified = modified_time(&path)?;
+        if found.as_ref().map_or(true, |(time, _)| modified > *time) {
+            found = Some((modified, path));
+        }
+    }
+    Ok(found.map(|(_, path)| path))
+}
+
+/// Does the `latest-trace` symlink point at `dir`?
+pub fn is_latest_trace(dir: &Path) -> bool {
+    match (
+        fs::canonicalize(latest_trace_symlink()),
+        fs::canonicalize(dir),
+    ) {
+        (Ok(latest), Ok(dir)) => latest == dir,
+        _ => false,
+    }
+}
+
+/// Delete the trace in `dir`. Refuses to touch anything that doesn't look like a trace.
+/// The `latest-trace` symlink is removed too if it pointed at `dir`.
+pub fn remove_trace(dir: &Path) -> io::Result<()> {
+    if !is_trace_dir(dir) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{}' is not a trace directory", dir.display()),
+        ));
+    }
+    let was_latest = is_latest_trace(dir);
+    // If `dir` is a symlink to a trace, delete the trace, not just the symlink.
+    let real_dir = fs::canonicalize(dir)?;
+    fs::remove_dir_all(&real_dir)?;
+    if dir != real_dir.as_path() && fs::symlink_metadata(dir).is_ok() {
+        fs::remove_file(dir)?;
+    }
+    if was_latest {
+        match fs::remove_file(latest_trace_symlink()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Metadata for a trace that the trace format has no place for, kept in a JSON sidecar file in
+//! the trace directory. Mostly supplied by the user, so that it can be changed after recording
+//! without touching the trace itself.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    fs::File,
+    io,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, Eq, PartialEq)]
+pub struct TraceMetadata {
+    /// Lets the trace be selected as `@name` instead of by path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Free-form `key=value` tags, e.g. a CI job id or git sha.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Seed of the random scheduling decisions if the trace was recorded in chaos mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chaos_seed: Option<u64>,
+}
+
+fn metadata_path(trace_dir: &Path) -> PathBuf {
+    trace_dir.join("metadata")
+}
+
+impl TraceMetadata {
+    /// Traces without a metadata file have empty metadata.
+    pub fn load(trace_dir: &Path) -> io::Result<TraceMetadata> {
+        let file = match File::open(metadata_path(trace_dir)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Replaces the metadata file atomically, so readers never see a partial file.
+    pub fn save(&self, trace_dir: &Path) -> io::Result<()> {
+        let path = metadata_path(trace_dir);
+        let tmp_path = path.with_extension("tmp");
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// `key=value` pairs, comma separated.
+    pub fn tags_string(&self) -> String {
+        let tags: Vec<String> = self
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        tags.join(",")
+    }
+}
+
+/// Names must be usable as `@name` on the command line.
+pub fn is_valid_trace_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.chars().any(char::is_whitespace)
+}
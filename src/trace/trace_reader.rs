@@ -20,6 +20,8 @@ use crate::{
     session::{address_space::kernel_mapping::KernelMapping, record_session::TraceUuid},
     trace::{
         compressed_reader::{CompressedReader, CompressedReaderState},
+        file_snapshot_store::FileSnapshotStore,
+        trace_dir::find_trace_by_name,
         trace_frame::{FrameTime, TraceFrame},
         trace_stream::{
             latest_trace_symlink,
@@ -82,6 +84,7 @@ use std::{
     mem::size_of,
     ops::{Deref, DerefMut},
     os::unix::ffi::{OsStrExt, OsStringExt},
+    path::Path,
     process::exit,
     ptr::copy_nonoverlapping,
 };
@@ -130,6 +133,7 @@ pub struct TraceReader {
     uuid_: TraceUuid,
     trace_uses_cpuid_faulting: bool,
     preload_thread_locals_recorded_: bool,
+    ok_: bool,
 }
 
 impl Deref for TraceReader {
@@ -344,6 +348,8 @@ impl TraceReader {
                             let backing_file_name_int = f.get_backing_file_name().unwrap();
                             let is_clone = backing_file_name_int.starts_with(b"mmap_clone_");
                             let is_copy = backing_file_name_int.starts_with(b"mmap_copy_");
+                            let is_snapshot =
+                                FileSnapshotStore::is_snapshot_name(backing_file_name_int);
                             let mut backing_file_name_vec: Vec<u8> = Vec::new();
                             if backing_file_name_int[0] != b'/' {
                                 backing_file_name_vec.extend_from_slice(self.dir().as_bytes());
@@ -364,6 +370,7 @@ impl TraceReader {
                             let has_stat_buf = mode != 0 || uid != 0 || gid != 0 || mtime != 0;
                             if !is_clone
                                 && !is_copy
+                                && !is_snapshot
                                 && validate == ValidateSourceFile::Validate
                                 && has_stat_buf
                             {
@@ -718,6 +725,7 @@ impl TraceReader {
         }
         let xcr0_ = header.get_xcr0();
         let preload_thread_locals_recorded_ = header.get_preload_thread_locals_recorded();
+        let ok_ = header.get_ok();
         let ticks_semantics_ = from_trace_ticks_semantics(header.get_ticks_semantics().unwrap());
         let uuid_from_trace = header.get_uuid().unwrap();
         let mut uuid_ = TraceUuid::new();
@@ -738,6 +746,7 @@ impl TraceReader {
             uuid_,
             trace_uses_cpuid_faulting,
             preload_thread_locals_recorded_,
+            ok_,
             // @TODO Is this what we want?
             monotonic_time_: 0.0,
             raw_recs: vec![],
@@ -774,6 +783,10 @@ impl TraceReader {
     pub fn preload_thread_locals_recorded(&self) -> bool {
         self.preload_thread_locals_recorded_
     }
+    /// False if the recording rd crashed out due to a fatal assertion etc.
+    pub fn ok(&self) -> bool {
+        self.ok_
+    }
     pub fn uuid(&self) -> &TraceUuid {
         &self.uuid_
     }
@@ -871,12 +884,33 @@ fn i32_to_tid(tid: i32) -> pid_t {
     tid
 }
 
-fn resolve_trace_name<T: AsRef<OsStr>>(maybe_trace_name: Option<&T>) -> OsString {
+/// Turn a trace name from the command line into a path. `None` means the latest trace, `@name`
+/// the most recent trace in the trace save dir with that name in its metadata, and any other
+/// bare name is looked up in the current directory and then in the trace save dir.
+pub fn resolve_trace_name<T: AsRef<OsStr>>(maybe_trace_name: Option<&T>) -> OsString {
     if maybe_trace_name.is_none() {
         return latest_trace_symlink();
     }
 
     let trace_name = maybe_trace_name.unwrap().as_ref();
+    if trace_name.as_bytes().first() == Some(&b'@') {
+        let name = String::from_utf8_lossy(&trace_name.as_bytes()[1..]);
+        let save_dir = trace_save_dir();
+        match find_trace_by_name(Path::new(&save_dir), &name) {
+            Ok(Some(dir)) => return dir.into_os_string(),
+            Ok(None) => {
+                clean_fatal!("No trace named `{}' in {:?}", name, save_dir);
+            }
+            Err(e) => {
+                clean_fatal!(
+                    "Could not search {:?} for trace `{}': {}",
+                    save_dir,
+                    name,
+                    e
+                );
+            }
+        }
+    }
     // Single-component paths are looked up first in the current directory, next
     // in the default trace dir.
     if find(trace_name.as_bytes(), b"/").is_none() {
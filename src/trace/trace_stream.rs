@@ -307,7 +307,7 @@ pub(super) fn default_rd_trace_dir() -> OsString {
     cached_dir
 }
 
-pub(super) fn trace_save_dir() -> OsString {
+pub fn trace_save_dir() -> OsString {
     let maybe_output_dir = env::var_os("_RD_TRACE_DIR");
     let maybe_output_dir2 = env::var_os("_RR_TRACE_DIR");
     match maybe_output_dir {
@@ -319,7 +319,7 @@ pub(super) fn trace_save_dir() -> OsString {
     }
 }
 
-pub(super) fn latest_trace_symlink() -> OsString {
+pub fn latest_trace_symlink() -> OsString {
     let mut sym: Vec<u8> = Vec::from(trace_save_dir().as_bytes());
     sym.extend_from_slice(b"/latest-trace");
     OsString::from_vec(sym)
@@ -98,6 +98,39 @@ pub struct TraceTaskEvent {
 }
 
 impl TraceTaskEvent {
+    pub fn for_clone(tid: pid_t, parent_tid: pid_t, own_ns_tid: pid_t, clone_flags: i32) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Clone(TraceTaskEventClone {
+                parent_tid_: parent_tid,
+                own_ns_tid_: own_ns_tid,
+                clone_flags_: clone_flags,
+            }),
+            tid_: tid,
+        }
+    }
+    pub fn for_exec(
+        tid: pid_t,
+        file_name: &OsStr,
+        cmd_line: &[OsString],
+        exe_base: RemotePtr<Void>,
+    ) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Exec(TraceTaskEventExec {
+                file_name_: file_name.to_owned(),
+                cmd_line_: cmd_line.to_vec(),
+                exe_base_: exe_base,
+            }),
+            tid_: tid,
+        }
+    }
+    pub fn for_exit(tid: pid_t, exit_status: WaitStatus) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Exit(TraceTaskEventExit {
+                exit_status_: exit_status,
+            }),
+            tid_: tid,
+        }
+    }
     pub fn tid(&self) -> pid_t {
         self.tid_
     }
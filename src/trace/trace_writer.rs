@@ -9,8 +9,8 @@ use crate::{
         RD_NATIVE_ARCH,
     },
     kernel_supplement::{btrfs_ioctl_clone_range_args, BTRFS_IOC_CLONE_, BTRFS_IOC_CLONE_RANGE_},
-    log::LogLevel::LogDebug,
-    perf_counters::{PerfCounters, TicksSemantics},
+    log::LogLevel::{LogDebug, LogWarn},
+    perf_counters::TicksSemantics,
     registers::Registers,
     remote_ptr::{RemotePtr, Void},
     scoped_fd::ScopedFd,
@@ -21,6 +21,8 @@ use crate::{
     },
     trace::{
         compressed_writer::CompressedWriter,
+        file_snapshot_store::FileSnapshotStore,
+        trace_metadata::TraceMetadata,
         trace_stream::{
             latest_trace_symlink,
             make_trace_dir,
@@ -80,6 +82,7 @@ use std::{
     convert::TryInto,
     ffi::{OsStr, OsString},
     fs::{hard_link, rename, File},
+    io,
     io::Write,
     mem::size_of,
     ops::{Deref, DerefMut},
@@ -165,6 +168,8 @@ pub struct TraceWriter {
     mmap_count: u32,
     has_cpuid_faulting_: bool,
     supports_file_data_cloning_: bool,
+    /// Copies of the files the tracees depend on, if we're capturing them.
+    file_store: Option<FileSnapshotStore>,
 }
 
 impl Deref for TraceWriter {
@@ -384,18 +389,23 @@ impl TraceWriter {
                 && (km.inode() == 0 || km.fsname() == "/dev/zero (deleted)")
             {
                 src.reborrow().set_zero(());
-            } else if !km.fsname().as_bytes().starts_with(b"/") {
+            } else if !km.fsname().as_bytes().starts_with(b"/")
+                || km.fsname().as_bytes().starts_with(b"/memfd:")
+            {
+                // A memfd's name isn't a path we could open during replay.
                 src.reborrow().set_trace(());
             } else {
                 let file_name = try_make_process_file_name(t, km.fsname());
                 let assumed_immutable = self
                     .files_assumed_immutable
-                    .get(&(stat.st_dev, stat.st_ino));
+                    .get(&(stat.st_dev, stat.st_ino))
+                    .cloned();
 
                 if assumed_immutable.is_some() {
+                    backing_file_name = assumed_immutable.unwrap();
                     src.reborrow()
                         .init_file()
-                        .set_backing_file_name(assumed_immutable.unwrap().as_bytes());
+                        .set_backing_file_name(backing_file_name.as_bytes());
                 } else if km.flags().contains(MapFlags::MAP_PRIVATE)
                     && self.try_clone_file(t, &file_name, &mut backing_file_name)
                 {
@@ -445,6 +455,22 @@ impl TraceWriter {
                         .init_file()
                         .set_backing_file_name(backing_file_name.as_bytes());
                 }
+
+                // Replay will need the file itself, not a copy in the trace. If
+                // we're capturing files, point replay at a copy of the current
+                // contents instead. But not for a writable shared mapping, where
+                // replay would write to the copy.
+                if backing_file_name.as_bytes().starts_with(b"/") {
+                    let writable_shared = km.flags().contains(MapFlags::MAP_SHARED)
+                        && km.prot().contains(ProtFlags::PROT_WRITE);
+                    if let Some(snapshot) = self.capture_file(km.fsname(), &file_name, stat) {
+                        if !writable_shared {
+                            src.reborrow()
+                                .init_file()
+                                .set_backing_file_name(snapshot.as_bytes());
+                        }
+                    }
+                }
             }
 
             record_in_trace = if let Trace(_) = src.which().unwrap() {
@@ -588,6 +614,7 @@ impl TraceWriter {
             cpuid_records: vec![],
             version_fd: ScopedFd::new(),
             supports_file_data_cloning_: false,
+            file_store: None,
         };
 
         tw.bind_to_cpu = bind_to_cpu;
@@ -701,9 +728,7 @@ impl TraceWriter {
         };
         header.set_cpuid_records(cpuid_data);
         header.set_xcr0(xcr0());
-        header.set_ticks_semantics(to_trace_ticks_semantics(
-            PerfCounters::default_ticks_semantics(),
-        ));
+        header.set_ticks_semantics(to_trace_ticks_semantics(self.ticks_semantics_));
         header.set_syscallbuf_protocol_version(SYSCALLBUF_PROTOCOL_VERSION);
         header.set_preload_thread_locals_recorded(true);
         // Add a random UUID to the trace metadata. This lets tools identify a trace
@@ -717,6 +742,11 @@ impl TraceWriter {
             }
         }
         header.set_ok(status == CloseStatus::CloseOk);
+        if let Some(store) = &self.file_store {
+            if let Err(e) = store.save_index() {
+                fatal!("Unable to write file store index: {}", e);
+            }
+        }
         let mut f = unsafe { File::from_raw_fd(self.version_fd.as_raw()) };
         if write_message(&mut f, &header_msg).is_err() {
             fatal!("Unable to write {:?}", self.incomplete_version_path());
@@ -730,6 +760,19 @@ impl TraceWriter {
         self.version_fd.close();
     }
 
+    /// Change the trace's metadata with `update`, keeping what it doesn't touch.
+    pub fn update_metadata(&self, update: impl FnOnce(&mut TraceMetadata)) -> io::Result<()> {
+        let trace_dir = Path::new(&self.trace_dir);
+        let mut metadata = TraceMetadata::load(trace_dir)?;
+        update(&mut metadata);
+        metadata.save(trace_dir)
+    }
+
+    /// Record the seed chaos mode was run with, keeping any other metadata.
+    pub fn write_chaos_seed(&self, seed: Option<u64>) -> io::Result<()> {
+        self.update_metadata(|metadata| metadata.chaos_seed = seed)
+    }
+
     /// We got far enough into recording that we should set this as the latest
     /// trace.
     pub fn make_latest_trace(&self) {
@@ -740,8 +783,9 @@ impl TraceWriter {
         // and it "won".  The link is then valid and points at some
         // very-recent trace, so that's good enough.
         //
-        // DIFF NOTE: rr swallows any error on unlink. We don't for now.
-        if unlink(link_name.as_os_str()).is_err() {
+        // DIFF NOTE: rr swallows any error on unlink. We only tolerate the
+        // link not existing yet, which is the case for the first recording.
+        if unlink(link_name.as_os_str()).is_err() && errno() != libc::ENOENT {
             fatal!("Unable to unlink {:?}", link_name);
         }
 
@@ -763,6 +807,41 @@ impl TraceWriter {
         self.ticks_semantics_
     }
 
+    /// Start copying the files the tracees open read-only or map into the
+    /// trace, see `FileSnapshotStore`.
+    pub fn enable_file_capture(&mut self) {
+        if self.file_store.is_some() {
+            return;
+        }
+        match FileSnapshotStore::create(self.dir()) {
+            Ok(store) => self.file_store = Some(store),
+            Err(e) => fatal!("Unable to create file store in {:?}: {}", self.dir(), e),
+        }
+    }
+
+    pub fn captures_files(&self) -> bool {
+        self.file_store.is_some()
+    }
+
+    /// If we're capturing files, copy the file the tracee knows as `path`
+    /// (and we can read at `readable_path`) into the trace. Returns the name of
+    /// the copy relative to the trace directory, if one was made.
+    pub fn capture_file(
+        &mut self,
+        path: &OsStr,
+        readable_path: &OsStr,
+        stat: &libc::stat,
+    ) -> Option<OsString> {
+        let store = self.file_store.as_mut()?;
+        match store.capture(path, readable_path, stat) {
+            Ok(maybe_name) => maybe_name,
+            Err(e) => {
+                log!(LogWarn, "Unable to capture {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
     fn try_hardlink_file(&self, file_name: &OsStr, new_name: &mut OsString) -> bool {
         let base_file_name = Path::new(file_name).file_name().unwrap();
         let mut path: Vec<u8> = Vec::new();
@@ -940,3 +1019,111 @@ fn to_trace_ticks_semantics(semantics: TicksSemantics) -> TraceTicksSemantics {
         TicksSemantics::TicksTakenBranches => TraceTicksSemantics::TakenBranches,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::trace_reader::{TimeConstraint, TraceReader, ValidateSourceFile};
+    use nix::sys::stat::{stat, FileStat};
+    use std::{env, fs, process};
+
+    /// Write the mapped-region record `write_mapped_region()` makes for a mapping
+    /// of a captured file. That needs a tracee, this doesn't.
+    fn write_captured_mapping(
+        tw: &mut TraceWriter,
+        km: &KernelMapping,
+        file_stat: &FileStat,
+        snapshot: &OsStr,
+    ) {
+        let mut map_msg = message::Builder::new_default();
+        {
+            let mut map = map_msg.init_root::<m_map::Builder>();
+            map.set_frame_time(tw.global_time as i64);
+            map.set_start(km.start().as_usize() as u64);
+            map.set_end(km.end().as_usize() as u64);
+            map.set_fsname(km.fsname().as_bytes());
+            map.set_device(km.device());
+            map.set_inode(km.inode().into());
+            map.set_prot(km.prot().bits());
+            map.set_flags(km.flags().bits());
+            map.set_file_offset_bytes(0);
+            map.set_stat_mode(file_stat.st_mode);
+            map.set_stat_uid(file_stat.st_uid);
+            map.set_stat_gid(file_stat.st_gid);
+            map.set_stat_size(file_stat.st_size.into());
+            map.set_stat_m_time(file_stat.st_mtime.into());
+            map.get_source()
+                .init_file()
+                .set_backing_file_name(snapshot.as_bytes());
+        }
+        write_message(tw.writer_mut(Substream::Mmaps), &map_msg).unwrap();
+    }
+
+    /// A file captured at record time is what replay maps, even after the
+    /// original changed, and its metadata isn't checked against the original's.
+    #[test]
+    fn captured_file_round_trip() {
+        let dir = env::temp_dir().join(format!("rd-file-capture-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("original");
+        fs::write(&original, b"contents at record time").unwrap();
+        let original_stat = stat(&original).unwrap();
+        let trace_dir = dir.join("trace");
+
+        let mut tw = TraceWriter::new(
+            original.as_os_str(),
+            None,
+            trace_dir.as_os_str(),
+            TicksSemantics::TicksTakenBranches,
+        );
+        tw.enable_file_capture();
+        assert!(tw.captures_files());
+        let snapshot = tw
+            .capture_file(original.as_os_str(), original.as_os_str(), &original_stat)
+            .unwrap();
+        assert!(FileSnapshotStore::is_snapshot_name(snapshot.as_bytes()));
+        // Capturing the same file again doesn't make another copy.
+        assert_eq!(
+            Some(&snapshot),
+            tw.capture_file(original.as_os_str(), original.as_os_str(), &original_stat)
+                .as_ref()
+        );
+        let km = KernelMapping::new_with_opts(
+            0x10000.into(),
+            0x11000.into(),
+            original.as_os_str(),
+            original_stat.st_dev,
+            original_stat.st_ino,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_PRIVATE,
+            0,
+        );
+        write_captured_mapping(&mut tw, &km, &original_stat, &snapshot);
+        tw.close(CloseStatus::CloseOk, None);
+
+        fs::write(&original, b"contents at replay time").unwrap();
+
+        let mut reader = TraceReader::new(Some(&trace_dir));
+        let mut data = MappedData::default();
+        let replay_km = reader
+            .read_mapped_region(
+                Some(&mut data),
+                Some(ValidateSourceFile::Validate),
+                Some(TimeConstraint::AnyTime),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(km.fsname(), replay_km.fsname());
+        assert_eq!(MappedDataSource::SourceFile, data.source);
+        assert_eq!(trace_dir.join(&snapshot).as_os_str(), data.filename);
+        assert_eq!(
+            b"contents at record time".to_vec(),
+            fs::read(&data.filename).unwrap()
+        );
+
+        let index = fs::read_to_string(trace_dir.join("files").join("index")).unwrap();
+        assert!(index.contains(original.to_str().unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,794 @@
+//! Unwind and symbolize the stacks of tracees without involving gdb.
+//!
+//! Frames are unwound with the DWARF CFI in `.eh_frame` or `.debug_frame` of the
+//! mapped ELF objects, as parsed and evaluated by gimli. When there is no CFI for
+//! an address we fall back to following the frame pointer chain. Addresses are
+//! mapped to functions using the ELF symbol tables and to source lines with
+//! addr2line, which is also what symbolizes rd's own backtraces. The DWARF may live
+//! in a separate debug file found by build id.
+
+use crate::{
+    commands::build_id_command::BuildIdCommand,
+    elf_symbols::{symbolize, SymbolCache},
+    kernel_abi::SupportedArch,
+    log::LogLevel::LogDebug,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{address_space::address_space::AddressSpace, task::Task},
+};
+use addr2line::Context;
+use gimli::{
+    BaseAddresses,
+    CfaRule,
+    DebugFrame,
+    Dwarf,
+    EhFrame,
+    Encoding,
+    EndianRcSlice,
+    EndianSlice,
+    EvaluationResult,
+    Expression,
+    Format,
+    Location,
+    Piece,
+    Register,
+    RegisterRule,
+    RunTimeEndian,
+    SectionId,
+    UninitializedUnwindContext,
+    UnwindSection,
+    UnwindTableRow,
+    Value,
+};
+use goblin::elf::{
+    program_header::PT_LOAD,
+    section_header::{SHF_COMPRESSED, SHT_NOBITS},
+    Elf,
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    fs,
+    io,
+    ops::Range,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// Stop unwinding after this many frames, in case the stack is corrupt.
+const MAX_FRAMES: usize = 128;
+
+/// Registers in DWARF register number order. The last one is the return
+/// address column, which we use for the ip.
+const X64_DWARF_REGS: [&str; 17] = [
+    "rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip",
+];
+const X64_CALLEE_SAVED: [usize; 6] = [3, 6, 12, 13, 14, 15];
+
+const X86_DWARF_REGS: [&str; 9] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "eip",
+];
+const X86_CALLEE_SAVED: [usize; 4] = [3, 5, 6, 7];
+
+/// The DWARF register numbers we need to know about for each architecture.
+struct DwarfArch {
+    names: &'static [&'static str],
+    callee_saved: &'static [usize],
+    sp: usize,
+    bp: usize,
+    ip: usize,
+    word_size: usize,
+}
+
+fn dwarf_arch(arch: SupportedArch) -> DwarfArch {
+    match arch {
+        SupportedArch::X64 => DwarfArch {
+            names: &X64_DWARF_REGS,
+            callee_saved: &X64_CALLEE_SAVED,
+            sp: 7,
+            bp: 6,
+            ip: 16,
+            word_size: 8,
+        },
+        SupportedArch::X86 => DwarfArch {
+            names: &X86_DWARF_REGS,
+            callee_saved: &X86_CALLEE_SAVED,
+            sp: 4,
+            bp: 5,
+            ip: 8,
+            word_size: 4,
+        },
+    }
+}
+
+/// Register values indexed by DWARF register number. `None` means unknown.
+type DwarfRegs = Vec<Option<u64>>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnwindMethod {
+    /// The innermost frame, taken from the task's registers
+    Registers,
+    Cfi,
+    FramePointer,
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub ip: RemoteCodePtr,
+    pub sp: RemotePtr<Void>,
+    /// How this frame was found from the one below it.
+    pub method: UnwindMethod,
+    /// Whether `ip` is where a call returns to, rather than the instruction the
+    /// frame is stopped at. False for the innermost frame and for frames
+    /// interrupted by a signal.
+    pub is_return_address: bool,
+}
+
+/// What the DWARF sections are read through, so that the addr2line context can own
+/// them.
+type DwarfReader = EndianRcSlice<RunTimeEndian>;
+
+/// The CFI row for an address, and whether its CIE marks a signal trampoline.
+struct UnwindInfo<'a> {
+    row: UnwindTableRow<EndianSlice<'a, RunTimeEndian>>,
+    signal_frame: bool,
+}
+
+/// The unwinding and line information of a single ELF object. Addresses are ELF
+/// virtual addresses, i.e. without any load bias.
+struct DebugFile {
+    data: Vec<u8>,
+    endian: RunTimeEndian,
+    address_size: u8,
+    /// PT_LOAD segments as (virtual address, file offset, file size)
+    loads: Vec<(u64, u64, u64)>,
+    /// The virtual address and file range of `.eh_frame`
+    eh_frame: Option<(u64, Range<usize>)>,
+    debug_frame: Option<Range<usize>>,
+    /// `None` if there is no usable DWARF
+    lines: Option<Context<DwarfReader>>,
+}
+
+/// The virtual address and file range of the section `name`, unless the section
+/// is missing or compressed.
+fn find_section(elf: &Elf, name: &str) -> Option<(u64, Range<usize>)> {
+    elf.section_headers.iter().find_map(|sh| {
+        match elf.shdr_strtab.get(sh.sh_name) {
+            Some(Ok(n)) if n == name => (),
+            _ => return None,
+        }
+        if sh.sh_type == SHT_NOBITS || sh.sh_flags & SHF_COMPRESSED as u64 != 0 {
+            return None;
+        }
+        let start = sh.sh_offset as usize;
+        Some((sh.sh_addr, start..start + sh.sh_size as usize))
+    })
+}
+
+fn endian_of(elf: &Elf) -> RunTimeEndian {
+    if elf.little_endian {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    }
+}
+
+/// The addr2line context for the DWARF in `data`, if it has any.
+fn read_debug_info(data: &[u8], elf: &Elf) -> Option<Context<DwarfReader>> {
+    find_section(elf, ".debug_info")?;
+    let endian = endian_of(elf);
+    let load_section = |id: SectionId| -> Result<DwarfReader, gimli::Error> {
+        let section_data = match find_section(elf, id.name()) {
+            Some((_, range)) if range.end <= data.len() => &data[range],
+            _ => &[],
+        };
+        Ok(EndianRcSlice::new(Rc::from(section_data), endian))
+    };
+    let load_sup = |_| Ok(EndianRcSlice::new(Rc::from(&[][..]), endian));
+    let dwarf = Dwarf::load(load_section, load_sup).ok()?;
+    Context::from_dwarf(dwarf).ok()
+}
+
+/// Where the separate debug info for an object with `build_id` would be installed.
+fn separate_debug_file(build_id: &[u8]) -> Option<PathBuf> {
+    if build_id.len() < 2 {
+        return None;
+    }
+    let mut path = String::from("/usr/lib/debug/.build-id/");
+    write!(path, "{:02x}/", build_id[0]).unwrap();
+    for b in &build_id[1..] {
+        write!(path, "{:02x}", b).unwrap();
+    }
+    path.push_str(".debug");
+    Some(PathBuf::from(path))
+}
+
+impl DebugFile {
+    fn read(path: &Path) -> io::Result<DebugFile> {
+        let data = fs::read(path)?;
+        let elf = match Elf::parse(&data) {
+            Ok(elf) => elf,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        };
+
+        let loads = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| (ph.p_vaddr, ph.p_offset, ph.p_filesz))
+            .collect();
+        let eh_frame = find_section(&elf, ".eh_frame");
+        let debug_frame = find_section(&elf, ".debug_frame").map(|(_, range)| range);
+
+        let mut lines = read_debug_info(&data, &elf);
+        if lines.is_none() {
+            let maybe_debug_path = BuildIdCommand::build_id(path)
+                .ok()
+                .and_then(|build_id| separate_debug_file(&build_id));
+            if let Some(debug_path) = maybe_debug_path {
+                if let Ok(debug_data) = fs::read(&debug_path) {
+                    if let Ok(debug_elf) = Elf::parse(&debug_data) {
+                        log!(LogDebug, "Using debug info from {:?}", debug_path);
+                        lines = read_debug_info(&debug_data, &debug_elf);
+                    }
+                }
+            }
+        }
+
+        let endian = endian_of(&elf);
+        let address_size = if elf.is_64 { 8 } else { 4 };
+        drop(elf);
+        Ok(DebugFile {
+            data,
+            endian,
+            address_size,
+            loads,
+            eh_frame,
+            debug_frame,
+            lines,
+        })
+    }
+
+    fn file_offset_to_vaddr(&self, file_offset: u64) -> Option<u64> {
+        self.loads.iter().find_map(|&(vaddr, offset, size)| {
+            if file_offset >= offset && file_offset < offset + size {
+                Some(file_offset - offset + vaddr)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn find_line(&self, vaddr: u64) -> Option<(String, u32)> {
+        let location = self.lines.as_ref()?.find_location(vaddr).ok()??;
+        Some((location.file?.to_owned(), location.line?))
+    }
+
+    fn unwind_info(&self, vaddr: u64) -> Option<UnwindInfo<'_>> {
+        if let Some((eh_frame_vaddr, range)) = &self.eh_frame {
+            let mut eh_frame = EhFrame::new(&self.data[range.clone()], self.endian);
+            eh_frame.set_address_size(self.address_size);
+            let bases = BaseAddresses::default().set_eh_frame(*eh_frame_vaddr);
+            if let Some(info) = unwind_info(&eh_frame, &bases, vaddr) {
+                return Some(info);
+            }
+        }
+        if let Some(range) = &self.debug_frame {
+            let mut debug_frame = DebugFrame::new(&self.data[range.clone()], self.endian);
+            debug_frame.set_address_size(self.address_size);
+            return unwind_info(&debug_frame, &BaseAddresses::default(), vaddr);
+        }
+        None
+    }
+}
+
+fn unwind_info<'a, S: UnwindSection<EndianSlice<'a, RunTimeEndian>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    vaddr: u64,
+) -> Option<UnwindInfo<'a>> {
+    let fde = section
+        .fde_for_address(bases, vaddr, S::cie_from_offset)
+        .ok()?;
+    let mut ctx = UninitializedUnwindContext::new();
+    let row = fde
+        .unwind_info_for_address(section, bases, &mut ctx, vaddr)
+        .ok()?;
+    Some(UnwindInfo {
+        row,
+        signal_frame: fde.cie().is_signal_trampoline(),
+    })
+}
+
+fn read_word(t: &mut dyn Task, addr: u64, size: usize) -> Option<u64> {
+    if size > 8 {
+        return None;
+    }
+    let mut buf = [0u8; 8];
+    match t.read_bytes_fallible(RemotePtr::new_from_val(addr as usize), &mut buf[0..size]) {
+        Ok(nread) if nread == size => Some(u64::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+/// Unwinds tracee stacks. Parsed ELF objects are cached so it's cheaper to reuse
+/// an `Unwinder` for many tasks.
+#[derive(Default)]
+pub struct Unwinder {
+    symbols: SymbolCache,
+    debug_files: HashMap<OsString, Option<Rc<DebugFile>>>,
+}
+
+impl Unwinder {
+    pub fn new() -> Unwinder {
+        Default::default()
+    }
+
+    fn debug_file(&mut self, path: &OsStr) -> Option<Rc<DebugFile>> {
+        if let Some(maybe_file) = self.debug_files.get(path) {
+            return maybe_file.clone();
+        }
+        let maybe_file = match DebugFile::read(Path::new(path)) {
+            Ok(file) => Some(Rc::new(file)),
+            Err(e) => {
+                log!(
+                    LogDebug,
+                    "Could not read debug info from {:?}: {:?}",
+                    path,
+                    e
+                );
+                None
+            }
+        };
+        self.debug_files.insert(path.to_owned(), maybe_file.clone());
+        maybe_file
+    }
+
+    /// The object mapped at `addr` in `vm` and the ELF virtual address that `addr`
+    /// corresponds to.
+    fn debug_file_for_address(
+        &mut self,
+        vm: &AddressSpace,
+        addr: u64,
+    ) -> Option<(Rc<DebugFile>, u64)> {
+        let (file_offset, names) = {
+            let m = vm.mapping_of(RemotePtr::new_from_val(addr as usize))?;
+            let file_offset = addr - m.map.start().as_usize() as u64 + m.map.file_offset_bytes();
+            // During replay the file we actually mapped may differ from the
+            // recorded one (e.g. a copy in the trace directory) so try both.
+            let names = [
+                m.map.fsname().to_owned(),
+                m.recorded_map.fsname().to_owned(),
+            ];
+            (file_offset, names)
+        };
+        for name in names.iter() {
+            if !name.as_bytes().starts_with(b"/") {
+                continue;
+            }
+            if let Some(file) = self.debug_file(name) {
+                let vaddr = file.file_offset_to_vaddr(file_offset)?;
+                return Some((file, vaddr));
+            }
+        }
+        None
+    }
+
+    /// Unwind `t`'s stack, innermost frame first.
+    pub fn backtrace(&mut self, t: &mut dyn Task) -> Vec<Frame> {
+        let arch = dwarf_arch(t.arch());
+        let vm = t.vm_shr_ptr();
+        let register_values = t.regs_ref().register_values();
+        let mut regs: DwarfRegs = arch
+            .names
+            .iter()
+            .map(|name| {
+                register_values
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, v)| *v)
+            })
+            .collect();
+
+        let mut frames = Vec::new();
+        let mut method = UnwindMethod::Registers;
+        let mut is_return_address = false;
+        while frames.len() < MAX_FRAMES {
+            let (ip, sp) = match (regs[arch.ip], regs[arch.sp]) {
+                (Some(ip), Some(sp)) if ip != 0 => (ip, sp),
+                _ => break,
+            };
+            frames.push(Frame {
+                ip: RemoteCodePtr::from_val(ip as usize),
+                sp: RemotePtr::new_from_val(sp as usize),
+                method,
+                is_return_address,
+            });
+
+            // Return addresses point after the call, which may be in a different
+            // function (or have different CFI) if the call was the last instruction
+            // of a noreturn function.
+            let lookup = if is_return_address { ip - 1 } else { ip };
+            let maybe_cfi = self
+                .debug_file_for_address(&vm, lookup)
+                .and_then(|(file, vaddr)| {
+                    let info = file.unwind_info(vaddr)?;
+                    let next = cfi_step(
+                        &info.row,
+                        file.address_size,
+                        &regs,
+                        &arch,
+                        &mut |addr, size| read_word(t, addr, size),
+                    )?;
+                    Some((next, info.signal_frame))
+                });
+            let next = match maybe_cfi {
+                Some((next, signal_frame)) => {
+                    method = UnwindMethod::Cfi;
+                    // A signal trampoline's caller was interrupted, it didn't make a call.
+                    is_return_address = !signal_frame;
+                    next
+                }
+                None => match frame_pointer_step(&regs, &arch, t) {
+                    Some(next) => {
+                        method = UnwindMethod::FramePointer;
+                        is_return_address = true;
+                        next
+                    }
+                    None => break,
+                },
+            };
+            // The stack must grow towards the outermost frame or we'd loop forever.
+            match next[arch.sp] {
+                Some(next_sp) if next_sp > sp => (),
+                _ => break,
+            }
+            regs = next;
+        }
+        frames
+    }
+
+    /// Describe the location of `frame` like `func+0x1c at /src/file.c:42 (/lib/libfoo.so)`.
+    pub fn describe(&mut self, vm: &AddressSpace, frame: &Frame) -> String {
+        let ip = frame.ip.as_usize();
+        let lookup = if frame.is_return_address { ip - 1 } else { ip };
+        let mut s = String::new();
+        match symbolize(&mut self.symbols, vm, RemoteCodePtr::from_val(lookup)) {
+            Some(sym) => {
+                write!(s, "{}+{:#x}", sym.name, sym.offset + (ip - lookup)).unwrap();
+            }
+            None => s.push_str("??"),
+        }
+        if let Some((file, line)) = self
+            .debug_file_for_address(vm, lookup as u64)
+            .and_then(|(debug_file, vaddr)| debug_file.find_line(vaddr))
+        {
+            write!(s, " at {}:{}", file, line).unwrap();
+        }
+        if let Some(m) = vm.mapping_of(frame.ip.to_data_ptr()) {
+            write!(s, " ({})", m.recorded_map.fsname().to_string_lossy()).unwrap();
+        }
+        s
+    }
+}
+
+/// Reads `size` bytes of tracee memory at an address as a little endian number.
+type ReadMemory<'a> = dyn FnMut(u64, usize) -> Option<u64> + 'a;
+
+/// Evaluate a DWARF expression from the CFI. Only the common case of an expression
+/// that computes an address is supported. Expressions for register rules start
+/// with the CFA on the stack, CFA expressions with an empty stack.
+fn evaluate_cfi_expression(
+    expression: Expression<EndianSlice<RunTimeEndian>>,
+    address_size: u8,
+    initial_value: Option<u64>,
+    regs: &DwarfRegs,
+    read_memory: &mut ReadMemory,
+) -> Option<u64> {
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: 4,
+        address_size,
+    };
+    let mut evaluation = expression.evaluation(encoding);
+    if let Some(value) = initial_value {
+        evaluation.set_initial_value(value);
+    }
+    let mut result = evaluation.evaluate().ok()?;
+    loop {
+        result = match result {
+            EvaluationResult::Complete => break,
+            EvaluationResult::RequiresMemory { address, size, .. } => {
+                let value = read_memory(address, size as usize)?;
+                evaluation.resume_with_memory(Value::Generic(value)).ok()?
+            }
+            EvaluationResult::RequiresRegister { register, .. } => {
+                let value = (*regs.get(register.0 as usize)?)?;
+                evaluation
+                    .resume_with_register(Value::Generic(value))
+                    .ok()?
+            }
+            _ => return None,
+        };
+    }
+    match evaluation.result().as_slice() {
+        [Piece {
+            location: Location::Address { address },
+            ..
+        }] => Some(*address),
+        _ => None,
+    }
+}
+
+/// Recover the caller's registers using the CFI `row` for the current ip.
+fn cfi_step(
+    row: &UnwindTableRow<EndianSlice<RunTimeEndian>>,
+    address_size: u8,
+    regs: &DwarfRegs,
+    arch: &DwarfArch,
+    read_memory: &mut ReadMemory,
+) -> Option<DwarfRegs> {
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => {
+            let base = (*regs.get(register.0 as usize)?)?;
+            base.wrapping_add(*offset as u64)
+        }
+        // Signal trampolines find the interrupted frame's registers this way.
+        CfaRule::Expression(expression) => {
+            evaluate_cfi_expression(*expression, address_size, None, regs, read_memory)?
+        }
+    };
+
+    let mut next: DwarfRegs = vec![None; regs.len()];
+    for (reg, value) in next.iter_mut().enumerate() {
+        *value = match row.register(Register(reg as u16)) {
+            // Registers without a rule are assumed to be preserved if the ABI
+            // says the callee must preserve them.
+            RegisterRule::Undefined if arch.callee_saved.contains(&reg) => regs[reg],
+            RegisterRule::Undefined => None,
+            RegisterRule::SameValue => regs[reg],
+            RegisterRule::Offset(offset) => {
+                read_memory(cfa.wrapping_add(offset as u64), arch.word_size)
+            }
+            RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add(offset as u64)),
+            RegisterRule::Register(other) => regs.get(other.0 as usize).copied().flatten(),
+            RegisterRule::Expression(expression) => {
+                evaluate_cfi_expression(expression, address_size, Some(cfa), regs, read_memory)
+                    .and_then(|addr| read_memory(addr, arch.word_size))
+            }
+            RegisterRule::ValExpression(expression) => {
+                evaluate_cfi_expression(expression, address_size, Some(cfa), regs, read_memory)
+            }
+            _ => None,
+        };
+    }
+    // The CFA is the caller's sp by definition, unless the CFI says otherwise as
+    // it does for signal frames.
+    if let RegisterRule::Undefined = row.register(Register(arch.sp as u16)) {
+        next[arch.sp] = Some(cfa);
+    }
+    Some(next)
+}
+
+/// Recover the caller's registers assuming the standard `push bp; mov bp, sp`
+/// prologue.
+fn frame_pointer_step(regs: &DwarfRegs, arch: &DwarfArch, t: &mut dyn Task) -> Option<DwarfRegs> {
+    let bp = regs[arch.bp]?;
+    let sp = regs[arch.sp]?;
+    if bp < sp || bp % arch.word_size as u64 != 0 {
+        return None;
+    }
+    let word_size = arch.word_size as u64;
+    let mut next: DwarfRegs = vec![None; regs.len()];
+    next[arch.ip] = Some(read_word(t, bp + word_size, arch.word_size)?);
+    next[arch.bp] = Some(read_word(t, bp, arch.word_size)?);
+    next[arch.sp] = Some(bp + 2 * word_size);
+    Some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gimli::{
+        constants::{DW_EH_PE_absptr, DW_EH_PE_udata4, DW_OP_deref},
+        write::{self, Address, CallFrameInstruction, CommonInformationEntry, FrameTable},
+        LittleEndian,
+    };
+
+    const RBX: u16 = 3;
+    const RBP: u16 = 6;
+    const RSP: u16 = 7;
+    const RIP: u16 = 16;
+
+    fn x64_encoding() -> Encoding {
+        Encoding {
+            format: Format::Dwarf32,
+            // The version of .eh_frame CIEs
+            version: 1,
+            address_size: 8,
+        }
+    }
+
+    /// A CIE like the ones gcc emits for x86-64: the CFA is rsp+8 and the return
+    /// address is just below it.
+    fn x64_cie() -> CommonInformationEntry {
+        let mut cie = CommonInformationEntry::new(x64_encoding(), 1, -8, Register(RIP));
+        cie.add_instruction(CallFrameInstruction::Cfa(Register(RSP), 8));
+        cie.add_instruction(CallFrameInstruction::Offset(Register(RIP), -8));
+        cie
+    }
+
+    /// `.eh_frame` holding `cie` and an FDE for [0x1000, 0x1020) with `instructions`
+    /// at the given offsets from the start of the function.
+    fn eh_frame_with(
+        cie: CommonInformationEntry,
+        lsda: Option<Address>,
+        instructions: Vec<(u32, CallFrameInstruction)>,
+    ) -> Vec<u8> {
+        let mut table = FrameTable::default();
+        let cie_id = table.add_cie(cie);
+        let mut fde = write::FrameDescriptionEntry::new(Address::Constant(0x1000), 0x20);
+        fde.lsda = lsda;
+        for (offset, instruction) in instructions {
+            fde.add_instruction(offset, instruction);
+        }
+        table.add_fde(cie_id, fde);
+        let mut eh_frame = write::EhFrame(write::EndianVec::new(LittleEndian));
+        table.write_eh_frame(&mut eh_frame).unwrap();
+        eh_frame.0.into_vec()
+    }
+
+    /// The caller's registers when the callee is at `vaddr`, and whether the
+    /// callee is a signal trampoline.
+    fn step(
+        data: &[u8],
+        vaddr: u64,
+        regs: &[(u16, u64)],
+        memory: &[(u64, u64)],
+    ) -> Option<(DwarfRegs, bool)> {
+        let eh_frame = EhFrame::new(data, RunTimeEndian::Little);
+        let info = unwind_info(&eh_frame, &BaseAddresses::default().set_eh_frame(0), vaddr)?;
+        let arch = dwarf_arch(SupportedArch::X64);
+        let mut dwarf_regs: DwarfRegs = vec![None; arch.names.len()];
+        for &(reg, value) in regs {
+            dwarf_regs[reg as usize] = Some(value);
+        }
+        let mut read_memory = |addr: u64, size: usize| {
+            assert_eq!(size, 8);
+            memory
+                .iter()
+                .find(|&&(a, _)| a == addr)
+                .map(|&(_, value)| value)
+        };
+        let next = cfi_step(&info.row, 8, &dwarf_regs, &arch, &mut read_memory)?;
+        Some((next, info.signal_frame))
+    }
+
+    #[test]
+    fn cfi_augmentation() {
+        // "zPLR": a personality routine, an LSDA pointer in the FDE and 4 byte FDE
+        // addresses, as in C++ objects. The augmentation data has to be skipped
+        // correctly to get to the instructions.
+        let mut cie = x64_cie();
+        cie.personality = Some((DW_EH_PE_absptr, Address::Constant(0x1234)));
+        cie.lsda_encoding = Some(DW_EH_PE_absptr);
+        cie.fde_address_encoding = DW_EH_PE_udata4;
+        let data = eh_frame_with(
+            cie,
+            Some(Address::Constant(0x5678)),
+            vec![
+                // push rbp
+                (1, CallFrameInstruction::CfaOffset(16)),
+                (1, CallFrameInstruction::Offset(Register(RBP), -16)),
+            ],
+        );
+        let regs = [(RSP, 0x7000), (RBP, 0x1), (RBX, 0x2), (0, 0x3)];
+        let memory = [(0x7000, 0x7ff0), (0x7008, 0x4444)];
+
+        // Before the push only the CIE's rules apply.
+        let (next, signal_frame) = step(&data, 0x1000, &regs, &memory).unwrap();
+        assert!(!signal_frame);
+        assert_eq!(next[RIP as usize], Some(0x7ff0));
+        assert_eq!(next[RSP as usize], Some(0x7008));
+        assert_eq!(next[RBP as usize], Some(0x1));
+
+        let (next, _) = step(&data, 0x1004, &regs, &memory).unwrap();
+        assert_eq!(next[RIP as usize], Some(0x4444));
+        assert_eq!(next[RSP as usize], Some(0x7010));
+        assert_eq!(next[RBP as usize], Some(0x7ff0));
+        // Callee saved and not mentioned by the CFI, so unchanged.
+        assert_eq!(next[RBX as usize], Some(0x2));
+        // Caller saved, so unknown.
+        assert_eq!(next[0], None);
+
+        assert!(step(&data, 0x1020, &regs, &memory).is_none());
+    }
+
+    #[test]
+    fn cfi_restore() {
+        let data = eh_frame_with(
+            x64_cie(),
+            None,
+            vec![
+                // push rbp
+                (1, CallFrameInstruction::CfaOffset(16)),
+                (1, CallFrameInstruction::Offset(Register(RBP), -16)),
+                // pop rbp, back to the CIE's rules
+                (8, CallFrameInstruction::Restore(Register(RBP))),
+                (8, CallFrameInstruction::CfaOffset(8)),
+            ],
+        );
+        let regs = [(RSP, 0x7000), (RBP, 0x1)];
+        let memory = [(0x7000, 0x7ff0), (0x7008, 0x4444)];
+
+        let (next, _) = step(&data, 0x1004, &regs, &memory).unwrap();
+        assert_eq!(next[RBP as usize], Some(0x7ff0));
+        assert_eq!(next[RIP as usize], Some(0x4444));
+
+        let (next, _) = step(&data, 0x1008, &regs, &memory).unwrap();
+        assert_eq!(next[RBP as usize], Some(0x1));
+        assert_eq!(next[RIP as usize], Some(0x7ff0));
+        assert_eq!(next[RSP as usize], Some(0x7008));
+    }
+
+    #[test]
+    fn cfi_signal_frame() {
+        // Like glibc's __restore_rt: the interrupted registers are in the
+        // ucontext_t on the stack, found with DWARF expressions on rsp.
+        let breg_rsp = |offset, deref| {
+            let mut expression = write::Expression::new();
+            expression.op_breg(Register(RSP), offset);
+            if deref {
+                expression.op(DW_OP_deref);
+            }
+            expression
+        };
+        let mut cie = CommonInformationEntry::new(x64_encoding(), 1, -8, Register(RIP));
+        cie.signal_trampoline = true;
+        cie.fde_address_encoding = DW_EH_PE_udata4;
+        cie.add_instruction(CallFrameInstruction::CfaExpression(breg_rsp(160, true)));
+        let data = eh_frame_with(
+            cie,
+            None,
+            vec![
+                (
+                    0,
+                    CallFrameInstruction::Expression(Register(RBP), breg_rsp(120, false)),
+                ),
+                (
+                    0,
+                    CallFrameInstruction::Expression(Register(RSP), breg_rsp(160, false)),
+                ),
+                (
+                    0,
+                    CallFrameInstruction::Expression(Register(RIP), breg_rsp(168, false)),
+                ),
+            ],
+        );
+        let regs = [(RSP, 0x8000), (RBP, 0x1)];
+        let memory = [
+            (0x8000 + 120, 0x9100),
+            (0x8000 + 160, 0x9000),
+            (0x8000 + 168, 0x4321),
+        ];
+
+        let (next, signal_frame) = step(&data, 0x1000, &regs, &memory).unwrap();
+        assert!(signal_frame);
+        assert_eq!(next[RIP as usize], Some(0x4321));
+        assert_eq!(next[RSP as usize], Some(0x9000));
+        assert_eq!(next[RBP as usize], Some(0x9100));
+    }
+
+    #[test]
+    fn separate_debug_file_test() {
+        assert_eq!(
+            Some(PathBuf::from("/usr/lib/debug/.build-id/ab/cdef01.debug")),
+            separate_debug_file(&[0xab, 0xcd, 0xef, 0x01])
+        );
+        assert_eq!(None, separate_debug_file(&[0xab]));
+    }
+}
@@ -4,10 +4,10 @@ use crate::{
         kernel::timeval,
         signal::{SI_KERNEL, TRAP_BRKPT},
     },
-    event::{Event, EventType},
+    event::{Event, EventType, SignalDeterministic},
     flags::{DumpOn, Flags},
     kernel_abi::CloneParameterOrdering,
-    kernel_supplement::ARCH_SET_CPUID,
+    kernel_supplement::{sig_set_t, ARCH_SET_CPUID},
     log::LogLevel::{LogDebug, LogWarn},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
@@ -193,6 +193,54 @@ pub fn default_action(sig: i32) -> SignalAction {
     }
 }
 
+/// SIGKILL and SIGSTOP can't be blocked, caught or ignored.
+pub fn is_unstoppable_signal(sig: i32) -> bool {
+    sig == libc::SIGKILL || sig == libc::SIGSTOP
+}
+
+pub fn signal_bit(sig: i32) -> sig_set_t {
+    1 << (sig - 1)
+}
+
+/// Return DeterministicSig if the signal `t` is stopped for was raised by retiring the
+/// instruction at which it stopped (and so will be raised again at the same point during
+/// replay), NondeterministicSig if it was sent by someone.
+pub fn is_deterministic_signal(t: &mut dyn Task) -> SignalDeterministic {
+    let si = t.get_siginfo();
+    match si.si_signo {
+        // These signals may be delivered deterministically; we'll check for sure below.
+        libc::SIGILL | libc::SIGBUS | libc::SIGFPE | libc::SIGSEGV => {
+            // As bits/siginfo.h documents,
+            //
+            //   Values for `si_code'.  Positive values are
+            //   reserved for kernel-generated signals.
+            //
+            // So if the signal is maybe-synchronous, and the
+            // kernel delivered it, then it must have been
+            // delivered deterministically.
+            if si.si_code > 0 {
+                SignalDeterministic::DeterministicSig
+            } else {
+                SignalDeterministic::NondeterministicSig
+            }
+        }
+        libc::SIGTRAP => {
+            // The kernel code is wrong about this one. It treats singlestep
+            // traps as deterministic, but they aren't. PTRACE_ATTACH traps aren't
+            // really deterministic either.
+            let reasons = t.compute_trap_reasons();
+            if reasons.breakpoint || reasons.watchpoint {
+                SignalDeterministic::DeterministicSig
+            } else {
+                SignalDeterministic::NondeterministicSig
+            }
+        }
+        // All other signals can never be delivered deterministically (to the
+        // approximation required by rd).
+        _ => SignalDeterministic::NondeterministicSig,
+    }
+}
+
 // 0 means XSAVE not detected
 pub fn xsave_area_size() -> usize {
     xsave_native_layout().full_size
@@ -258,6 +306,18 @@ pub fn cpuid(code: u32, subrequest: u32) -> CPUIDData {
     }
 }
 
+/// Read the time stamp counter of the CPU we're running on.
+#[cfg(target_arch = "x86")]
+pub fn rdtsc() -> u64 {
+    unsafe { std::arch::x86::_rdtsc() }
+}
+
+/// Read the time stamp counter of the CPU we're running on.
+#[cfg(target_arch = "x86_64")]
+pub fn rdtsc() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
 fn cpuid_record(eax: u32, ecx: u32) -> CPUIDRecord {
     CPUIDRecord {
         eax_in: eax,
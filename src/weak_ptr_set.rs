@@ -1,6 +1,7 @@
 use crate::log::LogLevel::LogDebug;
 use std::{
     cell::RefCell,
+    cmp::Ordering,
     collections::{hash_set::Iter, HashSet},
     hash::{Hash, Hasher},
     ops::Deref,
@@ -26,6 +27,19 @@ impl<T> PartialEq for WeakPtrWrap<T> {
 
 impl<T> Eq for WeakPtrWrap<T> {}
 
+/// Ordered by address, like the pointers in rr's `std::set`s.
+impl<T> Ord for WeakPtrWrap<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.as_ptr().cast::<u8>() as usize).cmp(&(other.0.as_ptr().cast::<u8>() as usize))
+    }
+}
+
+impl<T> PartialOrd for WeakPtrWrap<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl<T> Hash for WeakPtrWrap<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         // We could upgrade the weak pointer and then take numeric address